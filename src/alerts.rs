@@ -0,0 +1,250 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{
+    db::queries::{AlertQueries, Queries},
+    model::{
+        alert::{Alert, AlertRule, AlertStatus},
+        vehicle::Vehicle,
+    },
+    result::AppResult,
+};
+
+/// SoC rise above the threshold needed to resolve an alert automatically
+pub const ALERT_HYSTERESIS_IN_PERCENT: i32 = 5;
+
+/// Minimum time between the resolution of an alert and the next alert of the same rule
+pub const ALERT_COOLDOWN_MINUTES: i64 = 60;
+
+/// New state of the latest alert of the rule for the vehicle, if it changed
+///
+/// An alert is fired once while the condition holds, and resolved only when the SoC is back
+/// above the threshold plus the hysteresis, so that a flapping SoC does not fire new alerts.
+pub fn evaluate_alert_rule(
+    rule: &AlertRule,
+    vin: &str,
+    soc_in_percent: i32,
+    latest_alert: Option<&Alert>,
+    now: DateTime<Utc>,
+) -> Option<Alert> {
+    match latest_alert {
+        Some(alert) if !alert.is_resolved() => {
+            if soc_in_percent < rule.soc_below_in_percent + ALERT_HYSTERESIS_IN_PERCENT {
+                return None;
+            }
+
+            Some(Alert {
+                status: AlertStatus::Resolved,
+                resolved_at: Some(now),
+                ..alert.clone()
+            })
+        }
+        Some(Alert {
+            resolved_at: Some(resolved_at),
+            ..
+        }) if now - *resolved_at < chrono::Duration::minutes(ALERT_COOLDOWN_MINUTES) => None,
+        _ if soc_in_percent < rule.soc_below_in_percent => Some(Alert {
+            id: Uuid::new_v4(),
+            rule_id: rule.id,
+            vin: vin.to_string(),
+            status: AlertStatus::Active,
+            soc_in_percent,
+            fired_at: now,
+            acknowledged_at: None,
+            resolved_at: None,
+        }),
+        _ => None,
+    }
+}
+
+/// Evaluate the rules matching the vehicle against its current SoC
+pub async fn evaluate_alert_rules<Q: Queries>(queries: &Q, vehicle: &Vehicle) -> AppResult<()> {
+    let soc_in_percent = match &vehicle.ev_data {
+        Some(ev_data) => ev_data.soc_in_percent,
+        None => return Ok(()),
+    };
+
+    let rules: Vec<AlertRule> = queries
+        .alert_queries()
+        .find_alert_rules()
+        .await?
+        .into_iter()
+        .filter(|rule| rule.scope.matches(vehicle))
+        .collect();
+    if rules.is_empty() {
+        return Ok(());
+    }
+
+    let alerts = queries
+        .alert_queries()
+        .find_vehicle_alerts(&vehicle.vin)
+        .await?;
+    let now = Utc::now();
+
+    for rule in rules.iter() {
+        let latest_alert = alerts.iter().rev().find(|alert| alert.rule_id == rule.id);
+
+        if let Some(alert) =
+            evaluate_alert_rule(rule, &vehicle.vin, soc_in_percent, latest_alert, now)
+        {
+            tracing::info!(
+                "alert {} of rule {} for vehicle {}: {}",
+                alert.id,
+                rule.id,
+                vehicle.vin,
+                alert.status.to_string()
+            );
+            queries.alert_queries().upsert_alert(&alert).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::{eq, function};
+
+    use super::*;
+    use crate::{
+        db::queries::{self},
+        model::{
+            alert::AlertScope,
+            vehicle::{Engine, EvData},
+        },
+    };
+
+    fn datetime(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn rule(scope: AlertScope) -> AlertRule {
+        AlertRule {
+            id: Uuid::nil(),
+            scope,
+            soc_below_in_percent: 15,
+            created_at: datetime("2021-09-01T00:00:00Z"),
+        }
+    }
+
+    fn active_alert() -> Alert {
+        Alert {
+            id: Uuid::nil(),
+            rule_id: Uuid::nil(),
+            vin: "vin".to_string(),
+            status: AlertStatus::Active,
+            soc_in_percent: 14,
+            fired_at: datetime("2021-09-01T10:00:00Z"),
+            acknowledged_at: None,
+            resolved_at: None,
+        }
+    }
+
+    #[test]
+    fn test_evaluate_alert_rule() {
+        let rule = rule(AlertScope::Fleet);
+        let now = datetime("2021-09-01T12:00:00Z");
+
+        // No alert yet
+        assert_eq!(evaluate_alert_rule(&rule, "vin", 15, None, now), None);
+        let fired = evaluate_alert_rule(&rule, "vin", 14, None, now).unwrap();
+        assert_eq!(fired.status, AlertStatus::Active);
+        assert_eq!(fired.soc_in_percent, 14);
+        assert_eq!(fired.fired_at, now);
+
+        // Flapping around the threshold => no new alert, not resolved
+        let alert = active_alert();
+        assert_eq!(
+            evaluate_alert_rule(&rule, "vin", 13, Some(&alert), now),
+            None
+        );
+        assert_eq!(
+            evaluate_alert_rule(&rule, "vin", 19, Some(&alert), now),
+            None
+        );
+
+        // Back above the hysteresis => resolved
+        assert_eq!(
+            evaluate_alert_rule(&rule, "vin", 20, Some(&alert), now),
+            Some(Alert {
+                status: AlertStatus::Resolved,
+                resolved_at: Some(now),
+                ..active_alert()
+            })
+        );
+
+        // Recently resolved => no new alert until the end of the cooldown
+        let resolved_alert = Alert {
+            status: AlertStatus::Resolved,
+            resolved_at: Some(datetime("2021-09-01T11:30:00Z")),
+            ..active_alert()
+        };
+        assert_eq!(
+            evaluate_alert_rule(&rule, "vin", 10, Some(&resolved_alert), now),
+            None
+        );
+        assert!(evaluate_alert_rule(
+            &rule,
+            "vin",
+            10,
+            Some(&resolved_alert),
+            datetime("2021-09-01T12:30:00Z")
+        )
+        .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_alert_rules() -> anyhow::Result<()> {
+        let vehicle = Vehicle {
+            vin: "vin".to_string(),
+            owner: Some("owner".to_string()),
+            engine: Engine::Ev,
+            ev_data: Some(EvData {
+                battery_capacity_in_kwh: 50,
+                soc_in_percent: 10,
+            }),
+        };
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .alert_queries
+            .expect_find_alert_rules()
+            .returning(|| {
+                Ok(vec![
+                    rule(AlertScope::Owner("owner".to_string())),
+                    AlertRule {
+                        id: Uuid::from_u128(1),
+                        ..rule(AlertScope::EngineType(Engine::Phev))
+                    },
+                    AlertRule {
+                        id: Uuid::from_u128(2),
+                        ..rule(AlertScope::Vin("vin".to_string()))
+                    },
+                ])
+            });
+
+        // The PHEV rule does not match, the VIN rule has already fired
+        mock_queries
+            .alert_queries
+            .expect_find_vehicle_alerts()
+            .with(eq("vin"))
+            .returning(|_| {
+                Ok(vec![Alert {
+                    rule_id: Uuid::from_u128(2),
+                    ..active_alert()
+                }])
+            });
+        mock_queries
+            .alert_queries
+            .expect_upsert_alert()
+            .with(function(|alert: &Alert| {
+                alert.rule_id == Uuid::nil() && alert.status == AlertStatus::Active
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        evaluate_alert_rules(&mock_queries, &vehicle).await?;
+
+        Ok(())
+    }
+}
@@ -2,29 +2,41 @@ use axum::routing::BoxRoute;
 use axum::Router;
 use std::sync::{Arc, RwLock};
 
+use crate::config::Config;
 use crate::db::queries::Queries;
 use crate::routing;
+use crate::shutdown::ShutdownSignal;
 use crate::state::State;
 
 pub struct App<Q: Queries> {
     pub router: Router<BoxRoute>,
 
-    #[allow(dead_code)]
-    shared_state: Arc<RwLock<State>>,
+    pub shared_state: Arc<RwLock<State>>,
 
     #[allow(dead_code)]
     queries: Arc<Q>,
 }
 
 impl<Q: Queries> App<Q> {
-    pub fn new(queries: Arc<Q>) -> Self {
+    /// The event streams (SSE, WebSocket) of the app end on the shutdown
+    pub fn new(queries: Arc<Q>, config: Config, shutdown: ShutdownSignal) -> Self {
         // Shared state
-        let shared_state = Arc::new(RwLock::new(State {}));
+        let shared_state = Arc::new(RwLock::new(State::default()));
 
         App {
-            router: routing::create_router(shared_state.clone(), queries.clone()),
+            router: routing::create_router(
+                shared_state.clone(),
+                queries.clone(),
+                Arc::new(config),
+                shutdown,
+            ),
             shared_state,
             queries,
         }
     }
+
+    /// Router of the app, the other references to the queries are dropped
+    pub fn into_router(self) -> Router<BoxRoute> {
+        self.router
+    }
 }
@@ -0,0 +1,124 @@
+use std::{convert::Infallible, fmt, str::FromStr};
+
+use async_trait::async_trait;
+use axum::extract::{FromRequest, RequestParts};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, error::AppError, rate_limit::API_KEY_HEADER, result::AppResult};
+
+/// Secret API key (not written in the logs)
+#[derive(Clone)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    pub fn new(key: impl Into<String>) -> Self {
+        ApiKey(key.into())
+    }
+
+    /// The digests are compared, so that the comparison time does not depend on the keys
+    pub fn matches(&self, other: &ApiKey) -> bool {
+        Sha256::digest(self.0.as_bytes()) == Sha256::digest(other.0.as_bytes())
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ApiKey(***)")
+    }
+}
+
+impl FromStr for ApiKey {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ApiKey::new(s))
+    }
+}
+
+/// API key of a request (`X-Api-Key` header)
+#[derive(Debug)]
+pub struct RequestApiKey(pub Option<ApiKey>);
+
+impl RequestApiKey {
+    pub fn from_value(value: Option<&[u8]>) -> AppResult<Self> {
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(RequestApiKey(None)),
+        };
+
+        let key = std::str::from_utf8(value).map_err(|_| AppError::InvalidInput("X-Api-Key"))?;
+
+        Ok(RequestApiKey(Some(ApiKey::new(key.trim()))))
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for RequestApiKey
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let value = req
+            .headers()
+            .and_then(|headers| headers.get(API_KEY_HEADER))
+            .map(|value| value.as_bytes());
+
+        RequestApiKey::from_value(value)
+    }
+}
+
+/// Ensure that the request is made with the admin API key (e.g. to purge a vehicle)
+///
+/// The admin operations are forbidden if no admin API key is configured.
+pub fn ensure_admin(api_key: &RequestApiKey, config: &Config) -> AppResult<()> {
+    let admin_api_key = config
+        .admin_api_key
+        .as_ref()
+        .ok_or(AppError::Forbidden("Admin API key not configured"))?;
+
+    match &api_key.0 {
+        Some(api_key) if api_key.matches(admin_api_key) => Ok(()),
+        _ => Err(AppError::Forbidden("Admin API key")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_admin() {
+        let config = Config {
+            admin_api_key: Some(ApiKey::new("admin")),
+            ..Config::default()
+        };
+
+        assert!(ensure_admin(&RequestApiKey(Some(ApiKey::new("admin"))), &config).is_ok());
+
+        // TODO: user assert_matches! when stable
+        assert!(matches!(
+            ensure_admin(&RequestApiKey(Some(ApiKey::new("other"))), &config),
+            Err(AppError::Forbidden("Admin API key"))
+        ));
+        assert!(matches!(
+            ensure_admin(&RequestApiKey(None), &config),
+            Err(AppError::Forbidden("Admin API key"))
+        ));
+
+        // No admin API key => always forbidden
+        assert!(matches!(
+            ensure_admin(
+                &RequestApiKey(Some(ApiKey::new("admin"))),
+                &Config::default()
+            ),
+            Err(AppError::Forbidden("Admin API key not configured"))
+        ));
+    }
+
+    #[test]
+    fn test_api_key_debug() {
+        assert_eq!(format!("{:?}", ApiKey::new("secret")), "ApiKey(***)");
+    }
+}
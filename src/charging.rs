@@ -0,0 +1,438 @@
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, NaiveDate};
+use uuid::Uuid;
+
+use crate::{
+    db::queries::{ChargingQueries, Queries, TelemetryQueries},
+    model::{
+        charging::{ChargingSession, ChargingSummary, WeeklyChargingSummary},
+        telemetry::SocReading,
+        vehicle::Vehicle,
+    },
+    result::AppResult,
+};
+
+/// Minimum SoC rise for a detected charging session
+const MIN_DETECTED_SOC_RISE: i32 = 3;
+
+/// Maximum time between 2 readings of the same detected charging session
+const MAX_DETECTED_READING_GAP_MINUTES: i64 = 60;
+
+/// How far before new readings to look for the start of a detected charging session
+const DETECTION_LOOKBACK_HOURS: i64 = 24;
+
+/// How far before the lookback to look for a stored detected session continued by new readings
+const MAX_DETECTED_SESSION_DAYS: i64 = 7;
+
+/// Detect the charging sessions from the SoC rises of the readings (ordered by timestamp)
+///
+/// Detected sessions get a deterministic id (from VIN and start time), so that detecting the
+/// same session again (e.g. with more readings) replaces it instead of creating a new one.
+pub fn detect_charging_sessions(
+    vin: &str,
+    battery_capacity_in_kwh: i32,
+    readings: &[SocReading],
+) -> Vec<ChargingSession> {
+    let mut sessions = Vec::new();
+
+    let mut start = 0;
+    while start < readings.len() {
+        // Extend the run while the SoC does not decrease, ending on the last rise
+        let mut end = start;
+        let mut current = start;
+        while current + 1 < readings.len() {
+            let (previous, next) = (&readings[current], &readings[current + 1]);
+            if next.soc_in_percent < previous.soc_in_percent
+                || next.timestamp - previous.timestamp
+                    > chrono::Duration::minutes(MAX_DETECTED_READING_GAP_MINUTES)
+            {
+                break;
+            }
+
+            current += 1;
+            if next.soc_in_percent > previous.soc_in_percent {
+                end = current;
+            }
+        }
+
+        let (first, last) = (&readings[start], &readings[end]);
+        if last.soc_in_percent - first.soc_in_percent >= MIN_DETECTED_SOC_RISE {
+            sessions.push(detected_session(
+                vin,
+                battery_capacity_in_kwh,
+                &readings[start..=end],
+            ));
+        }
+
+        start = current + 1;
+    }
+
+    sessions
+}
+
+fn detected_session(
+    vin: &str,
+    battery_capacity_in_kwh: i32,
+    readings: &[SocReading],
+) -> ChargingSession {
+    let energy_in_kwh = |soc_rise: i32| soc_rise as f64 * battery_capacity_in_kwh as f64 / 100.0;
+
+    let max_power_in_kw = readings
+        .windows(2)
+        .map(|pair| {
+            let hours = (pair[1].timestamp - pair[0].timestamp).num_seconds() as f64 / 3600.0;
+            energy_in_kwh(pair[1].soc_in_percent - pair[0].soc_in_percent) / hours
+        })
+        .filter(|power| power.is_finite())
+        .fold(None, |max: Option<f64>, power| {
+            Some(max.map_or(power, |max| max.max(power)))
+        })
+        .filter(|_| battery_capacity_in_kwh > 0);
+
+    // Never empty: detected sessions contain at least 2 readings
+    let (first, last) = (&readings[0], &readings[readings.len() - 1]);
+    let id = Uuid::new_v5(
+        &Uuid::NAMESPACE_OID,
+        format!("{}/{}", vin, first.timestamp.timestamp_millis()).as_bytes(),
+    );
+
+    ChargingSession {
+        id,
+        vin: vin.to_string(),
+        start_time: first.timestamp,
+        end_time: Some(last.timestamp),
+        start_soc_in_percent: first.soc_in_percent,
+        end_soc_in_percent: Some(last.soc_in_percent),
+        energy_added_in_kwh: energy_in_kwh(last.soc_in_percent - first.soc_in_percent),
+        max_power_in_kw,
+        location: None,
+        detected: true,
+    }
+}
+
+/// Detect and store the charging sessions around the new readings
+///
+/// The readings are read from the start of the stored detected session continued by the new
+/// readings (if any), so that the session keeps its id instead of being detected again from a
+/// later reading. Detected sessions overlapping a session reported by the vehicle are ignored.
+pub async fn detect_charging_sessions_from_telemetry<Q: Queries>(
+    queries: &Q,
+    vehicle: &Vehicle,
+    new_readings: &[SocReading],
+) -> AppResult<()> {
+    let (oldest, newest) = match (
+        new_readings.iter().map(|reading| reading.timestamp).min(),
+        new_readings.iter().map(|reading| reading.timestamp).max(),
+    ) {
+        (Some(oldest), Some(newest)) => (oldest, newest),
+        _ => return Ok(()),
+    };
+    let mut from = oldest - chrono::Duration::hours(DETECTION_LOOKBACK_HOURS);
+    let to = newest + chrono::Duration::milliseconds(1);
+
+    let stored_sessions = queries
+        .charging_queries()
+        .find_charging_sessions(
+            &vehicle.vin,
+            from - chrono::Duration::days(MAX_DETECTED_SESSION_DAYS),
+            to,
+        )
+        .await?;
+    let continued_session_start = stored_sessions
+        .iter()
+        .filter(|session| {
+            session.detected
+                && session.start_time < from
+                && session.end_time.map_or(true, |end_time| {
+                    end_time >= from - chrono::Duration::minutes(MAX_DETECTED_READING_GAP_MINUTES)
+                })
+        })
+        .map(|session| session.start_time)
+        .min();
+    if let Some(start_time) = continued_session_start {
+        from = start_time;
+    }
+
+    let readings = queries
+        .telemetry_queries()
+        .find_soc_readings(&vehicle.vin, from, to)
+        .await?;
+    let battery_capacity_in_kwh = vehicle
+        .ev_data
+        .as_ref()
+        .map_or(0, |ev_data| ev_data.battery_capacity_in_kwh);
+    let detected_sessions =
+        detect_charging_sessions(&vehicle.vin, battery_capacity_in_kwh, &readings);
+    if detected_sessions.is_empty() {
+        return Ok(());
+    }
+
+    let reported_sessions: Vec<&ChargingSession> = stored_sessions
+        .iter()
+        .filter(|session| !session.detected)
+        .collect();
+
+    for detected_session in detected_sessions.iter() {
+        if reported_sessions
+            .iter()
+            .any(|reported_session| reported_session.overlaps(detected_session))
+        {
+            continue;
+        }
+
+        queries
+            .charging_queries()
+            .upsert_charging_session(detected_session)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Summarize the sessions per (ISO) week
+pub fn summarize_charging_sessions(sessions: &[ChargingSession]) -> ChargingSummary {
+    let mut weeks: BTreeMap<NaiveDate, WeeklyChargingSummary> = BTreeMap::new();
+
+    for session in sessions {
+        let day = session.start_time.naive_utc().date();
+        let week_start = day - chrono::Duration::days(day.weekday().num_days_from_monday() as i64);
+
+        let week = weeks
+            .entry(week_start)
+            .or_insert_with(|| WeeklyChargingSummary {
+                week_start,
+                session_count: 0,
+                energy_in_kwh: 0.0,
+            });
+        week.session_count += 1;
+        week.energy_in_kwh += session.energy_added_in_kwh;
+    }
+
+    ChargingSummary {
+        session_count: sessions.len(),
+        total_energy_in_kwh: sessions
+            .iter()
+            .map(|session| session.energy_added_in_kwh)
+            .sum(),
+        weeks: weeks.into_iter().map(|(_, week)| week).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use mockall::predicate::{always, eq, function};
+
+    use super::*;
+    use crate::{
+        db::queries::{self},
+        model::vehicle::{Engine, EvData},
+    };
+
+    fn datetime(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn reading(timestamp: &str, soc_in_percent: i32) -> SocReading {
+        SocReading {
+            timestamp: datetime(timestamp),
+            soc_in_percent,
+        }
+    }
+
+    fn session(start_time: &str, energy_added_in_kwh: f64) -> ChargingSession {
+        ChargingSession {
+            id: Uuid::new_v4(),
+            vin: "vin".to_string(),
+            start_time: datetime(start_time),
+            end_time: None,
+            start_soc_in_percent: 20,
+            end_soc_in_percent: None,
+            energy_added_in_kwh,
+            max_power_in_kw: None,
+            location: None,
+            detected: false,
+        }
+    }
+
+    #[test]
+    fn test_detect_charging_sessions() {
+        let readings = vec![
+            // Driving
+            reading("2021-09-01T08:00:00Z", 60),
+            reading("2021-09-01T08:30:00Z", 40),
+            // Charging (then parked)
+            reading("2021-09-01T09:00:00Z", 40),
+            reading("2021-09-01T09:30:00Z", 60),
+            reading("2021-09-01T10:00:00Z", 80),
+            reading("2021-09-01T10:30:00Z", 80),
+            // Small rise (e.g. regenerative braking)
+            reading("2021-09-01T11:00:00Z", 78),
+            reading("2021-09-01T11:10:00Z", 79),
+            // Gap in the readings
+            reading("2021-09-01T13:00:00Z", 90),
+        ];
+
+        let sessions = detect_charging_sessions("vin", 50, &readings);
+        assert_eq!(sessions.len(), 1);
+
+        let session = &sessions[0];
+        assert_eq!(session.start_time, datetime("2021-09-01T08:30:00Z"));
+        assert_eq!(session.end_time, Some(datetime("2021-09-01T10:00:00Z")));
+        assert_eq!(session.start_soc_in_percent, 40);
+        assert_eq!(session.end_soc_in_percent, Some(80));
+        assert_eq!(session.energy_added_in_kwh, 20.0);
+        assert_eq!(session.max_power_in_kw, Some(20.0));
+        assert!(session.detected);
+
+        // Detecting the same session again gives the same id
+        assert_eq!(
+            detect_charging_sessions("vin", 50, &readings[1..6])[0].id,
+            session.id
+        );
+
+        // Unknown battery capacity => no energy nor power
+        let sessions = detect_charging_sessions("vin", 0, &readings);
+        assert_eq!(sessions[0].energy_added_in_kwh, 0.0);
+        assert_eq!(sessions[0].max_power_in_kw, None);
+    }
+
+    #[tokio::test]
+    async fn test_detect_charging_sessions_from_telemetry() -> anyhow::Result<()> {
+        let vehicle = Vehicle {
+            vin: "vin".to_string(),
+            owner: None,
+            engine: Engine::Ev,
+            ev_data: Some(EvData {
+                battery_capacity_in_kwh: 50,
+                soc_in_percent: 80,
+            }),
+        };
+        let readings = vec![
+            reading("2021-09-01T09:00:00Z", 40),
+            reading("2021-09-01T10:00:00Z", 80),
+            reading("2021-09-01T15:00:00Z", 60),
+            reading("2021-09-01T16:00:00Z", 70),
+        ];
+        let readings_clone = readings.clone();
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .telemetry_queries
+            .expect_find_soc_readings()
+            .with(
+                eq("vin"),
+                eq(datetime("2021-08-31T16:00:00Z")),
+                eq(datetime("2021-09-01T16:00:00.001Z")),
+            )
+            .returning(move |_, _, _| Ok(readings_clone.clone()));
+        mock_queries
+            .charging_queries
+            .expect_find_charging_sessions()
+            .with(eq("vin"), always(), always())
+            .returning(|_, _, _| {
+                Ok(vec![ChargingSession {
+                    end_time: Some(datetime("2021-09-01T16:30:00Z")),
+                    ..session("2021-09-01T14:55:00Z", 5.0)
+                }])
+            });
+
+        // Only the first session is stored, the second one has been reported by the vehicle
+        mock_queries
+            .charging_queries
+            .expect_upsert_charging_session()
+            .with(function(|session: &ChargingSession| {
+                session.detected && session.start_time == datetime("2021-09-01T09:00:00Z")
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        detect_charging_sessions_from_telemetry(&mock_queries, &vehicle, &readings[3..]).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_detect_charging_sessions_from_telemetry_continued() -> anyhow::Result<()> {
+        let vehicle = Vehicle {
+            vin: "vin".to_string(),
+            owner: None,
+            engine: Engine::Ev,
+            ev_data: Some(EvData {
+                battery_capacity_in_kwh: 50,
+                soc_in_percent: 65,
+            }),
+        };
+
+        // Slow charging for 25 hours, one reading per hour
+        let start_time = datetime("2021-09-01T10:00:00Z");
+        let readings: Vec<SocReading> = (0..=25)
+            .map(|hour| SocReading {
+                timestamp: start_time + chrono::Duration::hours(hour),
+                soc_in_percent: 40 + hour as i32,
+            })
+            .collect();
+        let readings_clone = readings.clone();
+        let stored_session = detect_charging_sessions("vin", 50, &readings[..25])[0].clone();
+        let stored_session_id = stored_session.id;
+
+        // The lookback starts after the stored session => readings from its start
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .charging_queries
+            .expect_find_charging_sessions()
+            .returning(move |_, _, _| Ok(vec![stored_session.clone()]));
+        mock_queries
+            .telemetry_queries
+            .expect_find_soc_readings()
+            .with(eq("vin"), eq(start_time), always())
+            .returning(move |_, _, _| Ok(readings_clone.clone()));
+
+        // The stored session is extended (same id)
+        mock_queries
+            .charging_queries
+            .expect_upsert_charging_session()
+            .with(function(move |session: &ChargingSession| {
+                session.id == stored_session_id
+                    && session.end_time == Some(datetime("2021-09-02T11:00:00Z"))
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        detect_charging_sessions_from_telemetry(&mock_queries, &vehicle, &readings[25..]).await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_summarize_charging_sessions() {
+        let sessions = vec![
+            // Wednesday, Sunday and Monday
+            session("2021-09-01T10:00:00Z", 10.0),
+            session("2021-09-05T10:00:00Z", 20.0),
+            session("2021-09-06T10:00:00Z", 5.5),
+        ];
+
+        assert_eq!(
+            summarize_charging_sessions(&sessions),
+            ChargingSummary {
+                session_count: 3,
+                total_energy_in_kwh: 35.5,
+                weeks: vec![
+                    WeeklyChargingSummary {
+                        week_start: NaiveDate::from_ymd_opt(2021, 8, 30).unwrap(),
+                        session_count: 2,
+                        energy_in_kwh: 30.0,
+                    },
+                    WeeklyChargingSummary {
+                        week_start: NaiveDate::from_ymd_opt(2021, 9, 6).unwrap(),
+                        session_count: 1,
+                        energy_in_kwh: 5.5,
+                    },
+                ],
+            }
+        );
+        assert_eq!(summarize_charging_sessions(&[]), ChargingSummary::default());
+    }
+}
@@ -0,0 +1,94 @@
+use axum::http::{header, HeaderName, HeaderValue, Method};
+
+use crate::{
+    auth::ApiKey,
+    idempotency::IDEMPOTENCY_KEY_HEADER,
+    rate_limit::{RateLimitRule, API_KEY_HEADER},
+};
+
+/// Application settings (from the command line)
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// EV consumption used when a vehicle has neither a configured nor a learned consumption
+    pub default_consumption_in_kwh_per_100km: f64,
+
+    /// Battery state of health below which a vehicle is flagged in the fleet report
+    pub battery_health_alert_threshold_in_percent: f64,
+
+    /// Maximum number of VINs of a batch request
+    pub max_batch_size: usize,
+
+    /// Maximum number of SoC readings of a telemetry batch
+    pub max_telemetry_batch_size: usize,
+
+    /// Limits of the requests of each client (the first rule matching a request applies)
+    pub rate_limits: Vec<RateLimitRule>,
+
+    /// Store the rate limit buckets in the database, to share them between the instances
+    pub rate_limit_shared: bool,
+
+    /// Header of the IP address of the client, set by the trusted reverse proxy in front (e.g.
+    /// `X-Forwarded-For`), used to rate limit the clients, e.g. of a Unix socket
+    pub rate_limit_client_ip_header: Option<HeaderName>,
+
+    /// API keys of the known clients, rate limited by API key (the other ones by IP address)
+    pub api_keys: Vec<ApiKey>,
+
+    /// Origins allowed to call the API from a browser (`*` for any origin, none to disable CORS)
+    pub cors_allowed_origins: Vec<HeaderValue>,
+
+    /// Methods allowed in CORS requests
+    pub cors_allowed_methods: Vec<Method>,
+
+    /// Request headers allowed in CORS requests
+    pub cors_allowed_headers: Vec<HeaderName>,
+
+    /// Compress the responses (gzip, brotli, zstd or deflate, negotiated with `Accept-Encoding`)
+    pub compression: bool,
+
+    /// Maximum size in bytes of a request body (after decompression)
+    pub max_body_size: usize,
+
+    /// Maximum size in bytes of the body of an import run within the request (after
+    /// decompression), the larger imports are submitted as jobs
+    pub max_sync_import_body_size: usize,
+
+    /// Maximum size in bytes of an import job body (after decompression)
+    pub max_import_body_size: usize,
+
+    /// API key of the admin operations, e.g. purging a vehicle (forbidden if not set)
+    pub admin_api_key: Option<ApiKey>,
+
+    /// Allow the webhooks to call loopback, private and link-local hosts (e.g. for development)
+    pub webhook_allow_private_hosts: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            default_consumption_in_kwh_per_100km: 18.0,
+            battery_health_alert_threshold_in_percent: 80.0,
+            max_batch_size: 100,
+            max_telemetry_batch_size: 1000,
+            rate_limits: vec![],
+            rate_limit_shared: false,
+            rate_limit_client_ip_header: None,
+            api_keys: vec![],
+            cors_allowed_origins: vec![],
+            cors_allowed_methods: vec![Method::GET, Method::POST, Method::PUT, Method::DELETE],
+            cors_allowed_headers: vec![
+                header::ACCEPT,
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                HeaderName::from_static(API_KEY_HEADER),
+                HeaderName::from_static(IDEMPOTENCY_KEY_HEADER),
+            ],
+            compression: true,
+            max_body_size: 1024 * 1024,
+            max_sync_import_body_size: 512 * 1024,
+            max_import_body_size: 256 * 1024 * 1024,
+            admin_api_key: None,
+            webhook_allow_private_hosts: false,
+        }
+    }
+}
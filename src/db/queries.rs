@@ -1,6 +1,26 @@
+use std::{collections::HashMap, time::Duration};
+
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use uuid::Uuid;
 
-use crate::{model::vehicle::Vehicle, result::AppResult};
+use crate::{
+    events::EventBus,
+    model::{
+        alert::{Alert, AlertRule},
+        charging::ChargingSession,
+        ev::{BatteryHealth, EvConsumption, Trip},
+        idempotency::IdempotentResponse,
+        job::{Job, JobChunk},
+        outbox::OutboxEntry,
+        rate_limit::TokenBucket,
+        telemetry::{AggregateInterval, SocAggregate, SocReading},
+        vehicle::{EvData, Vehicle},
+        webhook::{Webhook, WebhookDelivery},
+    },
+    result::AppResult,
+};
 
 /// Define all the queries for DB abstraction
 ///
@@ -9,14 +29,384 @@ use crate::{model::vehicle::Vehicle, result::AppResult};
 /// - Mocked database (for tests)
 pub trait Queries: std::fmt::Debug + Send + Sync + 'static {
     type VQ: VehicleQueries;
+    type TQ: TelemetryQueries;
+    type CQ: ChargingQueries;
+    type EQ: EvQueries;
+    type AQ: AlertQueries;
+    type WQ: WebhookQueries;
+    type OQ: OutboxQueries;
+    type JQ: JobQueries;
+    type IQ: IdempotencyQueries;
+    type RQ: RateLimitQueries;
 
     fn vehicle_queries(&self) -> &Self::VQ;
+    fn telemetry_queries(&self) -> &Self::TQ;
+    fn charging_queries(&self) -> &Self::CQ;
+    fn ev_queries(&self) -> &Self::EQ;
+    fn alert_queries(&self) -> &Self::AQ;
+    fn webhook_queries(&self) -> &Self::WQ;
+    fn outbox_queries(&self) -> &Self::OQ;
+    fn job_queries(&self) -> &Self::JQ;
+    fn idempotency_queries(&self) -> &Self::IQ;
+    fn rate_limit_queries(&self) -> &Self::RQ;
+
+    /// Bus of the vehicle changes and telemetry, published by the vehicle and telemetry queries
+    fn event_bus(&self) -> &EventBus;
 }
 
 #[mockall::automock]
 #[async_trait]
 pub trait VehicleQueries: std::fmt::Debug + Send + Sync + 'static {
     async fn create_vehicle(&self, vehicle: &Vehicle) -> AppResult<()>;
+
+    /// Deleted vehicles are not found
     async fn find_one_vehicle(&self, vin: &str) -> AppResult<Vehicle>;
+
+    /// Replace the EV data of an existing vehicle
+    async fn update_vehicle_ev_data(&self, vin: &str, ev_data: &EvData) -> AppResult<()>;
+
+    /// Mark the vehicle as deleted (soft delete), it can be restored until purged
     async fn delete_one_vehicle(&self, vin: &str) -> AppResult<()>;
+
+    /// Restore a (soft) deleted vehicle
+    async fn restore_one_vehicle(&self, vin: &str) -> AppResult<Vehicle>;
+
+    /// Remove the vehicle from the database (hard delete), whether it has been deleted or not
+    async fn purge_one_vehicle(&self, vin: &str) -> AppResult<()>;
+
+    /// Remove all the vehicles deleted before the given time, returns the number of purged vehicles
+    async fn purge_deleted_vehicles(&self, deleted_before: DateTime<Utc>) -> AppResult<usize>;
+
+    /// All the (not deleted) vehicles, streamed from the database page by page
+    async fn find_all_vehicles(&self) -> AppResult<BoxStream<'static, AppResult<Vehicle>>>;
+
+    /// The (not deleted) vehicles among the VINs, read in one query (unknown VINs are skipped)
+    async fn find_vehicles(&self, vins: &[String]) -> AppResult<Vec<Vehicle>>;
+
+    /// Mark the vehicles as deleted, concurrently, returns one result per VIN (in order)
+    async fn delete_vehicles(&self, vins: &[String]) -> Vec<AppResult<()>>;
+
+    /// Up to `limit` rows following the row of `after_vin` (in token order), deleted vehicles
+    /// skipped
+    ///
+    /// Also returns the VIN of the last row read, to continue from (None at the end of the table).
+    async fn find_vehicles_page(
+        &self,
+        after_vin: Option<String>,
+        limit: i32,
+    ) -> AppResult<(Vec<Vehicle>, Option<String>)>;
+}
+
+#[mockall::automock]
+#[async_trait]
+pub trait TelemetryQueries: std::fmt::Debug + Send + Sync + 'static {
+    async fn insert_soc_readings(&self, vin: &str, readings: &[SocReading]) -> AppResult<()>;
+
+    /// Readings in [from, to), ordered by timestamp
+    async fn find_soc_readings(
+        &self,
+        vin: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> AppResult<Vec<SocReading>>;
+
+    /// Most recent reading ever inserted for the vehicle
+    async fn find_latest_soc_reading(&self, vin: &str) -> AppResult<Option<SocReading>>;
+
+    /// Most recent reading of each of the vehicles, read in one query (by VIN)
+    async fn find_latest_soc_readings(
+        &self,
+        vins: &[String],
+    ) -> AppResult<HashMap<String, SocReading>>;
+
+    /// VINs of all the vehicles with at least one reading
+    async fn find_vins_with_telemetry(&self) -> AppResult<Vec<String>>;
+
+    /// Insert or replace pre-computed aggregates
+    async fn upsert_soc_aggregates(
+        &self,
+        vin: &str,
+        interval: AggregateInterval,
+        aggregates: &[SocAggregate],
+    ) -> AppResult<()>;
+
+    /// Pre-computed aggregates starting in [from, to), ordered by start
+    async fn find_soc_aggregates(
+        &self,
+        vin: &str,
+        interval: AggregateInterval,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> AppResult<Vec<SocAggregate>>;
+}
+
+#[mockall::automock]
+#[async_trait]
+pub trait ChargingQueries: std::fmt::Debug + Send + Sync + 'static {
+    /// Insert or replace the session (identified by VIN and id)
+    async fn upsert_charging_session(&self, session: &ChargingSession) -> AppResult<()>;
+
+    async fn find_one_charging_session(&self, vin: &str, id: Uuid) -> AppResult<ChargingSession>;
+
+    /// Sessions of the vehicle started in [from, to), ordered by start time
+    async fn find_charging_sessions(
+        &self,
+        vin: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> AppResult<Vec<ChargingSession>>;
+
+    /// Sessions of all the vehicles started in [from, to)
+    async fn find_all_charging_sessions(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> AppResult<Vec<ChargingSession>>;
+}
+
+#[mockall::automock]
+#[async_trait]
+pub trait EvQueries: std::fmt::Debug + Send + Sync + 'static {
+    /// Default (empty) consumption if nothing has been configured or reported yet
+    async fn find_ev_consumption(&self, vin: &str) -> AppResult<EvConsumption>;
+
+    /// Set (or unset) the configured consumption
+    async fn configure_ev_consumption(
+        &self,
+        vin: &str,
+        consumption_in_kwh_per_100km: Option<f64>,
+    ) -> AppResult<()>;
+
+    /// Add the trip to the statistics used to learn the consumption
+    async fn add_ev_trip(&self, vin: &str, trip: &Trip) -> AppResult<()>;
+
+    /// Insert or replace the measurement (identified by VIN and measurement time)
+    async fn insert_battery_health(&self, health: &BatteryHealth) -> AppResult<()>;
+
+    /// Measurements of the vehicle, ordered by measurement time
+    async fn find_battery_health(&self, vin: &str) -> AppResult<Vec<BatteryHealth>>;
+
+    /// Measurements of all the vehicles
+    async fn find_all_battery_health(&self) -> AppResult<Vec<BatteryHealth>>;
+}
+
+#[mockall::automock]
+#[async_trait]
+pub trait AlertQueries: std::fmt::Debug + Send + Sync + 'static {
+    async fn create_alert_rule(&self, rule: &AlertRule) -> AppResult<()>;
+
+    /// The rules changed by another instance may only be returned after a few seconds (cached)
+    async fn find_alert_rules(&self) -> AppResult<Vec<AlertRule>>;
+
+    async fn delete_alert_rule(&self, id: Uuid) -> AppResult<()>;
+
+    /// Insert or replace the alert (identified by VIN and id)
+    async fn upsert_alert(&self, alert: &Alert) -> AppResult<()>;
+
+    async fn find_one_alert(&self, vin: &str, id: Uuid) -> AppResult<Alert>;
+
+    /// Alerts of the vehicle, ordered by firing time
+    async fn find_vehicle_alerts(&self, vin: &str) -> AppResult<Vec<Alert>>;
+
+    /// Alerts of all the vehicles, ordered by firing time
+    async fn find_all_alerts(&self) -> AppResult<Vec<Alert>>;
+}
+
+#[mockall::automock]
+#[async_trait]
+pub trait WebhookQueries: std::fmt::Debug + Send + Sync + 'static {
+    async fn create_webhook(&self, webhook: &Webhook) -> AppResult<()>;
+
+    async fn find_webhooks(&self) -> AppResult<Vec<Webhook>>;
+
+    async fn find_one_webhook(&self, id: Uuid) -> AppResult<Webhook>;
+
+    /// Replace the URL, secret and events of an existing webhook
+    async fn update_webhook(&self, webhook: &Webhook) -> AppResult<()>;
+
+    async fn delete_webhook(&self, id: Uuid) -> AppResult<()>;
+
+    /// Insert the delivery if it does not exist yet (`AlreadyExists` otherwise), kept for 30 days
+    async fn insert_delivery(&self, delivery: &WebhookDelivery) -> AppResult<()>;
+
+    /// Insert or replace the delivery (identified by webhook id and id), kept for 30 days
+    async fn upsert_delivery(&self, delivery: &WebhookDelivery) -> AppResult<()>;
+
+    async fn find_one_delivery(&self, webhook_id: Uuid, id: Uuid) -> AppResult<WebhookDelivery>;
+
+    /// Delivery log of the webhook, ordered by creation time
+    async fn find_deliveries(&self, webhook_id: Uuid) -> AppResult<Vec<WebhookDelivery>>;
+
+    /// Pending deliveries of all the webhooks which are due at the given time
+    async fn find_due_deliveries(&self, now: DateTime<Utc>) -> AppResult<Vec<WebhookDelivery>>;
+}
+
+/// Entries written by the vehicle queries, in the same batch as each vehicle mutation
+#[mockall::automock]
+#[async_trait]
+pub trait OutboxQueries: std::fmt::Debug + Send + Sync + 'static {
+    /// Oldest entries of the bucket in the time window, ordered by time of the change
+    async fn find_outbox_entries(
+        &self,
+        bucket: i32,
+        time_window: i64,
+        limit: usize,
+    ) -> AppResult<Vec<OutboxEntry>>;
+
+    /// First time window of the bucket which may still have entries to relay
+    async fn find_outbox_cursor(&self, bucket: i32) -> AppResult<Option<i64>>;
+
+    async fn update_outbox_cursor(&self, bucket: i32, time_window: i64) -> AppResult<()>;
+
+    /// Remove a delivered entry
+    async fn delete_outbox_entry(&self, entry: &OutboxEntry) -> AppResult<()>;
+}
+
+#[mockall::automock]
+#[async_trait]
+pub trait JobQueries: std::fmt::Debug + Send + Sync + 'static {
+    async fn create_job(&self, job: &Job) -> AppResult<()>;
+
+    async fn find_one_job(&self, id: Uuid) -> AppResult<Job>;
+
+    /// Pending and running jobs whose lease is not held by a worker, ordered by creation time
+    async fn find_unfinished_jobs(&self) -> AppResult<Vec<Job>>;
+
+    /// Lease an unfinished job to the owner (worker) until the given time, unless its lease is
+    /// held by another owner, returns whether the job has been claimed
+    async fn claim_job(&self, id: Uuid, owner: Uuid, until: DateTime<Utc>) -> AppResult<bool>;
+
+    /// Extend the lease of a job held by the owner, returns false if the lease has been lost
+    async fn renew_job_lease(&self, id: Uuid, owner: Uuid, until: DateTime<Utc>)
+        -> AppResult<bool>;
+
+    /// Replace the status, progress (including the total), result and error of an existing job
+    /// (not the cancellation)
+    async fn update_job_state(&self, job: &Job) -> AppResult<()>;
+
+    /// Request the cancellation of an existing job, applied by the worker at the next chunk
+    async fn cancel_job(&self, id: Uuid) -> AppResult<()>;
+
+    async fn insert_job_chunk(&self, job_id: Uuid, chunk: &JobChunk) -> AppResult<()>;
+
+    /// Insert a chunk of the output of the job (export), expired after a few days
+    async fn insert_job_output_chunk(&self, job_id: Uuid, chunk: &JobChunk) -> AppResult<()>;
+
+    async fn find_job_chunk(&self, job_id: Uuid, index: i32) -> AppResult<JobChunk>;
+
+    /// Remove the input or output of the job
+    async fn delete_job_chunks(&self, job_id: Uuid) -> AppResult<()>;
+}
+
+#[mockall::automock]
+#[async_trait]
+pub trait IdempotencyQueries: std::fmt::Debug + Send + Sync + 'static {
+    /// Expired responses are not found
+    async fn find_idempotent_response(&self, key: &str) -> AppResult<Option<IdempotentResponse>>;
+
+    /// Store the pending response (claim of the key) unless the key is already claimed, expired
+    /// after the claim TTL; returns the response (possibly pending) already stored, if any
+    async fn claim_idempotency_key(
+        &self,
+        pending: &IdempotentResponse,
+    ) -> AppResult<Option<IdempotentResponse>>;
+
+    /// Store the response of a request (replacing its claim), expired after the TTL of the table
+    async fn insert_idempotent_response(&self, response: &IdempotentResponse) -> AppResult<()>;
+
+    /// Release the claim of a key, e.g. the request has failed and can be retried
+    async fn delete_idempotency_key(&self, key: &str) -> AppResult<()>;
+}
+
+#[mockall::automock]
+#[async_trait]
+pub trait RateLimitQueries: std::fmt::Debug + Send + Sync + 'static {
+    /// Expired buckets (i.e. full again) are not found
+    async fn find_token_bucket(&self, key: &str) -> AppResult<Option<TokenBucket>>;
+
+    /// Replace the bucket, expired after `ttl` (the time to refill it), unless it has been changed
+    /// since it has been read (updated at `read_updated_at`, or not found); returns whether it
+    /// has been replaced
+    async fn save_token_bucket(
+        &self,
+        key: &str,
+        bucket: &TokenBucket,
+        read_updated_at: Option<DateTime<Utc>>,
+        ttl: Duration,
+    ) -> AppResult<bool>;
+}
+
+/// Mocked queries (for tests)
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockQueries {
+    pub vehicle_queries: MockVehicleQueries,
+    pub telemetry_queries: MockTelemetryQueries,
+    pub charging_queries: MockChargingQueries,
+    pub ev_queries: MockEvQueries,
+    pub alert_queries: MockAlertQueries,
+    pub webhook_queries: MockWebhookQueries,
+    pub outbox_queries: MockOutboxQueries,
+    pub job_queries: MockJobQueries,
+    pub idempotency_queries: MockIdempotencyQueries,
+    pub rate_limit_queries: MockRateLimitQueries,
+    pub event_bus: EventBus,
+}
+
+#[cfg(test)]
+impl Queries for MockQueries {
+    type VQ = MockVehicleQueries;
+    type TQ = MockTelemetryQueries;
+    type CQ = MockChargingQueries;
+    type EQ = MockEvQueries;
+    type AQ = MockAlertQueries;
+    type WQ = MockWebhookQueries;
+    type OQ = MockOutboxQueries;
+    type JQ = MockJobQueries;
+    type IQ = MockIdempotencyQueries;
+    type RQ = MockRateLimitQueries;
+
+    fn vehicle_queries(&self) -> &Self::VQ {
+        &self.vehicle_queries
+    }
+
+    fn telemetry_queries(&self) -> &Self::TQ {
+        &self.telemetry_queries
+    }
+
+    fn charging_queries(&self) -> &Self::CQ {
+        &self.charging_queries
+    }
+
+    fn ev_queries(&self) -> &Self::EQ {
+        &self.ev_queries
+    }
+
+    fn alert_queries(&self) -> &Self::AQ {
+        &self.alert_queries
+    }
+
+    fn webhook_queries(&self) -> &Self::WQ {
+        &self.webhook_queries
+    }
+
+    fn outbox_queries(&self) -> &Self::OQ {
+        &self.outbox_queries
+    }
+
+    fn job_queries(&self) -> &Self::JQ {
+        &self.job_queries
+    }
+
+    fn idempotency_queries(&self) -> &Self::IQ {
+        &self.idempotency_queries
+    }
+
+    fn rate_limit_queries(&self) -> &Self::RQ {
+        &self.rate_limit_queries
+    }
+
+    fn event_bus(&self) -> &EventBus {
+        &self.event_bus
+    }
 }
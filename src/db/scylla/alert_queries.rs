@@ -0,0 +1,459 @@
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::StreamExt;
+use scylla::cql_to_rust::{FromCqlVal, FromRow};
+use scylla::{prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::{
+    db::{queries::AlertQueries, scylla::is_applied},
+    error::AppError,
+    model::{
+        alert::{Alert, AlertRule, AlertScope, AlertStatus},
+        vehicle::Engine,
+    },
+    result::AppResult,
+};
+
+/// The alert rules are evaluated on every vehicle write and telemetry batch: they are cached
+/// for this duration (the rules changed by the other instances are applied after this delay)
+pub const ALERT_RULES_CACHE_TTL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct CachedAlertRules {
+    rules: Option<(Instant, Vec<AlertRule>)>,
+    /// Incremented on each change of the rules, so that a read started before is not cached
+    generation: u64,
+}
+
+/// Alert rules of the last read, until they expire or are changed by this instance
+#[derive(Default)]
+struct AlertRulesCache(RwLock<CachedAlertRules>);
+
+impl AlertRulesCache {
+    fn get(&self, now: Instant) -> Result<Vec<AlertRule>, u64> {
+        let cached = self.0.read().expect("alert rules lock poisoned");
+        match &cached.rules {
+            Some((loaded_at, rules)) if now.duration_since(*loaded_at) < ALERT_RULES_CACHE_TTL => {
+                Ok(rules.clone())
+            }
+            _ => Err(cached.generation),
+        }
+    }
+
+    /// Cache the rules read at `loaded_at`, unless they changed since `generation`
+    fn set(&self, rules: Vec<AlertRule>, loaded_at: Instant, generation: u64) {
+        let mut cached = self.0.write().expect("alert rules lock poisoned");
+        if cached.generation == generation {
+            cached.rules = Some((loaded_at, rules));
+        }
+    }
+
+    fn invalidate(&self) {
+        let mut cached = self.0.write().expect("alert rules lock poisoned");
+        cached.rules = None;
+        cached.generation += 1;
+    }
+}
+
+pub struct ScyllaAlertQueries {
+    session: Arc<Session>,
+    alert_rules_cache: AlertRulesCache,
+    insert_alert_rule_statement: PreparedStatement,
+    select_alert_rules_statement: PreparedStatement,
+    delete_alert_rule_statement: PreparedStatement,
+    upsert_alert_statement: PreparedStatement,
+    select_alert_statement: PreparedStatement,
+    select_vehicle_alerts_statement: PreparedStatement,
+    select_all_alerts_statement: PreparedStatement,
+}
+
+impl std::fmt::Debug for ScyllaAlertQueries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScyllaAlertQueries").finish()
+    }
+}
+
+impl ScyllaAlertQueries {
+    pub async fn try_new(session: Arc<Session>) -> AppResult<Self> {
+        // Prepare "insert alert rule" statement
+        let cql = format!(
+            "INSERT INTO alert_rules ({}) VALUES ({})",
+            AlertRuleRow::FIELDS.join(","),
+            vec!["?"; AlertRuleRow::FIELDS.len()].join(",")
+        );
+        let insert_alert_rule_statement = session.prepare(cql).await?;
+
+        // Prepare "select alert rules" statement
+        // Note: full table scan, the number of rules is expected to be small
+        let cql = format!("SELECT {} from alert_rules", AlertRuleRow::FIELDS.join(","));
+        let select_alert_rules_statement = session.prepare(cql).await?;
+
+        // Prepare "delete alert rule" statement
+        let cql = "DELETE from alert_rules where id = ? IF EXISTS";
+        let delete_alert_rule_statement = session.prepare(cql).await?;
+
+        // Prepare "upsert alert" statement
+        let cql = format!(
+            "INSERT INTO alerts ({}) VALUES ({})",
+            AlertRow::FIELDS.join(","),
+            vec!["?"; AlertRow::FIELDS.len()].join(",")
+        );
+        let upsert_alert_statement = session.prepare(cql).await?;
+
+        // Prepare "select alert" statement
+        let cql = format!(
+            "SELECT {} from alerts where vin = ? and id = ?",
+            AlertRow::FIELDS.join(",")
+        );
+        let select_alert_statement = session.prepare(cql).await?;
+
+        // Prepare "select vehicle alerts" statement
+        let cql = format!(
+            "SELECT {} from alerts where vin = ?",
+            AlertRow::FIELDS.join(",")
+        );
+        let select_vehicle_alerts_statement = session.prepare(cql).await?;
+
+        // Prepare "select all alerts" statement
+        // Note: full table scan, acceptable for fleet-wide reports only
+        let cql = format!("SELECT {} from alerts", AlertRow::FIELDS.join(","));
+        let select_all_alerts_statement = session.prepare(cql).await?;
+
+        Ok(ScyllaAlertQueries {
+            session,
+            alert_rules_cache: AlertRulesCache::default(),
+            insert_alert_rule_statement,
+            select_alert_rules_statement,
+            delete_alert_rule_statement,
+            upsert_alert_statement,
+            select_alert_statement,
+            select_vehicle_alerts_statement,
+            select_all_alerts_statement,
+        })
+    }
+
+    /// Alert rules read from the database (not cached)
+    async fn select_alert_rules(&self) -> AppResult<Vec<AlertRule>> {
+        let mut rows = self
+            .session
+            .execute_iter(self.select_alert_rules_statement.clone(), &[])
+            .await?
+            .into_typed::<AlertRuleRow>();
+
+        let mut rules = Vec::new();
+        while let Some(row) = rows.next().await {
+            rules.push(AlertRule::try_from(&row?)?);
+        }
+        rules.sort_by_key(|rule| rule.created_at);
+
+        Ok(rules)
+    }
+
+    async fn collect_alerts(
+        &self,
+        statement: &PreparedStatement,
+        values: impl scylla::frame::value::ValueList,
+    ) -> AppResult<Vec<Alert>> {
+        let mut rows = self
+            .session
+            .execute_iter(statement.clone(), values)
+            .await?
+            .into_typed::<AlertRow>();
+
+        let mut alerts = Vec::new();
+        while let Some(row) = rows.next().await {
+            alerts.push(Alert::try_from(&row?)?);
+        }
+        alerts.sort_by_key(|alert| alert.fired_at);
+
+        Ok(alerts)
+    }
+}
+
+#[async_trait]
+impl AlertQueries for ScyllaAlertQueries {
+    async fn create_alert_rule(&self, rule: &AlertRule) -> AppResult<()> {
+        self.session
+            .execute(&self.insert_alert_rule_statement, &AlertRuleRow::from(rule))
+            .await?;
+        self.alert_rules_cache.invalidate();
+
+        Ok(())
+    }
+
+    async fn find_alert_rules(&self) -> AppResult<Vec<AlertRule>> {
+        let now = Instant::now();
+        let generation = match self.alert_rules_cache.get(now) {
+            Ok(rules) => return Ok(rules),
+            Err(generation) => generation,
+        };
+
+        let rules = self.select_alert_rules().await?;
+        self.alert_rules_cache.set(rules.clone(), now, generation);
+
+        Ok(rules)
+    }
+
+    async fn delete_alert_rule(&self, id: Uuid) -> AppResult<()> {
+        let result = self
+            .session
+            .execute(&self.delete_alert_rule_statement, (id,))
+            .await?;
+        if !is_applied(&result)? {
+            return Err(AppError::NotFound("Alert rule"));
+        }
+        self.alert_rules_cache.invalidate();
+
+        Ok(())
+    }
+
+    async fn upsert_alert(&self, alert: &Alert) -> AppResult<()> {
+        self.session
+            .execute(&self.upsert_alert_statement, &AlertRow::from(alert))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn find_one_alert(&self, vin: &str, id: Uuid) -> AppResult<Alert> {
+        let rows = self
+            .session
+            .execute(&self.select_alert_statement, (vin, id))
+            .await?
+            .rows
+            .ok_or(AppError::NotFound("Alert"))?;
+
+        let row = rows
+            .into_typed::<AlertRow>()
+            .next()
+            .ok_or(AppError::NotFound("Alert"))??;
+
+        Alert::try_from(&row)
+    }
+
+    async fn find_vehicle_alerts(&self, vin: &str) -> AppResult<Vec<Alert>> {
+        self.collect_alerts(&self.select_vehicle_alerts_statement, (vin,))
+            .await
+    }
+
+    async fn find_all_alerts(&self) -> AppResult<Vec<Alert>> {
+        self.collect_alerts(&self.select_all_alerts_statement, &[])
+            .await
+    }
+}
+
+fn to_datetime(millis: i64) -> AppResult<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .ok_or(AppError::ConversionError("Timestamp to DateTime"))
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct AlertRuleRow {
+    id: Uuid,
+    /// vin, owner, engine_type or fleet
+    scope_type: String,
+    scope_value: Option<String>,
+    soc_below_in_percent: i32,
+    /// Milliseconds since epoch
+    created_at: i64,
+}
+
+// &AlertRule -> AlertRuleRow
+impl From<&AlertRule> for AlertRuleRow {
+    fn from(rule: &AlertRule) -> Self {
+        let (scope_type, scope_value) = match &rule.scope {
+            AlertScope::Vin(vin) => ("vin", Some(vin.clone())),
+            AlertScope::Owner(owner) => ("owner", Some(owner.clone())),
+            AlertScope::EngineType(engine) => ("engine_type", Some(engine.to_string())),
+            AlertScope::Fleet => ("fleet", None),
+        };
+
+        AlertRuleRow {
+            id: rule.id,
+            scope_type: scope_type.to_string(),
+            scope_value,
+            soc_below_in_percent: rule.soc_below_in_percent,
+            created_at: rule.created_at.timestamp_millis(),
+        }
+    }
+}
+
+// &AlertRuleRow -> AlertRule
+impl TryFrom<&AlertRuleRow> for AlertRule {
+    type Error = AppError;
+
+    fn try_from(row: &AlertRuleRow) -> Result<Self, Self::Error> {
+        let error = AppError::ConversionError("AlertRuleRow to AlertRule");
+        let scope = match (row.scope_type.as_str(), row.scope_value.clone()) {
+            ("vin", Some(vin)) => AlertScope::Vin(vin),
+            ("owner", Some(owner)) => AlertScope::Owner(owner),
+            ("engine_type", Some(engine)) => {
+                AlertScope::EngineType(Engine::from_str(&engine).map_err(|_| error)?)
+            }
+            ("fleet", _) => AlertScope::Fleet,
+            _ => return Err(error),
+        };
+
+        Ok(AlertRule {
+            id: row.id,
+            scope,
+            soc_below_in_percent: row.soc_below_in_percent,
+            created_at: to_datetime(row.created_at)?,
+        })
+    }
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct AlertRow {
+    vin: String,
+    id: Uuid,
+    rule_id: Uuid,
+    status: String,
+    soc_in_percent: i32,
+    /// Milliseconds since epoch
+    fired_at: i64,
+    /// Milliseconds since epoch
+    acknowledged_at: Option<i64>,
+    /// Milliseconds since epoch
+    resolved_at: Option<i64>,
+}
+
+// &Alert -> AlertRow
+impl From<&Alert> for AlertRow {
+    fn from(alert: &Alert) -> Self {
+        AlertRow {
+            vin: alert.vin.clone(),
+            id: alert.id,
+            rule_id: alert.rule_id,
+            status: alert.status.to_string(),
+            soc_in_percent: alert.soc_in_percent,
+            fired_at: alert.fired_at.timestamp_millis(),
+            acknowledged_at: alert.acknowledged_at.map(|time| time.timestamp_millis()),
+            resolved_at: alert.resolved_at.map(|time| time.timestamp_millis()),
+        }
+    }
+}
+
+// &AlertRow -> Alert
+impl TryFrom<&AlertRow> for Alert {
+    type Error = AppError;
+
+    fn try_from(row: &AlertRow) -> Result<Self, Self::Error> {
+        Ok(Alert {
+            id: row.id,
+            rule_id: row.rule_id,
+            vin: row.vin.clone(),
+            status: AlertStatus::from_str(&row.status)
+                .map_err(|_| AppError::ConversionError("AlertRow to Alert"))?,
+            soc_in_percent: row.soc_in_percent,
+            fired_at: to_datetime(row.fired_at)?,
+            acknowledged_at: row.acknowledged_at.map(to_datetime).transpose()?,
+            resolved_at: row.resolved_at.map(to_datetime).transpose()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule1() -> AlertRule {
+        AlertRule {
+            id: Uuid::nil(),
+            scope: AlertScope::EngineType(Engine::Phev),
+            soc_below_in_percent: 15,
+            created_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+        }
+    }
+
+    fn rule1_row() -> AlertRuleRow {
+        AlertRuleRow {
+            id: Uuid::nil(),
+            scope_type: "engine_type".to_string(),
+            scope_value: Some("Phev".to_string()),
+            soc_below_in_percent: 15,
+            created_at: 1_630_490_400_000,
+        }
+    }
+
+    fn alert1() -> Alert {
+        Alert {
+            id: Uuid::nil(),
+            rule_id: Uuid::nil(),
+            vin: "vin".to_string(),
+            status: AlertStatus::Acknowledged,
+            soc_in_percent: 12,
+            fired_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+            acknowledged_at: Some("2021-09-01T11:00:00Z".parse().unwrap()),
+            resolved_at: None,
+        }
+    }
+
+    fn alert1_row() -> AlertRow {
+        AlertRow {
+            vin: "vin".to_string(),
+            id: Uuid::nil(),
+            rule_id: Uuid::nil(),
+            status: "acknowledged".to_string(),
+            soc_in_percent: 12,
+            fired_at: 1_630_490_400_000,
+            acknowledged_at: Some(1_630_494_000_000),
+            resolved_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn model_to_row() {
+        assert_eq!(AlertRuleRow::from(&rule1()), rule1_row());
+        assert_eq!(AlertRow::from(&alert1()), alert1_row());
+    }
+
+    #[tokio::test]
+    async fn row_to_model_ok() -> anyhow::Result<()> {
+        assert_eq!(AlertRule::try_from(&rule1_row())?, rule1());
+        assert_eq!(Alert::try_from(&alert1_row())?, alert1());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn row_to_model_error() {
+        let row = AlertRuleRow {
+            scope_value: None,
+            ..rule1_row()
+        };
+
+        // TODO: user assert_matches! when stable
+        match AlertRule::try_from(&row) {
+            Err(AppError::ConversionError(_)) => (),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn alert_rules_cache() {
+        let cache = AlertRulesCache::default();
+        let now = Instant::now();
+
+        // Empty, then cached until expired
+        let generation = cache.get(now).unwrap_err();
+        cache.set(vec![rule1()], now, generation);
+        assert_eq!(cache.get(now).unwrap(), vec![rule1()]);
+        assert!(cache.get(now + ALERT_RULES_CACHE_TTL).is_err());
+
+        // Changed by this instance => not cached anymore
+        cache.invalidate();
+        assert!(cache.get(now).is_err());
+
+        // Read started before a change => not cached
+        cache.invalidate();
+        cache.set(vec![rule1()], now, generation);
+        assert!(cache.get(now).is_err());
+    }
+}
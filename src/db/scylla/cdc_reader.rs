@@ -0,0 +1,483 @@
+use chrono::{DateTime, TimeZone, Utc};
+use futures::StreamExt;
+use scylla::cql_to_rust::{FromCqlVal, FromRow};
+use scylla::{prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    db::scylla::{
+        outbox_queries::{OutboxRow, ScyllaOutboxQueries},
+        vehicle_queries::{EvDataUserType, VehicleRow},
+    },
+    error::AppError,
+    events::EventBus,
+    model::{event::VehicleEventType, outbox::OutboxEntry, vehicle::Vehicle},
+    result::AppResult,
+};
+
+/// CDC log of the vehicles table (created by Scylla when CDC is enabled)
+pub const VEHICLES_CDC_LOG_TABLE: &str = "vehicles_scylla_cdc_log";
+
+/// Only the log rows older than this delay are read, so that late writes are not skipped
+pub const CDC_READ_DELAY_SECS: i64 = 10;
+
+/// Time the rows of the CDC log are kept (the default TTL of the log): the streams of the CDC
+/// generations expired for longer have no more rows to read
+const CDC_LOG_TTL_SECS: i64 = 24 * 3600;
+
+/// Maximum time between a change published by the API and the same change in the CDC log
+const CDC_DUPLICATE_TOLERANCE_SECS: i64 = 5;
+
+// Values of the "cdc$operation" column
+const CDC_OPERATION_ROW_UPDATE: i8 = 1;
+const CDC_OPERATION_ROW_INSERT: i8 = 2;
+const CDC_OPERATION_ROW_DELETE: i8 = 3;
+const CDC_OPERATION_PARTITION_DELETE: i8 = 4;
+const CDC_OPERATION_POST_IMAGE: i8 = 9;
+
+/// Tail the CDC log of the vehicles table, and publish the changes to the event bus and outbox
+///
+/// The changes made through the API are skipped (they have already been published), so that
+/// only the changes made outside of the API (e.g. with cqlsh) are published by the reader.
+/// The position in each CDC stream is checkpointed in the `cdc_checkpoints` table; streams
+/// without checkpoint are read from the start of the reader. After a restart, the changes made
+/// through the API just before the restart may be published again (at-least-once), with the
+/// same outbox entry id.
+///
+/// The streams are listed from the CDC generations (`system_distributed` tables), instead of
+/// scanning the log.
+pub struct ScyllaCdcReader {
+    session: Arc<Session>,
+    event_bus: Arc<EventBus>,
+    started_at: DateTime<Utc>,
+    select_generations_statement: PreparedStatement,
+    select_streams_statement: PreparedStatement,
+    select_checkpoint_statement: PreparedStatement,
+    upsert_checkpoint_statement: PreparedStatement,
+    select_changes_statement: PreparedStatement,
+    select_changes_after_statement: PreparedStatement,
+    insert_outbox_statement: PreparedStatement,
+}
+
+impl std::fmt::Debug for ScyllaCdcReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScyllaCdcReader").finish()
+    }
+}
+
+impl ScyllaCdcReader {
+    pub async fn try_new(session: Arc<Session>, event_bus: Arc<EventBus>) -> AppResult<Self> {
+        // Prepare "select CDC generations" statement (start and expiration times in milliseconds)
+        let cql = "SELECT toUnixTimestamp(time), toUnixTimestamp(expired) from system_distributed.cdc_generation_timestamps where key = 'timestamps'";
+        let select_generations_statement = session.prepare(cql).await?;
+
+        // Prepare "select streams" statement (streams of a CDC generation, by token range)
+        let cql =
+            "SELECT streams from system_distributed.cdc_streams_descriptions_v2 where time = ?";
+        let select_streams_statement = session.prepare(cql).await?;
+
+        // Prepare "select checkpoint" statement
+        let cql = "SELECT last_time from cdc_checkpoints where table_name = ? and stream_id = ?";
+        let select_checkpoint_statement = session.prepare(cql).await?;
+
+        // Prepare "upsert checkpoint" statement
+        let cql = "INSERT INTO cdc_checkpoints (table_name, stream_id, last_time) VALUES (?, ?, ?)";
+        let upsert_checkpoint_statement = session.prepare(cql).await?;
+
+        // Prepare "select changes" statements (whole stream, or after the checkpoint)
+        let fields = CdcVehicleRow::FIELDS
+            .iter()
+            .map(|field| match field.strip_prefix("cdc_") {
+                Some(cdc_field) => format!("\"cdc${}\"", cdc_field),
+                None => field.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        let cql = format!(
+            "SELECT {} from {} where \"cdc$stream_id\" = ?",
+            fields, VEHICLES_CDC_LOG_TABLE
+        );
+        let select_changes_statement = session.prepare(cql).await?;
+        let cql = format!(
+            "SELECT {} from {} where \"cdc$stream_id\" = ? and \"cdc$time\" > ?",
+            fields, VEHICLES_CDC_LOG_TABLE
+        );
+        let select_changes_after_statement = session.prepare(cql).await?;
+
+        // Prepare "insert outbox entry" statement
+        let insert_outbox_statement = ScyllaOutboxQueries::prepare_insert_entry(&session).await?;
+
+        Ok(ScyllaCdcReader {
+            session,
+            event_bus,
+            started_at: Utc::now(),
+            select_generations_statement,
+            select_streams_statement,
+            select_checkpoint_statement,
+            upsert_checkpoint_statement,
+            select_changes_statement,
+            select_changes_after_statement,
+            insert_outbox_statement,
+        })
+    }
+
+    /// Streams of the current CDC generation, and of the previous ones which may still have rows
+    async fn find_stream_ids(&self) -> AppResult<Vec<Vec<u8>>> {
+        let mut rows = self
+            .session
+            .execute_iter(self.select_generations_statement.clone(), &[])
+            .await?
+            .into_typed::<(i64, Option<i64>)>();
+
+        let expired_before =
+            (Utc::now() - chrono::Duration::seconds(CDC_LOG_TTL_SECS)).timestamp_millis();
+        let mut generations = Vec::new();
+        while let Some(row) = rows.next().await {
+            let (time, expired) = row?;
+            if expired.map_or(true, |expired| expired >= expired_before) {
+                generations.push(time);
+            }
+        }
+
+        let mut stream_ids = Vec::new();
+        for time in generations.iter() {
+            let mut rows = self
+                .session
+                .execute_iter(self.select_streams_statement.clone(), (time,))
+                .await?
+                .into_typed::<(Vec<Vec<u8>>,)>();
+            while let Some(row) = rows.next().await {
+                let (streams,) = row?;
+                stream_ids.extend(streams);
+            }
+        }
+
+        Ok(stream_ids)
+    }
+
+    /// Read the new changes of all the streams, returns the number of published changes
+    pub async fn read_changes(&self) -> AppResult<usize> {
+        let stream_ids = self.find_stream_ids().await?;
+
+        let mut count = 0;
+        for stream_id in stream_ids.iter() {
+            count += self.read_stream_changes(stream_id).await?;
+        }
+
+        Ok(count)
+    }
+
+    async fn read_stream_changes(&self, stream_id: &[u8]) -> AppResult<usize> {
+        let checkpoint = self
+            .session
+            .execute(
+                &self.select_checkpoint_statement,
+                (VEHICLES_CDC_LOG_TABLE, stream_id.to_vec()),
+            )
+            .await?
+            .rows
+            .and_then(|rows| rows.into_typed::<(Uuid,)>().next())
+            .transpose()?
+            .map(|(last_time,)| last_time);
+
+        let mut rows = match checkpoint {
+            Some(last_time) => {
+                self.session
+                    .execute_iter(
+                        self.select_changes_after_statement.clone(),
+                        (stream_id.to_vec(), last_time),
+                    )
+                    .await?
+            }
+            None => {
+                self.session
+                    .execute_iter(self.select_changes_statement.clone(), (stream_id.to_vec(),))
+                    .await?
+            }
+        }
+        .into_typed::<CdcVehicleRow>();
+
+        // Rows are ordered by time: group the rows of each change (delta and post-image)
+        let read_before = Utc::now() - chrono::Duration::seconds(CDC_READ_DELAY_SECS);
+        let mut changes: Vec<Vec<CdcVehicleRow>> = Vec::new();
+        while let Some(row) = rows.next().await {
+            let row = row?;
+            if timeuuid_to_datetime(&row.cdc_time).map_or(true, |time| time >= read_before) {
+                break;
+            }
+
+            match changes.last_mut() {
+                Some(change) if change[0].cdc_time == row.cdc_time => change.push(row),
+                _ => changes.push(vec![row]),
+            }
+        }
+
+        let last_time = match changes.last() {
+            Some(change) => change[0].cdc_time,
+            None => return Ok(0),
+        };
+
+        // The changes before the start are skipped only when the stream has no checkpoint yet,
+        // otherwise the changes made while the reader was stopped would be lost
+        let not_before = match checkpoint {
+            Some(_) => None,
+            None => Some(self.started_at),
+        };
+
+        let mut count = 0;
+        for change in changes.iter() {
+            if self.publish_change(stream_id, change, not_before).await? {
+                count += 1;
+            }
+        }
+
+        self.session
+            .execute(
+                &self.upsert_checkpoint_statement,
+                (VEHICLES_CDC_LOG_TABLE, stream_id.to_vec(), last_time),
+            )
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Publish the change (if not already published), returns whether it has been published
+    async fn publish_change(
+        &self,
+        stream_id: &[u8],
+        rows: &[CdcVehicleRow],
+        not_before: Option<DateTime<Utc>>,
+    ) -> AppResult<bool> {
+        let time = timeuuid_to_datetime(&rows[0].cdc_time)
+            .ok_or(AppError::ConversionError("CDC time to DateTime"))?;
+        if not_before.map_or(false, |not_before| time < not_before) {
+            return Ok(false);
+        }
+
+        let (event_type, vin, vehicle) = match change_of_rows(rows)? {
+            Some(change) => change,
+            None => return Ok(false),
+        };
+
+        if self.event_bus.is_recently_published(
+            event_type,
+            &vin,
+            vehicle.as_ref(),
+            time,
+            chrono::Duration::seconds(CDC_DUPLICATE_TOLERANCE_SECS),
+        ) {
+            return Ok(false);
+        }
+
+        // Written to the outbox before the checkpoint, so that the change cannot be missed, with
+        // an id derived from the change, so that the change read again after a restart keeps it
+        let entry = OutboxEntry {
+            id: outbox_entry_id(stream_id, &rows[0].cdc_time),
+            occurred_at: time,
+            ..OutboxEntry::new(event_type, &vin, vehicle.clone())
+        };
+        self.session
+            .execute(&self.insert_outbox_statement, &OutboxRow::try_from(&entry)?)
+            .await?;
+
+        self.event_bus.publish(event_type, &vin, vehicle);
+
+        Ok(true)
+    }
+}
+
+/// Id of the outbox entry of a change, identified by its CDC stream and time
+fn outbox_entry_id(stream_id: &[u8], cdc_time: &Uuid) -> Uuid {
+    Uuid::new_v5(cdc_time, stream_id)
+}
+
+/// Change of the vehicle described by the CDC log rows of one write (delta and post-image)
+fn change_of_rows(
+    rows: &[CdcVehicleRow],
+) -> AppResult<Option<(VehicleEventType, String, Option<Vehicle>)>> {
+    let delta = match rows
+        .iter()
+        .find(|row| row.cdc_operation != CDC_OPERATION_POST_IMAGE)
+    {
+        Some(delta) => delta,
+        None => return Ok(None),
+    };
+    let vin = match &delta.vin {
+        Some(vin) => vin.clone(),
+        None => return Ok(None),
+    };
+
+    // Purge
+    if delta.cdc_operation == CDC_OPERATION_ROW_DELETE
+        || delta.cdc_operation == CDC_OPERATION_PARTITION_DELETE
+    {
+        return Ok(Some((VehicleEventType::Deleted, vin, None)));
+    }
+
+    let post_image = match rows
+        .iter()
+        .find(|row| row.cdc_operation == CDC_OPERATION_POST_IMAGE)
+    {
+        Some(post_image) => post_image,
+        None => return Ok(None),
+    };
+    let vehicle_row = VehicleRow::try_from(post_image)?;
+
+    let change = if vehicle_row.deleted_at.is_some() {
+        // Soft deletion (or update of a deleted vehicle)
+        (VehicleEventType::Deleted, vin, None)
+    } else {
+        let event_type = match delta.cdc_operation {
+            CDC_OPERATION_ROW_INSERT => VehicleEventType::Created,
+            CDC_OPERATION_ROW_UPDATE => VehicleEventType::Updated,
+            _ => return Ok(None),
+        };
+        (event_type, vin, Some(Vehicle::try_from(&vehicle_row)?))
+    };
+
+    Ok(Some(change))
+}
+
+/// Time of a time-based (version 1) UUID
+pub fn timeuuid_to_datetime(uuid: &Uuid) -> Option<DateTime<Utc>> {
+    // 100ns intervals between the UUID epoch (1582-10-15) and the Unix epoch
+    const UUID_EPOCH_OFFSET: u64 = 0x01B2_1DD2_1381_4000;
+
+    let bytes = uuid.as_bytes();
+    let time_low = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64;
+    let time_mid = u16::from_be_bytes([bytes[4], bytes[5]]) as u64;
+    let time_hi = (u16::from_be_bytes([bytes[6], bytes[7]]) & 0x0fff) as u64;
+    let timestamp = (time_hi << 48) | (time_mid << 32) | time_low;
+
+    let millis = timestamp.checked_sub(UUID_EPOCH_OFFSET)? / 10_000;
+    Utc.timestamp_millis_opt(millis as i64).single()
+}
+
+/// Row of the CDC log (fields prefixed by `cdc_` are the `cdc$` columns)
+#[derive(PartialEq, scylla::FromRow, field_names::FieldNames, Debug)]
+struct CdcVehicleRow {
+    cdc_time: Uuid,
+    cdc_operation: i8,
+    vin: Option<String>,
+    owner: Option<String>,
+    engine_type: Option<String>,
+    ev_data: Option<EvDataUserType>,
+    deleted_at: Option<i64>,
+}
+
+// &CdcVehicleRow (post-image) -> VehicleRow
+impl TryFrom<&CdcVehicleRow> for VehicleRow {
+    type Error = AppError;
+
+    fn try_from(row: &CdcVehicleRow) -> Result<Self, Self::Error> {
+        Ok(VehicleRow {
+            vin: row
+                .vin
+                .clone()
+                .ok_or(AppError::ConversionError("CdcVehicleRow to VehicleRow"))?,
+            owner: row.owner.clone(),
+            engine_type: row
+                .engine_type
+                .clone()
+                .ok_or(AppError::ConversionError("CdcVehicleRow to VehicleRow"))?,
+            ev_data: row.ev_data.as_ref().map(|ev_data| EvDataUserType {
+                battery_capacity_in_kwh: ev_data.battery_capacity_in_kwh,
+                soc_in_percent: ev_data.soc_in_percent,
+            }),
+            deleted_at: row.deleted_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::vehicle::Engine;
+
+    fn row(cdc_operation: i8) -> CdcVehicleRow {
+        CdcVehicleRow {
+            cdc_time: Uuid::nil(),
+            cdc_operation,
+            vin: Some("vin".to_string()),
+            owner: None,
+            engine_type: Some("Combustion".to_string()),
+            ev_data: None,
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn test_timeuuid_to_datetime() {
+        // Version 1 UUID of 2021-09-01T10:00:00Z
+        let uuid = Uuid::parse_str("5e859000-0b0b-11ec-8080-808080808080").unwrap();
+        assert_eq!(
+            timeuuid_to_datetime(&uuid),
+            Some("2021-09-01T10:00:00Z".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_outbox_entry_id() {
+        let time = Uuid::parse_str("5e859000-0b0b-11ec-8080-808080808080").unwrap();
+
+        // Same change => same id
+        assert_eq!(
+            outbox_entry_id(&[1, 2], &time),
+            outbox_entry_id(&[1, 2], &time)
+        );
+        assert_ne!(
+            outbox_entry_id(&[1, 2], &time),
+            outbox_entry_id(&[1, 3], &time)
+        );
+        assert_ne!(
+            outbox_entry_id(&[1, 2], &time),
+            outbox_entry_id(&[1, 2], &Uuid::nil())
+        );
+    }
+
+    #[test]
+    fn test_change_of_rows() -> anyhow::Result<()> {
+        let vehicle = Vehicle {
+            vin: "vin".to_string(),
+            owner: None,
+            engine: Engine::Combustion,
+            ev_data: None,
+        };
+
+        // Insert => created
+        assert_eq!(
+            change_of_rows(&[row(CDC_OPERATION_ROW_INSERT), row(CDC_OPERATION_POST_IMAGE)])?,
+            Some((
+                VehicleEventType::Created,
+                "vin".to_string(),
+                Some(vehicle.clone())
+            ))
+        );
+
+        // Update => updated
+        assert_eq!(
+            change_of_rows(&[row(CDC_OPERATION_ROW_UPDATE), row(CDC_OPERATION_POST_IMAGE)])?,
+            Some((VehicleEventType::Updated, "vin".to_string(), Some(vehicle)))
+        );
+
+        // Soft delete => deleted
+        let post_image = CdcVehicleRow {
+            deleted_at: Some(1_630_490_400_000),
+            ..row(CDC_OPERATION_POST_IMAGE)
+        };
+        assert_eq!(
+            change_of_rows(&[row(CDC_OPERATION_ROW_UPDATE), post_image])?,
+            Some((VehicleEventType::Deleted, "vin".to_string(), None))
+        );
+
+        // Purge => deleted
+        assert_eq!(
+            change_of_rows(&[row(CDC_OPERATION_PARTITION_DELETE)])?,
+            Some((VehicleEventType::Deleted, "vin".to_string(), None))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::StreamExt;
+use scylla::cql_to_rust::{FromCqlVal, FromRow};
+use scylla::{prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    db::queries::ChargingQueries, error::AppError, model::charging::ChargingSession,
+    result::AppResult,
+};
+
+pub struct ScyllaChargingQueries {
+    session: Arc<Session>,
+    upsert_charging_session_statement: PreparedStatement,
+    select_charging_session_statement: PreparedStatement,
+    select_charging_sessions_statement: PreparedStatement,
+    select_all_charging_sessions_statement: PreparedStatement,
+}
+
+impl std::fmt::Debug for ScyllaChargingQueries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScyllaChargingQueries").finish()
+    }
+}
+
+impl ScyllaChargingQueries {
+    pub async fn try_new(session: Arc<Session>) -> AppResult<Self> {
+        // Prepare "upsert charging session" statement
+        let cql = format!(
+            "INSERT INTO charging_sessions ({}) VALUES ({})",
+            ChargingSessionRow::FIELDS.join(","),
+            vec!["?"; ChargingSessionRow::FIELDS.len()].join(",")
+        );
+        let upsert_charging_session_statement = session.prepare(cql).await?;
+
+        // Prepare "select charging session" statement
+        let cql = format!(
+            "SELECT {} from charging_sessions where vin = ? and id = ?",
+            ChargingSessionRow::FIELDS.join(",")
+        );
+        let select_charging_session_statement = session.prepare(cql).await?;
+
+        // Prepare "select charging sessions" statement (filtering within a single partition)
+        let cql = format!(
+            "SELECT {} from charging_sessions where vin = ? and start_time >= ? and start_time < ? ALLOW FILTERING",
+            ChargingSessionRow::FIELDS.join(",")
+        );
+        let select_charging_sessions_statement = session.prepare(cql).await?;
+
+        // Prepare "select all charging sessions" statement
+        // Note: full table scan, acceptable for fleet-wide reports only
+        let cql = format!(
+            "SELECT {} from charging_sessions where start_time >= ? and start_time < ? ALLOW FILTERING",
+            ChargingSessionRow::FIELDS.join(",")
+        );
+        let select_all_charging_sessions_statement = session.prepare(cql).await?;
+
+        Ok(ScyllaChargingQueries {
+            session,
+            upsert_charging_session_statement,
+            select_charging_session_statement,
+            select_charging_sessions_statement,
+            select_all_charging_sessions_statement,
+        })
+    }
+
+    async fn collect_charging_sessions(
+        &self,
+        statement: &PreparedStatement,
+        values: impl scylla::frame::value::ValueList,
+    ) -> AppResult<Vec<ChargingSession>> {
+        let mut rows = self
+            .session
+            .execute_iter(statement.clone(), values)
+            .await?
+            .into_typed::<ChargingSessionRow>();
+
+        let mut sessions = Vec::new();
+        while let Some(row) = rows.next().await {
+            sessions.push(ChargingSession::try_from(&row?)?);
+        }
+        sessions.sort_by_key(|session| session.start_time);
+
+        Ok(sessions)
+    }
+}
+
+#[async_trait]
+impl ChargingQueries for ScyllaChargingQueries {
+    async fn upsert_charging_session(&self, session: &ChargingSession) -> AppResult<()> {
+        self.session
+            .execute(
+                &self.upsert_charging_session_statement,
+                &ChargingSessionRow::from(session),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn find_one_charging_session(&self, vin: &str, id: Uuid) -> AppResult<ChargingSession> {
+        let rows = self
+            .session
+            .execute(&self.select_charging_session_statement, (vin, id))
+            .await?
+            .rows
+            .ok_or(AppError::NotFound("Charging session"))?;
+
+        let row = rows
+            .into_typed::<ChargingSessionRow>()
+            .next()
+            .ok_or(AppError::NotFound("Charging session"))??;
+
+        ChargingSession::try_from(&row)
+    }
+
+    async fn find_charging_sessions(
+        &self,
+        vin: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> AppResult<Vec<ChargingSession>> {
+        self.collect_charging_sessions(
+            &self.select_charging_sessions_statement,
+            (vin, from.timestamp_millis(), to.timestamp_millis()),
+        )
+        .await
+    }
+
+    async fn find_all_charging_sessions(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> AppResult<Vec<ChargingSession>> {
+        self.collect_charging_sessions(
+            &self.select_all_charging_sessions_statement,
+            (from.timestamp_millis(), to.timestamp_millis()),
+        )
+        .await
+    }
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct ChargingSessionRow {
+    vin: String,
+    id: Uuid,
+    /// Milliseconds since epoch
+    start_time: i64,
+    /// Milliseconds since epoch
+    end_time: Option<i64>,
+    start_soc_in_percent: i32,
+    end_soc_in_percent: Option<i32>,
+    energy_added_in_kwh: f64,
+    max_power_in_kw: Option<f64>,
+    location: Option<String>,
+    detected: bool,
+}
+
+// &ChargingSession -> ChargingSessionRow
+impl From<&ChargingSession> for ChargingSessionRow {
+    fn from(session: &ChargingSession) -> Self {
+        ChargingSessionRow {
+            vin: session.vin.clone(),
+            id: session.id,
+            start_time: session.start_time.timestamp_millis(),
+            end_time: session.end_time.map(|end_time| end_time.timestamp_millis()),
+            start_soc_in_percent: session.start_soc_in_percent,
+            end_soc_in_percent: session.end_soc_in_percent,
+            energy_added_in_kwh: session.energy_added_in_kwh,
+            max_power_in_kw: session.max_power_in_kw,
+            location: session.location.clone(),
+            detected: session.detected,
+        }
+    }
+}
+
+// &ChargingSessionRow -> ChargingSession
+impl TryFrom<&ChargingSessionRow> for ChargingSession {
+    type Error = AppError;
+
+    fn try_from(row: &ChargingSessionRow) -> Result<Self, Self::Error> {
+        let to_datetime = |millis: i64| {
+            Utc.timestamp_millis_opt(millis)
+                .single()
+                .ok_or(AppError::ConversionError(
+                    "ChargingSessionRow to ChargingSession",
+                ))
+        };
+
+        Ok(ChargingSession {
+            id: row.id,
+            vin: row.vin.clone(),
+            start_time: to_datetime(row.start_time)?,
+            end_time: row.end_time.map(to_datetime).transpose()?,
+            start_soc_in_percent: row.start_soc_in_percent,
+            end_soc_in_percent: row.end_soc_in_percent,
+            energy_added_in_kwh: row.energy_added_in_kwh,
+            max_power_in_kw: row.max_power_in_kw,
+            location: row.location.clone(),
+            detected: row.detected,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session1() -> ChargingSession {
+        ChargingSession {
+            id: Uuid::nil(),
+            vin: "vin".to_string(),
+            start_time: "2021-09-01T10:00:00Z".parse().unwrap(),
+            end_time: Some("2021-09-01T11:00:00Z".parse().unwrap()),
+            start_soc_in_percent: 20,
+            end_soc_in_percent: Some(80),
+            energy_added_in_kwh: 37.2,
+            max_power_in_kw: Some(50.0),
+            location: Some("Home".to_string()),
+            detected: false,
+        }
+    }
+
+    fn session1_row() -> ChargingSessionRow {
+        ChargingSessionRow {
+            vin: "vin".to_string(),
+            id: Uuid::nil(),
+            start_time: 1_630_490_400_000,
+            end_time: Some(1_630_494_000_000),
+            start_soc_in_percent: 20,
+            end_soc_in_percent: Some(80),
+            energy_added_in_kwh: 37.2,
+            max_power_in_kw: Some(50.0),
+            location: Some("Home".to_string()),
+            detected: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn model_to_row() {
+        assert_eq!(ChargingSessionRow::from(&session1()), session1_row());
+    }
+
+    #[tokio::test]
+    async fn row_to_model_ok() -> anyhow::Result<()> {
+        assert_eq!(ChargingSession::try_from(&session1_row())?, session1());
+
+        Ok(())
+    }
+}
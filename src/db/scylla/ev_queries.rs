@@ -0,0 +1,288 @@
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use futures::StreamExt;
+use scylla::cql_to_rust::{FromCqlVal, FromRow};
+use scylla::{prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use crate::{
+    db::queries::EvQueries,
+    error::AppError,
+    model::ev::{BatteryHealth, EvConsumption, Trip},
+    result::AppResult,
+};
+
+pub struct ScyllaEvQueries {
+    session: Arc<Session>,
+    select_ev_consumption_statement: PreparedStatement,
+    update_configured_consumption_statement: PreparedStatement,
+    update_trip_statistics_statement: PreparedStatement,
+    insert_battery_health_statement: PreparedStatement,
+    select_battery_health_statement: PreparedStatement,
+    select_all_battery_health_statement: PreparedStatement,
+}
+
+impl std::fmt::Debug for ScyllaEvQueries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScyllaEvQueries").finish()
+    }
+}
+
+impl ScyllaEvQueries {
+    pub async fn try_new(session: Arc<Session>) -> AppResult<Self> {
+        // Prepare "select EV consumption" statement
+        let cql = format!(
+            "SELECT {} from ev_consumption where vin = ?",
+            EvConsumptionRow::FIELDS.join(",")
+        );
+        let select_ev_consumption_statement = session.prepare(cql).await?;
+
+        // Prepare "update configured consumption" statement
+        let cql = "UPDATE ev_consumption SET configured_in_kwh_per_100km = ? where vin = ?";
+        let update_configured_consumption_statement = session.prepare(cql).await?;
+
+        // Prepare "update trip statistics" statement
+        let cql = "UPDATE ev_consumption SET trip_distance_in_km = ?, trip_energy_in_kwh = ? where vin = ?";
+        let update_trip_statistics_statement = session.prepare(cql).await?;
+
+        // Prepare "insert battery health" statement
+        let cql = format!(
+            "INSERT INTO battery_health ({}) VALUES ({})",
+            BatteryHealthRow::FIELDS.join(","),
+            vec!["?"; BatteryHealthRow::FIELDS.len()].join(",")
+        );
+        let insert_battery_health_statement = session.prepare(cql).await?;
+
+        // Prepare "select battery health" statement
+        let cql = format!(
+            "SELECT {} from battery_health where vin = ?",
+            BatteryHealthRow::FIELDS.join(",")
+        );
+        let select_battery_health_statement = session.prepare(cql).await?;
+
+        // Prepare "select all battery health" statement
+        // Note: full table scan, acceptable for fleet-wide reports only
+        let cql = format!(
+            "SELECT {} from battery_health",
+            BatteryHealthRow::FIELDS.join(",")
+        );
+        let select_all_battery_health_statement = session.prepare(cql).await?;
+
+        Ok(ScyllaEvQueries {
+            session,
+            select_ev_consumption_statement,
+            update_configured_consumption_statement,
+            update_trip_statistics_statement,
+            insert_battery_health_statement,
+            select_battery_health_statement,
+            select_all_battery_health_statement,
+        })
+    }
+
+    async fn collect_battery_health(
+        &self,
+        statement: &PreparedStatement,
+        values: impl scylla::frame::value::ValueList,
+    ) -> AppResult<Vec<BatteryHealth>> {
+        let mut rows = self
+            .session
+            .execute_iter(statement.clone(), values)
+            .await?
+            .into_typed::<BatteryHealthRow>();
+
+        let mut measurements = Vec::new();
+        while let Some(row) = rows.next().await {
+            measurements.push(BatteryHealth::try_from(&row?)?);
+        }
+
+        Ok(measurements)
+    }
+}
+
+#[async_trait]
+impl EvQueries for ScyllaEvQueries {
+    async fn find_ev_consumption(&self, vin: &str) -> AppResult<EvConsumption> {
+        let rows = match self
+            .session
+            .execute(&self.select_ev_consumption_statement, (vin,))
+            .await?
+            .rows
+        {
+            Some(rows) => rows,
+            None => return Ok(EvConsumption::default()),
+        };
+
+        Ok(rows
+            .into_typed::<EvConsumptionRow>()
+            .next()
+            .transpose()?
+            .map(|row| EvConsumption::from(&row))
+            .unwrap_or_default())
+    }
+
+    async fn configure_ev_consumption(
+        &self,
+        vin: &str,
+        consumption_in_kwh_per_100km: Option<f64>,
+    ) -> AppResult<()> {
+        self.session
+            .execute(
+                &self.update_configured_consumption_statement,
+                (consumption_in_kwh_per_100km, vin),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn add_ev_trip(&self, vin: &str, trip: &Trip) -> AppResult<()> {
+        // TODO: use LWT (or counters) to avoid losing concurrent trips
+        let consumption = self.find_ev_consumption(vin).await?;
+
+        self.session
+            .execute(
+                &self.update_trip_statistics_statement,
+                (
+                    consumption.trip_distance_in_km + trip.distance_in_km,
+                    consumption.trip_energy_in_kwh + trip.energy_used_in_kwh,
+                    vin,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn insert_battery_health(&self, health: &BatteryHealth) -> AppResult<()> {
+        self.session
+            .execute(
+                &self.insert_battery_health_statement,
+                &BatteryHealthRow::from(health),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn find_battery_health(&self, vin: &str) -> AppResult<Vec<BatteryHealth>> {
+        // Ordered by the clustering key
+        self.collect_battery_health(&self.select_battery_health_statement, (vin,))
+            .await
+    }
+
+    async fn find_all_battery_health(&self) -> AppResult<Vec<BatteryHealth>> {
+        self.collect_battery_health(&self.select_all_battery_health_statement, &[])
+            .await
+    }
+}
+
+#[derive(PartialEq, scylla::FromRow, field_names::FieldNames, Debug)]
+struct EvConsumptionRow {
+    vin: String,
+    configured_in_kwh_per_100km: Option<f64>,
+    trip_distance_in_km: Option<f64>,
+    trip_energy_in_kwh: Option<f64>,
+}
+
+// &EvConsumptionRow -> EvConsumption
+impl From<&EvConsumptionRow> for EvConsumption {
+    fn from(row: &EvConsumptionRow) -> Self {
+        EvConsumption {
+            configured_in_kwh_per_100km: row.configured_in_kwh_per_100km,
+            trip_distance_in_km: row.trip_distance_in_km.unwrap_or_default(),
+            trip_energy_in_kwh: row.trip_energy_in_kwh.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct BatteryHealthRow {
+    vin: String,
+    /// Milliseconds since epoch
+    measured_at: i64,
+    usable_capacity_in_kwh: f64,
+    nominal_capacity_in_kwh: i32,
+}
+
+// &BatteryHealth -> BatteryHealthRow
+impl From<&BatteryHealth> for BatteryHealthRow {
+    fn from(health: &BatteryHealth) -> Self {
+        BatteryHealthRow {
+            vin: health.vin.clone(),
+            measured_at: health.measured_at.timestamp_millis(),
+            usable_capacity_in_kwh: health.usable_capacity_in_kwh,
+            nominal_capacity_in_kwh: health.nominal_capacity_in_kwh,
+        }
+    }
+}
+
+// &BatteryHealthRow -> BatteryHealth
+impl TryFrom<&BatteryHealthRow> for BatteryHealth {
+    type Error = AppError;
+
+    fn try_from(row: &BatteryHealthRow) -> Result<Self, Self::Error> {
+        Ok(BatteryHealth {
+            vin: row.vin.clone(),
+            measured_at: Utc.timestamp_millis_opt(row.measured_at).single().ok_or(
+                AppError::ConversionError("BatteryHealthRow to BatteryHealth"),
+            )?,
+            usable_capacity_in_kwh: row.usable_capacity_in_kwh,
+            nominal_capacity_in_kwh: row.nominal_capacity_in_kwh,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health1() -> BatteryHealth {
+        BatteryHealth {
+            vin: "vin".to_string(),
+            measured_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+            usable_capacity_in_kwh: 45.0,
+            nominal_capacity_in_kwh: 50,
+        }
+    }
+
+    fn health1_row() -> BatteryHealthRow {
+        BatteryHealthRow {
+            vin: "vin".to_string(),
+            measured_at: 1_630_490_400_000,
+            usable_capacity_in_kwh: 45.0,
+            nominal_capacity_in_kwh: 50,
+        }
+    }
+
+    #[tokio::test]
+    async fn battery_health_model_to_row() {
+        assert_eq!(BatteryHealthRow::from(&health1()), health1_row());
+    }
+
+    #[tokio::test]
+    async fn battery_health_row_to_model() -> anyhow::Result<()> {
+        assert_eq!(BatteryHealth::try_from(&health1_row())?, health1());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn row_to_model() {
+        let row = EvConsumptionRow {
+            vin: "vin".to_string(),
+            configured_in_kwh_per_100km: Some(16.5),
+            trip_distance_in_km: None,
+            trip_energy_in_kwh: None,
+        };
+
+        assert_eq!(
+            EvConsumption::from(&row),
+            EvConsumption {
+                configured_in_kwh_per_100km: Some(16.5),
+                trip_distance_in_km: 0.0,
+                trip_energy_in_kwh: 0.0,
+            }
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use scylla::cql_to_rust::{FromCqlVal, FromRow};
+use scylla::{prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use crate::{
+    db::{queries::IdempotencyQueries, scylla::is_applied},
+    error::AppError,
+    idempotency::IDEMPOTENCY_CLAIM_TTL_IN_SECONDS,
+    model::idempotency::IdempotentResponse,
+    result::AppResult,
+};
+
+pub struct ScyllaIdempotencyQueries {
+    session: Arc<Session>,
+    insert_response_statement: PreparedStatement,
+    claim_key_statement: PreparedStatement,
+    select_response_statement: PreparedStatement,
+    delete_key_statement: PreparedStatement,
+}
+
+impl std::fmt::Debug for ScyllaIdempotencyQueries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScyllaIdempotencyQueries").finish()
+    }
+}
+
+impl ScyllaIdempotencyQueries {
+    pub async fn try_new(session: Arc<Session>) -> AppResult<Self> {
+        // Prepare "insert response" statement
+        // Note: the rows expire after the default TTL of the table
+        let cql = format!(
+            "INSERT INTO idempotency_keys ({}) VALUES ({})",
+            IdempotencyRow::FIELDS.join(","),
+            vec!["?"; IdempotencyRow::FIELDS.len()].join(",")
+        );
+        let insert_response_statement = session.prepare(cql).await?;
+
+        // Prepare "claim key" statement (conditional, expired after
+        // IDEMPOTENCY_CLAIM_TTL_IN_SECONDS if the response is not stored)
+        let cql = format!(
+            "INSERT INTO idempotency_keys ({}) VALUES ({}) IF NOT EXISTS USING TTL {}",
+            IdempotencyRow::FIELDS.join(","),
+            vec!["?"; IdempotencyRow::FIELDS.len()].join(","),
+            IDEMPOTENCY_CLAIM_TTL_IN_SECONDS
+        );
+        let claim_key_statement = session.prepare(cql).await?;
+
+        // Prepare "select response" statement
+        let cql = format!(
+            "SELECT {} from idempotency_keys where key = ?",
+            IdempotencyRow::FIELDS.join(",")
+        );
+        let select_response_statement = session.prepare(cql).await?;
+
+        // Prepare "delete key" statement
+        let cql = "DELETE from idempotency_keys where key = ?";
+        let delete_key_statement = session.prepare(cql).await?;
+
+        Ok(ScyllaIdempotencyQueries {
+            session,
+            insert_response_statement,
+            claim_key_statement,
+            select_response_statement,
+            delete_key_statement,
+        })
+    }
+}
+
+#[async_trait]
+impl IdempotencyQueries for ScyllaIdempotencyQueries {
+    async fn find_idempotent_response(&self, key: &str) -> AppResult<Option<IdempotentResponse>> {
+        let rows = match self
+            .session
+            .execute(&self.select_response_statement, (key,))
+            .await?
+            .rows
+        {
+            Some(rows) => rows,
+            None => return Ok(None),
+        };
+
+        rows.into_typed::<IdempotencyRow>()
+            .next()
+            .map(|row| IdempotentResponse::try_from(&row?))
+            .transpose()
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        pending: &IdempotentResponse,
+    ) -> AppResult<Option<IdempotentResponse>> {
+        let result = self
+            .session
+            .execute(&self.claim_key_statement, &IdempotencyRow::from(pending))
+            .await?;
+        if is_applied(&result)? {
+            return Ok(None);
+        }
+
+        // Claimed by another request (the claim may have expired since, it is then still
+        // considered as in flight)
+        Ok(Some(
+            self.find_idempotent_response(&pending.key)
+                .await?
+                .unwrap_or_else(|| pending.clone()),
+        ))
+    }
+
+    async fn insert_idempotent_response(&self, response: &IdempotentResponse) -> AppResult<()> {
+        self.session
+            .execute(
+                &self.insert_response_statement,
+                &IdempotencyRow::from(response),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_idempotency_key(&self, key: &str) -> AppResult<()> {
+        self.session
+            .execute(&self.delete_key_statement, (key,))
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct IdempotencyRow {
+    key: String,
+    fingerprint: String,
+    status: i32,
+    content_type: Option<String>,
+    body: Vec<u8>,
+    /// Milliseconds since epoch
+    created_at: i64,
+}
+
+// &IdempotentResponse -> IdempotencyRow
+impl From<&IdempotentResponse> for IdempotencyRow {
+    fn from(response: &IdempotentResponse) -> Self {
+        IdempotencyRow {
+            key: response.key.clone(),
+            fingerprint: response.fingerprint.clone(),
+            status: response.status as i32,
+            content_type: response.content_type.clone(),
+            body: response.body.clone(),
+            created_at: response.created_at.timestamp_millis(),
+        }
+    }
+}
+
+// &IdempotencyRow -> IdempotentResponse
+impl TryFrom<&IdempotencyRow> for IdempotentResponse {
+    type Error = AppError;
+
+    fn try_from(row: &IdempotencyRow) -> Result<Self, Self::Error> {
+        let created_at: DateTime<Utc> = Utc
+            .timestamp_millis_opt(row.created_at)
+            .single()
+            .ok_or(AppError::ConversionError("Timestamp to DateTime"))?;
+
+        Ok(IdempotentResponse {
+            key: row.key.clone(),
+            fingerprint: row.fingerprint.clone(),
+            status: u16::try_from(row.status)
+                .map_err(|_| AppError::ConversionError("IdempotencyRow to IdempotentResponse"))?,
+            content_type: row.content_type.clone(),
+            body: row.body.clone(),
+            created_at,
+        })
+    }
+}
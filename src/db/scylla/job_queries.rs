@@ -0,0 +1,503 @@
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::StreamExt;
+use scylla::cql_to_rust::{FromCqlVal, FromRow};
+use scylla::{batch::Batch, prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    db::{queries::JobQueries, scylla::is_applied},
+    error::AppError,
+    model::job::{Job, JobChunk, JobKind, JobStatus},
+    result::AppResult,
+};
+
+/// The output of the export jobs is kept for 7 days
+pub const JOB_OUTPUT_TTL_IN_SECONDS: i32 = 7 * 24 * 3600;
+
+pub struct ScyllaJobQueries {
+    session: Arc<Session>,
+    insert_job_batch: Batch,
+    select_job_statement: PreparedStatement,
+    select_unfinished_jobs_statement: PreparedStatement,
+    claim_job_statement: PreparedStatement,
+    renew_job_lease_statement: PreparedStatement,
+    update_job_state_statement: PreparedStatement,
+    delete_unfinished_job_statement: PreparedStatement,
+    cancel_job_statement: PreparedStatement,
+    insert_chunk_statement: PreparedStatement,
+    insert_output_chunk_statement: PreparedStatement,
+    select_chunk_statement: PreparedStatement,
+    delete_chunks_statement: PreparedStatement,
+}
+
+impl std::fmt::Debug for ScyllaJobQueries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScyllaJobQueries").finish()
+    }
+}
+
+impl ScyllaJobQueries {
+    pub async fn try_new(session: Arc<Session>) -> AppResult<Self> {
+        // Prepare "insert job" batch, the job is indexed as unfinished (and not leased)
+        let cql = format!(
+            "INSERT INTO jobs ({}) VALUES ({})",
+            JobRow::FIELDS.join(","),
+            vec!["?"; JobRow::FIELDS.len()].join(",")
+        );
+        let mut insert_job_batch = Batch::default();
+        insert_job_batch.append_statement(session.prepare(cql).await?);
+        let cql = "INSERT INTO unfinished_jobs (id, created_at, lease_expires_at) VALUES (?, ?, 0)";
+        insert_job_batch.append_statement(session.prepare(cql).await?);
+
+        // Prepare "select job" statement
+        let cql = format!("SELECT {} from jobs where id = ?", JobRow::FIELDS.join(","));
+        let select_job_statement = session.prepare(cql).await?;
+
+        // Prepare "select unfinished jobs" statement
+        // Note: full scan of the index, which only holds the pending and running jobs
+        let cql = format!(
+            "SELECT {} from unfinished_jobs",
+            UnfinishedJobRow::FIELDS.join(",")
+        );
+        let select_unfinished_jobs_statement = session.prepare(cql).await?;
+
+        // Prepare "claim job" statement (not applied if the job is finished or leased)
+        let cql = "UPDATE unfinished_jobs SET lease_owner = ?, lease_expires_at = ? where id = ? IF lease_expires_at < ?";
+        let claim_job_statement = session.prepare(cql).await?;
+
+        // Prepare "renew job lease" statement (not applied if the lease has been lost)
+        let cql = "UPDATE unfinished_jobs SET lease_expires_at = ? where id = ? IF lease_owner = ?";
+        let renew_job_lease_statement = session.prepare(cql).await?;
+
+        // Prepare "update job state" statement
+        let cql = "UPDATE jobs SET status = ?, updated_at = ?, total_chunks = ?, processed_chunks = ?, result = ?, error = ? where id = ? IF EXISTS";
+        let update_job_state_statement = session.prepare(cql).await?;
+
+        // Prepare "delete unfinished job" statement (removed from the index once finished)
+        let cql = "DELETE from unfinished_jobs where id = ?";
+        let delete_unfinished_job_statement = session.prepare(cql).await?;
+
+        // Prepare "cancel job" statement
+        let cql = "UPDATE jobs SET cancel_requested = true where id = ? IF EXISTS";
+        let cancel_job_statement = session.prepare(cql).await?;
+
+        // Prepare "insert chunk" statement
+        let cql = format!(
+            "INSERT INTO job_chunks ({}) VALUES ({})",
+            JobChunkRow::FIELDS.join(","),
+            vec!["?"; JobChunkRow::FIELDS.len()].join(",")
+        );
+        let insert_chunk_statement = session.prepare(cql).await?;
+
+        // Prepare "insert output chunk" statement (expired after JOB_OUTPUT_TTL_IN_SECONDS)
+        let cql = format!(
+            "INSERT INTO job_chunks ({}) VALUES ({}) USING TTL {}",
+            JobChunkRow::FIELDS.join(","),
+            vec!["?"; JobChunkRow::FIELDS.len()].join(","),
+            JOB_OUTPUT_TTL_IN_SECONDS
+        );
+        let insert_output_chunk_statement = session.prepare(cql).await?;
+
+        // Prepare "select chunk" statement
+        let cql = format!(
+            "SELECT {} from job_chunks where job_id = ? and chunk_index = ?",
+            JobChunkRow::FIELDS.join(",")
+        );
+        let select_chunk_statement = session.prepare(cql).await?;
+
+        // Prepare "delete chunks" statement
+        let cql = "DELETE from job_chunks where job_id = ?";
+        let delete_chunks_statement = session.prepare(cql).await?;
+
+        Ok(ScyllaJobQueries {
+            session,
+            insert_job_batch,
+            select_job_statement,
+            select_unfinished_jobs_statement,
+            claim_job_statement,
+            renew_job_lease_statement,
+            update_job_state_statement,
+            delete_unfinished_job_statement,
+            cancel_job_statement,
+            insert_chunk_statement,
+            insert_output_chunk_statement,
+            select_chunk_statement,
+            delete_chunks_statement,
+        })
+    }
+}
+
+#[async_trait]
+impl JobQueries for ScyllaJobQueries {
+    async fn create_job(&self, job: &Job) -> AppResult<()> {
+        let row = JobRow::try_from(job)?;
+        let index_values = (row.id, row.created_at);
+        self.session
+            .batch(&self.insert_job_batch, (&row, index_values))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn find_one_job(&self, id: Uuid) -> AppResult<Job> {
+        let rows = self
+            .session
+            .execute(&self.select_job_statement, (id,))
+            .await?
+            .rows
+            .ok_or(AppError::NotFound("Job"))?;
+
+        let row = rows
+            .into_typed::<JobRow>()
+            .next()
+            .ok_or(AppError::NotFound("Job"))??;
+
+        Job::try_from(&row)
+    }
+
+    async fn find_unfinished_jobs(&self) -> AppResult<Vec<Job>> {
+        let now = Utc::now().timestamp_millis();
+        let mut rows = self
+            .session
+            .execute_iter(self.select_unfinished_jobs_statement.clone(), &[])
+            .await?
+            .into_typed::<UnfinishedJobRow>();
+
+        let mut unleased = Vec::new();
+        while let Some(row) = rows.next().await {
+            let row = row?;
+            if row.lease_expires_at.unwrap_or(0) < now {
+                unleased.push(row);
+            }
+        }
+        unleased.sort_by_key(|row| row.created_at);
+
+        let mut jobs = Vec::with_capacity(unleased.len());
+        for row in unleased.iter() {
+            let job = self.find_one_job(row.id).await?;
+            if job.status.is_finished() {
+                // Left in the index by an interrupted update of the job state
+                self.session
+                    .execute(&self.delete_unfinished_job_statement, (row.id,))
+                    .await?;
+            } else {
+                jobs.push(job);
+            }
+        }
+
+        Ok(jobs)
+    }
+
+    async fn claim_job(&self, id: Uuid, owner: Uuid, until: DateTime<Utc>) -> AppResult<bool> {
+        let now = Utc::now().timestamp_millis();
+        let result = self
+            .session
+            .execute(
+                &self.claim_job_statement,
+                (owner, until.timestamp_millis(), id, now),
+            )
+            .await?;
+
+        is_applied(&result)
+    }
+
+    async fn renew_job_lease(
+        &self,
+        id: Uuid,
+        owner: Uuid,
+        until: DateTime<Utc>,
+    ) -> AppResult<bool> {
+        let result = self
+            .session
+            .execute(
+                &self.renew_job_lease_statement,
+                (until.timestamp_millis(), id, owner),
+            )
+            .await?;
+
+        is_applied(&result)
+    }
+
+    async fn update_job_state(&self, job: &Job) -> AppResult<()> {
+        // "IF EXISTS": an update would otherwise create a new row
+        // Note: a conditional update cannot be batched with the removal from the index
+        let row = JobRow::try_from(job)?;
+        let result = self
+            .session
+            .execute(
+                &self.update_job_state_statement,
+                (
+                    row.status,
+                    row.updated_at,
+                    row.total_chunks,
+                    row.processed_chunks,
+                    row.result,
+                    row.error,
+                    row.id,
+                ),
+            )
+            .await?;
+        if !is_applied(&result)? {
+            return Err(AppError::NotFound("Job"));
+        }
+
+        if job.status.is_finished() {
+            self.session
+                .execute(&self.delete_unfinished_job_statement, (job.id,))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn cancel_job(&self, id: Uuid) -> AppResult<()> {
+        let result = self
+            .session
+            .execute(&self.cancel_job_statement, (id,))
+            .await?;
+        if !is_applied(&result)? {
+            return Err(AppError::NotFound("Job"));
+        }
+
+        Ok(())
+    }
+
+    async fn insert_job_chunk(&self, job_id: Uuid, chunk: &JobChunk) -> AppResult<()> {
+        self.session
+            .execute(
+                &self.insert_chunk_statement,
+                &JobChunkRow::from((job_id, chunk)),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn insert_job_output_chunk(&self, job_id: Uuid, chunk: &JobChunk) -> AppResult<()> {
+        self.session
+            .execute(
+                &self.insert_output_chunk_statement,
+                &JobChunkRow::from((job_id, chunk)),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn find_job_chunk(&self, job_id: Uuid, index: i32) -> AppResult<JobChunk> {
+        let rows = self
+            .session
+            .execute(&self.select_chunk_statement, (job_id, index))
+            .await?
+            .rows
+            .ok_or(AppError::NotFound("Job chunk"))?;
+
+        let row = rows
+            .into_typed::<JobChunkRow>()
+            .next()
+            .ok_or(AppError::NotFound("Job chunk"))??;
+
+        Ok(JobChunk::from(&row))
+    }
+
+    async fn delete_job_chunks(&self, job_id: Uuid) -> AppResult<()> {
+        self.session
+            .execute(&self.delete_chunks_statement, (job_id,))
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn to_datetime(millis: i64) -> AppResult<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .ok_or(AppError::ConversionError("Timestamp to DateTime"))
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct JobRow {
+    id: Uuid,
+    kind: String,
+    status: String,
+    /// JSON
+    params: String,
+    /// Milliseconds since epoch
+    created_at: i64,
+    /// Milliseconds since epoch
+    updated_at: i64,
+    total_chunks: i32,
+    processed_chunks: i32,
+    cancel_requested: bool,
+    /// JSON
+    result: Option<String>,
+    error: Option<String>,
+}
+
+// &Job -> JobRow
+impl TryFrom<&Job> for JobRow {
+    type Error = AppError;
+
+    fn try_from(job: &Job) -> Result<Self, Self::Error> {
+        let error = |_| AppError::ConversionError("Job to JobRow");
+
+        Ok(JobRow {
+            id: job.id,
+            kind: job.kind.to_string(),
+            status: job.status.to_string(),
+            params: serde_json::to_string(&job.params).map_err(error)?,
+            created_at: job.created_at.timestamp_millis(),
+            updated_at: job.updated_at.timestamp_millis(),
+            total_chunks: job.total_chunks,
+            processed_chunks: job.processed_chunks,
+            cancel_requested: job.cancel_requested,
+            result: job
+                .result
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()
+                .map_err(error)?,
+            error: job.error.clone(),
+        })
+    }
+}
+
+// &JobRow -> Job
+impl TryFrom<&JobRow> for Job {
+    type Error = AppError;
+
+    fn try_from(row: &JobRow) -> Result<Self, Self::Error> {
+        let error = || AppError::ConversionError("JobRow to Job");
+
+        Ok(Job {
+            id: row.id,
+            kind: JobKind::from_str(&row.kind).map_err(|_| error())?,
+            status: JobStatus::from_str(&row.status).map_err(|_| error())?,
+            params: serde_json::from_str(&row.params).map_err(|_| error())?,
+            created_at: to_datetime(row.created_at)?,
+            updated_at: to_datetime(row.updated_at)?,
+            total_chunks: row.total_chunks,
+            processed_chunks: row.processed_chunks,
+            cancel_requested: row.cancel_requested,
+            result: row
+                .result
+                .as_deref()
+                .map(serde_json::from_str)
+                .transpose()
+                .map_err(|_| error())?,
+            error: row.error.clone(),
+        })
+    }
+}
+
+/// Index of the pending and running jobs, with the expiry of the lease of the worker running it
+#[derive(PartialEq, scylla::FromRow, field_names::FieldNames, Debug)]
+struct UnfinishedJobRow {
+    id: Uuid,
+    /// Milliseconds since epoch
+    created_at: i64,
+    /// Milliseconds since epoch
+    lease_expires_at: Option<i64>,
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct JobChunkRow {
+    job_id: Uuid,
+    chunk_index: i32,
+    first_line: i64,
+    data: String,
+}
+
+// (job id, &JobChunk) -> JobChunkRow
+impl From<(Uuid, &JobChunk)> for JobChunkRow {
+    fn from((job_id, chunk): (Uuid, &JobChunk)) -> Self {
+        JobChunkRow {
+            job_id,
+            chunk_index: chunk.index,
+            first_line: chunk.first_line,
+            data: chunk.data.clone(),
+        }
+    }
+}
+
+// &JobChunkRow -> JobChunk
+impl From<&JobChunkRow> for JobChunk {
+    fn from(row: &JobChunkRow) -> Self {
+        JobChunk {
+            index: row.chunk_index,
+            first_line: row.first_line,
+            data: row.data.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn job1() -> Job {
+        Job {
+            id: Uuid::nil(),
+            kind: JobKind::Import,
+            status: JobStatus::Running,
+            params: json!({"format": "csv"}),
+            created_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+            updated_at: "2021-09-01T11:00:00Z".parse().unwrap(),
+            total_chunks: 3,
+            processed_chunks: 1,
+            cancel_requested: false,
+            result: Some(json!({"created": 2})),
+            error: None,
+        }
+    }
+
+    fn job1_row() -> JobRow {
+        JobRow {
+            id: Uuid::nil(),
+            kind: "import".to_string(),
+            status: "running".to_string(),
+            params: r#"{"format":"csv"}"#.to_string(),
+            created_at: 1_630_490_400_000,
+            updated_at: 1_630_494_000_000,
+            total_chunks: 3,
+            processed_chunks: 1,
+            cancel_requested: false,
+            result: Some(r#"{"created":2}"#.to_string()),
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn model_to_row() -> anyhow::Result<()> {
+        assert_eq!(JobRow::try_from(&job1())?, job1_row());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn row_to_model_ok() -> anyhow::Result<()> {
+        assert_eq!(Job::try_from(&job1_row())?, job1());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn row_to_model_error() {
+        let row = JobRow {
+            status: "paused".to_string(),
+            ..job1_row()
+        };
+
+        // TODO: user assert_matches! when stable
+        match Job::try_from(&row) {
+            Err(AppError::ConversionError(_)) => (),
+            _ => assert!(false),
+        }
+    }
+}
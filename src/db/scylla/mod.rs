@@ -4,8 +4,18 @@ use std::sync::Arc;
 use crate::error::AppError;
 use crate::register_db_error;
 
+pub mod alert_queries;
+pub mod cdc_reader;
+pub mod charging_queries;
+pub mod ev_queries;
+pub mod idempotency_queries;
+pub mod job_queries;
+pub mod outbox_queries;
 pub mod queries;
+pub mod rate_limit_queries;
+pub mod telemetry_queries;
 pub mod vehicle_queries;
+pub mod webhook_queries;
 
 pub async fn create_session(addr: &str, port: u16) -> AppResult<scylla::Session> {
     // Database session
@@ -17,7 +27,23 @@ pub async fn create_session(addr: &str, port: u16) -> AppResult<scylla::Session>
     Ok(session)
 }
 
+/// Whether a conditional query (lightweight transaction) has been applied
+///
+/// The first column of the result of a conditional query is "[applied]".
+pub(crate) fn is_applied(result: &scylla::QueryResult) -> AppResult<bool> {
+    match result
+        .rows
+        .as_ref()
+        .and_then(|rows| rows.first())
+        .and_then(|row| row.columns.first())
+    {
+        Some(Some(scylla::frame::response::result::CqlValue::Boolean(applied))) => Ok(*applied),
+        _ => Err(AppError::ConversionError("Query result to [applied]")),
+    }
+}
+
 register_db_error!(scylla::transport::errors::NewSessionError);
 register_db_error!(scylla::transport::errors::QueryError);
 register_db_error!(Arc<scylla::transport::errors::QueryError>);
 register_db_error!(scylla::cql_to_rust::FromRowError);
+register_db_error!(scylla::transport::iterator::NextRowError);
@@ -0,0 +1,265 @@
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::StreamExt;
+use scylla::cql_to_rust::{FromCqlVal, FromRow};
+use scylla::{prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    db::queries::OutboxQueries,
+    error::AppError,
+    model::{event::VehicleEventType, outbox::OutboxEntry},
+    result::AppResult,
+};
+
+pub struct ScyllaOutboxQueries {
+    session: Arc<Session>,
+    select_entries_statement: PreparedStatement,
+    delete_entry_statement: PreparedStatement,
+    select_cursor_statement: PreparedStatement,
+    update_cursor_statement: PreparedStatement,
+}
+
+impl std::fmt::Debug for ScyllaOutboxQueries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScyllaOutboxQueries").finish()
+    }
+}
+
+impl ScyllaOutboxQueries {
+    pub async fn try_new(session: Arc<Session>) -> AppResult<Self> {
+        // Prepare "select entries" statement (ordered by clustering key)
+        let cql = format!(
+            "SELECT {} from outbox where bucket = ? and time_window = ? LIMIT ?",
+            OutboxRow::FIELDS.join(",")
+        );
+        let select_entries_statement = session.prepare(cql).await?;
+
+        // Prepare "delete entry" statement
+        let cql = "DELETE from outbox where bucket = ? and time_window = ? and occurred_at = ? and id = ?";
+        let delete_entry_statement = session.prepare(cql).await?;
+
+        // Prepare "select cursor" statement
+        let cql = "SELECT time_window from outbox_cursors where bucket = ?";
+        let select_cursor_statement = session.prepare(cql).await?;
+
+        // Prepare "update cursor" statement
+        let cql = "INSERT INTO outbox_cursors (bucket, time_window) VALUES (?, ?)";
+        let update_cursor_statement = session.prepare(cql).await?;
+
+        Ok(ScyllaOutboxQueries {
+            session,
+            select_entries_statement,
+            delete_entry_statement,
+            select_cursor_statement,
+            update_cursor_statement,
+        })
+    }
+
+    /// Statement inserting an outbox entry, to be batched with the vehicle mutations
+    pub async fn prepare_insert_entry(session: &Session) -> AppResult<PreparedStatement> {
+        let cql = format!(
+            "INSERT INTO outbox ({}) VALUES ({})",
+            OutboxRow::FIELDS.join(","),
+            vec!["?"; OutboxRow::FIELDS.len()].join(",")
+        );
+
+        Ok(session.prepare(cql).await?)
+    }
+}
+
+#[async_trait]
+impl OutboxQueries for ScyllaOutboxQueries {
+    async fn find_outbox_entries(
+        &self,
+        bucket: i32,
+        time_window: i64,
+        limit: usize,
+    ) -> AppResult<Vec<OutboxEntry>> {
+        let mut rows = self
+            .session
+            .execute_iter(
+                self.select_entries_statement.clone(),
+                (bucket, time_window, limit as i32),
+            )
+            .await?
+            .into_typed::<OutboxRow>();
+
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next().await {
+            entries.push(OutboxEntry::try_from(&row?)?);
+        }
+
+        Ok(entries)
+    }
+
+    async fn delete_outbox_entry(&self, entry: &OutboxEntry) -> AppResult<()> {
+        self.session
+            .execute(
+                &self.delete_entry_statement,
+                (
+                    entry.bucket,
+                    entry.time_window,
+                    entry.occurred_at.timestamp_millis(),
+                    entry.id,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn find_outbox_cursor(&self, bucket: i32) -> AppResult<Option<i64>> {
+        let cursor = self
+            .session
+            .execute(&self.select_cursor_statement, (bucket,))
+            .await?
+            .rows
+            .and_then(|rows| rows.into_typed::<(i64,)>().next())
+            .transpose()?
+            .map(|(time_window,)| time_window);
+
+        Ok(cursor)
+    }
+
+    async fn update_outbox_cursor(&self, bucket: i32, time_window: i64) -> AppResult<()> {
+        self.session
+            .execute(&self.update_cursor_statement, (bucket, time_window))
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn to_datetime(millis: i64) -> AppResult<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .ok_or(AppError::ConversionError("Timestamp to DateTime"))
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+pub(crate) struct OutboxRow {
+    bucket: i32,
+    time_window: i64,
+    /// Milliseconds since epoch
+    occurred_at: i64,
+    id: Uuid,
+    event_type: String,
+    vin: String,
+    /// Vehicle after the change (JSON)
+    vehicle: Option<String>,
+}
+
+// &OutboxEntry -> OutboxRow
+impl TryFrom<&OutboxEntry> for OutboxRow {
+    type Error = AppError;
+
+    fn try_from(entry: &OutboxEntry) -> Result<Self, Self::Error> {
+        let vehicle = entry
+            .vehicle
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|_| AppError::ConversionError("OutboxEntry to OutboxRow"))?;
+
+        Ok(OutboxRow {
+            bucket: entry.bucket,
+            time_window: entry.time_window,
+            occurred_at: entry.occurred_at.timestamp_millis(),
+            id: entry.id,
+            event_type: entry.event_type.to_string(),
+            vin: entry.vin.clone(),
+            vehicle,
+        })
+    }
+}
+
+// &OutboxRow -> OutboxEntry
+impl TryFrom<&OutboxRow> for OutboxEntry {
+    type Error = AppError;
+
+    fn try_from(row: &OutboxRow) -> Result<Self, Self::Error> {
+        let error = || AppError::ConversionError("OutboxRow to OutboxEntry");
+
+        Ok(OutboxEntry {
+            bucket: row.bucket,
+            time_window: row.time_window,
+            id: row.id,
+            event_type: VehicleEventType::from_str(&row.event_type).map_err(|_| error())?,
+            vin: row.vin.clone(),
+            occurred_at: to_datetime(row.occurred_at)?,
+            vehicle: row
+                .vehicle
+                .as_deref()
+                .map(serde_json::from_str)
+                .transpose()
+                .map_err(|_| error())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::vehicle::{Engine, Vehicle};
+
+    fn entry1() -> OutboxEntry {
+        OutboxEntry {
+            bucket: 3,
+            time_window: 27_174_840,
+            id: Uuid::nil(),
+            event_type: VehicleEventType::Created,
+            vin: "vin1".to_string(),
+            occurred_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+            vehicle: Some(Vehicle {
+                vin: "vin1".to_string(),
+                owner: None,
+                engine: Engine::Combustion,
+                ev_data: None,
+            }),
+        }
+    }
+
+    fn entry1_row() -> OutboxRow {
+        OutboxRow {
+            bucket: 3,
+            time_window: 27_174_840,
+            occurred_at: 1_630_490_400_000,
+            id: Uuid::nil(),
+            event_type: "created".to_string(),
+            vin: "vin1".to_string(),
+            vehicle: Some(r#"{"vin":"vin1","engine_type":"Combustion"}"#.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn model_to_row() -> anyhow::Result<()> {
+        assert_eq!(OutboxRow::try_from(&entry1())?, entry1_row());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn row_to_model_ok() -> anyhow::Result<()> {
+        assert_eq!(OutboxEntry::try_from(&entry1_row())?, entry1());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn row_to_model_error() {
+        let row = OutboxRow {
+            event_type: "renamed".to_string(),
+            ..entry1_row()
+        };
+
+        // TODO: user assert_matches! when stable
+        match OutboxEntry::try_from(&row) {
+            Err(AppError::ConversionError(_)) => (),
+            _ => assert!(false),
+        }
+    }
+}
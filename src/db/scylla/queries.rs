@@ -2,13 +2,34 @@ use anyhow::Result;
 use std::sync::Arc;
 
 use crate::db::queries::Queries;
+use crate::db::scylla::alert_queries::ScyllaAlertQueries;
+use crate::db::scylla::cdc_reader::ScyllaCdcReader;
+use crate::db::scylla::charging_queries::ScyllaChargingQueries;
+use crate::db::scylla::ev_queries::ScyllaEvQueries;
+use crate::db::scylla::idempotency_queries::ScyllaIdempotencyQueries;
+use crate::db::scylla::job_queries::ScyllaJobQueries;
+use crate::db::scylla::outbox_queries::ScyllaOutboxQueries;
+use crate::db::scylla::rate_limit_queries::ScyllaRateLimitQueries;
+use crate::db::scylla::telemetry_queries::ScyllaTelemetryQueries;
 use crate::db::scylla::vehicle_queries::ScyllaVehicleQueries;
+use crate::db::scylla::webhook_queries::ScyllaWebhookQueries;
 use crate::error::AppError;
+use crate::events::EventBus;
+use crate::idempotency::IDEMPOTENCY_KEY_TTL_IN_SECONDS;
 
 pub struct ScyllaQueries {
     vehicle_queries: ScyllaVehicleQueries,
+    telemetry_queries: ScyllaTelemetryQueries,
+    charging_queries: ScyllaChargingQueries,
+    ev_queries: ScyllaEvQueries,
+    alert_queries: ScyllaAlertQueries,
+    webhook_queries: ScyllaWebhookQueries,
+    outbox_queries: ScyllaOutboxQueries,
+    job_queries: ScyllaJobQueries,
+    idempotency_queries: ScyllaIdempotencyQueries,
+    rate_limit_queries: ScyllaRateLimitQueries,
+    event_bus: Arc<EventBus>,
 
-    #[allow(dead_code)]
     session: Arc<scylla::Session>,
 }
 
@@ -20,31 +41,183 @@ impl ScyllaQueries {
         let cql_array = [
             format!("CREATE KEYSPACE IF NOT EXISTS {} WITH REPLICATION = {{'class' : 'SimpleStrategy', 'replication_factor' : 1}}", keyspace),
             format!("CREATE TYPE IF NOT EXISTS {}.ev_data (battery_capacity_in_kwh int, soc_in_percent int)", keyspace),
-            format!("CREATE TABLE IF NOT EXISTS {}.vehicles (vin text primary key, engine_type text, ev_data ev_data)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.vehicles (vin text primary key, owner text, engine_type text, ev_data ev_data, deleted_at bigint)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.soc_readings (vin text, day date, recorded_at bigint, soc_in_percent int, PRIMARY KEY ((vin, day), recorded_at)) WITH CLUSTERING ORDER BY (recorded_at ASC)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.soc_reading_days (vin text, day date, PRIMARY KEY (vin, day))", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.latest_soc_readings (vin text primary key, day date, recorded_at bigint, soc_in_percent int)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.soc_rollups (vin text, bucket_size text, year int, bucket_start bigint, min_soc int, max_soc int, avg_soc double, first_soc int, last_soc int, count bigint, PRIMARY KEY ((vin, bucket_size, year), bucket_start)) WITH CLUSTERING ORDER BY (bucket_start ASC)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.soc_rollup_partitions (vin text, bucket_size text, year int, PRIMARY KEY (vin, bucket_size, year))", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.charging_sessions (vin text, id uuid, start_time bigint, end_time bigint, start_soc_in_percent int, end_soc_in_percent int, energy_added_in_kwh double, max_power_in_kw double, location text, detected boolean, PRIMARY KEY (vin, id))", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.ev_consumption (vin text primary key, configured_in_kwh_per_100km double, trip_distance_in_km double, trip_energy_in_kwh double)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.battery_health (vin text, measured_at bigint, usable_capacity_in_kwh double, nominal_capacity_in_kwh int, PRIMARY KEY (vin, measured_at)) WITH CLUSTERING ORDER BY (measured_at ASC)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.alert_rules (id uuid primary key, scope_type text, scope_value text, soc_below_in_percent int, created_at bigint)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.alerts (vin text, id uuid, rule_id uuid, status text, soc_in_percent int, fired_at bigint, acknowledged_at bigint, resolved_at bigint, PRIMARY KEY (vin, id))", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.webhooks (id uuid primary key, url text, secret text, events list<text>, created_at bigint)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.webhook_deliveries (webhook_id uuid, id uuid, event text, payload text, status text, attempts int, created_at bigint, next_attempt_at bigint, last_attempt_at bigint, last_status_code int, last_error text, PRIMARY KEY (webhook_id, id))", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.webhook_due_deliveries (bucket int, next_attempt_at bigint, webhook_id uuid, id uuid, PRIMARY KEY (bucket, next_attempt_at, webhook_id, id))", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.outbox (bucket int, time_window bigint, occurred_at bigint, id uuid, event_type text, vin text, vehicle text, PRIMARY KEY ((bucket, time_window), occurred_at, id)) WITH CLUSTERING ORDER BY (occurred_at ASC, id ASC)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.outbox_cursors (bucket int primary key, time_window bigint)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.jobs (id uuid primary key, kind text, status text, params text, created_at bigint, updated_at bigint, total_chunks int, processed_chunks int, cancel_requested boolean, result text, error text)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.unfinished_jobs (id uuid primary key, created_at bigint, lease_owner uuid, lease_expires_at bigint)", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.job_chunks (job_id uuid, chunk_index int, first_line bigint, data text, PRIMARY KEY (job_id, chunk_index))", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.idempotency_keys (key text primary key, fingerprint text, status int, content_type text, body blob, created_at bigint) WITH default_time_to_live = {}", keyspace, IDEMPOTENCY_KEY_TTL_IN_SECONDS),
+            format!("CREATE TABLE IF NOT EXISTS {}.rate_limit_buckets (key text primary key, tokens double, updated_at bigint)", keyspace),
+            format!("ALTER TABLE {}.vehicles WITH cdc = {{'enabled': true, 'postimage': true}}", keyspace),
+            format!("CREATE TABLE IF NOT EXISTS {}.cdc_checkpoints (table_name text, stream_id blob, last_time timeuuid, PRIMARY KEY (table_name, stream_id))", keyspace),
         ];
         for cql in cql_array.iter() {
             session.query(cql.as_ref(), &[]).await?;
         }
 
+        // Add the columns missing from the tables created by previous versions
+        let added_columns = [
+            ("vehicles", "owner", "text"),
+            ("vehicles", "deleted_at", "bigint"),
+        ];
+        for (table, column, cql_type) in added_columns.iter() {
+            add_column_if_missing(&session, keyspace, table, column, cql_type).await?;
+        }
+
         // Use keyspace
         session.use_keyspace(keyspace, false).await?;
 
-        // Create (lazily-prepared) vehicle queries
-        let vehicle_queries = ScyllaVehicleQueries::try_new(session.clone()).await?;
+        // Create (lazily-prepared) vehicle, telemetry, charging, EV, alert, webhook, outbox, job, idempotency and rate limit queries
+        let event_bus = Arc::new(EventBus::default());
+        let vehicle_queries =
+            ScyllaVehicleQueries::try_new(session.clone(), event_bus.clone()).await?;
+        let telemetry_queries =
+            ScyllaTelemetryQueries::try_new(session.clone(), event_bus.clone()).await?;
+        let charging_queries = ScyllaChargingQueries::try_new(session.clone()).await?;
+        let ev_queries = ScyllaEvQueries::try_new(session.clone()).await?;
+        let alert_queries = ScyllaAlertQueries::try_new(session.clone()).await?;
+        let webhook_queries = ScyllaWebhookQueries::try_new(session.clone()).await?;
+        let outbox_queries = ScyllaOutboxQueries::try_new(session.clone()).await?;
+        let job_queries = ScyllaJobQueries::try_new(session.clone()).await?;
+        let idempotency_queries = ScyllaIdempotencyQueries::try_new(session.clone()).await?;
+        let rate_limit_queries = ScyllaRateLimitQueries::try_new(session.clone()).await?;
 
         Ok(ScyllaQueries {
             vehicle_queries,
+            telemetry_queries,
+            charging_queries,
+            ev_queries,
+            alert_queries,
+            webhook_queries,
+            outbox_queries,
+            job_queries,
+            idempotency_queries,
+            rate_limit_queries,
+            event_bus,
             session,
         })
     }
+
+    /// Reader publishing the changes of the CDC log made outside of the API
+    pub async fn create_cdc_reader(&self) -> Result<ScyllaCdcReader, AppError> {
+        ScyllaCdcReader::try_new(self.session.clone(), self.event_bus.clone()).await
+    }
+}
+
+async fn column_exists(
+    session: &scylla::Session,
+    keyspace: &str,
+    table: &str,
+    column: &str,
+) -> Result<bool, AppError> {
+    let rows = session
+        .query(
+            "SELECT column_name FROM system_schema.columns WHERE keyspace_name = ? AND table_name = ? AND column_name = ?",
+            (keyspace, table, column),
+        )
+        .await?
+        .rows;
+
+    Ok(rows.map_or(false, |rows| !rows.is_empty()))
+}
+
+/// `CREATE TABLE IF NOT EXISTS` does not add the new columns to an existing table
+///
+/// The column may also be added concurrently by another instance, the failed `ALTER TABLE` is
+/// then ignored.
+async fn add_column_if_missing(
+    session: &scylla::Session,
+    keyspace: &str,
+    table: &str,
+    column: &str,
+    cql_type: &str,
+) -> Result<(), AppError> {
+    if column_exists(session, keyspace, table, column).await? {
+        return Ok(());
+    }
+
+    let cql = format!(
+        "ALTER TABLE {}.{} ADD {} {}",
+        keyspace, table, column, cql_type
+    );
+    if let Err(e) = session.query(cql, &[]).await {
+        if !column_exists(session, keyspace, table, column).await? {
+            return Err(e.into());
+        }
+    }
+
+    Ok(())
 }
 
 impl Queries for ScyllaQueries {
     type VQ = ScyllaVehicleQueries;
+    type TQ = ScyllaTelemetryQueries;
+    type CQ = ScyllaChargingQueries;
+    type EQ = ScyllaEvQueries;
+    type AQ = ScyllaAlertQueries;
+    type WQ = ScyllaWebhookQueries;
+    type OQ = ScyllaOutboxQueries;
+    type JQ = ScyllaJobQueries;
+    type IQ = ScyllaIdempotencyQueries;
+    type RQ = ScyllaRateLimitQueries;
 
     fn vehicle_queries(&self) -> &Self::VQ {
         &self.vehicle_queries
     }
+
+    fn telemetry_queries(&self) -> &Self::TQ {
+        &self.telemetry_queries
+    }
+
+    fn charging_queries(&self) -> &Self::CQ {
+        &self.charging_queries
+    }
+
+    fn ev_queries(&self) -> &Self::EQ {
+        &self.ev_queries
+    }
+
+    fn alert_queries(&self) -> &Self::AQ {
+        &self.alert_queries
+    }
+
+    fn webhook_queries(&self) -> &Self::WQ {
+        &self.webhook_queries
+    }
+
+    fn outbox_queries(&self) -> &Self::OQ {
+        &self.outbox_queries
+    }
+
+    fn job_queries(&self) -> &Self::JQ {
+        &self.job_queries
+    }
+
+    fn idempotency_queries(&self) -> &Self::IQ {
+        &self.idempotency_queries
+    }
+
+    fn rate_limit_queries(&self) -> &Self::RQ {
+        &self.rate_limit_queries
+    }
+
+    fn event_bus(&self) -> &EventBus {
+        &self.event_bus
+    }
 }
 
 impl std::fmt::Debug for ScyllaQueries {
@@ -52,6 +225,15 @@ impl std::fmt::Debug for ScyllaQueries {
         f.debug_struct("ScyllaQueries")
             //.field("session", &self.session)
             .field("vehicle_queries", &self.vehicle_queries)
+            .field("telemetry_queries", &self.telemetry_queries)
+            .field("charging_queries", &self.charging_queries)
+            .field("ev_queries", &self.ev_queries)
+            .field("alert_queries", &self.alert_queries)
+            .field("webhook_queries", &self.webhook_queries)
+            .field("outbox_queries", &self.outbox_queries)
+            .field("job_queries", &self.job_queries)
+            .field("idempotency_queries", &self.idempotency_queries)
+            .field("rate_limit_queries", &self.rate_limit_queries)
             .finish()
     }
 }
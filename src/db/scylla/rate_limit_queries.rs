@@ -0,0 +1,144 @@
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use scylla::cql_to_rust::{FromCqlVal, FromRow};
+use scylla::{prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    db::{queries::RateLimitQueries, scylla::is_applied},
+    error::AppError,
+    model::rate_limit::TokenBucket,
+    result::AppResult,
+};
+
+pub struct ScyllaRateLimitQueries {
+    session: Arc<Session>,
+    insert_bucket_statement: PreparedStatement,
+    update_bucket_statement: PreparedStatement,
+    select_bucket_statement: PreparedStatement,
+}
+
+impl std::fmt::Debug for ScyllaRateLimitQueries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScyllaRateLimitQueries").finish()
+    }
+}
+
+impl ScyllaRateLimitQueries {
+    pub async fn try_new(session: Arc<Session>) -> AppResult<Self> {
+        // Prepare "insert bucket" statement (conditional, not applied if created concurrently)
+        let cql = format!(
+            "INSERT INTO rate_limit_buckets ({}) VALUES ({}) IF NOT EXISTS USING TTL ?",
+            TokenBucketRow::FIELDS.join(","),
+            vec!["?"; TokenBucketRow::FIELDS.len()].join(",")
+        );
+        let insert_bucket_statement = session.prepare(cql).await?;
+
+        // Prepare "update bucket" statement (conditional, not applied if updated concurrently)
+        let cql = "UPDATE rate_limit_buckets USING TTL ? SET tokens = ?, updated_at = ? where key = ? IF updated_at = ?";
+        let update_bucket_statement = session.prepare(cql).await?;
+
+        // Prepare "select bucket" statement
+        let cql = format!(
+            "SELECT {} from rate_limit_buckets where key = ?",
+            TokenBucketRow::FIELDS.join(",")
+        );
+        let select_bucket_statement = session.prepare(cql).await?;
+
+        Ok(ScyllaRateLimitQueries {
+            session,
+            insert_bucket_statement,
+            update_bucket_statement,
+            select_bucket_statement,
+        })
+    }
+}
+
+#[async_trait]
+impl RateLimitQueries for ScyllaRateLimitQueries {
+    async fn find_token_bucket(&self, key: &str) -> AppResult<Option<TokenBucket>> {
+        let rows = match self
+            .session
+            .execute(&self.select_bucket_statement, (key,))
+            .await?
+            .rows
+        {
+            Some(rows) => rows,
+            None => return Ok(None),
+        };
+
+        rows.into_typed::<TokenBucketRow>()
+            .next()
+            .map(|row| TokenBucket::try_from(&row?))
+            .transpose()
+    }
+
+    async fn save_token_bucket(
+        &self,
+        key: &str,
+        bucket: &TokenBucket,
+        read_updated_at: Option<DateTime<Utc>>,
+        ttl: Duration,
+    ) -> AppResult<bool> {
+        // Note: a TTL of 0 would mean no expiration
+        let ttl_in_secs = i32::try_from(ttl.as_secs().max(1))
+            .map_err(|_| AppError::ConversionError("Rate limit TTL"))?;
+
+        let result = match read_updated_at {
+            Some(read_updated_at) => {
+                self.session
+                    .execute(
+                        &self.update_bucket_statement,
+                        (
+                            ttl_in_secs,
+                            bucket.tokens,
+                            bucket.updated_at.timestamp_millis(),
+                            key,
+                            read_updated_at.timestamp_millis(),
+                        ),
+                    )
+                    .await?
+            }
+            None => {
+                self.session
+                    .execute(
+                        &self.insert_bucket_statement,
+                        (
+                            key,
+                            bucket.tokens,
+                            bucket.updated_at.timestamp_millis(),
+                            ttl_in_secs,
+                        ),
+                    )
+                    .await?
+            }
+        };
+
+        is_applied(&result)
+    }
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct TokenBucketRow {
+    key: String,
+    tokens: f64,
+    /// Milliseconds since epoch
+    updated_at: i64,
+}
+
+// &TokenBucketRow -> TokenBucket
+impl TryFrom<&TokenBucketRow> for TokenBucket {
+    type Error = AppError;
+
+    fn try_from(row: &TokenBucketRow) -> Result<Self, Self::Error> {
+        Ok(TokenBucket {
+            tokens: row.tokens,
+            updated_at: Utc
+                .timestamp_millis_opt(row.updated_at)
+                .single()
+                .ok_or(AppError::ConversionError("Timestamp to DateTime"))?,
+        })
+    }
+}
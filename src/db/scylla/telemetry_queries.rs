@@ -0,0 +1,505 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use futures::{StreamExt, TryStreamExt};
+use scylla::cql_to_rust::{FromCqlVal, FromRow};
+use scylla::{prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::{
+    db::queries::TelemetryQueries,
+    error::AppError,
+    events::EventBus,
+    model::telemetry::{AggregateInterval, SocAggregate, SocReading},
+    result::AppResult,
+};
+
+/// Maximum number of rows written concurrently by a telemetry batch
+const TELEMETRY_WRITE_CONCURRENCY: usize = 16;
+
+pub struct ScyllaTelemetryQueries {
+    session: Arc<Session>,
+    event_bus: Arc<EventBus>,
+    insert_soc_reading_statement: PreparedStatement,
+    select_soc_readings_statement: PreparedStatement,
+    insert_soc_reading_day_statement: PreparedStatement,
+    upsert_latest_soc_reading_statement: PreparedStatement,
+    select_latest_soc_reading_statement: PreparedStatement,
+    select_latest_soc_readings_statement: PreparedStatement,
+    select_vins_statement: PreparedStatement,
+    upsert_soc_aggregate_statement: PreparedStatement,
+    insert_soc_rollup_partition_statement: PreparedStatement,
+    select_soc_aggregates_statement: PreparedStatement,
+}
+
+impl std::fmt::Debug for ScyllaTelemetryQueries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScyllaTelemetryQueries").finish()
+    }
+}
+
+impl ScyllaTelemetryQueries {
+    pub async fn try_new(session: Arc<Session>, event_bus: Arc<EventBus>) -> AppResult<Self> {
+        // Prepare "insert SoC reading" statement
+        let cql = format!(
+            "INSERT INTO soc_readings ({}) VALUES ({})",
+            SocReadingRow::FIELDS.join(","),
+            vec!["?"; SocReadingRow::FIELDS.len()].join(",")
+        );
+        let insert_soc_reading_statement = session.prepare(cql).await?;
+
+        // Prepare "select SoC readings" statement (one day partition at a time)
+        let cql = format!(
+            "SELECT {} from soc_readings where vin = ? and day = ? and recorded_at >= ? and recorded_at < ?",
+            SocReadingRow::FIELDS.join(",")
+        );
+        let select_soc_readings_statement = session.prepare(cql).await?;
+
+        // Prepare "insert SoC reading day" statement (per-vehicle index of the partitions)
+        let cql = "INSERT INTO soc_reading_days (vin, day) VALUES (?, ?)";
+        let insert_soc_reading_day_statement = session.prepare(cql).await?;
+
+        // Prepare "upsert latest SoC reading" statement
+        // (the write timestamp is the reading time, so that only the newest reading wins)
+        let cql = format!(
+            "INSERT INTO latest_soc_readings ({}) VALUES ({}) USING TIMESTAMP ?",
+            SocReadingRow::FIELDS.join(","),
+            vec!["?"; SocReadingRow::FIELDS.len()].join(",")
+        );
+        let upsert_latest_soc_reading_statement = session.prepare(cql).await?;
+
+        // Prepare "select latest SoC reading" statement
+        let cql = format!(
+            "SELECT {} from latest_soc_readings where vin = ?",
+            SocReadingRow::FIELDS.join(",")
+        );
+        let select_latest_soc_reading_statement = session.prepare(cql).await?;
+
+        // Prepare "select latest SoC readings" statement (several vehicles at once)
+        let cql = format!(
+            "SELECT {} from latest_soc_readings where vin in ?",
+            SocReadingRow::FIELDS.join(",")
+        );
+        let select_latest_soc_readings_statement = session.prepare(cql).await?;
+
+        // Prepare "select VINs" statement
+        let cql = "SELECT vin from latest_soc_readings";
+        let select_vins_statement = session.prepare(cql).await?;
+
+        // Prepare "upsert SoC aggregate" statement
+        let cql = format!(
+            "INSERT INTO soc_rollups ({}) VALUES ({})",
+            SocAggregateRow::FIELDS.join(","),
+            vec!["?"; SocAggregateRow::FIELDS.len()].join(",")
+        );
+        let upsert_soc_aggregate_statement = session.prepare(cql).await?;
+
+        // Prepare "insert SoC rollup partition" statement (per-vehicle index of the partitions)
+        let cql = "INSERT INTO soc_rollup_partitions (vin, bucket_size, year) VALUES (?, ?, ?)";
+        let insert_soc_rollup_partition_statement = session.prepare(cql).await?;
+
+        // Prepare "select SoC aggregates" statement (one year partition at a time)
+        let cql = format!(
+            "SELECT {} from soc_rollups where vin = ? and bucket_size = ? and year = ? and bucket_start >= ? and bucket_start < ?",
+            SocAggregateRow::FIELDS.join(",")
+        );
+        let select_soc_aggregates_statement = session.prepare(cql).await?;
+
+        Ok(ScyllaTelemetryQueries {
+            session,
+            event_bus,
+            insert_soc_reading_statement,
+            select_soc_readings_statement,
+            insert_soc_reading_day_statement,
+            upsert_latest_soc_reading_statement,
+            select_latest_soc_reading_statement,
+            select_latest_soc_readings_statement,
+            select_vins_statement,
+            upsert_soc_aggregate_statement,
+            insert_soc_rollup_partition_statement,
+            select_soc_aggregates_statement,
+        })
+    }
+}
+
+#[async_trait]
+impl TelemetryQueries for ScyllaTelemetryQueries {
+    async fn insert_soc_readings(&self, vin: &str, readings: &[SocReading]) -> AppResult<()> {
+        let rows: Vec<SocReadingRow> = readings
+            .iter()
+            .map(|reading| SocReadingRow::new(vin, reading))
+            .collect();
+
+        // Index the (vin, day) partitions first, so that a purge finds every partition written
+        let days: HashSet<NaiveDate> = rows.iter().map(|row| row.day).collect();
+        futures::stream::iter(days.into_iter().map(|day| {
+            self.session
+                .execute(&self.insert_soc_reading_day_statement, (vin, day))
+        }))
+        .buffer_unordered(TELEMETRY_WRITE_CONCURRENCY)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        // Readings may span several (vin, day) partitions: insert them concurrently (bounded)
+        futures::stream::iter(rows.iter().map(|row| {
+            self.session
+                .execute(&self.insert_soc_reading_statement, row)
+        }))
+        .buffer_unordered(TELEMETRY_WRITE_CONCURRENCY)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        if let Some(newest_row) = rows.iter().max_by_key(|row| row.recorded_at) {
+            self.session
+                .execute(
+                    &self.upsert_latest_soc_reading_statement,
+                    (
+                        newest_row.vin.as_str(),
+                        newest_row.day,
+                        newest_row.recorded_at,
+                        newest_row.soc_in_percent,
+                        newest_row.recorded_at * 1000,
+                    ),
+                )
+                .await?;
+        }
+
+        self.event_bus.publish_telemetry(vin, readings);
+
+        Ok(())
+    }
+
+    async fn find_soc_readings(
+        &self,
+        vin: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> AppResult<Vec<SocReading>> {
+        let mut readings = Vec::new();
+
+        let mut day = from.naive_utc().date();
+        while day <= to.naive_utc().date() {
+            let mut rows = self
+                .session
+                .execute_iter(
+                    self.select_soc_readings_statement.clone(),
+                    (vin, day, from.timestamp_millis(), to.timestamp_millis()),
+                )
+                .await?
+                .into_typed::<SocReadingRow>();
+
+            while let Some(row) = rows.next().await {
+                readings.push(SocReading::try_from(&row?)?);
+            }
+
+            day = match day.succ_opt() {
+                Some(next_day) => next_day,
+                None => break,
+            };
+        }
+
+        Ok(readings)
+    }
+
+    async fn find_latest_soc_reading(&self, vin: &str) -> AppResult<Option<SocReading>> {
+        let rows = match self
+            .session
+            .execute(&self.select_latest_soc_reading_statement, (vin,))
+            .await?
+            .rows
+        {
+            Some(rows) => rows,
+            None => return Ok(None),
+        };
+
+        rows.into_typed::<SocReadingRow>()
+            .next()
+            .transpose()?
+            .as_ref()
+            .map(SocReading::try_from)
+            .transpose()
+    }
+
+    async fn find_latest_soc_readings(
+        &self,
+        vins: &[String],
+    ) -> AppResult<HashMap<String, SocReading>> {
+        let rows = match self
+            .session
+            .execute(&self.select_latest_soc_readings_statement, (vins.to_vec(),))
+            .await?
+            .rows
+        {
+            Some(rows) => rows,
+            None => return Ok(HashMap::new()),
+        };
+
+        rows.into_typed::<SocReadingRow>()
+            .map(|row| {
+                let row = row?;
+                Ok((row.vin.clone(), SocReading::try_from(&row)?))
+            })
+            .collect()
+    }
+
+    async fn find_vins_with_telemetry(&self) -> AppResult<Vec<String>> {
+        let mut rows = self
+            .session
+            .execute_iter(self.select_vins_statement.clone(), &[])
+            .await?
+            .into_typed::<(String,)>();
+
+        let mut vins = Vec::new();
+        while let Some(row) = rows.next().await {
+            let (vin,) = row?;
+            vins.push(vin);
+        }
+
+        Ok(vins)
+    }
+
+    async fn upsert_soc_aggregates(
+        &self,
+        vin: &str,
+        interval: AggregateInterval,
+        aggregates: &[SocAggregate],
+    ) -> AppResult<()> {
+        let rows: Vec<SocAggregateRow> = aggregates
+            .iter()
+            .map(|aggregate| SocAggregateRow::new(vin, interval, aggregate))
+            .collect();
+
+        // Index the (vin, bucket size, year) partitions first, so that a purge finds them
+        let partitions: HashSet<(&str, i32)> = rows
+            .iter()
+            .map(|row| (row.bucket_size.as_str(), row.year))
+            .collect();
+        futures::stream::iter(partitions.into_iter().map(|(bucket_size, year)| {
+            self.session.execute(
+                &self.insert_soc_rollup_partition_statement,
+                (vin, bucket_size, year),
+            )
+        }))
+        .buffer_unordered(TELEMETRY_WRITE_CONCURRENCY)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        futures::stream::iter(rows.iter().map(|row| {
+            self.session
+                .execute(&self.upsert_soc_aggregate_statement, row)
+        }))
+        .buffer_unordered(TELEMETRY_WRITE_CONCURRENCY)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        Ok(())
+    }
+
+    async fn find_soc_aggregates(
+        &self,
+        vin: &str,
+        interval: AggregateInterval,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> AppResult<Vec<SocAggregate>> {
+        let mut aggregates = Vec::new();
+
+        for year in from.year()..=to.year() {
+            let mut rows = self
+                .session
+                .execute_iter(
+                    self.select_soc_aggregates_statement.clone(),
+                    (
+                        vin,
+                        interval.to_string(),
+                        year,
+                        from.timestamp_millis(),
+                        to.timestamp_millis(),
+                    ),
+                )
+                .await?
+                .into_typed::<SocAggregateRow>();
+
+            while let Some(row) = rows.next().await {
+                aggregates.push(SocAggregate::try_from(&row?)?);
+            }
+        }
+
+        Ok(aggregates)
+    }
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct SocReadingRow {
+    vin: String,
+    day: NaiveDate,
+    /// Reading time (milliseconds since epoch)
+    recorded_at: i64,
+    soc_in_percent: i32,
+}
+
+impl SocReadingRow {
+    fn new(vin: &str, reading: &SocReading) -> Self {
+        SocReadingRow {
+            vin: vin.to_string(),
+            day: reading.timestamp.naive_utc().date(),
+            recorded_at: reading.timestamp.timestamp_millis(),
+            soc_in_percent: reading.soc_in_percent,
+        }
+    }
+}
+
+// &SocReadingRow -> SocReading
+impl TryFrom<&SocReadingRow> for SocReading {
+    type Error = AppError;
+
+    fn try_from(row: &SocReadingRow) -> Result<Self, Self::Error> {
+        let timestamp = Utc
+            .timestamp_millis_opt(row.recorded_at)
+            .single()
+            .ok_or(AppError::ConversionError("SocReadingRow to SocReading"))?;
+
+        Ok(SocReading {
+            timestamp,
+            soc_in_percent: row.soc_in_percent,
+        })
+    }
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct SocAggregateRow {
+    vin: String,
+    bucket_size: String,
+    year: i32,
+    /// Bucket start (milliseconds since epoch)
+    bucket_start: i64,
+    min_soc: i32,
+    max_soc: i32,
+    avg_soc: f64,
+    first_soc: i32,
+    last_soc: i32,
+    count: i64,
+}
+
+impl SocAggregateRow {
+    fn new(vin: &str, interval: AggregateInterval, aggregate: &SocAggregate) -> Self {
+        SocAggregateRow {
+            vin: vin.to_string(),
+            bucket_size: interval.to_string(),
+            year: aggregate.start.year(),
+            bucket_start: aggregate.start.timestamp_millis(),
+            min_soc: aggregate.min,
+            max_soc: aggregate.max,
+            avg_soc: aggregate.avg,
+            first_soc: aggregate.first,
+            last_soc: aggregate.last,
+            count: aggregate.count,
+        }
+    }
+}
+
+// &SocAggregateRow -> SocAggregate
+impl TryFrom<&SocAggregateRow> for SocAggregate {
+    type Error = AppError;
+
+    fn try_from(row: &SocAggregateRow) -> Result<Self, Self::Error> {
+        // Ensure that the bucket size is known
+        let _ = AggregateInterval::from_str(&row.bucket_size)
+            .map_err(|_| AppError::ConversionError("SocAggregateRow to SocAggregate"))?;
+
+        let start = Utc
+            .timestamp_millis_opt(row.bucket_start)
+            .single()
+            .ok_or(AppError::ConversionError("SocAggregateRow to SocAggregate"))?;
+
+        Ok(SocAggregate {
+            start,
+            min: row.min_soc,
+            max: row.max_soc,
+            avg: row.avg_soc,
+            first: row.first_soc,
+            last: row.last_soc,
+            count: row.count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading1() -> SocReading {
+        SocReading {
+            timestamp: "2021-09-01T23:59:59.250Z".parse().unwrap(),
+            soc_in_percent: 42,
+        }
+    }
+
+    fn reading1_row() -> SocReadingRow {
+        SocReadingRow {
+            vin: "vin".to_string(),
+            day: NaiveDate::from_ymd_opt(2021, 9, 1).unwrap(),
+            recorded_at: 1_630_540_799_250,
+            soc_in_percent: 42,
+        }
+    }
+
+    fn aggregate1() -> SocAggregate {
+        SocAggregate {
+            start: "2021-09-01T23:00:00Z".parse().unwrap(),
+            min: 40,
+            max: 50,
+            avg: 45.5,
+            first: 50,
+            last: 41,
+            count: 12,
+        }
+    }
+
+    fn aggregate1_row() -> SocAggregateRow {
+        SocAggregateRow {
+            vin: "vin".to_string(),
+            bucket_size: "1h".to_string(),
+            year: 2021,
+            bucket_start: 1_630_537_200_000,
+            min_soc: 40,
+            max_soc: 50,
+            avg_soc: 45.5,
+            first_soc: 50,
+            last_soc: 41,
+            count: 12,
+        }
+    }
+
+    #[tokio::test]
+    async fn model_to_row() {
+        assert_eq!(SocReadingRow::new("vin", &reading1()), reading1_row());
+        assert_eq!(
+            SocAggregateRow::new("vin", AggregateInterval::Hour, &aggregate1()),
+            aggregate1_row()
+        );
+    }
+
+    #[tokio::test]
+    async fn row_to_model_ok() -> anyhow::Result<()> {
+        assert_eq!(SocReading::try_from(&reading1_row())?, reading1());
+        assert_eq!(SocAggregate::try_from(&aggregate1_row())?, aggregate1());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn row_to_model_error() {
+        let invalid_row = SocAggregateRow {
+            bucket_size: "1y".to_string(),
+            ..aggregate1_row()
+        };
+
+        match SocAggregate::try_from(&invalid_row) {
+            Err(AppError::ConversionError(_)) => (),
+            _ => assert!(false),
+        }
+    }
+}
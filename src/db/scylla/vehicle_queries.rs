@@ -1,22 +1,70 @@
 use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
 use scylla::cql_to_rust::{FromCqlVal, FromRow};
-use scylla::{prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use scylla::{batch::Batch, prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::str::FromStr;
 use std::{string::ToString, sync::Arc};
 
 use crate::{
-    db::queries::VehicleQueries,
+    db::{
+        queries::VehicleQueries,
+        scylla::{
+            is_applied,
+            outbox_queries::{OutboxRow, ScyllaOutboxQueries},
+        },
+    },
     error::AppError,
-    model::vehicle::{Engine, EvData, Vehicle},
+    events::EventBus,
+    model::{
+        event::VehicleEventType,
+        outbox::OutboxEntry,
+        vehicle::{Engine, EvData, Vehicle},
+    },
     result::AppResult,
 };
 
+/// Number of vehicles fetched at once by the full table scans
+pub const VEHICLES_PAGE_SIZE: i32 = 1000;
+
+/// Number of telemetry or rollup partitions deleted concurrently by a purge
+const PURGE_CONCURRENCY: usize = 16;
+
+/// Tables partitioned by VIN only, whose rows are deleted with the purged vehicles
+/// (the partition indexes are deleted after the partitions they list)
+const VEHICLE_DATA_TABLES: [&str; 7] = [
+    "soc_reading_days",
+    "soc_rollup_partitions",
+    "latest_soc_readings",
+    "charging_sessions",
+    "ev_consumption",
+    "battery_health",
+    "alerts",
+];
+
 pub struct ScyllaVehicleQueries {
     session: Arc<Session>,
+    event_bus: Arc<EventBus>,
     insert_vehicle_statement: PreparedStatement,
+    insert_outbox_statement: PreparedStatement,
     select_vehicle_statement: PreparedStatement,
+    update_vehicle_ev_data_batch: Batch,
+    soft_delete_vehicle_batch: Batch,
+    restore_vehicle_batch: Batch,
+    delete_vehicle_batch: Batch,
     delete_vehicle_statement: PreparedStatement,
+    select_deleted_vehicles_statement: PreparedStatement,
+    select_vehicles_statement: PreparedStatement,
+    select_vehicles_by_vin_statement: PreparedStatement,
+    select_first_vehicles_statement: PreparedStatement,
+    select_next_vehicles_statement: PreparedStatement,
+    delete_vehicle_data_statements: Vec<PreparedStatement>,
+    select_soc_readings_partitions_statement: PreparedStatement,
+    delete_soc_readings_statement: PreparedStatement,
+    select_soc_rollups_partitions_statement: PreparedStatement,
+    delete_soc_rollups_statement: PreparedStatement,
 }
 
 impl std::fmt::Debug for ScyllaVehicleQueries {
@@ -26,29 +74,205 @@ impl std::fmt::Debug for ScyllaVehicleQueries {
 }
 
 impl ScyllaVehicleQueries {
-    pub async fn try_new(session: Arc<Session>) -> AppResult<Self> {
+    pub async fn try_new(session: Arc<Session>, event_bus: Arc<EventBus>) -> AppResult<Self> {
+        // Prepare "insert outbox entry" statement, batched with each mutation
+        // (the entry is written if and only if the mutation is applied)
+        let insert_outbox_statement = ScyllaOutboxQueries::prepare_insert_entry(&session).await?;
+        let outbox_batch = |statement: PreparedStatement| {
+            let mut batch = Batch::default();
+            batch.append_statement(statement);
+            batch.append_statement(insert_outbox_statement.clone());
+            batch
+        };
+
         // Prepare "insert vehicle" statement
+        // Note: "IF NOT EXISTS" cannot be used in a batch spanning several tables, the outbox
+        // entry is written once the insert has been applied
         let cql = format!(
-            "INSERT INTO vehicles ({}) VALUES (?, ?, ?) IF NOT EXISTS",
-            VehicleRow::FIELDS.join(",")
+            "INSERT INTO vehicles ({}) VALUES ({}) IF NOT EXISTS",
+            VehicleRow::FIELDS.join(","),
+            vec!["?"; VehicleRow::FIELDS.len()].join(",")
         );
         let insert_vehicle_statement = session.prepare(cql).await?;
 
         // Prepare "select vehicle" statement
-        let cql = "SELECT * from vehicles where vin = ?";
+        // (fields are explicitly listed because VehicleRow is decoded by position)
+        let cql = format!(
+            "SELECT {} from vehicles where vin = ?",
+            VehicleRow::FIELDS.join(",")
+        );
         let select_vehicle_statement = session.prepare(cql).await?;
 
-        // Prepare "delete vehicle" statement
+        // Prepare "update vehicle EV data" batch
+        let cql = "UPDATE vehicles SET ev_data = ? where vin = ?";
+        let update_vehicle_ev_data_batch = outbox_batch(session.prepare(cql).await?);
+
+        // Prepare "soft delete vehicle" batch
+        let cql = "UPDATE vehicles SET deleted_at = ? where vin = ?";
+        let soft_delete_vehicle_batch = outbox_batch(session.prepare(cql).await?);
+
+        // Prepare "restore vehicle" batch
+        let cql = "UPDATE vehicles SET deleted_at = null where vin = ?";
+        let restore_vehicle_batch = outbox_batch(session.prepare(cql).await?);
+
+        // Prepare "delete vehicle" statement and batch
         let cql = "DELETE from vehicles where vin = ?";
         let delete_vehicle_statement = session.prepare(cql).await?;
+        let delete_vehicle_batch = outbox_batch(delete_vehicle_statement.clone());
+
+        // Prepare "select deleted vehicles" statement
+        let cql = "SELECT vin from vehicles where deleted_at < ? ALLOW FILTERING";
+        let select_deleted_vehicles_statement = session.prepare(cql).await?;
+
+        // Prepare "select vehicles" statement
+        // Note: full table scan, paged (the pages are fetched as the rows are consumed)
+        let cql = format!("SELECT {} from vehicles", VehicleRow::FIELDS.join(","));
+        let mut select_vehicles_statement = session.prepare(cql).await?;
+        select_vehicles_statement.set_page_size(VEHICLES_PAGE_SIZE);
+
+        // Prepare "select vehicles by VIN" statement (several partitions in one query)
+        let cql = format!(
+            "SELECT {} from vehicles where vin in ?",
+            VehicleRow::FIELDS.join(",")
+        );
+        let select_vehicles_by_vin_statement = session.prepare(cql).await?;
+
+        // Prepare "select first/next vehicles" statements (pages in token order)
+        let cql = format!(
+            "SELECT {} from vehicles limit ?",
+            VehicleRow::FIELDS.join(",")
+        );
+        let select_first_vehicles_statement = session.prepare(cql).await?;
+        let cql = format!(
+            "SELECT {} from vehicles where token(vin) > token(?) limit ?",
+            VehicleRow::FIELDS.join(",")
+        );
+        let select_next_vehicles_statement = session.prepare(cql).await?;
+
+        // Prepare "delete vehicle data" statements (purge)
+        let mut delete_vehicle_data_statements = Vec::with_capacity(VEHICLE_DATA_TABLES.len());
+        for table in VEHICLE_DATA_TABLES.iter() {
+            let cql = format!("DELETE from {} where vin = ?", table);
+            delete_vehicle_data_statements.push(session.prepare(cql).await?);
+        }
+
+        // Prepare "select/delete SoC readings partitions" statements
+        // (the partitions are also keyed by day, the days of each vehicle are indexed)
+        let cql = "SELECT day from soc_reading_days where vin = ?";
+        let select_soc_readings_partitions_statement = session.prepare(cql).await?;
+        let cql = "DELETE from soc_readings where vin = ? and day = ?";
+        let delete_soc_readings_statement = session.prepare(cql).await?;
+
+        // Prepare "select/delete SoC rollups partitions" statements (same as above)
+        let cql = "SELECT bucket_size, year from soc_rollup_partitions where vin = ?";
+        let select_soc_rollups_partitions_statement = session.prepare(cql).await?;
+        let cql = "DELETE from soc_rollups where vin = ? and bucket_size = ? and year = ?";
+        let delete_soc_rollups_statement = session.prepare(cql).await?;
 
         Ok(ScyllaVehicleQueries {
             session,
+            event_bus,
             insert_vehicle_statement,
+            insert_outbox_statement,
             select_vehicle_statement,
+            update_vehicle_ev_data_batch,
+            soft_delete_vehicle_batch,
+            restore_vehicle_batch,
+            delete_vehicle_batch,
             delete_vehicle_statement,
+            select_deleted_vehicles_statement,
+            select_vehicles_statement,
+            select_vehicles_by_vin_statement,
+            select_first_vehicles_statement,
+            select_next_vehicles_statement,
+            delete_vehicle_data_statements,
+            select_soc_readings_partitions_statement,
+            delete_soc_readings_statement,
+            select_soc_rollups_partitions_statement,
+            delete_soc_rollups_statement,
         })
     }
+
+    /// Find the vehicle row, including the deleted ones
+    async fn find_one_vehicle_row(&self, vin: &str) -> AppResult<Option<VehicleRow>> {
+        let rows = match self
+            .session
+            .execute(&self.select_vehicle_statement, (vin,))
+            .await?
+            .rows
+        {
+            Some(rows) => rows,
+            None => return Ok(None),
+        };
+
+        Ok(rows.into_typed::<VehicleRow>().next().transpose()?)
+    }
+
+    /// Apply the mutation and write the outbox entry of the change in the same logged batch
+    async fn execute_with_outbox(
+        &self,
+        batch: &Batch,
+        values: impl scylla::frame::value::ValueList,
+        entry: &OutboxEntry,
+    ) -> AppResult<()> {
+        let outbox_row = OutboxRow::try_from(entry)?;
+        self.session.batch(batch, (values, &outbox_row)).await?;
+
+        Ok(())
+    }
+
+    /// Delete the telemetry, rollups, charging sessions, EV consumption, battery health and
+    /// alerts of the purged vehicles
+    ///
+    /// The telemetry and rollup partitions are found from the per-vehicle indexes (no scan), and
+    /// deleted `PURGE_CONCURRENCY` at a time.
+    async fn delete_vehicles_data(&self, vins: &HashSet<String>) -> AppResult<()> {
+        for vin in vins.iter() {
+            let mut days = Vec::new();
+            let mut rows = self
+                .session
+                .execute_iter(
+                    self.select_soc_readings_partitions_statement.clone(),
+                    (vin,),
+                )
+                .await?
+                .into_typed::<(NaiveDate,)>();
+            while let Some(row) = rows.next().await {
+                let (day,) = row?;
+                days.push(day);
+            }
+            futures::stream::iter(days.into_iter().map(|day| {
+                self.session
+                    .execute(&self.delete_soc_readings_statement, (vin, day))
+            }))
+            .buffer_unordered(PURGE_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+            let mut partitions = Vec::new();
+            let mut rows = self
+                .session
+                .execute_iter(self.select_soc_rollups_partitions_statement.clone(), (vin,))
+                .await?
+                .into_typed::<(String, i32)>();
+            while let Some(row) = rows.next().await {
+                partitions.push(row?);
+            }
+            futures::stream::iter(partitions.into_iter().map(|(bucket_size, year)| {
+                self.session
+                    .execute(&self.delete_soc_rollups_statement, (vin, bucket_size, year))
+            }))
+            .buffer_unordered(PURGE_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+            for statement in self.delete_vehicle_data_statements.iter() {
+                self.session.execute(statement, (vin,)).await?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -56,59 +280,269 @@ impl VehicleQueries for ScyllaVehicleQueries {
     async fn create_vehicle(&self, vehicle: &Vehicle) -> AppResult<()> {
         let row = VehicleRow::from(vehicle);
 
-        // TODO: check if the insert query has been applied, instead, as soon as lightweight transactions are supported
-        // -> see https://github.com/scylladb/scylla-rust-driver/issues/100
-        if self.find_one_vehicle(&vehicle.vin).await.is_ok() {
-            return Err(AppError::AlreadyExists("Vehicle"));
+        // Note: a deleted vehicle keeps its VIN until it is purged
+        match self.find_one_vehicle_row(&vehicle.vin).await? {
+            Some(existing_row) if existing_row.deleted_at.is_some() => {
+                return Err(AppError::AlreadyExists("Deleted vehicle"))
+            }
+            Some(_) => return Err(AppError::AlreadyExists("Vehicle")),
+            None => (),
         }
 
-        self.session
+        // Concurrent creations of the same vehicle: only one of them is applied
+        let result = self
+            .session
             .execute(&self.insert_vehicle_statement, &row)
             .await?;
+        if !is_applied(&result)? {
+            return Err(AppError::AlreadyExists("Vehicle"));
+        }
+
+        // If the outbox entry cannot be written, the request fails (the vehicle is created but
+        // not published: the creation is then read back from the CDC log, and published and
+        // written to the outbox by the reader)
+        let entry = OutboxEntry::new(
+            VehicleEventType::Created,
+            &vehicle.vin,
+            Some(vehicle.clone()),
+        );
+        self.session
+            .execute(&self.insert_outbox_statement, &OutboxRow::try_from(&entry)?)
+            .await
+            .map_err(|e| {
+                tracing::error!(
+                    "failed to write the outbox entry of vehicle {}: {}",
+                    vehicle.vin,
+                    e
+                );
+                e
+            })?;
+
+        self.event_bus.publish(
+            VehicleEventType::Created,
+            &vehicle.vin,
+            Some(vehicle.clone()),
+        );
 
         Ok(())
     }
 
     async fn find_one_vehicle(&self, vin: &str) -> Result<Vehicle, AppError> {
-        let rows = self
-            .session
-            .execute(&self.select_vehicle_statement, (vin,))
+        let vehicle_row = self
+            .find_one_vehicle_row(vin)
             .await?
-            .rows
+            .filter(|row| row.deleted_at.is_none())
             .ok_or(AppError::NotFound("Vehicle"))?;
 
-        let first_vehicle_row = rows
-            .into_typed::<VehicleRow>()
-            .next()
-            .ok_or(AppError::NotFound("Vehicle"))??;
+        Vehicle::try_from(&vehicle_row)
+    }
 
-        Vehicle::try_from(&first_vehicle_row)
+    async fn update_vehicle_ev_data(&self, vin: &str, ev_data: &EvData) -> AppResult<()> {
+        // Ensure that the vehicle can be found (an update would otherwise create a new row)
+        let vehicle = self.find_one_vehicle(vin).await?;
+
+        let vehicle = Vehicle {
+            ev_data: Some(ev_data.clone()),
+            ..vehicle
+        };
+        let entry = OutboxEntry::new(VehicleEventType::Updated, vin, Some(vehicle.clone()));
+        self.execute_with_outbox(
+            &self.update_vehicle_ev_data_batch,
+            (EvDataUserType::from(ev_data), vin),
+            &entry,
+        )
+        .await?;
+
+        self.event_bus
+            .publish(VehicleEventType::Updated, vin, Some(vehicle));
+
+        Ok(())
     }
 
     async fn delete_one_vehicle(&self, vin: &str) -> AppResult<()> {
-        // Ensure that the vehicle can be found
-        // TODO: check if the insert query has been applied, instead, as soon as lightweight transactions are supported
+        // Ensure that the vehicle can be found (and is not already deleted)
+        // TODO: check if the update query has been applied, instead, as soon as lightweight transactions are supported
         // -> see https://github.com/scylladb/scylla-rust-driver/issues/100
         let _ = self.find_one_vehicle(vin).await?;
 
-        self.session
-            .execute(&self.delete_vehicle_statement, (vin,))
-            .await
-            .map_err(|_| AppError::NotFound("Vehicle"))?;
+        let entry = OutboxEntry::new(VehicleEventType::Deleted, vin, None);
+        self.execute_with_outbox(
+            &self.soft_delete_vehicle_batch,
+            (Utc::now().timestamp_millis(), vin),
+            &entry,
+        )
+        .await
+        .map_err(|_| AppError::NotFound("Vehicle"))?;
+
+        self.event_bus.publish(VehicleEventType::Deleted, vin, None);
+
+        Ok(())
+    }
+
+    async fn restore_one_vehicle(&self, vin: &str) -> AppResult<Vehicle> {
+        // Ensure that the vehicle has been deleted
+        let vehicle_row = self
+            .find_one_vehicle_row(vin)
+            .await?
+            .filter(|row| row.deleted_at.is_some())
+            .ok_or(AppError::NotFound("Deleted vehicle"))?;
+
+        let vehicle = Vehicle::try_from(&vehicle_row)?;
+        let entry = OutboxEntry::new(VehicleEventType::Updated, vin, Some(vehicle.clone()));
+        self.execute_with_outbox(&self.restore_vehicle_batch, (vin,), &entry)
+            .await?;
+
+        self.event_bus
+            .publish(VehicleEventType::Updated, vin, Some(vehicle.clone()));
+
+        Ok(vehicle)
+    }
+
+    async fn purge_one_vehicle(&self, vin: &str) -> AppResult<()> {
+        // Ensure that the vehicle can be found (deleted or not)
+        let vehicle_row = self
+            .find_one_vehicle_row(vin)
+            .await?
+            .ok_or(AppError::NotFound("Vehicle"))?;
+
+        // Delete the data first: the purge can be retried as long as the vehicle is there
+        self.delete_vehicles_data(&std::iter::once(vin.to_string()).collect())
+            .await?;
+
+        // The deletion of a soft-deleted vehicle has already been published
+        if vehicle_row.deleted_at.is_none() {
+            let entry = OutboxEntry::new(VehicleEventType::Deleted, vin, None);
+            self.execute_with_outbox(&self.delete_vehicle_batch, (vin,), &entry)
+                .await
+                .map_err(|_| AppError::NotFound("Vehicle"))?;
+
+            self.event_bus.publish(VehicleEventType::Deleted, vin, None);
+        } else {
+            self.session
+                .execute(&self.delete_vehicle_statement, (vin,))
+                .await
+                .map_err(|_| AppError::NotFound("Vehicle"))?;
+        }
 
         Ok(())
     }
+
+    async fn purge_deleted_vehicles(&self, deleted_before: DateTime<Utc>) -> AppResult<usize> {
+        // Collect the VINs first (paged), then delete them one by one
+        let mut rows = self
+            .session
+            .execute_iter(
+                self.select_deleted_vehicles_statement.clone(),
+                (deleted_before.timestamp_millis(),),
+            )
+            .await?
+            .into_typed::<(String,)>();
+
+        let mut vins = HashSet::new();
+        while let Some(row) = rows.next().await {
+            let (vin,) = row?;
+            vins.insert(vin);
+        }
+
+        self.delete_vehicles_data(&vins).await?;
+        for vin in vins.iter() {
+            self.session
+                .execute(&self.delete_vehicle_statement, (vin,))
+                .await?;
+        }
+
+        Ok(vins.len())
+    }
+
+    async fn find_all_vehicles(&self) -> AppResult<BoxStream<'static, AppResult<Vehicle>>> {
+        let rows = self
+            .session
+            .execute_iter(self.select_vehicles_statement.clone(), &[])
+            .await?
+            .into_typed::<VehicleRow>();
+
+        let vehicles = rows.filter_map(|row| async move {
+            match row {
+                Ok(row) if row.deleted_at.is_some() => None,
+                Ok(row) => Some(Vehicle::try_from(&row)),
+                Err(e) => Some(Err(e.into())),
+            }
+        });
+
+        Ok(vehicles.boxed())
+    }
+
+    async fn find_vehicles(&self, vins: &[String]) -> AppResult<Vec<Vehicle>> {
+        let rows = match self
+            .session
+            .execute(&self.select_vehicles_by_vin_statement, (vins.to_vec(),))
+            .await?
+            .rows
+        {
+            Some(rows) => rows,
+            None => return Ok(vec![]),
+        };
+
+        rows.into_typed::<VehicleRow>()
+            .filter(|row| !matches!(row, Ok(row) if row.deleted_at.is_some()))
+            .map(|row| Vehicle::try_from(&row?))
+            .collect()
+    }
+
+    async fn delete_vehicles(&self, vins: &[String]) -> Vec<AppResult<()>> {
+        futures::future::join_all(vins.iter().map(|vin| self.delete_one_vehicle(vin))).await
+    }
+
+    async fn find_vehicles_page(
+        &self,
+        after_vin: Option<String>,
+        limit: i32,
+    ) -> AppResult<(Vec<Vehicle>, Option<String>)> {
+        let result = match after_vin {
+            Some(after_vin) => {
+                self.session
+                    .execute(&self.select_next_vehicles_statement, (after_vin, limit))
+                    .await?
+            }
+            None => {
+                self.session
+                    .execute(&self.select_first_vehicles_statement, (limit,))
+                    .await?
+            }
+        };
+        let rows = result
+            .rows
+            .unwrap_or_default()
+            .into_typed::<VehicleRow>()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Less rows than requested => end of the table
+        let last_vin = match rows.last() {
+            Some(row) if rows.len() as i32 == limit => Some(row.vin.clone()),
+            _ => None,
+        };
+        let vehicles = rows
+            .iter()
+            .filter(|row| row.deleted_at.is_none())
+            .map(Vehicle::try_from)
+            .collect::<AppResult<Vec<_>>>()?;
+
+        Ok((vehicles, last_vin))
+    }
 }
 
 #[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
-struct VehicleRow {
-    vin: String,
-    engine_type: String,
-    ev_data: Option<EvDataUserType>,
+pub(crate) struct VehicleRow {
+    pub vin: String,
+    pub owner: Option<String>,
+    pub engine_type: String,
+    pub ev_data: Option<EvDataUserType>,
+    /// Soft deletion time (milliseconds since epoch)
+    pub deleted_at: Option<i64>,
 }
 
 #[derive(PartialEq, scylla::FromUserType, scylla::IntoUserType, Debug)]
-struct EvDataUserType {
+pub(crate) struct EvDataUserType {
     pub battery_capacity_in_kwh: i32,
     pub soc_in_percent: i32,
 }
@@ -120,8 +554,10 @@ impl From<Vehicle> for VehicleRow {
 
         VehicleRow {
             vin: vehicle.vin,
+            owner: vehicle.owner,
             engine_type: vehicle.engine.to_string(),
             ev_data,
+            deleted_at: None,
         }
     }
 }
@@ -149,6 +585,7 @@ impl TryFrom<&VehicleRow> for Vehicle {
 
         Ok(Vehicle {
             vin: vehicle_row.vin.clone(),
+            owner: vehicle_row.owner.clone(),
             engine,
             ev_data,
         })
@@ -187,6 +624,7 @@ mod tests {
     fn vehicle1() -> Vehicle {
         Vehicle {
             vin: "vin".to_string(),
+            owner: None,
             engine: vehicle::Engine::Combustion,
             ev_data: None,
         }
@@ -195,14 +633,17 @@ mod tests {
     fn vehicle1_row() -> VehicleRow {
         VehicleRow {
             vin: "vin".to_string(),
+            owner: None,
             engine_type: "Combustion".to_string(),
             ev_data: None,
+            deleted_at: None,
         }
     }
 
     fn vehicle2() -> Vehicle {
         Vehicle {
             vin: "vin".to_string(),
+            owner: Some("owner".to_string()),
             engine: vehicle::Engine::Combustion,
             ev_data: Some(vehicle::EvData {
                 battery_capacity_in_kwh: 69,
@@ -214,19 +655,30 @@ mod tests {
     fn vehicle2_row() -> VehicleRow {
         VehicleRow {
             vin: "vin".to_string(),
+            owner: Some("owner".to_string()),
             engine_type: "Combustion".to_string(),
             ev_data: Some(EvDataUserType {
                 battery_capacity_in_kwh: 69,
                 soc_in_percent: 12,
             }),
+            deleted_at: None,
+        }
+    }
+
+    fn deleted_vehicle1_row() -> VehicleRow {
+        VehicleRow {
+            deleted_at: Some(1_630_000_000_000),
+            ..vehicle1_row()
         }
     }
 
     fn invalid_vehicle_row() -> VehicleRow {
         VehicleRow {
             vin: "vin".to_string(),
+            owner: None,
             engine_type: "Invalid".to_string(),
             ev_data: None,
+            deleted_at: None,
         }
     }
 
@@ -243,6 +695,7 @@ mod tests {
     async fn row_to_model_ok() -> anyhow::Result<()> {
         assert_eq!(Vehicle::try_from(&vehicle1_row())?, vehicle1());
         assert_eq!(Vehicle::try_from(&vehicle2_row())?, vehicle2());
+        assert_eq!(Vehicle::try_from(&deleted_vehicle1_row())?, vehicle1());
 
         Ok(())
     }
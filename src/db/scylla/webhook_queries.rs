@@ -0,0 +1,607 @@
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::StreamExt;
+use scylla::cql_to_rust::{FromCqlVal, FromRow};
+use scylla::{prepared_statement::PreparedStatement, IntoTypedRows, Session};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    db::{queries::WebhookQueries, scylla::is_applied},
+    error::AppError,
+    model::webhook::{DeliveryStatus, Webhook, WebhookDelivery, WebhookEvent},
+    result::AppResult,
+};
+
+/// The deliveries (and their log) are kept for 30 days
+pub const WEBHOOK_DELIVERY_TTL_IN_SECONDS: i32 = 30 * 24 * 3600;
+
+/// Number of partitions of the index of the pending deliveries by due time
+pub const WEBHOOK_DUE_BUCKETS: i32 = 16;
+
+pub struct ScyllaWebhookQueries {
+    session: Arc<Session>,
+    insert_webhook_statement: PreparedStatement,
+    select_webhooks_statement: PreparedStatement,
+    select_webhook_statement: PreparedStatement,
+    update_webhook_statement: PreparedStatement,
+    delete_webhook_statement: PreparedStatement,
+    insert_delivery_statement: PreparedStatement,
+    upsert_delivery_statement: PreparedStatement,
+    select_delivery_statement: PreparedStatement,
+    select_deliveries_statement: PreparedStatement,
+    insert_due_delivery_statement: PreparedStatement,
+    select_due_deliveries_statement: PreparedStatement,
+    delete_due_delivery_statement: PreparedStatement,
+}
+
+impl std::fmt::Debug for ScyllaWebhookQueries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScyllaWebhookQueries").finish()
+    }
+}
+
+impl ScyllaWebhookQueries {
+    pub async fn try_new(session: Arc<Session>) -> AppResult<Self> {
+        // Prepare "insert webhook" statement
+        let cql = format!(
+            "INSERT INTO webhooks ({}) VALUES ({})",
+            WebhookRow::FIELDS.join(","),
+            vec!["?"; WebhookRow::FIELDS.len()].join(",")
+        );
+        let insert_webhook_statement = session.prepare(cql).await?;
+
+        // Prepare "select webhooks" statement
+        // Note: full table scan, the number of webhooks is expected to be small
+        let cql = format!("SELECT {} from webhooks", WebhookRow::FIELDS.join(","));
+        let select_webhooks_statement = session.prepare(cql).await?;
+
+        // Prepare "select webhook" statement
+        let cql = format!(
+            "SELECT {} from webhooks where id = ?",
+            WebhookRow::FIELDS.join(",")
+        );
+        let select_webhook_statement = session.prepare(cql).await?;
+
+        // Prepare "update webhook" statement
+        let cql = "UPDATE webhooks SET url = ?, secret = ?, events = ? where id = ? IF EXISTS";
+        let update_webhook_statement = session.prepare(cql).await?;
+
+        // Prepare "delete webhook" statement
+        let cql = "DELETE from webhooks where id = ? IF EXISTS";
+        let delete_webhook_statement = session.prepare(cql).await?;
+
+        // Prepare "insert delivery" statement (conditional, expired after
+        // WEBHOOK_DELIVERY_TTL_IN_SECONDS)
+        let cql = format!(
+            "INSERT INTO webhook_deliveries ({}) VALUES ({}) IF NOT EXISTS USING TTL {}",
+            WebhookDeliveryRow::FIELDS.join(","),
+            vec!["?"; WebhookDeliveryRow::FIELDS.len()].join(","),
+            WEBHOOK_DELIVERY_TTL_IN_SECONDS
+        );
+        let insert_delivery_statement = session.prepare(cql).await?;
+
+        // Prepare "upsert delivery" statement (expired after WEBHOOK_DELIVERY_TTL_IN_SECONDS)
+        let cql = format!(
+            "INSERT INTO webhook_deliveries ({}) VALUES ({}) USING TTL {}",
+            WebhookDeliveryRow::FIELDS.join(","),
+            vec!["?"; WebhookDeliveryRow::FIELDS.len()].join(","),
+            WEBHOOK_DELIVERY_TTL_IN_SECONDS
+        );
+        let upsert_delivery_statement = session.prepare(cql).await?;
+
+        // Prepare "select delivery" statement
+        let cql = format!(
+            "SELECT {} from webhook_deliveries where webhook_id = ? and id = ?",
+            WebhookDeliveryRow::FIELDS.join(",")
+        );
+        let select_delivery_statement = session.prepare(cql).await?;
+
+        // Prepare "select deliveries" statement
+        let cql = format!(
+            "SELECT {} from webhook_deliveries where webhook_id = ?",
+            WebhookDeliveryRow::FIELDS.join(",")
+        );
+        let select_deliveries_statement = session.prepare(cql).await?;
+
+        // Prepare "insert due delivery" statement (index of the pending deliveries)
+        let cql = format!(
+            "INSERT INTO webhook_due_deliveries ({}) VALUES ({}) USING TTL {}",
+            DueDeliveryRow::FIELDS.join(","),
+            vec!["?"; DueDeliveryRow::FIELDS.len()].join(","),
+            WEBHOOK_DELIVERY_TTL_IN_SECONDS
+        );
+        let insert_due_delivery_statement = session.prepare(cql).await?;
+
+        // Prepare "select due deliveries" statement (ordered by due time)
+        let cql = format!(
+            "SELECT {} from webhook_due_deliveries where bucket = ? and next_attempt_at <= ?",
+            DueDeliveryRow::FIELDS.join(",")
+        );
+        let select_due_deliveries_statement = session.prepare(cql).await?;
+
+        // Prepare "delete due delivery" statement
+        let cql = "DELETE from webhook_due_deliveries where bucket = ? and next_attempt_at = ? and webhook_id = ? and id = ?";
+        let delete_due_delivery_statement = session.prepare(cql).await?;
+
+        Ok(ScyllaWebhookQueries {
+            session,
+            insert_webhook_statement,
+            select_webhooks_statement,
+            select_webhook_statement,
+            update_webhook_statement,
+            delete_webhook_statement,
+            insert_delivery_statement,
+            upsert_delivery_statement,
+            select_delivery_statement,
+            select_deliveries_statement,
+            insert_due_delivery_statement,
+            select_due_deliveries_statement,
+            delete_due_delivery_statement,
+        })
+    }
+
+    async fn delete_due_delivery(&self, row: &DueDeliveryRow) -> AppResult<()> {
+        self.session
+            .execute(
+                &self.delete_due_delivery_statement,
+                (row.bucket, row.next_attempt_at, row.webhook_id, row.id),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn collect_deliveries(
+        &self,
+        statement: &PreparedStatement,
+        values: impl scylla::frame::value::ValueList,
+    ) -> AppResult<Vec<WebhookDelivery>> {
+        let mut rows = self
+            .session
+            .execute_iter(statement.clone(), values)
+            .await?
+            .into_typed::<WebhookDeliveryRow>();
+
+        let mut deliveries = Vec::new();
+        while let Some(row) = rows.next().await {
+            deliveries.push(WebhookDelivery::try_from(&row?)?);
+        }
+        deliveries.sort_by_key(|delivery| delivery.created_at);
+
+        Ok(deliveries)
+    }
+}
+
+#[async_trait]
+impl WebhookQueries for ScyllaWebhookQueries {
+    async fn create_webhook(&self, webhook: &Webhook) -> AppResult<()> {
+        self.session
+            .execute(&self.insert_webhook_statement, &WebhookRow::from(webhook))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn find_webhooks(&self) -> AppResult<Vec<Webhook>> {
+        let mut rows = self
+            .session
+            .execute_iter(self.select_webhooks_statement.clone(), &[])
+            .await?
+            .into_typed::<WebhookRow>();
+
+        let mut webhooks = Vec::new();
+        while let Some(row) = rows.next().await {
+            webhooks.push(Webhook::try_from(&row?)?);
+        }
+        webhooks.sort_by_key(|webhook| webhook.created_at);
+
+        Ok(webhooks)
+    }
+
+    async fn find_one_webhook(&self, id: Uuid) -> AppResult<Webhook> {
+        let rows = self
+            .session
+            .execute(&self.select_webhook_statement, (id,))
+            .await?
+            .rows
+            .ok_or(AppError::NotFound("Webhook"))?;
+
+        let row = rows
+            .into_typed::<WebhookRow>()
+            .next()
+            .ok_or(AppError::NotFound("Webhook"))??;
+
+        Webhook::try_from(&row)
+    }
+
+    async fn update_webhook(&self, webhook: &Webhook) -> AppResult<()> {
+        let row = WebhookRow::from(webhook);
+        let result = self
+            .session
+            .execute(
+                &self.update_webhook_statement,
+                (row.url, row.secret, row.events, row.id),
+            )
+            .await?;
+        if !is_applied(&result)? {
+            return Err(AppError::NotFound("Webhook"));
+        }
+
+        Ok(())
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> AppResult<()> {
+        let result = self
+            .session
+            .execute(&self.delete_webhook_statement, (id,))
+            .await?;
+        if !is_applied(&result)? {
+            return Err(AppError::NotFound("Webhook"));
+        }
+
+        Ok(())
+    }
+
+    async fn insert_delivery(&self, delivery: &WebhookDelivery) -> AppResult<()> {
+        let result = self
+            .session
+            .execute(
+                &self.insert_delivery_statement,
+                &WebhookDeliveryRow::from(delivery),
+            )
+            .await?;
+        if !is_applied(&result)? {
+            return Err(AppError::AlreadyExists("Webhook delivery"));
+        }
+
+        if let Some(due) = DueDeliveryRow::of_delivery(delivery) {
+            self.session
+                .execute(&self.insert_due_delivery_statement, &due)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn upsert_delivery(&self, delivery: &WebhookDelivery) -> AppResult<()> {
+        let previous = match self
+            .find_one_delivery(delivery.webhook_id, delivery.id)
+            .await
+        {
+            Ok(previous) => DueDeliveryRow::of_delivery(&previous),
+            Err(AppError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        let due = DueDeliveryRow::of_delivery(delivery);
+
+        self.session
+            .execute(
+                &self.upsert_delivery_statement,
+                &WebhookDeliveryRow::from(delivery),
+            )
+            .await?;
+
+        // Keep the index up to date (the stale entries left by a failure are removed by
+        // find_due_deliveries)
+        if let Some(due) = &due {
+            self.session
+                .execute(&self.insert_due_delivery_statement, due)
+                .await?;
+        }
+        if let Some(previous) = previous.filter(|previous| Some(previous) != due.as_ref()) {
+            self.delete_due_delivery(&previous).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn find_one_delivery(&self, webhook_id: Uuid, id: Uuid) -> AppResult<WebhookDelivery> {
+        let rows = self
+            .session
+            .execute(&self.select_delivery_statement, (webhook_id, id))
+            .await?
+            .rows
+            .ok_or(AppError::NotFound("Webhook delivery"))?;
+
+        let row = rows
+            .into_typed::<WebhookDeliveryRow>()
+            .next()
+            .ok_or(AppError::NotFound("Webhook delivery"))??;
+
+        WebhookDelivery::try_from(&row)
+    }
+
+    async fn find_deliveries(&self, webhook_id: Uuid) -> AppResult<Vec<WebhookDelivery>> {
+        self.collect_deliveries(&self.select_deliveries_statement, (webhook_id,))
+            .await
+    }
+
+    async fn find_due_deliveries(&self, now: DateTime<Utc>) -> AppResult<Vec<WebhookDelivery>> {
+        let mut deliveries = Vec::new();
+
+        for bucket in 0..WEBHOOK_DUE_BUCKETS {
+            let mut rows = self
+                .session
+                .execute_iter(
+                    self.select_due_deliveries_statement.clone(),
+                    (bucket, now.timestamp_millis()),
+                )
+                .await?
+                .into_typed::<DueDeliveryRow>();
+
+            let mut due_rows = Vec::new();
+            while let Some(row) = rows.next().await {
+                due_rows.push(row?);
+            }
+
+            for row in due_rows.iter() {
+                match self.find_one_delivery(row.webhook_id, row.id).await {
+                    Ok(delivery)
+                        if DueDeliveryRow::of_delivery(&delivery).as_ref() == Some(row) =>
+                    {
+                        deliveries.push(delivery)
+                    }
+                    // Stale entry (delivery attempted, rescheduled or expired)
+                    Ok(_) | Err(AppError::NotFound(_)) => self.delete_due_delivery(row).await?,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        deliveries.sort_by_key(|delivery| delivery.next_attempt_at);
+
+        Ok(deliveries)
+    }
+}
+
+fn to_datetime(millis: i64) -> AppResult<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .ok_or(AppError::ConversionError("Timestamp to DateTime"))
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct WebhookRow {
+    id: Uuid,
+    url: String,
+    secret: String,
+    events: Vec<String>,
+    /// Milliseconds since epoch
+    created_at: i64,
+}
+
+// &Webhook -> WebhookRow
+impl From<&Webhook> for WebhookRow {
+    fn from(webhook: &Webhook) -> Self {
+        WebhookRow {
+            id: webhook.id,
+            url: webhook.url.clone(),
+            secret: webhook.secret.clone(),
+            events: webhook
+                .events
+                .iter()
+                .map(|event| event.to_string())
+                .collect(),
+            created_at: webhook.created_at.timestamp_millis(),
+        }
+    }
+}
+
+// &WebhookRow -> Webhook
+impl TryFrom<&WebhookRow> for Webhook {
+    type Error = AppError;
+
+    fn try_from(row: &WebhookRow) -> Result<Self, Self::Error> {
+        let events = row
+            .events
+            .iter()
+            .map(|event| WebhookEvent::from_str(event))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| AppError::ConversionError("WebhookRow to Webhook"))?;
+
+        Ok(Webhook {
+            id: row.id,
+            url: row.url.clone(),
+            secret: row.secret.clone(),
+            events,
+            created_at: to_datetime(row.created_at)?,
+        })
+    }
+}
+
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct WebhookDeliveryRow {
+    webhook_id: Uuid,
+    id: Uuid,
+    event: String,
+    payload: String,
+    status: String,
+    attempts: i32,
+    /// Milliseconds since epoch
+    created_at: i64,
+    /// Milliseconds since epoch
+    next_attempt_at: Option<i64>,
+    /// Milliseconds since epoch
+    last_attempt_at: Option<i64>,
+    last_status_code: Option<i32>,
+    last_error: Option<String>,
+}
+
+// &WebhookDelivery -> WebhookDeliveryRow
+impl From<&WebhookDelivery> for WebhookDeliveryRow {
+    fn from(delivery: &WebhookDelivery) -> Self {
+        WebhookDeliveryRow {
+            webhook_id: delivery.webhook_id,
+            id: delivery.id,
+            event: delivery.event.to_string(),
+            payload: delivery.payload.clone(),
+            status: delivery.status.to_string(),
+            attempts: delivery.attempts,
+            created_at: delivery.created_at.timestamp_millis(),
+            next_attempt_at: delivery.next_attempt_at.map(|time| time.timestamp_millis()),
+            last_attempt_at: delivery.last_attempt_at.map(|time| time.timestamp_millis()),
+            last_status_code: delivery.last_status_code,
+            last_error: delivery.last_error.clone(),
+        }
+    }
+}
+
+/// Entry of the index of the pending deliveries by due time
+#[derive(PartialEq, scylla::FromRow, scylla::ValueList, field_names::FieldNames, Debug)]
+struct DueDeliveryRow {
+    bucket: i32,
+    /// Milliseconds since epoch
+    next_attempt_at: i64,
+    webhook_id: Uuid,
+    id: Uuid,
+}
+
+impl DueDeliveryRow {
+    /// Index entry of the delivery, if it is pending
+    fn of_delivery(delivery: &WebhookDelivery) -> Option<Self> {
+        if delivery.status != DeliveryStatus::Pending {
+            return None;
+        }
+
+        Some(DueDeliveryRow {
+            bucket: (delivery.id.as_u128() % WEBHOOK_DUE_BUCKETS as u128) as i32,
+            next_attempt_at: delivery
+                .next_attempt_at
+                .unwrap_or(delivery.created_at)
+                .timestamp_millis(),
+            webhook_id: delivery.webhook_id,
+            id: delivery.id,
+        })
+    }
+}
+
+// &WebhookDeliveryRow -> WebhookDelivery
+impl TryFrom<&WebhookDeliveryRow> for WebhookDelivery {
+    type Error = AppError;
+
+    fn try_from(row: &WebhookDeliveryRow) -> Result<Self, Self::Error> {
+        let error = || AppError::ConversionError("WebhookDeliveryRow to WebhookDelivery");
+
+        Ok(WebhookDelivery {
+            webhook_id: row.webhook_id,
+            id: row.id,
+            event: WebhookEvent::from_str(&row.event).map_err(|_| error())?,
+            payload: row.payload.clone(),
+            status: DeliveryStatus::from_str(&row.status).map_err(|_| error())?,
+            attempts: row.attempts,
+            created_at: to_datetime(row.created_at)?,
+            next_attempt_at: row.next_attempt_at.map(to_datetime).transpose()?,
+            last_attempt_at: row.last_attempt_at.map(to_datetime).transpose()?,
+            last_status_code: row.last_status_code,
+            last_error: row.last_error.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook1() -> Webhook {
+        Webhook {
+            id: Uuid::nil(),
+            url: "http://localhost:8080/hook".to_string(),
+            secret: "secret".to_string(),
+            events: vec![WebhookEvent::VehicleCreated, WebhookEvent::VehicleDeleted],
+            created_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+        }
+    }
+
+    fn webhook1_row() -> WebhookRow {
+        WebhookRow {
+            id: Uuid::nil(),
+            url: "http://localhost:8080/hook".to_string(),
+            secret: "secret".to_string(),
+            events: vec!["vehicle.created".to_string(), "vehicle.deleted".to_string()],
+            created_at: 1_630_490_400_000,
+        }
+    }
+
+    fn delivery1() -> WebhookDelivery {
+        WebhookDelivery {
+            webhook_id: Uuid::nil(),
+            id: Uuid::nil(),
+            event: WebhookEvent::VehicleUpdated,
+            payload: "{}".to_string(),
+            status: DeliveryStatus::DeadLetter,
+            attempts: 8,
+            created_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+            next_attempt_at: None,
+            last_attempt_at: Some("2021-09-01T11:00:00Z".parse().unwrap()),
+            last_status_code: Some(500),
+            last_error: None,
+        }
+    }
+
+    fn delivery1_row() -> WebhookDeliveryRow {
+        WebhookDeliveryRow {
+            webhook_id: Uuid::nil(),
+            id: Uuid::nil(),
+            event: "vehicle.updated".to_string(),
+            payload: "{}".to_string(),
+            status: "dead_letter".to_string(),
+            attempts: 8,
+            created_at: 1_630_490_400_000,
+            next_attempt_at: None,
+            last_attempt_at: Some(1_630_494_000_000),
+            last_status_code: Some(500),
+            last_error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn model_to_row() {
+        assert_eq!(WebhookRow::from(&webhook1()), webhook1_row());
+        assert_eq!(WebhookDeliveryRow::from(&delivery1()), delivery1_row());
+    }
+
+    #[test]
+    fn due_delivery_row() {
+        // Only the pending deliveries are indexed
+        assert_eq!(DueDeliveryRow::of_delivery(&delivery1()), None);
+
+        let delivery = WebhookDelivery {
+            status: DeliveryStatus::Pending,
+            next_attempt_at: Some("2021-09-01T12:00:00Z".parse().unwrap()),
+            ..delivery1()
+        };
+        assert_eq!(
+            DueDeliveryRow::of_delivery(&delivery),
+            Some(DueDeliveryRow {
+                bucket: 0,
+                next_attempt_at: 1_630_497_600_000,
+                webhook_id: Uuid::nil(),
+                id: Uuid::nil(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn row_to_model_ok() -> anyhow::Result<()> {
+        assert_eq!(Webhook::try_from(&webhook1_row())?, webhook1());
+        assert_eq!(WebhookDelivery::try_from(&delivery1_row())?, delivery1());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn row_to_model_error() {
+        let row = WebhookRow {
+            events: vec!["vehicle.renamed".to_string()],
+            ..webhook1_row()
+        };
+
+        // TODO: user assert_matches! when stable
+        match Webhook::try_from(&row) {
+            Err(AppError::ConversionError(_)) => (),
+            _ => assert!(false),
+        }
+    }
+}
@@ -16,12 +16,30 @@ pub enum AppError {
     // App-specific errors
     #[error("DB error ({0})")]
     DatabaseError(anyhow::Error),
+    #[error("Forbidden ({0})")]
+    Forbidden(&'static str),
     #[error("Not found ({0})")]
     NotFound(&'static str),
     #[error("Already exists ({0})")]
     AlreadyExists(&'static str),
+    #[error("Conflict ({0})")]
+    Conflict(&'static str),
     #[error("Conversion error ({0})")]
     ConversionError(&'static str),
+    #[error("Invalid input ({0})")]
+    InvalidInput(&'static str),
+    #[error("Not acceptable ({0})")]
+    NotAcceptable(&'static str),
+    #[error("Unsupported media type ({0})")]
+    UnsupportedMediaType(&'static str),
+    #[error("Unprocessable entity ({0})")]
+    UnprocessableEntity(&'static str),
+    #[error("Payload too large ({0})")]
+    PayloadTooLarge(&'static str),
+    #[error("Too many requests ({0})")]
+    TooManyRequests(&'static str),
+    #[error("Service unavailable ({0})")]
+    ServiceUnavailable(&'static str),
 
     // Generic errors (standard, anyhow)
     #[error(transparent)]
@@ -40,8 +58,17 @@ impl AppError {
     fn status_code(&self) -> StatusCode {
         match self {
             AppError::TimeoutError(_) => StatusCode::REQUEST_TIMEOUT,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
             AppError::NotFound(_) => StatusCode::NOT_FOUND,
             AppError::AlreadyExists(_) => StatusCode::CONFLICT,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            AppError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::UnprocessableEntity(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -0,0 +1,477 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    db::queries::{EvQueries, Queries, VehicleQueries},
+    error::AppError,
+    model::{
+        charging::ChargingSession,
+        ev::{BatteryHealth, EvConsumption, EvEstimate, VehicleBatteryHealth},
+        vehicle::EvData,
+    },
+    result::AppResult,
+};
+
+/// SoC above which the charging power decreases
+pub const TAPER_START_SOC: i32 = 80;
+
+/// Ratio of the charger power still used at 100% SoC (linear taper from `TAPER_START_SOC`)
+const TAPER_END_POWER_RATIO: f64 = 0.2;
+
+/// Range of the charger power accepted by the estimates
+pub const MIN_CHARGER_IN_KW: f64 = 0.1;
+pub const MAX_CHARGER_IN_KW: f64 = 1000.0;
+
+/// Minimum SoC rise of a charging session to measure the usable capacity
+const MIN_MEASUREMENT_SOC_RISE: i32 = 20;
+
+/// Energy left in the battery
+pub fn remaining_energy_in_kwh(ev_data: &EvData) -> f64 {
+    ev_data.battery_capacity_in_kwh as f64 * ev_data.soc_in_percent as f64 / 100.0
+}
+
+pub fn estimate_range_in_km(energy_in_kwh: f64, consumption_in_kwh_per_100km: f64) -> f64 {
+    energy_in_kwh / consumption_in_kwh_per_100km * 100.0
+}
+
+/// Ratio of the charger power accepted by the battery at the given SoC
+pub fn charging_power_ratio(soc_in_percent: f64) -> f64 {
+    let taper_start = TAPER_START_SOC as f64;
+    if soc_in_percent <= taper_start {
+        return 1.0;
+    }
+
+    1.0 - (1.0 - TAPER_END_POWER_RATIO) * (soc_in_percent - taper_start) / (100.0 - taper_start)
+}
+
+/// Time to charge from one SoC to another, integrated percent by percent
+///
+/// Fails if the time cannot be represented (e.g. with a near-zero charger power).
+pub fn estimate_charge_time(
+    battery_capacity_in_kwh: i32,
+    from_soc_in_percent: i32,
+    to_soc_in_percent: i32,
+    charger_in_kw: f64,
+) -> AppResult<chrono::Duration> {
+    let energy_per_percent_in_kwh = battery_capacity_in_kwh as f64 / 100.0;
+
+    let hours: f64 = (from_soc_in_percent..to_soc_in_percent)
+        .map(|soc| {
+            energy_per_percent_in_kwh / (charger_in_kw * charging_power_ratio(soc as f64 + 0.5))
+        })
+        .sum();
+
+    let seconds = (hours * 3600.0).round();
+    if !seconds.is_finite() || seconds.abs() > chrono::Duration::max_value().num_seconds() as f64 {
+        return Err(AppError::InvalidInput("Charge time out of range"));
+    }
+
+    Ok(chrono::Duration::seconds(seconds as i64))
+}
+
+/// Estimate the range and the charge time of a vehicle
+pub fn estimate(
+    ev_data: &EvData,
+    consumption: &EvConsumption,
+    default_consumption_in_kwh_per_100km: f64,
+    charger_in_kw: f64,
+    target_soc_in_percent: i32,
+) -> AppResult<EvEstimate> {
+    if ev_data.battery_capacity_in_kwh <= 0 {
+        return Err(AppError::InvalidInput("Unknown battery capacity"));
+    }
+    if !charger_in_kw.is_finite() || charger_in_kw <= 0.0 {
+        return Err(AppError::InvalidInput("Charger power must be positive"));
+    }
+    if !(0..=100).contains(&target_soc_in_percent) {
+        return Err(AppError::InvalidInput("SoC must be between 0 and 100"));
+    }
+
+    let (consumption_in_kwh_per_100km, consumption_source) =
+        consumption.resolve(default_consumption_in_kwh_per_100km);
+    if !consumption_in_kwh_per_100km.is_finite() || consumption_in_kwh_per_100km <= 0.0 {
+        return Err(AppError::InvalidInput("Consumption must be positive"));
+    }
+
+    let remaining_energy_in_kwh = remaining_energy_in_kwh(ev_data);
+    let target_soc = target_soc_in_percent.max(ev_data.soc_in_percent);
+    let charge_time = estimate_charge_time(
+        ev_data.battery_capacity_in_kwh,
+        ev_data.soc_in_percent,
+        target_soc,
+        charger_in_kw,
+    )?;
+
+    Ok(EvEstimate {
+        soc_in_percent: ev_data.soc_in_percent,
+        remaining_energy_in_kwh,
+        consumption_in_kwh_per_100km,
+        consumption_source,
+        range_in_km: estimate_range_in_km(remaining_energy_in_kwh, consumption_in_kwh_per_100km),
+        charger_in_kw,
+        target_soc_in_percent,
+        energy_to_target_in_kwh: ev_data.battery_capacity_in_kwh as f64
+            * (target_soc - ev_data.soc_in_percent) as f64
+            / 100.0,
+        charge_time_in_minutes: (charge_time.num_seconds() as f64 / 60.0).round() as i64,
+    })
+}
+
+/// Usable capacity measured by a charging session reported by the vehicle
+///
+/// Detected sessions are ignored: their energy is derived from the nominal capacity.
+pub fn measure_usable_capacity(session: &ChargingSession) -> Option<f64> {
+    let soc_rise = session.end_soc_in_percent? - session.start_soc_in_percent;
+    if session.detected
+        || !session.is_ended()
+        || soc_rise < MIN_MEASUREMENT_SOC_RISE
+        || session.energy_added_in_kwh <= 0.0
+    {
+        return None;
+    }
+
+    Some(session.energy_added_in_kwh / soc_rise as f64 * 100.0)
+}
+
+/// Store the usable capacity measured by an ended charging session, if any
+pub async fn record_battery_health_from_charging_session<Q: Queries>(
+    queries: &Q,
+    session: &ChargingSession,
+) -> AppResult<()> {
+    let (usable_capacity_in_kwh, measured_at) =
+        match (measure_usable_capacity(session), session.end_time) {
+            (Some(capacity), Some(end_time)) => (capacity, end_time),
+            _ => return Ok(()),
+        };
+
+    let vehicle = queries
+        .vehicle_queries()
+        .find_one_vehicle(&session.vin)
+        .await?;
+    let nominal_capacity_in_kwh = match vehicle.ev_data {
+        Some(ev_data) if ev_data.battery_capacity_in_kwh > 0 => ev_data.battery_capacity_in_kwh,
+        _ => return Ok(()),
+    };
+
+    queries
+        .ev_queries()
+        .insert_battery_health(&BatteryHealth {
+            vin: session.vin.clone(),
+            measured_at,
+            usable_capacity_in_kwh,
+            nominal_capacity_in_kwh,
+        })
+        .await
+}
+
+/// SoH lost per year (least squares fit of the measurements)
+pub fn degradation_in_percent_per_year(history: &[&BatteryHealth]) -> Option<f64> {
+    let first = history.iter().map(|health| health.measured_at).min()?;
+    let points: Vec<(f64, f64)> = history
+        .iter()
+        .map(|health| {
+            let years = (health.measured_at - first).num_seconds() as f64 / (365.25 * 86400.0);
+            (years, health.soh_in_percent())
+        })
+        .collect();
+
+    let count = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if variance == 0.0 {
+        return None;
+    }
+
+    Some(-covariance / variance)
+}
+
+/// Rank the vehicles by degradation rate (fastest first, then lowest SoH)
+pub fn rank_battery_health(
+    measurements: &[BatteryHealth],
+    alert_threshold_in_percent: f64,
+) -> Vec<VehicleBatteryHealth> {
+    let mut histories: BTreeMap<&str, Vec<&BatteryHealth>> = BTreeMap::new();
+    for health in measurements {
+        histories.entry(&health.vin).or_default().push(health);
+    }
+
+    let mut ranking: Vec<VehicleBatteryHealth> = histories
+        .into_iter()
+        .filter_map(|(vin, history)| {
+            let latest = history.iter().max_by_key(|health| health.measured_at)?;
+            let soh_in_percent = latest.soh_in_percent();
+
+            Some(VehicleBatteryHealth {
+                vin: vin.to_string(),
+                soh_in_percent,
+                degradation_in_percent_per_year: degradation_in_percent_per_year(&history),
+                measurement_count: history.len(),
+                alert: soh_in_percent < alert_threshold_in_percent,
+            })
+        })
+        .collect();
+
+    ranking.sort_by(|a, b| {
+        let degradation = |health: &VehicleBatteryHealth| {
+            health
+                .degradation_in_percent_per_year
+                .unwrap_or(f64::NEG_INFINITY)
+        };
+        degradation(b)
+            .partial_cmp(&degradation(a))
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(
+                a.soh_in_percent
+                    .partial_cmp(&b.soh_in_percent)
+                    .unwrap_or(std::cmp::Ordering::Equal),
+            )
+    });
+
+    ranking
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use mockall::predicate::eq;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        db::queries::{self},
+        model::{
+            ev::ConsumptionSource,
+            vehicle::{Engine, Vehicle},
+        },
+    };
+
+    fn datetime(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn health(vin: &str, measured_at: &str, usable_capacity_in_kwh: f64) -> BatteryHealth {
+        BatteryHealth {
+            vin: vin.to_string(),
+            measured_at: datetime(measured_at),
+            usable_capacity_in_kwh,
+            nominal_capacity_in_kwh: 50,
+        }
+    }
+
+    fn ended_session() -> ChargingSession {
+        ChargingSession {
+            id: Uuid::nil(),
+            vin: "vin".to_string(),
+            start_time: datetime("2021-09-01T10:00:00Z"),
+            end_time: Some(datetime("2021-09-01T13:00:00Z")),
+            start_soc_in_percent: 20,
+            end_soc_in_percent: Some(80),
+            energy_added_in_kwh: 27.0,
+            max_power_in_kw: None,
+            location: None,
+            detected: false,
+        }
+    }
+
+    fn ev_data(battery_capacity_in_kwh: i32, soc_in_percent: i32) -> EvData {
+        EvData {
+            battery_capacity_in_kwh,
+            soc_in_percent,
+        }
+    }
+
+    #[test]
+    fn test_estimate_charge_time() {
+        let minutes = |capacity, from, to, charger| {
+            (estimate_charge_time(capacity, from, to, charger)
+                .unwrap()
+                .num_seconds() as f64
+                / 60.0)
+                .round()
+        };
+
+        // Constant power below 80%
+        assert_eq!(minutes(50, 20, 80, 10.0), 180.0);
+
+        // Tapered power above 80% => twice slower than constant power
+        assert_eq!(minutes(50, 80, 100, 10.0), 121.0);
+        assert_eq!(minutes(60, 70, 100, 11.0), 164.0);
+
+        // Nothing to charge
+        assert_eq!(minutes(50, 80, 80, 10.0), 0.0);
+
+        // Near-zero charger power => too long to represent
+        // TODO: user assert_matches! when stable
+        assert!(matches!(
+            estimate_charge_time(50, 20, 80, 1e-12),
+            Err(AppError::InvalidInput("Charge time out of range"))
+        ));
+    }
+
+    #[test]
+    fn test_estimate() -> anyhow::Result<()> {
+        let consumption = EvConsumption::default();
+
+        assert_eq!(
+            estimate(&ev_data(50, 20), &consumption, 20.0, 10.0, 80)?,
+            EvEstimate {
+                soc_in_percent: 20,
+                remaining_energy_in_kwh: 10.0,
+                consumption_in_kwh_per_100km: 20.0,
+                consumption_source: ConsumptionSource::Default,
+                range_in_km: 50.0,
+                charger_in_kw: 10.0,
+                target_soc_in_percent: 80,
+                energy_to_target_in_kwh: 30.0,
+                charge_time_in_minutes: 180,
+            }
+        );
+
+        // Target already reached
+        let estimate = estimate(&ev_data(50, 90), &consumption, 20.0, 10.0, 80)?;
+        assert_eq!(estimate.energy_to_target_in_kwh, 0.0);
+        assert_eq!(estimate.charge_time_in_minutes, 0);
+
+        // Invalid inputs
+        assert!(super::estimate(&ev_data(0, 20), &consumption, 20.0, 10.0, 80).is_err());
+        assert!(super::estimate(&ev_data(50, 20), &consumption, 20.0, 0.0, 80).is_err());
+        assert!(super::estimate(&ev_data(50, 20), &consumption, 20.0, 10.0, 101).is_err());
+
+        // Not finite charger power or consumption
+        // TODO: user assert_matches! when stable
+        for charger_in_kw in vec![f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(matches!(
+                super::estimate(&ev_data(50, 20), &consumption, 20.0, charger_in_kw, 80),
+                Err(AppError::InvalidInput("Charger power must be positive"))
+            ));
+        }
+        assert!(matches!(
+            super::estimate(&ev_data(50, 20), &consumption, f64::NAN, 10.0, 80),
+            Err(AppError::InvalidInput("Consumption must be positive"))
+        ));
+        assert!(matches!(
+            super::estimate(&ev_data(50, 20), &consumption, 20.0, 1e-12, 80),
+            Err(AppError::InvalidInput("Charge time out of range"))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_consumption() {
+        let mut consumption = EvConsumption {
+            configured_in_kwh_per_100km: None,
+            trip_distance_in_km: 50.0,
+            trip_energy_in_kwh: 10.0,
+        };
+
+        // Not enough distance to learn
+        assert_eq!(
+            consumption.resolve(18.0),
+            (18.0, ConsumptionSource::Default)
+        );
+
+        consumption.trip_distance_in_km = 200.0;
+        consumption.trip_energy_in_kwh = 30.0;
+        assert_eq!(
+            consumption.resolve(18.0),
+            (15.0, ConsumptionSource::Learned)
+        );
+
+        consumption.configured_in_kwh_per_100km = Some(16.5);
+        assert_eq!(
+            consumption.resolve(18.0),
+            (16.5, ConsumptionSource::Configured)
+        );
+    }
+
+    #[test]
+    fn test_measure_usable_capacity() {
+        assert_eq!(measure_usable_capacity(&ended_session()), Some(45.0));
+
+        // Ongoing, detected or too small sessions
+        assert_eq!(
+            measure_usable_capacity(&ChargingSession {
+                end_time: None,
+                ..ended_session()
+            }),
+            None
+        );
+        assert_eq!(
+            measure_usable_capacity(&ChargingSession {
+                detected: true,
+                ..ended_session()
+            }),
+            None
+        );
+        assert_eq!(
+            measure_usable_capacity(&ChargingSession {
+                end_soc_in_percent: Some(30),
+                ..ended_session()
+            }),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_battery_health_from_charging_session() -> anyhow::Result<()> {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin"))
+            .returning(|_| {
+                Ok(Vehicle {
+                    vin: "vin".to_string(),
+                    owner: None,
+                    engine: Engine::Ev,
+                    ev_data: Some(ev_data(50, 80)),
+                })
+            });
+        mock_queries
+            .ev_queries
+            .expect_insert_battery_health()
+            .with(eq(health("vin", "2021-09-01T13:00:00Z", 45.0)))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        record_battery_health_from_charging_session(&mock_queries, &ended_session()).await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rank_battery_health() {
+        let measurements = vec![
+            // 2% per year
+            health("vin1", "2020-01-01T00:00:00Z", 50.0),
+            health("vin1", "2021-01-01T00:00:00Z", 49.0),
+            // 10% per year
+            health("vin2", "2020-01-01T00:00:00Z", 45.0),
+            health("vin2", "2020-07-02T00:00:00Z", 42.5),
+            health("vin2", "2021-01-01T00:00:00Z", 40.0),
+            // Single measurement
+            health("vin3", "2021-01-01T00:00:00Z", 35.0),
+        ];
+
+        let ranking = rank_battery_health(&measurements, 85.0);
+        let summary: Vec<(&str, bool)> = ranking
+            .iter()
+            .map(|health| (health.vin.as_str(), health.alert))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("vin2", true), ("vin1", false), ("vin3", true)]
+        );
+
+        let rate = ranking[0].degradation_in_percent_per_year.unwrap();
+        assert!((rate - 10.0).abs() < 0.1);
+        assert_eq!(ranking[0].soh_in_percent, 80.0);
+        assert_eq!(ranking[0].measurement_count, 3);
+        assert_eq!(ranking[2].degradation_in_percent_per_year, None);
+    }
+}
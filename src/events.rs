@@ -0,0 +1,333 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+
+use crate::model::{
+    event::{TelemetryEvent, VehicleEvent, VehicleEventType},
+    telemetry::SocReading,
+    vehicle::Vehicle,
+};
+
+/// Number of events kept for the subscribers resuming after a disconnection
+pub const DEFAULT_REPLAY_CAPACITY: usize = 1024;
+
+/// Time the published vehicle events are remembered by `is_recently_published`
+///
+/// Must be longer than the delay before a change is read back from the CDC log, whatever the
+/// number of events published in the meantime.
+pub const RECENTLY_PUBLISHED_RETENTION_SECS: i64 = 300;
+
+/// Maximum number of vehicle events remembered by `is_recently_published`, the oldest ones are
+/// forgotten first if more events are published during the retention period
+pub const MAX_RECENTLY_PUBLISHED: usize = 100_000;
+
+/// In-process bus of the vehicle changes and telemetry
+///
+/// Each published vehicle event gets the next sequence number and is kept in a bounded replay
+/// buffer, so that a subscriber can resume after the last event it has received. Telemetry
+/// events are not kept. The vehicle events of the last `RECENTLY_PUBLISHED_RETENTION_SECS` are
+/// also kept (up to `MAX_RECENTLY_PUBLISHED`) to detect the changes read back from the CDC log.
+pub struct EventBus {
+    sender: broadcast::Sender<VehicleEvent>,
+    telemetry_sender: broadcast::Sender<TelemetryEvent>,
+    replay: Mutex<ReplayBuffer>,
+}
+
+struct ReplayBuffer {
+    next_id: u64,
+    capacity: usize,
+    events: VecDeque<VehicleEvent>,
+    /// Vehicle and event type of the events of the retention period, oldest first
+    recent: VecDeque<(String, VehicleEventType)>,
+    /// Events of the retention period by vehicle and event type, oldest first
+    recent_by_key: HashMap<(String, VehicleEventType), VecDeque<VehicleEvent>>,
+}
+
+impl ReplayBuffer {
+    /// Forget the oldest recently published event
+    fn forget_oldest_recent(&mut self) {
+        let key = match self.recent.pop_front() {
+            Some(key) => key,
+            None => return,
+        };
+        // The oldest event of the key is the oldest one overall
+        if let Some(events) = self.recent_by_key.get_mut(&key) {
+            events.pop_front();
+            if events.is_empty() {
+                self.recent_by_key.remove(&key);
+            }
+        }
+    }
+}
+
+impl EventBus {
+    pub fn new(replay_capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(replay_capacity.max(1));
+        let (telemetry_sender, _) = broadcast::channel(replay_capacity.max(1));
+
+        EventBus {
+            sender,
+            telemetry_sender,
+            replay: Mutex::new(ReplayBuffer {
+                next_id: 1,
+                capacity: replay_capacity,
+                events: VecDeque::with_capacity(replay_capacity),
+                recent: VecDeque::new(),
+                recent_by_key: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn publish(
+        &self,
+        event_type: VehicleEventType,
+        vin: &str,
+        vehicle: Option<Vehicle>,
+    ) -> VehicleEvent {
+        // Note: the lock is kept while sending, so that subscribers see the events in order
+        let mut replay = self.replay.lock().expect("event bus lock poisoned");
+
+        let event = VehicleEvent {
+            id: replay.next_id,
+            event_type,
+            vin: vin.to_string(),
+            occurred_at: Utc::now(),
+            vehicle,
+        };
+        replay.next_id += 1;
+
+        if replay.capacity > 0 {
+            if replay.events.len() == replay.capacity {
+                replay.events.pop_front();
+            }
+            replay.events.push_back(event.clone());
+        }
+
+        let retained_after =
+            event.occurred_at - chrono::Duration::seconds(RECENTLY_PUBLISHED_RETENTION_SECS);
+        loop {
+            let expired = match replay.recent.front() {
+                Some(key) => replay
+                    .recent_by_key
+                    .get(key)
+                    .and_then(|events| events.front())
+                    .map_or(true, |oldest| oldest.occurred_at < retained_after),
+                None => false,
+            };
+            if !expired && replay.recent.len() < MAX_RECENTLY_PUBLISHED {
+                break;
+            }
+            replay.forget_oldest_recent();
+        }
+        let key = (event.vin.clone(), event.event_type);
+        replay.recent.push_back(key.clone());
+        replay
+            .recent_by_key
+            .entry(key)
+            .or_default()
+            .push_back(event.clone());
+
+        // No receiver is not an error
+        let _ = self.sender.send(event.clone());
+
+        event
+    }
+
+    /// Subscribe to the next events, returns the buffered events following `last_event_id`
+    ///
+    /// If `last_event_id` is unknown (e.g. the process has been restarted), all the buffered
+    /// events are returned.
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> (Vec<VehicleEvent>, broadcast::Receiver<VehicleEvent>) {
+        let replay = self.replay.lock().expect("event bus lock poisoned");
+        let receiver = self.sender.subscribe();
+
+        let missed = match last_event_id {
+            Some(id) if id < replay.next_id => replay
+                .events
+                .iter()
+                .filter(|event| event.id > id)
+                .cloned()
+                .collect(),
+            Some(_) => replay.events.iter().cloned().collect(),
+            None => vec![],
+        };
+
+        (missed, receiver)
+    }
+
+    /// Whether the same change of the vehicle has been published around the given time
+    ///
+    /// Used to skip the changes already published by the API when they are read back from
+    /// another source (e.g. the CDC log).
+    pub fn is_recently_published(
+        &self,
+        event_type: VehicleEventType,
+        vin: &str,
+        vehicle: Option<&Vehicle>,
+        at: DateTime<Utc>,
+        tolerance: chrono::Duration,
+    ) -> bool {
+        let replay = self.replay.lock().expect("event bus lock poisoned");
+
+        replay
+            .recent_by_key
+            .get(&(vin.to_string(), event_type))
+            .map_or(false, |events| {
+                events.iter().rev().any(|event| {
+                    event.vehicle.as_ref() == vehicle
+                        && (event.occurred_at - at).num_milliseconds().abs()
+                            <= tolerance.num_milliseconds()
+                })
+            })
+    }
+
+    pub fn publish_telemetry(&self, vin: &str, readings: &[SocReading]) {
+        // No receiver is not an error
+        let _ = self.telemetry_sender.send(TelemetryEvent {
+            vin: vin.to_string(),
+            readings: readings.to_vec(),
+        });
+    }
+
+    pub fn subscribe_telemetry(&self) -> broadcast::Receiver<TelemetryEvent> {
+        self.telemetry_sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new(DEFAULT_REPLAY_CAPACITY)
+    }
+}
+
+impl std::fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventBus").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(events: &[VehicleEvent]) -> Vec<u64> {
+        events.iter().map(|event| event.id).collect()
+    }
+
+    #[tokio::test]
+    async fn test_publish_and_subscribe() -> anyhow::Result<()> {
+        let bus = EventBus::new(2);
+        let _ = bus.publish(VehicleEventType::Created, "vin1", None);
+
+        // Live events
+        let (missed, mut receiver) = bus.subscribe(None);
+        assert!(missed.is_empty());
+
+        let _ = bus.publish(VehicleEventType::Updated, "vin1", None);
+        let event = receiver.recv().await?;
+        assert_eq!(event.id, 2);
+        assert_eq!(event.event_type, VehicleEventType::Updated);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe_replay() {
+        let bus = EventBus::new(2);
+        for _ in 0..3 {
+            let _ = bus.publish(VehicleEventType::Updated, "vin1", None);
+        }
+
+        // Resume after the last received event, bounded by the buffer
+        assert_eq!(ids(&bus.subscribe(Some(2)).0), vec![3]);
+        assert_eq!(ids(&bus.subscribe(Some(0)).0), vec![2, 3]);
+        assert_eq!(ids(&bus.subscribe(Some(3)).0), Vec::<u64>::new());
+
+        // Unknown event id => whole buffer
+        assert_eq!(ids(&bus.subscribe(Some(42)).0), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_is_recently_published() {
+        let bus = EventBus::new(2);
+        let event = bus.publish(VehicleEventType::Deleted, "vin1", None);
+        let tolerance = chrono::Duration::seconds(5);
+
+        assert!(bus.is_recently_published(
+            VehicleEventType::Deleted,
+            "vin1",
+            None,
+            event.occurred_at + chrono::Duration::seconds(1),
+            tolerance
+        ));
+        assert!(!bus.is_recently_published(
+            VehicleEventType::Deleted,
+            "vin2",
+            None,
+            event.occurred_at,
+            tolerance
+        ));
+        assert!(!bus.is_recently_published(
+            VehicleEventType::Deleted,
+            "vin1",
+            None,
+            event.occurred_at - chrono::Duration::seconds(10),
+            tolerance
+        ));
+    }
+
+    #[test]
+    fn test_is_recently_published_burst() {
+        // More events than the replay capacity => still remembered
+        let bus = EventBus::new(2);
+        let first = bus.publish(VehicleEventType::Deleted, "vin0", None);
+        for i in 1..10 {
+            let _ = bus.publish(VehicleEventType::Deleted, &format!("vin{}", i), None);
+        }
+
+        assert!(bus.is_recently_published(
+            VehicleEventType::Deleted,
+            "vin0",
+            None,
+            first.occurred_at,
+            chrono::Duration::seconds(5)
+        ));
+    }
+
+    #[test]
+    fn test_is_recently_published_capped() {
+        // More events than the cap => the oldest ones are forgotten
+        let bus = EventBus::new(2);
+        let first = bus.publish(VehicleEventType::Deleted, "vin0", None);
+        let second = bus.publish(VehicleEventType::Deleted, "vin1", None);
+        for _ in 2..MAX_RECENTLY_PUBLISHED + 1 {
+            let _ = bus.publish(VehicleEventType::Created, "vin2", None);
+        }
+
+        let tolerance = chrono::Duration::seconds(5);
+        assert!(!bus.is_recently_published(
+            VehicleEventType::Deleted,
+            "vin0",
+            None,
+            first.occurred_at,
+            tolerance
+        ));
+        assert!(bus.is_recently_published(
+            VehicleEventType::Deleted,
+            "vin1",
+            None,
+            second.occurred_at,
+            tolerance
+        ));
+        let replay = bus.replay.lock().unwrap();
+        assert_eq!(replay.recent.len(), MAX_RECENTLY_PUBLISHED);
+        assert_eq!(replay.recent_by_key.len(), 2);
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    io::{Seek, SeekFrom, Write},
+    sync::{Arc, Mutex},
+};
+
+use axum::body::Bytes;
+use futures::{Stream, StreamExt};
+use parquet::{
+    column::writer::ColumnWriter,
+    data_type::ByteArray,
+    file::{
+        properties::WriterProperties,
+        writer::{FileWriter, RowGroupWriter, SerializedFileWriter},
+    },
+    schema::parser::parse_message_type,
+    util::io::TryClone,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::AppError,
+    model::vehicle::{Engine, Vehicle},
+    result::AppResult,
+};
+
+/// Number of vehicles per Parquet row group (the unit of streaming)
+pub const PARQUET_ROW_GROUP_SIZE: usize = 10_000;
+
+const PARQUET_SCHEMA: &str = "
+    message vehicle {
+        REQUIRED BYTE_ARRAY vin (UTF8);
+        OPTIONAL BYTE_ARRAY owner (UTF8);
+        REQUIRED BYTE_ARRAY engine_type (UTF8);
+        OPTIONAL INT32 battery_capacity_in_kwh;
+        OPTIONAL INT32 soc_in_percent;
+    }
+";
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Debug,
+    strum_macros::ToString,
+    strum_macros::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON vehicle per line
+    Ndjson,
+    /// Header line, then one vehicle per line (flattened EV data)
+    Csv,
+    /// Apache Parquet file (flattened EV data)
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+/// Vehicle with flattened EV data (tabular formats)
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ExportRow {
+    pub vin: String,
+    pub owner: Option<String>,
+    pub engine_type: String,
+    pub battery_capacity_in_kwh: Option<i32>,
+    pub soc_in_percent: Option<i32>,
+}
+
+impl From<&Vehicle> for ExportRow {
+    fn from(vehicle: &Vehicle) -> Self {
+        ExportRow {
+            vin: vehicle.vin.clone(),
+            owner: vehicle.owner.clone(),
+            engine_type: vehicle.engine.to_string(),
+            battery_capacity_in_kwh: vehicle
+                .ev_data
+                .as_ref()
+                .map(|ev_data| ev_data.battery_capacity_in_kwh),
+            soc_in_percent: vehicle
+                .ev_data
+                .as_ref()
+                .map(|ev_data| ev_data.soc_in_percent),
+        }
+    }
+}
+
+/// Encode the vehicles in the format, chunk by chunk (only one Parquet row group is buffered)
+///
+/// The vehicles can be filtered by engine type. An error ends the stream, the output is then
+/// truncated.
+pub fn export_vehicles<S>(
+    vehicles: S,
+    format: ExportFormat,
+    engine: Option<Engine>,
+) -> impl Stream<Item = AppResult<Bytes>> + Send
+where
+    S: Stream<Item = AppResult<Vehicle>> + Send + 'static,
+{
+    let vehicles = vehicles.filter(move |vehicle| {
+        futures::future::ready(match (vehicle, &engine) {
+            (Ok(vehicle), Some(engine)) => vehicle.engine == *engine,
+            _ => true,
+        })
+    });
+
+    match format {
+        ExportFormat::Ndjson => vehicles
+            .map(|vehicle| {
+                let mut line = serde_json::to_vec(&vehicle?)
+                    .map_err(|_| AppError::ConversionError("Vehicle to JSON"))?;
+                line.push(b'\n');
+                Ok(Bytes::from(line))
+            })
+            .boxed(),
+        ExportFormat::Csv => {
+            let header = futures::stream::once(async { csv_header() });
+            let rows = vehicles.map(|vehicle| csv_row(&ExportRow::from(&vehicle?)));
+            header.chain(rows).boxed()
+        }
+        ExportFormat::Parquet => parquet_stream(vehicles).boxed(),
+    }
+}
+
+/// Vehicles encoded in a line format (NDJSON, or CSV without the header), e.g. a chunk of an
+/// export job
+///
+/// A Parquet file cannot be written chunk by chunk (its metadata is written at the end).
+pub fn export_lines(vehicles: &[Vehicle], format: ExportFormat) -> AppResult<String> {
+    let mut lines = Vec::new();
+    for vehicle in vehicles.iter() {
+        let line = match format {
+            ExportFormat::Ndjson => {
+                let mut line = serde_json::to_vec(vehicle)
+                    .map_err(|_| AppError::ConversionError("Vehicle to JSON"))?;
+                line.push(b'\n');
+                Bytes::from(line)
+            }
+            ExportFormat::Csv => csv_row(&ExportRow::from(vehicle))?,
+            ExportFormat::Parquet => return Err(AppError::InvalidInput("Line export format")),
+        };
+        lines.extend_from_slice(&line);
+    }
+
+    String::from_utf8(lines).map_err(|_| AppError::ConversionError("Export lines to UTF-8"))
+}
+
+pub fn csv_header() -> AppResult<Bytes> {
+    Ok(Bytes::from_static(
+        b"vin,owner,engine_type,battery_capacity_in_kwh,soc_in_percent\n",
+    ))
+}
+
+fn csv_row(row: &ExportRow) -> AppResult<Bytes> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(vec![]);
+    writer
+        .serialize(row)
+        .map_err(|_| AppError::ConversionError("ExportRow to CSV"))?;
+    let line = writer
+        .into_inner()
+        .map_err(|_| AppError::ConversionError("ExportRow to CSV"))?;
+
+    Ok(Bytes::from(line))
+}
+
+/// Output of the Parquet writer, drained after each row group
+///
+/// The writer only seeks to get its current position (the number of bytes written so far).
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<BufferState>>);
+
+#[derive(Default)]
+struct BufferState {
+    data: Vec<u8>,
+    position: u64,
+}
+
+impl SharedBuffer {
+    fn take(&self) -> Bytes {
+        let mut state = self.0.lock().expect("buffer lock poisoned");
+        Bytes::from(std::mem::take(&mut state.data))
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.0.lock().expect("buffer lock poisoned");
+        state.data.extend_from_slice(buf);
+        state.position += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SharedBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let state = self.0.lock().expect("buffer lock poisoned");
+        match pos {
+            SeekFrom::Current(0) => Ok(state.position),
+            SeekFrom::Start(position) if position == state.position => Ok(state.position),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Streamed Parquet output cannot be seeked",
+            )),
+        }
+    }
+}
+
+impl TryClone for SharedBuffer {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+struct ParquetEncoder {
+    writer: SerializedFileWriter<SharedBuffer>,
+    buffer: SharedBuffer,
+}
+
+impl ParquetEncoder {
+    fn try_new() -> AppResult<Self> {
+        let schema = Arc::new(parse_message_type(PARQUET_SCHEMA).map_err(anyhow::Error::from)?);
+        let properties = Arc::new(WriterProperties::builder().build());
+        let buffer = SharedBuffer::default();
+        let writer = SerializedFileWriter::new(buffer.clone(), schema, properties)
+            .map_err(anyhow::Error::from)?;
+
+        Ok(ParquetEncoder { writer, buffer })
+    }
+
+    /// Write a row group, returns the bytes written so far
+    fn write_row_group(&mut self, rows: &[ExportRow]) -> AppResult<Bytes> {
+        let mut row_group_writer = self.writer.next_row_group().map_err(anyhow::Error::from)?;
+
+        let mut column_index = 0;
+        while let Some(mut column_writer) = row_group_writer
+            .next_column()
+            .map_err(anyhow::Error::from)?
+        {
+            let result = match (&mut column_writer, column_index) {
+                (ColumnWriter::ByteArrayColumnWriter(writer), 0) => {
+                    let values = byte_arrays(rows.iter().map(|row| Some(row.vin.as_str())));
+                    writer.write_batch(&values.0, None, None)
+                }
+                (ColumnWriter::ByteArrayColumnWriter(writer), 1) => {
+                    let values = byte_arrays(rows.iter().map(|row| row.owner.as_deref()));
+                    writer.write_batch(&values.0, Some(&values.1), None)
+                }
+                (ColumnWriter::ByteArrayColumnWriter(writer), 2) => {
+                    let values = byte_arrays(rows.iter().map(|row| Some(row.engine_type.as_str())));
+                    writer.write_batch(&values.0, None, None)
+                }
+                (ColumnWriter::Int32ColumnWriter(writer), 3) => {
+                    let values = int32s(rows.iter().map(|row| row.battery_capacity_in_kwh));
+                    writer.write_batch(&values.0, Some(&values.1), None)
+                }
+                (ColumnWriter::Int32ColumnWriter(writer), 4) => {
+                    let values = int32s(rows.iter().map(|row| row.soc_in_percent));
+                    writer.write_batch(&values.0, Some(&values.1), None)
+                }
+                _ => return Err(AppError::ConversionError("Parquet schema")),
+            };
+            result.map_err(anyhow::Error::from)?;
+
+            row_group_writer
+                .close_column(column_writer)
+                .map_err(anyhow::Error::from)?;
+            column_index += 1;
+        }
+        self.writer
+            .close_row_group(row_group_writer)
+            .map_err(anyhow::Error::from)?;
+
+        Ok(self.buffer.take())
+    }
+
+    /// Write the footer, returns the remaining bytes
+    fn close(mut self) -> AppResult<Bytes> {
+        self.writer.close().map_err(anyhow::Error::from)?;
+
+        Ok(self.buffer.take())
+    }
+}
+
+/// Values and definition levels of an optional column
+fn byte_arrays<'a>(values: impl Iterator<Item = Option<&'a str>>) -> (Vec<ByteArray>, Vec<i16>) {
+    let mut present = Vec::new();
+    let mut levels = Vec::new();
+    for value in values {
+        levels.push(value.is_some() as i16);
+        if let Some(value) = value {
+            present.push(ByteArray::from(value));
+        }
+    }
+
+    (present, levels)
+}
+
+/// Values and definition levels of an optional column
+fn int32s(values: impl Iterator<Item = Option<i32>>) -> (Vec<i32>, Vec<i16>) {
+    let mut present = Vec::new();
+    let mut levels = Vec::new();
+    for value in values {
+        levels.push(value.is_some() as i16);
+        if let Some(value) = value {
+            present.push(value);
+        }
+    }
+
+    (present, levels)
+}
+
+fn parquet_stream<S>(vehicles: S) -> impl Stream<Item = AppResult<Bytes>>
+where
+    S: Stream<Item = AppResult<Vehicle>> + Send + 'static,
+{
+    let row_groups = vehicles.chunks(PARQUET_ROW_GROUP_SIZE).boxed();
+    let state = (row_groups, ParquetEncoder::try_new().map(Some));
+
+    futures::stream::unfold(state, |(mut row_groups, encoder)| async move {
+        let mut encoder = match encoder {
+            Ok(Some(encoder)) => encoder,
+            Ok(None) => return None,
+            Err(e) => return Some((Err(e), (row_groups, Ok(None)))),
+        };
+
+        let bytes = match row_groups.next().await {
+            Some(vehicles) => vehicles
+                .into_iter()
+                .map(|vehicle| vehicle.map(|vehicle| ExportRow::from(&vehicle)))
+                .collect::<AppResult<Vec<ExportRow>>>()
+                .and_then(|rows| encoder.write_row_group(&rows)),
+            None => return Some((encoder.close(), (row_groups, Ok(None)))),
+        };
+
+        match bytes {
+            Ok(bytes) => Some((Ok(bytes), (row_groups, Ok(Some(encoder))))),
+            Err(e) => Some((Err(e), (row_groups, Ok(None)))),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::vehicle::EvData;
+
+    fn vehicles() -> Vec<AppResult<Vehicle>> {
+        vec![
+            Ok(Vehicle {
+                vin: "vin1".to_string(),
+                owner: Some("Doe, John".to_string()),
+                engine: Engine::Ev,
+                ev_data: Some(EvData {
+                    battery_capacity_in_kwh: 50,
+                    soc_in_percent: 80,
+                }),
+            }),
+            Ok(Vehicle {
+                vin: "vin2".to_string(),
+                owner: None,
+                engine: Engine::Combustion,
+                ev_data: None,
+            }),
+        ]
+    }
+
+    async fn export(format: ExportFormat, engine: Option<Engine>) -> anyhow::Result<Vec<u8>> {
+        let chunks: Vec<AppResult<Bytes>> =
+            export_vehicles(futures::stream::iter(vehicles()), format, engine)
+                .collect()
+                .await;
+
+        let mut output = Vec::new();
+        for chunk in chunks.into_iter() {
+            output.extend_from_slice(&chunk?);
+        }
+
+        Ok(output)
+    }
+
+    #[tokio::test]
+    async fn test_export_ndjson() -> anyhow::Result<()> {
+        let output =
+            String::from_utf8(export(ExportFormat::Ndjson, Some(Engine::Combustion)).await?)?;
+
+        assert_eq!(
+            output,
+            "{\"vin\":\"vin2\",\"engine_type\":\"Combustion\"}\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_csv() -> anyhow::Result<()> {
+        let output = String::from_utf8(export(ExportFormat::Csv, None).await?)?;
+
+        assert_eq!(
+            output,
+            "vin,owner,engine_type,battery_capacity_in_kwh,soc_in_percent\n\
+             vin1,\"Doe, John\",Ev,50,80\n\
+             vin2,,Combustion,,\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_parquet() -> anyhow::Result<()> {
+        let output = export(ExportFormat::Parquet, None).await?;
+
+        // Magic number at the start and at the end of the file
+        assert!(output.starts_with(b"PAR1"));
+        assert!(output.ends_with(b"PAR1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_lines() -> anyhow::Result<()> {
+        let vehicles = vehicles()
+            .into_iter()
+            .collect::<AppResult<Vec<Vehicle>>>()?;
+
+        assert_eq!(
+            export_lines(&vehicles, ExportFormat::Csv)?,
+            "vin1,\"Doe, John\",Ev,50,80\n\
+             vin2,,Combustion,,\n"
+        );
+        assert_eq!(
+            export_lines(&vehicles[1..], ExportFormat::Ndjson)?,
+            "{\"vin\":\"vin2\",\"engine_type\":\"Combustion\"}\n"
+        );
+
+        // TODO: user assert_matches! when stable
+        assert!(matches!(
+            export_lines(&vehicles, ExportFormat::Parquet),
+            Err(AppError::InvalidInput(_))
+        ));
+
+        Ok(())
+    }
+}
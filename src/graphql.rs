@@ -0,0 +1,503 @@
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
+
+use async_graphql::{
+    connection::{Connection, Edge, EmptyFields},
+    dataloader::{DataLoader, Loader},
+    Context, EmptySubscription, Enum, InputObject, Object, Schema, SimpleObject,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    alerts,
+    auth::{self, RequestApiKey},
+    config::Config,
+    db::queries::{Queries, TelemetryQueries, VehicleQueries},
+    error::AppError,
+    model::{
+        telemetry::SocReading,
+        vehicle::{self, Vehicle},
+    },
+};
+
+/// Number of vehicles of a connection page without `first` argument
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+
+/// Maximum number of vehicles of a connection page
+pub const MAX_PAGE_SIZE: usize = 100;
+
+pub type GraphQLSchema<Q> = Schema<QueryRoot<Q>, MutationRoot<Q>, EmptySubscription>;
+
+pub fn create_schema<Q: Queries>(queries: Arc<Q>) -> GraphQLSchema<Q> {
+    Schema::build(
+        QueryRoot(PhantomData),
+        MutationRoot(PhantomData),
+        EmptySubscription,
+    )
+    .data(queries)
+    .finish()
+}
+
+/// Data loaders of a request (their cache only lives as long as the request)
+///
+/// The fields resolved concurrently (e.g. the latest SoC of each vehicle of a page) are batched
+/// into one query.
+pub fn create_loaders<Q: Queries>(
+    queries: Arc<Q>,
+) -> (DataLoader<VehicleLoader<Q>>, DataLoader<LatestSocLoader<Q>>) {
+    (
+        DataLoader::new(VehicleLoader(queries.clone())),
+        DataLoader::new(LatestSocLoader(queries)),
+    )
+}
+
+pub struct VehicleLoader<Q: Queries>(Arc<Q>);
+
+#[async_trait]
+impl<Q: Queries> Loader<String> for VehicleLoader<Q> {
+    type Value = Vehicle;
+    type Error = Arc<AppError>;
+
+    async fn load(&self, vins: &[String]) -> Result<HashMap<String, Vehicle>, Self::Error> {
+        let vehicles = self.0.vehicle_queries().find_vehicles(vins).await?;
+
+        Ok(vehicles
+            .into_iter()
+            .map(|vehicle| (vehicle.vin.clone(), vehicle))
+            .collect())
+    }
+}
+
+pub struct LatestSocLoader<Q: Queries>(Arc<Q>);
+
+#[async_trait]
+impl<Q: Queries> Loader<String> for LatestSocLoader<Q> {
+    type Value = SocReading;
+    type Error = Arc<AppError>;
+
+    async fn load(&self, vins: &[String]) -> Result<HashMap<String, SocReading>, Self::Error> {
+        Ok(self
+            .0
+            .telemetry_queries()
+            .find_latest_soc_readings(vins)
+            .await?)
+    }
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EngineType {
+    Combustion,
+    Phev,
+    Ev,
+}
+
+impl From<&vehicle::Engine> for EngineType {
+    fn from(engine: &vehicle::Engine) -> Self {
+        match engine {
+            vehicle::Engine::Combustion => EngineType::Combustion,
+            vehicle::Engine::Phev => EngineType::Phev,
+            vehicle::Engine::Ev => EngineType::Ev,
+        }
+    }
+}
+
+impl From<EngineType> for vehicle::Engine {
+    fn from(engine_type: EngineType) -> Self {
+        match engine_type {
+            EngineType::Combustion => vehicle::Engine::Combustion,
+            EngineType::Phev => vehicle::Engine::Phev,
+            EngineType::Ev => vehicle::Engine::Ev,
+        }
+    }
+}
+
+#[derive(SimpleObject, Clone, Debug)]
+pub struct EvData {
+    pub battery_capacity_in_kwh: i32,
+    pub soc_in_percent: i32,
+}
+
+#[derive(InputObject, Clone, Debug)]
+pub struct EvDataInput {
+    pub battery_capacity_in_kwh: i32,
+    pub soc_in_percent: i32,
+}
+
+#[derive(SimpleObject, Clone, Debug)]
+pub struct LatestSoc {
+    pub timestamp: DateTime<Utc>,
+    pub soc_in_percent: i32,
+}
+
+#[derive(InputObject, Clone, Debug)]
+pub struct VehicleInput {
+    pub vin: String,
+    pub owner: Option<String>,
+    pub engine_type: EngineType,
+    pub ev_data: Option<EvDataInput>,
+}
+
+impl From<VehicleInput> for Vehicle {
+    fn from(input: VehicleInput) -> Self {
+        Vehicle {
+            vin: input.vin,
+            owner: input.owner,
+            engine: input.engine_type.into(),
+            ev_data: input.ev_data.map(|ev_data| vehicle::EvData {
+                battery_capacity_in_kwh: ev_data.battery_capacity_in_kwh,
+                soc_in_percent: ev_data.soc_in_percent,
+            }),
+        }
+    }
+}
+
+#[derive(InputObject, Default, Clone, Debug)]
+pub struct VehicleFilter {
+    pub engine_type: Option<EngineType>,
+    pub owner: Option<String>,
+}
+
+impl VehicleFilter {
+    fn matches(&self, vehicle: &Vehicle) -> bool {
+        self.engine_type
+            .map_or(true, |engine_type| engine_type == (&vehicle.engine).into())
+            && self
+                .owner
+                .as_ref()
+                .map_or(true, |owner| vehicle.owner.as_ref() == Some(owner))
+    }
+}
+
+pub struct VehicleObject<Q: Queries> {
+    vehicle: Vehicle,
+    _queries: PhantomData<Q>,
+}
+
+impl<Q: Queries> From<Vehicle> for VehicleObject<Q> {
+    fn from(vehicle: Vehicle) -> Self {
+        VehicleObject {
+            vehicle,
+            _queries: PhantomData,
+        }
+    }
+}
+
+#[Object(name = "Vehicle")]
+impl<Q: Queries> VehicleObject<Q> {
+    async fn vin(&self) -> &str {
+        &self.vehicle.vin
+    }
+
+    async fn owner(&self) -> Option<&str> {
+        self.vehicle.owner.as_deref()
+    }
+
+    async fn engine_type(&self) -> EngineType {
+        (&self.vehicle.engine).into()
+    }
+
+    async fn ev_data(&self) -> Option<EvData> {
+        self.vehicle.ev_data.as_ref().map(|ev_data| EvData {
+            battery_capacity_in_kwh: ev_data.battery_capacity_in_kwh,
+            soc_in_percent: ev_data.soc_in_percent,
+        })
+    }
+
+    /// Most recent SoC reading (batched with the other vehicles of the request)
+    async fn latest_soc(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<LatestSoc>> {
+        let reading = ctx
+            .data::<DataLoader<LatestSocLoader<Q>>>()?
+            .load_one(self.vehicle.vin.clone())
+            .await?;
+
+        Ok(reading.map(|reading| LatestSoc {
+            timestamp: reading.timestamp,
+            soc_in_percent: reading.soc_in_percent,
+        }))
+    }
+}
+
+/// Relay cursor of a vehicle (opaque for the clients)
+fn encode_cursor(vin: &str) -> String {
+    hex::encode(vin)
+}
+
+fn decode_cursor(cursor: &str) -> Result<String, AppError> {
+    hex::decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or(AppError::InvalidInput("Cursor"))
+}
+
+pub struct QueryRoot<Q: Queries>(PhantomData<Q>);
+
+#[Object]
+impl<Q: Queries> QueryRoot<Q> {
+    /// Vehicle by VIN (batched with the other vehicles of the request)
+    async fn vehicle(
+        &self,
+        ctx: &Context<'_>,
+        vin: String,
+    ) -> async_graphql::Result<Option<VehicleObject<Q>>> {
+        let vehicle = ctx
+            .data::<DataLoader<VehicleLoader<Q>>>()?
+            .load_one(vin)
+            .await?;
+
+        Ok(vehicle.map(VehicleObject::from))
+    }
+
+    /// Vehicles in a stable (token) order, forward pagination only
+    ///
+    /// Note: the filter is applied while scanning the table, a page with a selective filter may
+    /// read many rows.
+    async fn vehicles(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<VehicleFilter>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> async_graphql::Result<Connection<String, VehicleObject<Q>, EmptyFields, EmptyFields>> {
+        let queries = ctx.data::<Arc<Q>>()?;
+        let filter = filter.unwrap_or_default();
+        let first = match first {
+            Some(first) if first < 0 => return Err(AppError::InvalidInput("first").into()),
+            Some(first) => (first as usize).min(MAX_PAGE_SIZE),
+            None => DEFAULT_PAGE_SIZE,
+        };
+        let mut after_vin = after.as_deref().map(decode_cursor).transpose()?;
+        let has_previous_page = after_vin.is_some();
+
+        // Read one more vehicle than requested to know whether there is a next page
+        let mut vehicles = Vec::with_capacity(first + 1);
+        while vehicles.len() <= first {
+            let (page, last_vin) = queries
+                .vehicle_queries()
+                .find_vehicles_page(after_vin.clone(), (first + 1) as i32)
+                .await?;
+            vehicles.extend(page.into_iter().filter(|vehicle| filter.matches(vehicle)));
+
+            match last_vin {
+                Some(last_vin) => after_vin = Some(last_vin),
+                None => break,
+            }
+        }
+        let has_next_page = vehicles.len() > first;
+        vehicles.truncate(first);
+
+        let mut connection = Connection::new(has_previous_page, has_next_page);
+        connection.append(
+            vehicles
+                .into_iter()
+                .map(|vehicle| Edge::new(encode_cursor(&vehicle.vin), vehicle.into())),
+        );
+
+        Ok(connection)
+    }
+}
+
+pub struct MutationRoot<Q: Queries>(PhantomData<Q>);
+
+#[Object]
+impl<Q: Queries> MutationRoot<Q> {
+    async fn create_vehicle(
+        &self,
+        ctx: &Context<'_>,
+        vehicle: VehicleInput,
+    ) -> async_graphql::Result<VehicleObject<Q>> {
+        let queries = ctx.data::<Arc<Q>>()?.as_ref();
+        let vehicle = Vehicle::from(vehicle);
+
+        queries.vehicle_queries().create_vehicle(&vehicle).await?;
+        alerts::evaluate_alert_rules(queries, &vehicle).await?;
+
+        Ok(vehicle.into())
+    }
+
+    /// Mark the vehicle as deleted (or remove it with `purge: true` and the admin API key),
+    /// returns true
+    async fn delete_vehicle(
+        &self,
+        ctx: &Context<'_>,
+        vin: String,
+        #[graphql(default)] purge: bool,
+    ) -> async_graphql::Result<bool> {
+        let queries = ctx.data::<Arc<Q>>()?.as_ref();
+
+        if purge {
+            auth::ensure_admin(ctx.data::<RequestApiKey>()?, ctx.data::<Arc<Config>>()?)?;
+            queries.vehicle_queries().purge_one_vehicle(&vin).await?;
+        } else {
+            queries.vehicle_queries().delete_one_vehicle(&vin).await?;
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+    use serde_json::json;
+
+    use super::*;
+    use crate::db::queries;
+
+    fn test_vehicle(vin: &str, engine: vehicle::Engine) -> Vehicle {
+        Vehicle {
+            vin: vin.to_string(),
+            owner: None,
+            engine,
+            ev_data: None,
+        }
+    }
+
+    async fn execute(queries: queries::MockQueries, query: &str) -> async_graphql::Response {
+        let queries = Arc::new(queries);
+        let (vehicle_loader, latest_soc_loader) = create_loaders(queries.clone());
+
+        create_schema(queries)
+            .execute(
+                async_graphql::Request::new(query)
+                    .data(vehicle_loader)
+                    .data(latest_soc_loader),
+            )
+            .await
+    }
+
+    #[test]
+    fn test_cursor() {
+        assert_eq!(decode_cursor(&encode_cursor("vin1")).unwrap(), "vin1");
+
+        // TODO: user assert_matches! when stable
+        assert!(matches!(
+            decode_cursor("not hex"),
+            Err(AppError::InvalidInput("Cursor"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_vehicles_batched() {
+        // Both vehicles (and their SoC) are read in one query each
+        let mut vehicle_queries = queries::MockVehicleQueries::default();
+        vehicle_queries
+            .expect_find_vehicles()
+            .times(1)
+            .returning(|vins| {
+                Ok(vins
+                    .iter()
+                    .map(|vin| test_vehicle(vin, vehicle::Engine::Ev))
+                    .collect())
+            });
+        let mut telemetry_queries = queries::MockTelemetryQueries::default();
+        telemetry_queries
+            .expect_find_latest_soc_readings()
+            .times(1)
+            .returning(|_| Ok(HashMap::new()));
+        let queries = queries::MockQueries {
+            vehicle_queries,
+            telemetry_queries,
+            ..Default::default()
+        };
+
+        let response = execute(
+            queries,
+            r#"{
+                a: vehicle(vin: "vin1") { vin engineType latestSoc { socInPercent } }
+                b: vehicle(vin: "vin2") { vin engineType latestSoc { socInPercent } }
+            }"#,
+        )
+        .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            json!({
+                "a": {"vin": "vin1", "engineType": "EV", "latestSoc": null},
+                "b": {"vin": "vin2", "engineType": "EV", "latestSoc": null},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vehicles_connection() {
+        // Pages of first + 1 rows: vin1 to vin3 (only vin1 matches), then vin4 and vin5
+        let mut vehicle_queries = queries::MockVehicleQueries::default();
+        vehicle_queries
+            .expect_find_vehicles_page()
+            .with(eq(None), eq(3))
+            .times(1)
+            .returning(|_, _| {
+                Ok((
+                    vec![
+                        test_vehicle("vin1", vehicle::Engine::Ev),
+                        test_vehicle("vin2", vehicle::Engine::Combustion),
+                        test_vehicle("vin3", vehicle::Engine::Phev),
+                    ],
+                    Some("vin3".to_string()),
+                ))
+            });
+        vehicle_queries
+            .expect_find_vehicles_page()
+            .with(eq(Some("vin3".to_string())), eq(3))
+            .times(1)
+            .returning(|_, _| {
+                Ok((
+                    vec![
+                        test_vehicle("vin4", vehicle::Engine::Ev),
+                        test_vehicle("vin5", vehicle::Engine::Ev),
+                    ],
+                    None,
+                ))
+            });
+        let queries = queries::MockQueries {
+            vehicle_queries,
+            ..Default::default()
+        };
+
+        let response = execute(
+            queries,
+            r#"{
+                vehicles(filter: {engineType: EV}, first: 2) {
+                    edges { cursor node { vin } }
+                    pageInfo { hasNextPage endCursor }
+                }
+            }"#,
+        )
+        .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            json!({
+                "vehicles": {
+                    "edges": [
+                        {"cursor": encode_cursor("vin1"), "node": {"vin": "vin1"}},
+                        {"cursor": encode_cursor("vin4"), "node": {"vin": "vin4"}},
+                    ],
+                    "pageInfo": {"hasNextPage": true, "endCursor": encode_cursor("vin4")},
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_vehicle_already_exists() {
+        let mut vehicle_queries = queries::MockVehicleQueries::default();
+        vehicle_queries
+            .expect_create_vehicle()
+            .with(eq(test_vehicle("vin1", vehicle::Engine::Combustion)))
+            .times(1)
+            .returning(|_| Err(AppError::AlreadyExists("Vehicle")));
+        let queries = queries::MockQueries {
+            vehicle_queries,
+            ..Default::default()
+        };
+
+        let response = execute(
+            queries,
+            r#"mutation { createVehicle(vehicle: {vin: "vin1", engineType: COMBUSTION}) { vin } }"#,
+        )
+        .await;
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].message, "Already exists (Vehicle)");
+    }
+}
@@ -0,0 +1,56 @@
+pub mod vehicle_service;
+
+/// Messages and services generated from proto/vehicle.proto
+pub mod proto {
+    tonic::include_proto!("hello.v1");
+}
+
+use tonic::{Code, Status};
+
+use crate::error::AppError;
+
+impl From<AppError> for Status {
+    fn from(e: AppError) -> Self {
+        let code = match e {
+            AppError::TimeoutError(_) => Code::DeadlineExceeded,
+            AppError::Forbidden(_) => Code::PermissionDenied,
+            AppError::NotFound(_) => Code::NotFound,
+            AppError::AlreadyExists(_) => Code::AlreadyExists,
+            AppError::Conflict(_) => Code::Aborted,
+            AppError::InvalidInput(_)
+            | AppError::NotAcceptable(_)
+            | AppError::UnsupportedMediaType(_)
+            | AppError::UnprocessableEntity(_) => Code::InvalidArgument,
+            AppError::PayloadTooLarge(_) | AppError::TooManyRequests(_) => Code::ResourceExhausted,
+            AppError::ServiceUnavailable(_) => Code::Unavailable,
+            _ => Code::Internal,
+        };
+
+        Status::new(code, e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_app_error_to_status() {
+        let status = Status::from(AppError::NotFound("Vehicle"));
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "Not found (Vehicle)");
+
+        assert_eq!(
+            Status::from(AppError::AlreadyExists("Vehicle")).code(),
+            Code::AlreadyExists
+        );
+        assert_eq!(
+            Status::from(AppError::InvalidInput("Engine type")).code(),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            Status::from(AppError::from("Test error")).code(),
+            Code::Internal
+        );
+    }
+}
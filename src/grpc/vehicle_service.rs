@@ -0,0 +1,409 @@
+use std::{convert::TryFrom, sync::Arc};
+
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+use super::proto::{self, vehicle_service_server::VehicleService};
+use crate::{
+    alerts,
+    auth::{self, RequestApiKey},
+    config::Config,
+    db::queries::{Queries, VehicleQueries},
+    error::AppError,
+    model::{
+        event::{VehicleEvent, VehicleEventType},
+        vehicle::{Engine, EvData, Vehicle},
+    },
+    rate_limit::API_KEY_HEADER,
+    result::AppResult,
+    routing::event_handlers::vehicle_event_stream,
+    shutdown::ShutdownSignal,
+};
+
+/// Number of messages buffered per streaming call (back pressure on slow clients)
+pub const STREAM_BUFFER_SIZE: usize = 64;
+
+/// gRPC counterpart of the vehicle handlers
+#[derive(Debug)]
+pub struct VehicleGrpcService<Q: Queries> {
+    queries: Arc<Q>,
+    config: Arc<Config>,
+    /// The `watch` streams end on the shutdown
+    shutdown: ShutdownSignal,
+}
+
+impl<Q: Queries> VehicleGrpcService<Q> {
+    pub fn new(queries: Arc<Q>, config: Arc<Config>, shutdown: ShutdownSignal) -> Self {
+        VehicleGrpcService {
+            queries,
+            config,
+            shutdown,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<Q: Queries> VehicleService for VehicleGrpcService<Q> {
+    type ListStream = ReceiverStream<Result<proto::Vehicle, Status>>;
+    type WatchStream = ReceiverStream<Result<proto::VehicleEvent, Status>>;
+
+    #[tracing::instrument(err)]
+    async fn create(
+        &self,
+        request: Request<proto::Vehicle>,
+    ) -> Result<Response<proto::Vehicle>, Status> {
+        let vehicle = Vehicle::try_from(request.into_inner())?;
+
+        let queries = self.queries.as_ref();
+        queries.vehicle_queries().create_vehicle(&vehicle).await?;
+        alerts::evaluate_alert_rules(queries, &vehicle).await?;
+
+        Ok(Response::new(vehicle.into()))
+    }
+
+    #[tracing::instrument(err)]
+    async fn get(
+        &self,
+        request: Request<proto::GetVehicleRequest>,
+    ) -> Result<Response<proto::Vehicle>, Status> {
+        let vehicle = self
+            .queries
+            .vehicle_queries()
+            .find_one_vehicle(&request.get_ref().vin)
+            .await?;
+
+        Ok(Response::new(vehicle.into()))
+    }
+
+    #[tracing::instrument(err)]
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteVehicleRequest>,
+    ) -> Result<Response<proto::DeleteVehicleResponse>, Status> {
+        // Same API key metadata as the header of the HTTP requests
+        let api_key = RequestApiKey::from_value(
+            request
+                .metadata()
+                .get(API_KEY_HEADER)
+                .map(|value| value.as_bytes()),
+        )?;
+        let request = request.into_inner();
+
+        let queries = self.queries.as_ref();
+        if request.purge {
+            auth::ensure_admin(&api_key, &self.config)?;
+            queries
+                .vehicle_queries()
+                .purge_one_vehicle(&request.vin)
+                .await?;
+        } else {
+            queries
+                .vehicle_queries()
+                .delete_one_vehicle(&request.vin)
+                .await?;
+        }
+
+        Ok(Response::new(proto::DeleteVehicleResponse {}))
+    }
+
+    #[tracing::instrument(err)]
+    async fn list(
+        &self,
+        request: Request<proto::ListVehiclesRequest>,
+    ) -> Result<Response<Self::ListStream>, Status> {
+        let engine = engine_from_proto(request.get_ref().engine_type)?;
+        let mut vehicles = self.queries.vehicle_queries().find_all_vehicles().await?;
+
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER_SIZE);
+        tokio::spawn(async move {
+            while let Some(vehicle) = vehicles.next().await {
+                let message = match vehicle {
+                    Ok(vehicle) if engine.as_ref().map_or(true, |e| vehicle.engine == *e) => {
+                        Ok(vehicle.into())
+                    }
+                    Ok(_) => continue,
+                    Err(e) => Err(Status::from(e)),
+                };
+
+                // The stream ends on the first error or when the client is gone
+                let is_error = message.is_err();
+                if sender.send(message).await.is_err() || is_error {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    #[tracing::instrument(err)]
+    async fn watch(
+        &self,
+        request: Request<proto::WatchVehiclesRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let request = request.into_inner();
+        let vin = Some(request.vin).filter(|vin| !vin.is_empty());
+        let last_event_id = Some(request.last_event_id).filter(|id| *id != 0);
+
+        let mut events = vehicle_event_stream(
+            self.queries.event_bus(),
+            vin,
+            last_event_id,
+            self.shutdown.clone(),
+        )
+        .boxed();
+
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER_SIZE);
+        tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                if sender.send(Ok(event.into())).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// None for ENGINE_TYPE_UNSPECIFIED
+fn engine_from_proto(engine_type: i32) -> AppResult<Option<Engine>> {
+    match proto::EngineType::from_i32(engine_type) {
+        Some(proto::EngineType::Unspecified) => Ok(None),
+        Some(proto::EngineType::Combustion) => Ok(Some(Engine::Combustion)),
+        Some(proto::EngineType::Phev) => Ok(Some(Engine::Phev)),
+        Some(proto::EngineType::Ev) => Ok(Some(Engine::Ev)),
+        None => Err(AppError::InvalidInput("Engine type")),
+    }
+}
+
+impl From<Engine> for proto::EngineType {
+    fn from(engine: Engine) -> Self {
+        match engine {
+            Engine::Combustion => proto::EngineType::Combustion,
+            Engine::Phev => proto::EngineType::Phev,
+            Engine::Ev => proto::EngineType::Ev,
+        }
+    }
+}
+
+impl From<Vehicle> for proto::Vehicle {
+    fn from(vehicle: Vehicle) -> Self {
+        proto::Vehicle {
+            vin: vehicle.vin,
+            owner: vehicle.owner.unwrap_or_default(),
+            engine_type: proto::EngineType::from(vehicle.engine) as i32,
+            ev_data: vehicle.ev_data.map(|ev_data| proto::EvData {
+                battery_capacity_in_kwh: ev_data.battery_capacity_in_kwh,
+                soc_in_percent: ev_data.soc_in_percent,
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::Vehicle> for Vehicle {
+    type Error = AppError;
+
+    fn try_from(vehicle: proto::Vehicle) -> Result<Self, Self::Error> {
+        Ok(Vehicle {
+            vin: vehicle.vin,
+            owner: Some(vehicle.owner).filter(|owner| !owner.is_empty()),
+            engine: engine_from_proto(vehicle.engine_type)?
+                .ok_or(AppError::InvalidInput("Engine type"))?,
+            ev_data: vehicle.ev_data.map(|ev_data| EvData {
+                battery_capacity_in_kwh: ev_data.battery_capacity_in_kwh,
+                soc_in_percent: ev_data.soc_in_percent,
+            }),
+        })
+    }
+}
+
+impl From<VehicleEvent> for proto::VehicleEvent {
+    fn from(event: VehicleEvent) -> Self {
+        let event_type = match event.event_type {
+            VehicleEventType::Created => proto::VehicleEventType::Created,
+            VehicleEventType::Updated => proto::VehicleEventType::Updated,
+            VehicleEventType::Deleted => proto::VehicleEventType::Deleted,
+        };
+
+        proto::VehicleEvent {
+            id: event.id,
+            event_type: event_type as i32,
+            vin: event.vin,
+            occurred_at_in_ms: event.occurred_at.timestamp_millis(),
+            vehicle: event.vehicle.map(proto::Vehicle::from),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::{auth::ApiKey, db::queries, shutdown::Shutdown};
+
+    fn ev() -> Vehicle {
+        Vehicle {
+            vin: "vin1".to_string(),
+            owner: Some("Alice".to_string()),
+            engine: Engine::Ev,
+            ev_data: Some(EvData {
+                battery_capacity_in_kwh: 50,
+                soc_in_percent: 80,
+            }),
+        }
+    }
+
+    fn combustion() -> Vehicle {
+        Vehicle {
+            vin: "vin2".to_string(),
+            owner: None,
+            engine: Engine::Combustion,
+            ev_data: None,
+        }
+    }
+
+    #[test]
+    fn test_vehicle_to_proto_and_back() {
+        for vehicle in vec![ev(), combustion()] {
+            let message = proto::Vehicle::from(vehicle.clone());
+            assert_eq!(Vehicle::try_from(message).unwrap(), vehicle);
+        }
+
+        // TODO: user assert_matches! when stable
+        let message = proto::Vehicle {
+            engine_type: proto::EngineType::Unspecified as i32,
+            ..proto::Vehicle::from(combustion())
+        };
+        assert!(matches!(
+            Vehicle::try_from(message),
+            Err(AppError::InvalidInput("Engine type"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_ok() {
+        let vehicle = combustion();
+
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_create_vehicle()
+            .with(eq(vehicle.clone()))
+            .times(1)
+            .returning(|_| Ok(()));
+        let service = create_service(mock_vehicle_queries);
+
+        let response = service
+            .create(Request::new(vehicle.clone().into()))
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner(), proto::Vehicle::from(vehicle));
+    }
+
+    #[tokio::test]
+    async fn test_create_already_exists() {
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_create_vehicle()
+            .times(1)
+            .returning(|_| Err(AppError::AlreadyExists("Vehicle")));
+        let service = create_service(mock_vehicle_queries);
+
+        let status = service
+            .create(Request::new(combustion().into()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn test_get_not_found() {
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin"))
+            .times(1)
+            .returning(|_| Err(AppError::NotFound("Vehicle")));
+        let service = create_service(mock_vehicle_queries);
+
+        let status = service
+            .get(Request::new(proto::GetVehicleRequest {
+                vin: "vin".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_delete_purge() {
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_purge_one_vehicle()
+            .with(eq("vin"))
+            .times(1)
+            .returning(|_| Ok(()));
+        let service = create_service(mock_vehicle_queries);
+        let purge_request = || {
+            Request::new(proto::DeleteVehicleRequest {
+                vin: "vin".to_string(),
+                purge: true,
+            })
+        };
+
+        // Without the admin API key => PERMISSION_DENIED
+        let status = service.delete(purge_request()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        // With the admin API key => OK
+        let mut request = purge_request();
+        request
+            .metadata_mut()
+            .insert(API_KEY_HEADER, "admin".parse().unwrap());
+        service.delete(request).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_engine_type() {
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_find_all_vehicles()
+            .times(1)
+            .returning(|| Ok(futures::stream::iter(vec![Ok(ev()), Ok(combustion())]).boxed()));
+        let service = create_service(mock_vehicle_queries);
+
+        let response = service
+            .list(Request::new(proto::ListVehiclesRequest {
+                engine_type: proto::EngineType::Ev as i32,
+            }))
+            .await
+            .unwrap();
+        let vehicles: Vec<_> = response.into_inner().collect().await;
+        assert_eq!(vehicles.len(), 1);
+        assert_eq!(vehicles[0].as_ref().unwrap(), &proto::Vehicle::from(ev()));
+    }
+
+    /// Note: `watch` is not tested, its stream would end right away (the shutdown is dropped)
+    fn create_service(
+        vehicle_queries: queries::MockVehicleQueries,
+    ) -> VehicleGrpcService<queries::MockQueries> {
+        VehicleGrpcService::new(
+            Arc::new(create_queries(vehicle_queries)),
+            Arc::new(Config {
+                admin_api_key: Some(ApiKey::new("admin")),
+                ..Config::default()
+            }),
+            Shutdown::new().signal(),
+        )
+    }
+
+    fn create_queries(vehicle_queries: queries::MockVehicleQueries) -> queries::MockQueries {
+        queries::MockQueries {
+            vehicle_queries,
+            ..Default::default()
+        }
+    }
+}
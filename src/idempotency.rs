@@ -0,0 +1,446 @@
+use std::{future::Future, net::SocketAddr};
+
+use async_trait::async_trait;
+use axum::{
+    body::Full,
+    extract::{ConnectInfo, FromRequest, RequestParts},
+    http::{self, header, Extensions, HeaderMap, HeaderValue, StatusCode},
+};
+use chrono::Utc;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::queries::{IdempotencyQueries, Queries},
+    error::AppError,
+    model::idempotency::IdempotentResponse,
+    rate_limit::API_KEY_HEADER,
+    response::AppResponseResult,
+    result::AppResult,
+    tls::ClientIdentity,
+};
+
+/// Header of the client-generated key of a request, e.g. a UUID
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Header set on the responses replayed from a previous request
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Lifetime of the stored responses (TTL of the idempotency_keys table)
+pub const IDEMPOTENCY_KEY_TTL_IN_SECONDS: i64 = 24 * 60 * 60;
+
+/// Lifetime of the claim of a key while the request is in flight, longer than the request
+/// timeout (the claim of an interrupted request expires)
+pub const IDEMPOTENCY_CLAIM_TTL_IN_SECONDS: i64 = 60;
+
+const IDEMPOTENCY_KEY_MAX_LEN: usize = 255;
+
+/// Extractor of the optional `Idempotency-Key` header
+///
+/// The key is scoped by client (API key, client certificate or IP address), so that a client
+/// can neither replay the responses of another one nor block its keys.
+#[derive(Clone, PartialEq, Debug)]
+pub struct IdempotencyKey(pub Option<String>);
+
+#[async_trait]
+impl<B> FromRequest<B> for IdempotencyKey
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let value = match req
+            .headers()
+            .and_then(|headers| headers.get(IDEMPOTENCY_KEY_HEADER))
+        {
+            Some(value) => value,
+            None => return Ok(IdempotencyKey(None)),
+        };
+
+        let key = value
+            .to_str()
+            .map_err(|_| AppError::InvalidInput("Idempotency-Key"))?
+            .trim();
+        if key.is_empty() || key.len() > IDEMPOTENCY_KEY_MAX_LEN {
+            return Err(AppError::InvalidInput("Idempotency-Key"));
+        }
+
+        let scope = client_scope(req.headers(), req.extensions());
+
+        Ok(IdempotencyKey(Some(format!("{}/{}", scope, key))))
+    }
+}
+
+/// Client of a request: its API key (hashed), its client certificate or its IP address
+fn client_scope(headers: Option<&HeaderMap>, extensions: Option<&Extensions>) -> String {
+    if let Some(api_key) = headers.and_then(|headers| headers.get(API_KEY_HEADER)) {
+        return format!("key:{}", hex::encode(Sha256::digest(api_key.as_bytes())));
+    }
+
+    if let Some(client_identity) =
+        extensions.and_then(|extensions| extensions.get::<ClientIdentity>())
+    {
+        return format!("cert:{}", client_identity.fingerprint);
+    }
+
+    match extensions.and_then(|extensions| extensions.get::<ConnectInfo<SocketAddr>>()) {
+        Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+        None => "anonymous".to_string(),
+    }
+}
+
+/// Hex-encoded SHA-256 of the route and the payload of a request
+pub fn fingerprint<T: Serialize>(route: &str, payload: &T) -> AppResult<String> {
+    let payload = serde_json::to_vec(payload)
+        .map_err(|_| AppError::ConversionError("Request fingerprint"))?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(route.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(&payload);
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Run the handler once per idempotency key, e.g.:
+/// idempotent(queries, key, "POST /vehicle", &payload, async { ... }).await
+///
+/// The key is claimed before the handler is run, so that a retry sent while the request is in
+/// flight is rejected (409). The successful responses are stored and replayed on retries with
+/// the same payload, a key reused with another payload is rejected. Failed requests are not
+/// stored (their claim is released): they can be retried with the same key.
+pub async fn idempotent<Q, T, F>(
+    queries: &Q,
+    key: IdempotencyKey,
+    route: &str,
+    payload: &T,
+    handler: F,
+) -> AppResponseResult
+where
+    Q: Queries,
+    T: Serialize,
+    F: Future<Output = AppResponseResult>,
+{
+    let key = match key.0 {
+        Some(key) => key,
+        None => return handler.await,
+    };
+    let fingerprint = fingerprint(route, payload)?;
+
+    let idempotency_queries = queries.idempotency_queries();
+    let pending = IdempotentResponse::pending(key.as_str(), fingerprint.as_str());
+    if let Some(stored) = idempotency_queries.claim_idempotency_key(&pending).await? {
+        if stored.fingerprint != fingerprint {
+            return Err(AppError::UnprocessableEntity("Idempotency-Key"));
+        }
+        if stored.is_pending() {
+            return Err(AppError::Conflict("Idempotency-Key in flight"));
+        }
+        return replay(stored);
+    }
+
+    let response = match handler.await {
+        Ok(response) => response,
+        Err(e) => {
+            release(idempotency_queries, &key).await;
+            return Err(e);
+        }
+    };
+    let (parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(_) => {
+            release(idempotency_queries, &key).await;
+            return Err(AppError::ConversionError("Response body"));
+        }
+    };
+
+    let stored = IdempotentResponse {
+        key,
+        fingerprint,
+        status: parts.status.as_u16(),
+        content_type: parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        body: body.to_vec(),
+        created_at: Utc::now(),
+    };
+    idempotency_queries
+        .insert_idempotent_response(&stored)
+        .await?;
+
+    Ok(http::Response::from_parts(parts, Full::from(body)))
+}
+
+/// Release the claim of a failed request, so that it can be retried right away (the claim
+/// expires otherwise)
+async fn release<I: IdempotencyQueries>(idempotency_queries: &I, key: &str) {
+    if let Err(e) = idempotency_queries.delete_idempotency_key(key).await {
+        tracing::error!("failed to release an idempotency key: {}", e);
+    }
+}
+
+fn replay(stored: IdempotentResponse) -> AppResponseResult {
+    let status = StatusCode::from_u16(stored.status)
+        .map_err(|_| AppError::ConversionError("Stored response status"))?;
+
+    let mut builder = http::Response::builder()
+        .status(status)
+        .header(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    if let Some(content_type) = stored.content_type {
+        builder = builder.header(header::CONTENT_TYPE, content_type);
+    }
+
+    let response = builder
+        .body(Full::from(stored.body))
+        .map_err(anyhow::Error::from)?;
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::db::queries;
+
+    fn stored_response(fingerprint: &str) -> IdempotentResponse {
+        IdempotentResponse {
+            key: "key1".to_string(),
+            fingerprint: fingerprint.to_string(),
+            status: 201,
+            content_type: Some("application/json".to_string()),
+            body: b"{\"vin\":\"vin1\"}".to_vec(),
+            created_at: Utc::now(),
+        }
+    }
+
+    async fn to_bytes(response: crate::response::AppResponse) -> Vec<u8> {
+        hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    fn created() -> AppResponseResult {
+        Ok((StatusCode::CREATED, "created").into_response())
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let fingerprint1 = fingerprint("POST /vehicle", &"vin1").unwrap();
+        assert_eq!(fingerprint1.len(), 64);
+        assert_eq!(fingerprint("POST /vehicle", &"vin1").unwrap(), fingerprint1);
+        assert_ne!(fingerprint("POST /vehicle", &"vin2").unwrap(), fingerprint1);
+        assert_ne!(
+            fingerprint("POST /vehicle/restore", &"vin1").unwrap(),
+            fingerprint1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_without_key() {
+        // No query expected
+        let mock_queries = queries::MockQueries::default();
+
+        let response = idempotent(
+            &mock_queries,
+            IdempotencyKey(None),
+            "POST /vehicle",
+            &"vin1",
+            async { created() },
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_stores_response() {
+        let fingerprint = fingerprint("POST /vehicle", &"vin1").unwrap();
+
+        let claimed_fingerprint = fingerprint.clone();
+
+        let mut mock_idempotency_queries = queries::MockIdempotencyQueries::default();
+        mock_idempotency_queries
+            .expect_claim_idempotency_key()
+            .withf(move |pending| {
+                pending.key == "key1"
+                    && pending.fingerprint == claimed_fingerprint
+                    && pending.is_pending()
+            })
+            .times(1)
+            .returning(|_| Ok(None));
+        mock_idempotency_queries
+            .expect_insert_idempotent_response()
+            .withf(move |response| {
+                response.key == "key1"
+                    && response.fingerprint == fingerprint
+                    && response.status == 201
+                    && response.body == b"created"
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+        let mock_queries = queries::MockQueries {
+            idempotency_queries: mock_idempotency_queries,
+            ..Default::default()
+        };
+
+        let response = idempotent(
+            &mock_queries,
+            IdempotencyKey(Some("key1".to_string())),
+            "POST /vehicle",
+            &"vin1",
+            async { created() },
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(to_bytes(response).await, b"created");
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_does_not_store_errors() {
+        let mut mock_idempotency_queries = queries::MockIdempotencyQueries::default();
+        mock_idempotency_queries
+            .expect_claim_idempotency_key()
+            .times(1)
+            .returning(|_| Ok(None));
+        mock_idempotency_queries
+            .expect_insert_idempotent_response()
+            .never();
+        mock_idempotency_queries
+            .expect_delete_idempotency_key()
+            .with(eq("key1"))
+            .times(1)
+            .returning(|_| Ok(()));
+        let mock_queries = queries::MockQueries {
+            idempotency_queries: mock_idempotency_queries,
+            ..Default::default()
+        };
+
+        let result = idempotent(
+            &mock_queries,
+            IdempotencyKey(Some("key1".to_string())),
+            "POST /vehicle",
+            &"vin1",
+            async { Err(AppError::AlreadyExists("Vehicle")) },
+        )
+        .await;
+
+        // TODO: user assert_matches! when stable
+        assert!(matches!(result, Err(AppError::AlreadyExists("Vehicle"))));
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_replays_response() {
+        let stored = stored_response(&fingerprint("POST /vehicle", &"vin1").unwrap());
+        let body = stored.body.clone();
+
+        let mut mock_idempotency_queries = queries::MockIdempotencyQueries::default();
+        mock_idempotency_queries
+            .expect_claim_idempotency_key()
+            .withf(|pending| pending.key == "key1")
+            .times(1)
+            .returning(move |_| Ok(Some(stored.clone())));
+        let mock_queries = queries::MockQueries {
+            idempotency_queries: mock_idempotency_queries,
+            ..Default::default()
+        };
+
+        let response = idempotent(
+            &mock_queries,
+            IdempotencyKey(Some("key1".to_string())),
+            "POST /vehicle",
+            &"vin1",
+            async { Err(AppError::from("Handler called for a replayed request")) },
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[IDEMPOTENT_REPLAYED_HEADER], "true");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(to_bytes(response).await, body);
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_other_payload() {
+        let stored = stored_response(&fingerprint("POST /vehicle", &"vin1").unwrap());
+
+        let mut mock_idempotency_queries = queries::MockIdempotencyQueries::default();
+        mock_idempotency_queries
+            .expect_claim_idempotency_key()
+            .times(1)
+            .returning(move |_| Ok(Some(stored.clone())));
+        let mock_queries = queries::MockQueries {
+            idempotency_queries: mock_idempotency_queries,
+            ..Default::default()
+        };
+
+        let result = idempotent(
+            &mock_queries,
+            IdempotencyKey(Some("key1".to_string())),
+            "POST /vehicle",
+            &"vin2",
+            async { created() },
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(AppError::UnprocessableEntity("Idempotency-Key"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_in_flight() {
+        let fingerprint = fingerprint("POST /vehicle", &"vin1").unwrap();
+        let pending = IdempotentResponse::pending("key1", fingerprint);
+
+        let mut mock_idempotency_queries = queries::MockIdempotencyQueries::default();
+        mock_idempotency_queries
+            .expect_claim_idempotency_key()
+            .times(1)
+            .returning(move |_| Ok(Some(pending.clone())));
+        let mock_queries = queries::MockQueries {
+            idempotency_queries: mock_idempotency_queries,
+            ..Default::default()
+        };
+
+        let result = idempotent(
+            &mock_queries,
+            IdempotencyKey(Some("key1".to_string())),
+            "POST /vehicle",
+            &"vin1",
+            async { Err(AppError::from("Handler called for a request in flight")) },
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(AppError::Conflict("Idempotency-Key in flight"))
+        ));
+    }
+
+    #[test]
+    fn test_client_scope() {
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY_HEADER, HeaderValue::from_static("key1"));
+        let mut extensions = Extensions::new();
+        extensions.insert(ConnectInfo("10.0.0.1:4000".parse::<SocketAddr>().unwrap()));
+
+        let scope1 = client_scope(Some(&headers), Some(&extensions));
+        assert!(scope1.starts_with("key:"));
+        headers.insert(API_KEY_HEADER, HeaderValue::from_static("key2"));
+        assert_ne!(client_scope(Some(&headers), Some(&extensions)), scope1);
+
+        assert_eq!(
+            client_scope(Some(&HeaderMap::new()), Some(&extensions)),
+            "ip:10.0.0.1"
+        );
+        assert_eq!(client_scope(None, None), "anonymous");
+    }
+}
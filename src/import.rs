@@ -0,0 +1,531 @@
+use std::{collections::HashSet, str::FromStr};
+
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap},
+};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tower::BoxError;
+
+use crate::{
+    alerts,
+    db::queries::{Queries, VehicleQueries},
+    error::AppError,
+    model::{
+        import::{ImportLine, ImportLineStatus, ImportReport},
+        vehicle::{Engine, EvData, Vehicle},
+    },
+    request_body,
+    result::AppResult,
+};
+
+/// Maximum number of vehicles stored concurrently
+pub const IMPORT_CONCURRENCY: usize = 16;
+
+/// Maximum length of a line, in bytes
+pub const MAX_IMPORT_LINE_LENGTH: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    /// Header line, then one vehicle per line (quoted fields cannot span several lines)
+    Csv,
+    /// One JSON vehicle per line
+    Ndjson,
+}
+
+impl ImportFormat {
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+
+        match mime {
+            "text/csv" => Some(ImportFormat::Csv),
+            "application/x-ndjson" => Some(ImportFormat::Ndjson),
+            _ => None,
+        }
+    }
+
+    /// Format of the request body, from its Content-Type
+    pub fn from_headers(headers: &HeaderMap) -> AppResult<Self> {
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(ImportFormat::from_content_type)
+            .ok_or(AppError::InvalidInput("Content-Type"))
+    }
+}
+
+/// Split a streamed body into lines (without line terminator)
+pub fn split_lines<S, E>(body: S) -> impl Stream<Item = AppResult<String>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<BoxError>,
+{
+    let state = (Box::pin(body), Vec::<u8>::new(), false);
+
+    futures::stream::unfold(state, |(mut body, mut buffer, mut done)| async move {
+        loop {
+            if let Some(pos) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=pos).collect();
+                return Some((Ok(decode_line(&line[..pos])), (body, buffer, done)));
+            }
+            if done {
+                if buffer.is_empty() {
+                    return None;
+                }
+                let line = decode_line(&buffer);
+                buffer.clear();
+                return Some((Ok(line), (body, buffer, done)));
+            }
+            if buffer.len() > MAX_IMPORT_LINE_LENGTH {
+                buffer.clear();
+                return Some((
+                    Err(AppError::InvalidInput("Line too long")),
+                    (body, buffer, true),
+                ));
+            }
+
+            match body.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    let e = e.into();
+                    tracing::warn!("failed to read the import body: {}", e);
+                    buffer.clear();
+                    let error = if request_body::is_too_large(&e) {
+                        AppError::PayloadTooLarge("Request body")
+                    } else {
+                        AppError::InvalidInput("Body")
+                    };
+                    return Some((Err(error), (body, buffer, true)));
+                }
+                None => done = true,
+            }
+        }
+    })
+}
+
+fn decode_line(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+    String::from_utf8_lossy(line).into_owned()
+}
+
+/// Semantic validation of an imported vehicle
+pub fn validate_vehicle(vehicle: &Vehicle) -> Result<(), &'static str> {
+    if vehicle.vin.is_empty() || vehicle.vin.chars().any(char::is_whitespace) {
+        return Err("Invalid VIN");
+    }
+
+    if let Some(ev_data) = &vehicle.ev_data {
+        if vehicle.engine == Engine::Combustion {
+            return Err("EV data of a combustion vehicle");
+        }
+        if ev_data.battery_capacity_in_kwh <= 0 {
+            return Err("Invalid battery capacity");
+        }
+        if !(0..=100).contains(&ev_data.soc_in_percent) {
+            return Err("Invalid SoC");
+        }
+    }
+
+    Ok(())
+}
+
+/// Columns of the CSV header
+#[derive(Debug)]
+struct CsvColumns {
+    vin: usize,
+    owner: Option<usize>,
+    engine_type: usize,
+    battery_capacity_in_kwh: Option<usize>,
+    soc_in_percent: Option<usize>,
+}
+
+impl CsvColumns {
+    fn parse(line: &str) -> AppResult<Self> {
+        let record = parse_csv_record(line).map_err(|_| AppError::InvalidInput("CSV header"))?;
+        let column = |name: &str| record.iter().position(|field| field.trim() == name);
+
+        Ok(CsvColumns {
+            vin: column("vin").ok_or(AppError::InvalidInput("CSV header"))?,
+            owner: column("owner"),
+            engine_type: column("engine_type").ok_or(AppError::InvalidInput("CSV header"))?,
+            battery_capacity_in_kwh: column("battery_capacity_in_kwh"),
+            soc_in_percent: column("soc_in_percent"),
+        })
+    }
+
+    fn parse_vehicle(&self, line: &str) -> Result<Vehicle, String> {
+        let record = parse_csv_record(line)?;
+        let field = |index: Option<usize>| {
+            index
+                .and_then(|index| record.get(index))
+                .map(str::trim)
+                .filter(|field| !field.is_empty())
+        };
+        let number = |index: Option<usize>, name: &str| {
+            field(index)
+                .map(|field| {
+                    i32::from_str(field).map_err(|_| format!("Invalid {} ({})", name, field))
+                })
+                .transpose()
+        };
+
+        let vin = field(Some(self.vin)).ok_or("Missing VIN")?;
+        let engine_type = field(Some(self.engine_type)).ok_or("Missing engine type")?;
+        let engine = Engine::from_str(engine_type)
+            .map_err(|_| format!("Invalid engine type ({})", engine_type))?;
+        let battery_capacity_in_kwh = number(self.battery_capacity_in_kwh, "battery capacity")?;
+        let soc_in_percent = number(self.soc_in_percent, "SoC")?;
+        let ev_data = match (battery_capacity_in_kwh, soc_in_percent) {
+            (Some(battery_capacity_in_kwh), Some(soc_in_percent)) => Some(EvData {
+                battery_capacity_in_kwh,
+                soc_in_percent,
+            }),
+            (None, None) => None,
+            _ => return Err("Incomplete EV data".to_string()),
+        };
+
+        Ok(Vehicle {
+            vin: vin.to_string(),
+            owner: field(self.owner).map(str::to_string),
+            engine,
+            ev_data,
+        })
+    }
+}
+
+fn parse_csv_record(line: &str) -> Result<csv::StringRecord, String> {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(line.as_bytes())
+        .records()
+        .next()
+        .unwrap_or_else(|| Ok(csv::StringRecord::new()))
+        .map_err(|e| format!("Invalid CSV ({})", e))
+}
+
+/// Parse the lines in order, keeping track of the CSV header and of the imported VINs
+struct LineParser {
+    format: ImportFormat,
+    columns: Option<CsvColumns>,
+    vins: HashSet<String>,
+}
+
+impl LineParser {
+    fn new(format: ImportFormat) -> Self {
+        LineParser {
+            format,
+            columns: None,
+            vins: HashSet::new(),
+        }
+    }
+
+    /// Vehicle of the line (or reason why it is invalid), None for headers and empty lines
+    fn parse(&mut self, line: &str) -> AppResult<Option<Result<Vehicle, String>>> {
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let vehicle = match (self.format, &self.columns) {
+            (ImportFormat::Csv, None) => {
+                self.columns = Some(CsvColumns::parse(line)?);
+                return Ok(None);
+            }
+            (ImportFormat::Csv, Some(columns)) => columns.parse_vehicle(line),
+            (ImportFormat::Ndjson, _) => {
+                serde_json::from_str::<Vehicle>(line).map_err(|e| format!("Invalid JSON ({})", e))
+            }
+        };
+
+        Ok(Some(vehicle.and_then(|vehicle| {
+            validate_vehicle(&vehicle)?;
+            Ok(vehicle)
+        })))
+    }
+
+    /// Whether the VIN has not been imported by a previous line
+    fn is_new_vin(&mut self, vin: &str) -> bool {
+        self.vins.insert(vin.to_string())
+    }
+}
+
+/// Create the vehicle like `POST /vehicle` (or only check that it does not exist, in dry run mode)
+async fn import_vehicle<Q: Queries>(
+    queries: &Q,
+    vehicle: &Vehicle,
+    dry_run: bool,
+) -> AppResult<ImportLineStatus> {
+    if dry_run {
+        // Note: the deleted vehicles are not found, their import will be reported on creation
+        return match queries
+            .vehicle_queries()
+            .find_one_vehicle(&vehicle.vin)
+            .await
+        {
+            Ok(_) => Ok(ImportLineStatus::AlreadyExists),
+            Err(AppError::NotFound(_)) => Ok(ImportLineStatus::Created),
+            Err(e) => Err(e),
+        };
+    }
+
+    match queries.vehicle_queries().create_vehicle(vehicle).await {
+        Ok(()) => (),
+        Err(AppError::AlreadyExists(_)) => return Ok(ImportLineStatus::AlreadyExists),
+        Err(e) => return Err(e),
+    }
+    alerts::evaluate_alert_rules(queries, vehicle).await?;
+
+    Ok(ImportLineStatus::Created)
+}
+
+/// Import the vehicles of the lines, storing up to `IMPORT_CONCURRENCY` vehicles at once
+///
+/// The lines are reported in order, numbered from `first_line`. An invalid CSV header or an unreadable body aborts the
+/// import (the vehicles of the previous lines are kept).
+pub async fn import_vehicles<Q, S>(
+    queries: &Q,
+    format: ImportFormat,
+    lines: S,
+    first_line: usize,
+    dry_run: bool,
+) -> AppResult<ImportReport>
+where
+    Q: Queries,
+    S: Stream<Item = AppResult<String>>,
+{
+    let mut parser = LineParser::new(format);
+
+    let results: Vec<AppResult<Option<ImportLine>>> = lines
+        .enumerate()
+        .map(|(index, line)| {
+            let parsed = line.and_then(|line| parser.parse(&line)).map(|parsed| {
+                parsed.map(|vehicle| match vehicle {
+                    Ok(vehicle) if !parser.is_new_vin(&vehicle.vin) => {
+                        Err((Some(vehicle.vin), ImportLineStatus::AlreadyExists, None))
+                    }
+                    Ok(vehicle) => Ok(vehicle),
+                    Err(reason) => Err((None, ImportLineStatus::Invalid, Some(reason))),
+                })
+            });
+
+            async move {
+                let (vin, status, reason) = match parsed? {
+                    None => return Ok(None),
+                    Some(Err(report)) => report,
+                    Some(Ok(vehicle)) => match import_vehicle(queries, &vehicle, dry_run).await {
+                        Ok(status) => (Some(vehicle.vin), status, None),
+                        Err(e) => (
+                            Some(vehicle.vin),
+                            ImportLineStatus::Failed,
+                            Some(e.to_string()),
+                        ),
+                    },
+                };
+
+                Ok(Some(ImportLine {
+                    line: first_line + index,
+                    vin,
+                    status,
+                    reason,
+                }))
+            }
+        })
+        .buffered(IMPORT_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut report = ImportReport {
+        dry_run,
+        ..ImportReport::default()
+    };
+    for result in results.into_iter() {
+        if let Some(line) = result? {
+            report.add_line(line);
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::db::queries;
+
+    fn lines(body: &'static str) -> impl Stream<Item = AppResult<String>> {
+        // Split in small chunks, to check lines spanning several chunks
+        let chunks: Vec<Result<Bytes, AppError>> = body
+            .as_bytes()
+            .chunks(5)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+
+        split_lines(futures::stream::iter(chunks))
+    }
+
+    fn report_lines(report: &ImportReport) -> Vec<(usize, ImportLineStatus)> {
+        report
+            .lines
+            .iter()
+            .map(|line| (line.line, line.status))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_split_lines() -> anyhow::Result<()> {
+        let lines: Vec<AppResult<String>> = lines("a,b\r\n\nlonger line\nlast").collect().await;
+        let lines = lines.into_iter().collect::<AppResult<Vec<_>>>()?;
+
+        assert_eq!(lines, vec!["a,b", "", "longer line", "last"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_csv_vehicle() {
+        let columns =
+            CsvColumns::parse("vin,engine_type,owner,battery_capacity_in_kwh,soc_in_percent")
+                .unwrap();
+
+        assert_eq!(
+            columns.parse_vehicle(r#"vin1,Ev,"Doe, John",50,80"#),
+            Ok(Vehicle {
+                vin: "vin1".to_string(),
+                owner: Some("Doe, John".to_string()),
+                engine: Engine::Ev,
+                ev_data: Some(EvData {
+                    battery_capacity_in_kwh: 50,
+                    soc_in_percent: 80,
+                }),
+            })
+        );
+        assert_eq!(
+            columns.parse_vehicle("vin2,Combustion,,,"),
+            Ok(Vehicle {
+                vin: "vin2".to_string(),
+                owner: None,
+                engine: Engine::Combustion,
+                ev_data: None,
+            })
+        );
+        assert_eq!(
+            columns.parse_vehicle("vin3,Diesel,,,"),
+            Err("Invalid engine type (Diesel)".to_string())
+        );
+        assert_eq!(
+            columns.parse_vehicle("vin4,Ev,,50,"),
+            Err("Incomplete EV data".to_string())
+        );
+        assert!(CsvColumns::parse("owner,engine_type").is_err());
+    }
+
+    #[test]
+    fn test_validate_vehicle() {
+        let vehicle = Vehicle {
+            vin: "vin1".to_string(),
+            owner: None,
+            engine: Engine::Ev,
+            ev_data: Some(EvData {
+                battery_capacity_in_kwh: 50,
+                soc_in_percent: 80,
+            }),
+        };
+        assert_eq!(validate_vehicle(&vehicle), Ok(()));
+
+        let invalid_vehicles = vec![
+            Vehicle {
+                vin: "vin 1".to_string(),
+                ..vehicle.clone()
+            },
+            Vehicle {
+                engine: Engine::Combustion,
+                ..vehicle.clone()
+            },
+            Vehicle {
+                ev_data: Some(EvData {
+                    battery_capacity_in_kwh: 50,
+                    soc_in_percent: 120,
+                }),
+                ..vehicle
+            },
+        ];
+        for vehicle in invalid_vehicles.iter() {
+            assert!(validate_vehicle(vehicle).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_vehicles() -> anyhow::Result<()> {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_create_vehicle()
+            .withf(|vehicle| vehicle.vin == "vin1")
+            .times(1)
+            .returning(|_| Ok(()));
+        mock_queries
+            .vehicle_queries
+            .expect_create_vehicle()
+            .withf(|vehicle| vehicle.vin == "vin2")
+            .times(1)
+            .returning(|_| Err(AppError::AlreadyExists("Vehicle")));
+        mock_queries
+            .alert_queries
+            .expect_find_alert_rules()
+            .returning(|| Ok(vec![]));
+
+        let body =
+            "vin,owner,engine_type\nvin1,,Combustion\n\nvin2,,Combustion\nvin1,,Phev\n,,Ev\n";
+        let report =
+            import_vehicles(&mock_queries, ImportFormat::Csv, lines(body), 1, false).await?;
+
+        assert_eq!(
+            report_lines(&report),
+            vec![
+                (2, ImportLineStatus::Created),
+                (4, ImportLineStatus::AlreadyExists),
+                (5, ImportLineStatus::AlreadyExists),
+                (6, ImportLineStatus::Invalid),
+            ]
+        );
+        assert_eq!(
+            (report.created, report.already_exists, report.invalid),
+            (1, 2, 1)
+        );
+        assert_eq!(report.lines[3].reason, Some("Missing VIN".to_string()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_vehicles_dry_run() -> anyhow::Result<()> {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin1"))
+            .returning(|_| Err(AppError::NotFound("Vehicle")));
+        mock_queries.vehicle_queries.expect_create_vehicle().never();
+
+        let body = r#"{"vin":"vin1","engine_type":"Combustion"}
+{"vin":"vin2"}
+"#;
+        let report =
+            import_vehicles(&mock_queries, ImportFormat::Ndjson, lines(body), 1, true).await?;
+
+        assert!(report.dry_run);
+        assert_eq!(
+            report_lines(&report),
+            vec![
+                (1, ImportLineStatus::Created),
+                (2, ImportLineStatus::Invalid)
+            ]
+        );
+
+        Ok(())
+    }
+}
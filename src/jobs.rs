@@ -0,0 +1,679 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    db::queries::{JobQueries, Queries, VehicleQueries},
+    error::AppError,
+    export::{self, ExportFormat},
+    import::{self, ImportFormat},
+    model::{
+        import::{ImportLineStatus, ImportReport},
+        job::{Job, JobChunk, JobKind, JobStatus},
+        vehicle::Engine,
+    },
+    result::AppResult,
+};
+
+/// Number of input lines per chunk (the unit of progress and checkpointing)
+pub const JOB_CHUNK_LINES: usize = 1000;
+
+/// Maximum number of lines kept in the report of an import job
+pub const MAX_JOB_REPORT_LINES: usize = 1000;
+
+/// Parameters of an import job
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ImportJobParams {
+    pub format: ImportFormat,
+    pub dry_run: bool,
+    /// Header of the CSV input, prepended to each chunk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub csv_header: Option<String>,
+}
+
+/// Parameters of an export job
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ExportJobParams {
+    /// NDJSON or CSV (stored chunk by chunk)
+    pub format: ExportFormat,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine_type: Option<Engine>,
+}
+
+/// Result of an export job, also its checkpoint (the output is stored in the job chunks)
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct ExportJobResult {
+    pub exported: u64,
+    /// VIN of the last row read, to continue from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after_vin: Option<String>,
+}
+
+/// Removes the stored input of a job not created, e.g. after an error or when the client
+/// disconnects during the upload (the storing is then cancelled)
+struct JobInputGuard<Q: Queries> {
+    queries: Arc<Q>,
+    job_id: Uuid,
+    created: bool,
+}
+
+impl<Q: Queries> Drop for JobInputGuard<Q> {
+    fn drop(&mut self) {
+        if self.created {
+            return;
+        }
+
+        let queries = self.queries.clone();
+        let job_id = self.job_id;
+        tokio::spawn(async move {
+            if let Err(e) = queries.job_queries().delete_job_chunks(job_id).await {
+                tracing::warn!("failed to delete the input of job {}: {}", job_id, e);
+            }
+        });
+    }
+}
+
+/// Store the input of an import job, then the job itself (to be run by the job task)
+pub async fn create_import_job<Q, S>(
+    queries: Arc<Q>,
+    format: ImportFormat,
+    lines: S,
+    dry_run: bool,
+) -> AppResult<Job>
+where
+    Q: Queries,
+    S: Stream<Item = AppResult<String>>,
+{
+    let id = Uuid::new_v4();
+    let mut params = ImportJobParams {
+        format,
+        dry_run,
+        csv_header: None,
+    };
+
+    let mut guard = JobInputGuard {
+        queries: queries.clone(),
+        job_id: id,
+        created: false,
+    };
+    let queries = queries.as_ref();
+    let total_chunks = store_job_chunks(queries, id, &mut params, lines).await?;
+
+    let now = Utc::now();
+    let job = Job {
+        id,
+        kind: JobKind::Import,
+        status: JobStatus::Pending,
+        params: serde_json::to_value(&params)
+            .map_err(|_| AppError::ConversionError("ImportJobParams to JSON"))?,
+        created_at: now,
+        updated_at: now,
+        total_chunks,
+        processed_chunks: 0,
+        cancel_requested: false,
+        result: None,
+        error: None,
+    };
+    queries.job_queries().create_job(&job).await?;
+    guard.created = true;
+
+    Ok(job)
+}
+
+/// Create an export job (to be run by the job task), its output is stored chunk by chunk
+pub async fn create_export_job<Q: Queries>(
+    queries: &Q,
+    format: ExportFormat,
+    engine_type: Option<Engine>,
+) -> AppResult<Job> {
+    if format == ExportFormat::Parquet {
+        return Err(AppError::InvalidInput("Export job format"));
+    }
+
+    let params = ExportJobParams {
+        format,
+        engine_type,
+    };
+    let now = Utc::now();
+    let job = Job {
+        id: Uuid::new_v4(),
+        kind: JobKind::Export,
+        status: JobStatus::Pending,
+        params: serde_json::to_value(&params)
+            .map_err(|_| AppError::ConversionError("ExportJobParams to JSON"))?,
+        created_at: now,
+        updated_at: now,
+        // Unknown until the end of the export
+        total_chunks: 0,
+        processed_chunks: 0,
+        cancel_requested: false,
+        result: None,
+        error: None,
+    };
+    queries.job_queries().create_job(&job).await?;
+
+    Ok(job)
+}
+
+/// Split the lines in chunks of `JOB_CHUNK_LINES`, returns the number of chunks
+async fn store_job_chunks<Q, S>(
+    queries: &Q,
+    job_id: Uuid,
+    params: &mut ImportJobParams,
+    lines: S,
+) -> AppResult<i32>
+where
+    Q: Queries,
+    S: Stream<Item = AppResult<String>>,
+{
+    futures::pin_mut!(lines);
+
+    let mut chunk = JobChunk {
+        index: 0,
+        first_line: 1,
+        data: String::new(),
+    };
+    let mut chunk_lines = 0;
+    let mut line_number = 0;
+
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        line_number += 1;
+
+        // The CSV header is kept in the parameters
+        if params.format == ImportFormat::Csv && params.csv_header.is_none() {
+            if !line.trim().is_empty() {
+                params.csv_header = Some(line);
+            }
+            chunk.first_line = line_number + 1;
+            continue;
+        }
+
+        if chunk_lines > 0 {
+            chunk.data.push('\n');
+        }
+        chunk.data.push_str(&line);
+        chunk_lines += 1;
+
+        if chunk_lines == JOB_CHUNK_LINES {
+            queries
+                .job_queries()
+                .insert_job_chunk(job_id, &chunk)
+                .await?;
+            chunk = JobChunk {
+                index: chunk.index + 1,
+                first_line: line_number + 1,
+                data: String::new(),
+            };
+            chunk_lines = 0;
+        }
+    }
+
+    if chunk_lines > 0 {
+        queries
+            .job_queries()
+            .insert_job_chunk(job_id, &chunk)
+            .await?;
+        chunk.index += 1;
+    }
+
+    Ok(chunk.index)
+}
+
+/// Add the report of a chunk to the report of the job (without the created lines)
+pub fn merge_import_reports(report: &mut ImportReport, chunk_report: ImportReport) {
+    report.created += chunk_report.created;
+    report.already_exists += chunk_report.already_exists;
+    report.invalid += chunk_report.invalid;
+    report.failed += chunk_report.failed;
+
+    let lines = chunk_report
+        .lines
+        .into_iter()
+        .filter(|line| line.status != ImportLineStatus::Created);
+    let free = MAX_JOB_REPORT_LINES.saturating_sub(report.lines.len());
+    report.lines.extend(lines.take(free));
+}
+
+async fn run_import_chunk<Q: Queries>(
+    queries: &Q,
+    job: &Job,
+    chunk: &JobChunk,
+) -> AppResult<ImportReport> {
+    let params: ImportJobParams = serde_json::from_value(job.params.clone())
+        .map_err(|_| AppError::ConversionError("JSON to ImportJobParams"))?;
+
+    // The CSV header precedes the first line of the chunk
+    let mut lines = Vec::new();
+    let mut first_line = chunk.first_line as usize;
+    if let Some(header) = &params.csv_header {
+        lines.push(header.clone());
+        first_line -= 1;
+    }
+    lines.extend(chunk.data.split('\n').map(str::to_string));
+
+    import::import_vehicles(
+        queries,
+        params.format,
+        futures::stream::iter(lines.into_iter().map(Ok)),
+        first_line,
+        params.dry_run,
+    )
+    .await
+}
+
+async fn is_cancel_requested<Q: Queries>(queries: &Q, job: &Job) -> AppResult<bool> {
+    Ok(queries
+        .job_queries()
+        .find_one_job(job.id)
+        .await?
+        .cancel_requested)
+}
+
+/// Process the remaining chunks of the job, checkpointing after each chunk
+async fn process_job<Q: Queries>(queries: &Q, job: &mut Job) -> AppResult<()> {
+    match job.kind {
+        JobKind::Import => process_import_job(queries, job).await,
+        JobKind::Export => process_export_job(queries, job).await,
+    }
+}
+
+async fn process_import_job<Q: Queries>(queries: &Q, job: &mut Job) -> AppResult<()> {
+    let mut report: ImportReport = match &job.result {
+        Some(result) => serde_json::from_value(result.clone())
+            .map_err(|_| AppError::ConversionError("JSON to ImportReport"))?,
+        None => ImportReport::default(),
+    };
+
+    while job.processed_chunks < job.total_chunks {
+        if is_cancel_requested(queries, job).await? {
+            job.status = JobStatus::Cancelled;
+            return Ok(());
+        }
+
+        let chunk = queries
+            .job_queries()
+            .find_job_chunk(job.id, job.processed_chunks)
+            .await?;
+        let chunk_report = run_import_chunk(queries, job, &chunk).await?;
+        merge_import_reports(&mut report, chunk_report);
+
+        job.processed_chunks += 1;
+        job.updated_at = Utc::now();
+        job.result = Some(
+            serde_json::to_value(&report)
+                .map_err(|_| AppError::ConversionError("ImportReport to JSON"))?,
+        );
+        queries.job_queries().update_job_state(job).await?;
+    }
+
+    job.status = JobStatus::Succeeded;
+
+    Ok(())
+}
+
+/// Export the vehicles page by page, one output chunk per page
+async fn process_export_job<Q: Queries>(queries: &Q, job: &mut Job) -> AppResult<()> {
+    let params: ExportJobParams = serde_json::from_value(job.params.clone())
+        .map_err(|_| AppError::ConversionError("JSON to ExportJobParams"))?;
+    let mut result: ExportJobResult = match &job.result {
+        Some(result) => serde_json::from_value(result.clone())
+            .map_err(|_| AppError::ConversionError("JSON to ExportJobResult"))?,
+        None => ExportJobResult::default(),
+    };
+
+    // The end of the table is reached when there is no row to continue from
+    while job.processed_chunks == 0 || result.after_vin.is_some() {
+        if is_cancel_requested(queries, job).await? {
+            job.status = JobStatus::Cancelled;
+            return Ok(());
+        }
+
+        let (vehicles, after_vin) = queries
+            .vehicle_queries()
+            .find_vehicles_page(result.after_vin.clone(), JOB_CHUNK_LINES as i32)
+            .await?;
+        let vehicles: Vec<_> = vehicles
+            .into_iter()
+            .filter(|vehicle| {
+                params
+                    .engine_type
+                    .as_ref()
+                    .map_or(true, |engine| vehicle.engine == *engine)
+            })
+            .collect();
+
+        // Written again if the job is resumed before the checkpoint
+        let chunk = JobChunk {
+            index: job.processed_chunks,
+            first_line: result.exported as i64 + 1,
+            data: export::export_lines(&vehicles, params.format)?,
+        };
+        queries
+            .job_queries()
+            .insert_job_output_chunk(job.id, &chunk)
+            .await?;
+
+        result.exported += vehicles.len() as u64;
+        result.after_vin = after_vin;
+        job.processed_chunks += 1;
+        job.total_chunks = job.processed_chunks;
+        job.updated_at = Utc::now();
+        job.result = Some(
+            serde_json::to_value(&result)
+                .map_err(|_| AppError::ConversionError("ExportJobResult to JSON"))?,
+        );
+        queries.job_queries().update_job_state(job).await?;
+    }
+
+    job.status = JobStatus::Succeeded;
+
+    Ok(())
+}
+
+/// Format and output of a succeeded export job (e.g. the CSV header, then the chunks)
+pub fn job_output<Q: Queries>(
+    queries: Arc<Q>,
+    job: &Job,
+) -> AppResult<(ExportFormat, impl Stream<Item = AppResult<String>>)> {
+    if job.kind != JobKind::Export || job.status != JobStatus::Succeeded {
+        return Err(AppError::InvalidInput("Succeeded export job"));
+    }
+    let params: ExportJobParams = serde_json::from_value(job.params.clone())
+        .map_err(|_| AppError::ConversionError("JSON to ExportJobParams"))?;
+
+    let header = match params.format {
+        ExportFormat::Csv => Some(
+            String::from_utf8(export::csv_header()?.to_vec())
+                .map_err(|_| AppError::ConversionError("CSV header to UTF-8"))?,
+        ),
+        _ => None,
+    };
+    let job_id = job.id;
+    let chunks = futures::stream::iter(0..job.total_chunks).then(move |index| {
+        let queries = queries.clone();
+        async move {
+            let chunk = queries.job_queries().find_job_chunk(job_id, index).await?;
+            Ok::<_, AppError>(chunk.data)
+        }
+    });
+
+    Ok((
+        params.format,
+        futures::stream::iter(header.map(Ok)).chain(chunks),
+    ))
+}
+
+/// Run (or resume) the job until it is finished, returns the finished job
+pub async fn run_job<Q: Queries>(queries: &Q, job: Job) -> AppResult<Job> {
+    let mut job = Job {
+        status: JobStatus::Running,
+        updated_at: Utc::now(),
+        ..job
+    };
+    queries.job_queries().update_job_state(&job).await?;
+
+    if let Err(e) = process_job(queries, &mut job).await {
+        job.status = JobStatus::Failed;
+        job.error = Some(e.to_string());
+    }
+    job.updated_at = Utc::now();
+    queries.job_queries().update_job_state(&job).await?;
+
+    // The input is not needed anymore, the output of a succeeded export is kept (until expired)
+    if job.kind == JobKind::Import || job.status != JobStatus::Succeeded {
+        queries.job_queries().delete_job_chunks(job.id).await?;
+    }
+
+    Ok(job)
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::{always, eq};
+
+    use super::*;
+    use crate::db::queries;
+
+    fn job(total_chunks: i32, params: ImportJobParams) -> Job {
+        Job {
+            id: Uuid::nil(),
+            kind: JobKind::Import,
+            status: JobStatus::Pending,
+            params: serde_json::to_value(params).unwrap(),
+            created_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+            updated_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+            total_chunks,
+            processed_chunks: 0,
+            cancel_requested: false,
+            result: None,
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_import_job() -> anyhow::Result<()> {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .job_queries
+            .expect_insert_job_chunk()
+            .withf(|_, chunk| {
+                chunk.index == 0 && chunk.first_line == 3 && chunk.data == "vin1,Ev\nvin2,Ev"
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock_queries
+            .job_queries
+            .expect_create_job()
+            .withf(|job| job.status == JobStatus::Pending && job.total_chunks == 1)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let lines = vec!["", "vin,engine_type", "vin1,Ev", "vin2,Ev"];
+        let job = create_import_job(
+            Arc::new(mock_queries),
+            ImportFormat::Csv,
+            futures::stream::iter(lines.into_iter().map(|line| Ok(line.to_string()))),
+            false,
+        )
+        .await?;
+
+        let params: ImportJobParams = serde_json::from_value(job.params)?;
+        assert_eq!(params.csv_header, Some("vin,engine_type".to_string()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_job() -> anyhow::Result<()> {
+        let params = ImportJobParams {
+            format: ImportFormat::Csv,
+            dry_run: true,
+            csv_header: Some("vin,engine_type".to_string()),
+        };
+        let pending_job = job(2, params);
+        let pending_job_clone = pending_job.clone();
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .job_queries
+            .expect_find_one_job()
+            .returning(move |_| Ok(pending_job_clone.clone()));
+        mock_queries
+            .job_queries
+            .expect_find_job_chunk()
+            .with(always(), eq(0))
+            .returning(|_, _| {
+                Ok(JobChunk {
+                    index: 0,
+                    first_line: 2,
+                    data: "vin1,Ev\nvin2,Diesel".to_string(),
+                })
+            });
+        mock_queries
+            .job_queries
+            .expect_find_job_chunk()
+            .with(always(), eq(1))
+            .returning(|_, _| {
+                Ok(JobChunk {
+                    index: 1,
+                    first_line: 4,
+                    data: "vin3,Ev".to_string(),
+                })
+            });
+        mock_queries
+            .job_queries
+            .expect_update_job_state()
+            .returning(|_| Ok(()));
+        mock_queries
+            .job_queries
+            .expect_delete_job_chunks()
+            .times(1)
+            .returning(|_| Ok(()));
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .returning(|_| Err(AppError::NotFound("Vehicle")));
+
+        let job = run_job(&mock_queries, pending_job).await?;
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert_eq!(job.processed_chunks, 2);
+
+        // Only the lines which would not be created are reported
+        let report: ImportReport = serde_json::from_value(job.result.unwrap())?;
+        assert_eq!((report.created, report.invalid), (2, 1));
+        assert_eq!(
+            report
+                .lines
+                .iter()
+                .map(|line| line.line)
+                .collect::<Vec<_>>(),
+            vec![3]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_cancelled_job() -> anyhow::Result<()> {
+        let params = ImportJobParams {
+            format: ImportFormat::Ndjson,
+            dry_run: false,
+            csv_header: None,
+        };
+        let cancelled_job = Job {
+            cancel_requested: true,
+            ..job(1, params)
+        };
+        let cancelled_job_clone = cancelled_job.clone();
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .job_queries
+            .expect_find_one_job()
+            .returning(move |_| Ok(cancelled_job_clone.clone()));
+        mock_queries.job_queries.expect_find_job_chunk().never();
+        mock_queries
+            .job_queries
+            .expect_update_job_state()
+            .returning(|_| Ok(()));
+        mock_queries
+            .job_queries
+            .expect_delete_job_chunks()
+            .returning(|_| Ok(()));
+
+        let job = run_job(&mock_queries, cancelled_job).await?;
+        assert_eq!(job.status, JobStatus::Cancelled);
+        assert_eq!(job.processed_chunks, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_export_job() -> anyhow::Result<()> {
+        let params = ExportJobParams {
+            format: ExportFormat::Ndjson,
+            engine_type: Some(Engine::Combustion),
+        };
+        let pending_job = Job {
+            kind: JobKind::Export,
+            params: serde_json::to_value(params)?,
+            ..job(
+                0,
+                ImportJobParams {
+                    format: ImportFormat::Ndjson,
+                    dry_run: false,
+                    csv_header: None,
+                },
+            )
+        };
+        let pending_job_clone = pending_job.clone();
+        let vehicle = |vin: &str, engine: Engine| crate::model::vehicle::Vehicle {
+            vin: vin.to_string(),
+            owner: None,
+            engine,
+            ev_data: None,
+        };
+        let vehicles = vec![
+            vehicle("vin1", Engine::Ev),
+            vehicle("vin2", Engine::Combustion),
+        ];
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .job_queries
+            .expect_find_one_job()
+            .returning(move |_| Ok(pending_job_clone.clone()));
+        mock_queries
+            .vehicle_queries
+            .expect_find_vehicles_page()
+            .with(eq(None), eq(JOB_CHUNK_LINES as i32))
+            .times(1)
+            .returning(move |_, _| Ok((vehicles.clone(), Some("vin2".to_string()))));
+        mock_queries
+            .vehicle_queries
+            .expect_find_vehicles_page()
+            .with(eq(Some("vin2".to_string())), always())
+            .times(1)
+            .returning(|_, _| Ok((vec![], None)));
+        mock_queries
+            .job_queries
+            .expect_insert_job_output_chunk()
+            .withf(|_, chunk| {
+                chunk.index == 0
+                    && chunk.data == "{\"vin\":\"vin2\",\"engine_type\":\"Combustion\"}\n"
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock_queries
+            .job_queries
+            .expect_insert_job_output_chunk()
+            .withf(|_, chunk| chunk.index == 1 && chunk.data.is_empty())
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock_queries
+            .job_queries
+            .expect_update_job_state()
+            .returning(|_| Ok(()));
+        // The output is kept
+        mock_queries.job_queries.expect_delete_job_chunks().never();
+
+        let job = run_job(&mock_queries, pending_job).await?;
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert_eq!((job.processed_chunks, job.total_chunks), (2, 2));
+
+        let result: ExportJobResult = serde_json::from_value(job.result.unwrap())?;
+        assert_eq!(
+            result,
+            ExportJobResult {
+                exported: 1,
+                after_vin: None,
+            }
+        );
+
+        Ok(())
+    }
+}
@@ -1,8 +1,31 @@
+pub mod alerts;
 pub mod app;
+pub mod auth;
+pub mod charging;
+pub mod config;
 pub mod db;
 pub mod error;
+pub mod ev;
+pub mod events;
+pub mod export;
+pub mod graphql;
+pub mod grpc;
+pub mod idempotency;
+pub mod import;
+pub mod jobs;
+pub mod listener;
 pub mod model;
+pub mod negotiation;
+pub mod outbox;
+pub mod rate_limit;
+pub mod request_body;
+pub mod request_timeout;
 pub mod response;
 pub mod result;
+pub mod rollups;
 pub mod routing;
+pub mod shutdown;
 pub mod state;
+pub mod tasks;
+pub mod tls;
+pub mod webhooks;
@@ -0,0 +1,519 @@
+use std::{
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    ops::Range,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{FromRawFd, IntoRawFd, RawFd},
+    },
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use axum::{extract::ConnectInfo, http::Request};
+use futures::{
+    future::{self, Ready},
+    stream::BoxStream,
+    Stream, StreamExt,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use tower::Service;
+
+use crate::{error::AppError, result::AppResult, tls::ClientIdentity};
+
+/// First file descriptor passed by systemd (socket activation)
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Pause of a listener after an accept error (e.g. too many open files)
+pub const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Parse a listen address: `unix:<path>` or `[tcp:]<ip>:<port>`
+pub fn parse_listen_addr(spec: &str) -> AppResult<ListenAddr> {
+    let error = || AppError::InvalidInput("Listen address");
+
+    if let Some(path) = spec.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err(error());
+        }
+        return Ok(ListenAddr::Unix(PathBuf::from(path)));
+    }
+
+    spec.strip_prefix("tcp:")
+        .unwrap_or(spec)
+        .parse()
+        .map(ListenAddr::Tcp)
+        .map_err(|_| error())
+}
+
+/// Parse the permissions of a Unix socket in octal, e.g. `660`
+pub fn parse_unix_socket_mode(spec: &str) -> AppResult<u32> {
+    u32::from_str_radix(spec, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or(AppError::InvalidInput("Unix socket mode"))
+}
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Bind the address, replacing the socket file of a previous run (Unix)
+    pub fn bind(addr: &ListenAddr, unix_socket_mode: Option<u32>) -> AppResult<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                let listener = std::net::TcpListener::bind(addr)
+                    .with_context(|| format!("failed to bind {}", addr))?;
+                Self::from_std_tcp(listener)
+            }
+            ListenAddr::Unix(path) => {
+                let is_socket = std::fs::symlink_metadata(path)
+                    .map_or(false, |metadata| metadata.file_type().is_socket());
+                if is_socket {
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("failed to remove {}", path.display()))?;
+                }
+
+                let listener = std::os::unix::net::UnixListener::bind(path)
+                    .with_context(|| format!("failed to bind {}", path.display()))?;
+                if let Some(mode) = unix_socket_mode {
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                        .with_context(|| format!("failed to set the mode of {}", path.display()))?;
+                }
+                Self::from_std_unix(listener)
+            }
+        }
+    }
+
+    /// Listeners passed by systemd (socket activation), empty if none
+    ///
+    /// The `LISTEN_*` environment variables are removed (not inherited by child processes).
+    pub fn from_systemd() -> AppResult<Vec<Self>> {
+        let pid = std::env::var("LISTEN_PID").ok();
+        let fds = std::env::var("LISTEN_FDS").ok();
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].iter() {
+            std::env::remove_var(name);
+        }
+
+        listen_fds(pid.as_deref(), fds.as_deref(), std::process::id())?
+            .map(|fd| {
+                // Safety: the descriptors passed by systemd are open listening sockets, owned by
+                // this process only (and only taken once since the variables are removed)
+                unsafe { Self::from_raw_fd(fd) }
+            })
+            .collect()
+    }
+
+    /// Safety: the descriptor must be an open listening socket, not owned by anything else
+    unsafe fn from_raw_fd(fd: RawFd) -> AppResult<Self> {
+        let listener = std::os::unix::net::UnixListener::from_raw_fd(fd);
+        if listener.local_addr().is_ok() {
+            return Self::from_std_unix(listener);
+        }
+
+        let listener = std::net::TcpListener::from_raw_fd(listener.into_raw_fd());
+        if listener.local_addr().is_ok() {
+            return Self::from_std_tcp(listener);
+        }
+
+        Err(AppError::InvalidInput("LISTEN_FDS"))
+    }
+
+    fn from_std_tcp(listener: std::net::TcpListener) -> AppResult<Self> {
+        listener
+            .set_nonblocking(true)
+            .context("failed to set the listener non-blocking")?;
+        let listener =
+            TcpListener::from_std(listener).context("failed to register the listener")?;
+        Ok(Listener::Tcp(listener))
+    }
+
+    fn from_std_unix(listener: std::os::unix::net::UnixListener) -> AppResult<Self> {
+        listener
+            .set_nonblocking(true)
+            .context("failed to set the listener non-blocking")?;
+        let listener =
+            UnixListener::from_std(listener).context("failed to register the listener")?;
+        Ok(Listener::Unix(listener))
+    }
+
+    /// Accepted connections, the accept errors are logged and skipped
+    pub fn incoming(self) -> BoxStream<'static, Connection> {
+        let connections = match self {
+            Listener::Tcp(listener) => TcpListenerStream::new(listener)
+                .map(|stream| stream.map(Connection::Tcp))
+                .boxed(),
+            Listener::Unix(listener) => UnixListenerStream::new(listener)
+                .map(|stream| stream.map(Connection::Unix))
+                .boxed(),
+        };
+
+        connections
+            .filter_map(|connection| async move {
+                match connection {
+                    Ok(connection) => Some(connection),
+                    Err(e) => {
+                        tracing::warn!("failed to accept a connection: {}", e);
+                        if !is_connection_error(&e) {
+                            tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        }
+                        None
+                    }
+                }
+            })
+            .boxed()
+    }
+}
+
+/// Range of the descriptors passed by systemd to the process `own_pid`
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> AppResult<Range<RawFd>> {
+    match (pid, fds) {
+        (Some(pid), Some(fds)) if pid.parse::<u32>().ok() == Some(own_pid) => {
+            let count: RawFd = fds
+                .parse()
+                .map_err(|_| AppError::InvalidInput("LISTEN_FDS"))?;
+            Ok(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        }
+        _ => Ok(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START),
+    }
+}
+
+/// Error of a single connection (the listener itself is fine)
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Accepted connections of all the listeners
+pub fn incoming(listeners: Vec<Listener>) -> impl Stream<Item = Connection> {
+    futures::stream::select_all(listeners.into_iter().map(Listener::incoming))
+}
+
+#[derive(Debug)]
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Connection::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Connection::Tcp(stream) => stream.is_write_vectored(),
+            Connection::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Info of a connection, added to its requests
+pub trait ConnectionInfo {
+    /// Address of the peer (None for Unix sockets)
+    fn remote_addr(&self) -> Option<SocketAddr>;
+
+    /// Identity of the client certificate (TLS)
+    fn client_identity(&self) -> Option<ClientIdentity> {
+        None
+    }
+}
+
+impl ConnectionInfo for Connection {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr().ok(),
+            Connection::Unix(_) => None,
+        }
+    }
+}
+
+/// Make service of the connections, e.g.:
+/// `axum::Server::builder(accept).serve(IntoMakeConnectionService::new(router))`
+///
+/// The requests get the `ConnectInfo<SocketAddr>` (if any) and the `ClientIdentity` (if any) of
+/// their connection as extensions.
+#[derive(Clone, Debug)]
+pub struct IntoMakeConnectionService<S> {
+    service: S,
+    open_connections: OpenConnections,
+}
+
+impl<S> IntoMakeConnectionService<S> {
+    pub fn new(service: S) -> Self {
+        IntoMakeConnectionService {
+            service,
+            open_connections: OpenConnections::default(),
+        }
+    }
+
+    pub fn open_connections(&self) -> OpenConnections {
+        self.open_connections.clone()
+    }
+}
+
+impl<'a, S: Clone, C: ConnectionInfo> Service<&'a C> for IntoMakeConnectionService<S> {
+    type Response = AddConnectionInfo<S>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, connection: &'a C) -> Self::Future {
+        future::ready(Ok(AddConnectionInfo {
+            service: self.service.clone(),
+            remote_addr: connection.remote_addr(),
+            client_identity: connection.client_identity(),
+            _guard: Arc::new(ConnectionGuard::new(self.open_connections.clone())),
+        }))
+    }
+}
+
+/// Number of open connections of a make service (e.g. to report the ones cut off on shutdown)
+#[derive(Clone, Default, Debug)]
+pub struct OpenConnections(Arc<AtomicUsize>);
+
+impl OpenConnections {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Counts a connection as open until dropped
+#[derive(Debug)]
+struct ConnectionGuard(OpenConnections);
+
+impl ConnectionGuard {
+    fn new(open_connections: OpenConnections) -> Self {
+        open_connections.0.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(open_connections)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        (self.0).0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Service of a connection, adding its info to the requests
+#[derive(Clone, Debug)]
+pub struct AddConnectionInfo<S> {
+    service: S,
+    remote_addr: Option<SocketAddr>,
+    client_identity: Option<ClientIdentity>,
+    _guard: Arc<ConnectionGuard>,
+}
+
+impl<S, B> Service<Request<B>> for AddConnectionInfo<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        if let Some(remote_addr) = self.remote_addr {
+            request.extensions_mut().insert(ConnectInfo(remote_addr));
+        }
+        if let Some(client_identity) = &self.client_identity {
+            request.extensions_mut().insert(client_identity.clone());
+        }
+
+        self.service.call(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!(
+            parse_listen_addr("127.0.0.1:3000").unwrap(),
+            ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 3000)))
+        );
+        assert_eq!(
+            parse_listen_addr("tcp:[::1]:3000").unwrap(),
+            ListenAddr::Tcp("[::1]:3000".parse().unwrap())
+        );
+        assert_eq!(
+            parse_listen_addr("unix:/run/hello.sock").unwrap(),
+            ListenAddr::Unix(PathBuf::from("/run/hello.sock"))
+        );
+
+        // TODO: user assert_matches! when stable
+        for spec in vec!["unix:", "localhost:3000", "3000", ""] {
+            assert!(matches!(
+                parse_listen_addr(spec),
+                Err(AppError::InvalidInput("Listen address"))
+            ));
+        }
+
+        assert_eq!(parse_unix_socket_mode("660").unwrap(), 0o660);
+        assert!(parse_unix_socket_mode("1777").is_err());
+        assert!(parse_unix_socket_mode("rw").is_err());
+    }
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42).unwrap(), 3..5);
+
+        // Other process or no socket activation
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42).unwrap(), 3..3);
+        assert_eq!(listen_fds(None, None, 42).unwrap(), 3..3);
+
+        assert!(listen_fds(Some("42"), Some("two"), 42).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("hello-listener-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let addr = ListenAddr::Unix(dir.join("hello.sock"));
+
+        // Socket file of a previous run => replaced
+        drop(Listener::bind(&addr, None).unwrap());
+        let listener = Listener::bind(&addr, Some(0o660)).unwrap();
+
+        let path = dir.join("hello.sock");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let mut connections = incoming(vec![listener]);
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let mut connection = connections.next().await.unwrap();
+        assert!(connection.remote_addr().is_none());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        connection.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    struct TestConnection;
+
+    impl ConnectionInfo for TestConnection {
+        fn remote_addr(&self) -> Option<SocketAddr> {
+            Some(SocketAddr::from(([10, 0, 0, 1], 1234)))
+        }
+
+        fn client_identity(&self) -> Option<ClientIdentity> {
+            Some(ClientIdentity {
+                subject: "CN=client1".to_string(),
+                common_name: Some("client1".to_string()),
+                fingerprint: "ab".to_string(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_into_make_connection_service() {
+        let mut make_service =
+            IntoMakeConnectionService::new(tower::service_fn(|request: Request<()>| async move {
+                Ok::<_, Infallible>(request)
+            }));
+        let open_connections = make_service.open_connections();
+
+        let service = make_service.call(&TestConnection).await.unwrap();
+        assert_eq!(open_connections.count(), 1);
+
+        let request = service.clone().oneshot(Request::new(())).await.unwrap();
+        assert_eq!(
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .unwrap()
+                .0,
+            SocketAddr::from(([10, 0, 0, 1], 1234))
+        );
+        assert_eq!(
+            request.extensions().get::<ClientIdentity>(),
+            TestConnection.client_identity().as_ref()
+        );
+
+        // Connection closed
+        drop(service);
+        assert_eq!(open_connections.count(), 0);
+    }
+}
@@ -1,11 +1,31 @@
-use std::{
-    net::{SocketAddr, TcpListener},
-    sync::Arc,
-};
+use std::{future::Future, io, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Result;
+use axum::http::{HeaderName, HeaderValue, Method};
+use chrono::{NaiveDate, Utc};
+use futures::StreamExt;
+use tokio::{io::AsyncWriteExt, task::JoinHandle, time::Instant};
 
-use hello::{app::App, db};
+use hello::{
+    app::App,
+    auth::ApiKey,
+    config::Config,
+    db::{
+        self,
+        queries::{Queries, TelemetryQueries, VehicleQueries},
+    },
+    export::{self, ExportFormat},
+    grpc::{
+        proto::vehicle_service_server::VehicleServiceServer, vehicle_service::VehicleGrpcService,
+    },
+    listener::{self, IntoMakeConnectionService, ListenAddr, Listener, OpenConnections},
+    model::vehicle::Engine,
+    outbox, rate_limit, rollups,
+    shutdown::{Shutdown, ShutdownSignal},
+    tasks,
+    tls::{self, ReloadingCertResolver, TlsConfig},
+    webhooks,
+};
 
 const KEYSPACE: &str = "hello";
 
@@ -19,7 +39,188 @@ struct CmdLineArgs {
     /// port of the ScyllaDB node (default: 9042)
     #[argh(option, default = "9042")]
     port: u16,
+
+    /// address of the HTTP API: <ip>:<port> or unix:<path>, can be repeated, ignored with systemd socket activation (default: 127.0.0.1:3000)
+    #[argh(option)]
+    listen: Vec<String>,
+
+    /// permissions of the Unix sockets in octal, e.g. 660 (default: from umask)
+    #[argh(option)]
+    unix_socket_mode: Option<String>,
+
+    /// port of the gRPC API (default: 50051)
+    #[argh(option, default = "50051")]
+    grpc_port: u16,
+
+    /// number of days a deleted vehicle can be restored before being purged (default: 30)
+    #[argh(option, default = "30")]
+    purge_retention_days: u64,
+
+    /// interval in seconds between two purges of the deleted vehicles (default: 3600)
+    #[argh(option, default = "3600")]
+    purge_interval_secs: u64,
+
+    /// interval in seconds between two attempts to deliver the pending webhook events (default: 5)
+    #[argh(option, default = "5")]
+    webhook_interval_secs: u64,
+
+    /// allow the webhooks to call loopback, private and link-local hosts, e.g. for development (default: public hosts only)
+    #[argh(switch)]
+    webhook_allow_private_hosts: bool,
+
+    /// number of jobs (e.g. imports) run concurrently (default: 2)
+    #[argh(option, default = "2")]
+    job_workers: usize,
+
+    /// interval in seconds between two looks for pending jobs (default: 1)
+    #[argh(option, default = "1")]
+    job_interval_secs: u64,
+
+    /// interval in seconds between two relays of the outbox entries (default: 1)
+    #[argh(option, default = "1")]
+    outbox_interval_secs: u64,
+
+    /// destination of the outbox entries: log, file:<path> or an http(s) URL, can be repeated (default: log)
+    #[argh(option)]
+    outbox_sink: Vec<String>,
+
+    /// interval in seconds between two reads of the CDC log of the vehicles (default: 1)
+    #[argh(option, default = "1")]
+    cdc_interval_secs: u64,
+
+    /// consumption in kWh/100km of the EVs without configured or learned consumption (default: 18)
+    #[argh(option, default = "18.0")]
+    default_consumption: f64,
+
+    /// battery state of health in percent below which vehicles are flagged (default: 80)
+    #[argh(option, default = "80.0")]
+    battery_health_alert_threshold: f64,
+
+    /// maximum number of VINs of a batch request (default: 100)
+    #[argh(option, default = "100")]
+    max_batch_size: usize,
+
+    /// maximum number of SoC readings of a telemetry batch (default: 1000)
+    #[argh(option, default = "1000")]
+    max_telemetry_batch_size: usize,
+
+    /// requests per client (API key, client certificate or IP) as `[METHOD ]PATH=CAPACITY/PERIOD_SECS`, e.g. "GET /vehicle/:vin=100/60", can be repeated (default: no limit)
+    #[argh(option)]
+    rate_limit: Vec<String>,
+
+    /// share the rate limits between the instances (buckets stored in the database)
+    #[argh(switch)]
+    rate_limit_shared: bool,
+
+    /// header of the IP address of the client, set by the trusted reverse proxy in front (e.g. x-forwarded-for, the last address is used), to rate limit the clients by IP (default: the address of the connection, the clients of a Unix socket are not limited by IP)
+    #[argh(option)]
+    rate_limit_client_ip_header: Option<HeaderName>,
+
+    /// API key of a known client, rate limited by API key (the other clients by IP address), can be repeated
+    #[argh(option)]
+    api_key: Vec<ApiKey>,
+
+    /// origin allowed to call the API from a browser, e.g. https://app.example.com or *, can be repeated (default: CORS disabled)
+    #[argh(option)]
+    cors_allowed_origin: Vec<HeaderValue>,
+
+    /// method allowed in CORS requests, can be repeated (default: GET, POST, PUT, DELETE)
+    #[argh(option)]
+    cors_allowed_method: Vec<Method>,
+
+    /// request header allowed in CORS requests, can be repeated (default: accept, authorization, content-type, x-api-key, idempotency-key)
+    #[argh(option)]
+    cors_allowed_header: Vec<HeaderName>,
+
+    /// do not compress the responses
+    #[argh(switch)]
+    no_compression: bool,
+
+    /// maximum size in bytes of a request body, after decompression (default: 1048576)
+    #[argh(option, default = "1024 * 1024")]
+    max_body_size: usize,
+
+    /// maximum size in bytes of the body of POST /vehicle/import, after decompression, larger imports must be submitted to /jobs/import (default: 524288)
+    #[argh(option, default = "512 * 1024")]
+    max_sync_import_body_size: usize,
+
+    /// maximum size in bytes of an import job body, after decompression (default: 268435456)
+    #[argh(option, default = "256 * 1024 * 1024")]
+    max_import_body_size: usize,
+
+    /// API key of the admin operations, e.g. purging a vehicle (default: admin operations forbidden)
+    #[argh(option)]
+    admin_api_key: Option<ApiKey>,
+
+    /// PEM file of the TLS certificate chain, serves HTTPS and HTTP/2 with --tls-key (default: HTTP)
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the TLS private key
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file of the CA of the client certificates, which are then required (mTLS)
+    #[argh(option)]
+    tls_client_ca: Option<PathBuf>,
+
+    /// interval in seconds between two checks of the TLS certificate files for changes (default: 10)
+    #[argh(option, default = "10")]
+    tls_reload_interval_secs: u64,
+
+    /// delay in seconds between the readiness probe failure and the stop of the listeners on SIGTERM/SIGINT, e.g. for the load balancers to notice (default: 0)
+    #[argh(option, default = "0")]
+    shutdown_delay_secs: u64,
+
+    /// maximum time in seconds to finish the in-flight requests and background work on shutdown, cut off afterwards (default: 30)
+    #[argh(option, default = "30")]
+    shutdown_timeout_secs: u64,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(argh::FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    RebuildRollups(RebuildRollupsCommand),
+    Export(ExportCommand),
+}
+
+/// Rebuild the telemetry rollups from the stored readings, then exit
+#[derive(argh::FromArgs)]
+#[argh(subcommand, name = "rebuild-rollups")]
+struct RebuildRollupsCommand {
+    /// first day to rebuild, e.g. 2021-09-01
+    #[argh(option)]
+    from: NaiveDate,
+
+    /// last day to rebuild (default: today)
+    #[argh(option)]
+    to: Option<NaiveDate>,
+
+    /// VIN of the vehicle to rebuild (default: all the vehicles with telemetry)
+    #[argh(option)]
+    vin: Option<String>,
 }
+
+/// Export the vehicles to a file, then exit
+#[derive(argh::FromArgs)]
+#[argh(subcommand, name = "export")]
+struct ExportCommand {
+    /// output file
+    #[argh(option)]
+    output: String,
+
+    /// ndjson, csv or parquet (default: ndjson)
+    #[argh(option, default = "ExportFormat::Ndjson")]
+    format: ExportFormat,
+
+    /// only export the vehicles with this engine type, e.g. Ev (default: all the vehicles)
+    #[argh(option)]
+    engine_type: Option<Engine>,
+}
+
 // Hint: start with RUST_LOG=hello=debug,tower_http=debug ./hello -- --help
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -30,28 +231,352 @@ async fn main() -> Result<()> {
     //console_subscriber::init();
     tracing_subscriber::fmt::init();
 
-    // TCP listener
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    let listener = TcpListener::bind(&addr)?;
-
     // DB session and queries
     let session = db::scylla::create_session(&args.addr, args.port).await?;
-    let queries = db::scylla::queries::ScyllaQueries::new(session, KEYSPACE).await?;
+    let queries = Arc::new(db::scylla::queries::ScyllaQueries::new(session, KEYSPACE).await?);
 
-    // Create app
-    let app = App::new(Arc::new(queries));
-
-    // Start server
-    tracing::debug!("listening on {:?}", listener);
-    axum::Server::from_tcp(listener)?
-        .serve(app.router.into_make_service())
-        .with_graceful_shutdown(async {
-            tokio::signal::ctrl_c()
+    // One-shot commands
+    match args.command {
+        Some(Command::RebuildRollups(command)) => {
+            return rebuild_rollups(queries.as_ref(), command).await
+        }
+        Some(Command::Export(command)) => return export_vehicles(queries.as_ref(), command).await,
+        None => (),
+    }
+
+    // TLS config
+    let tls_config = match (args.tls_cert, args.tls_key) {
+        (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+            cert_path,
+            key_path,
+            client_ca_path: args.tls_client_ca,
+        }),
+        (None, None) if args.tls_client_ca.is_none() => None,
+        _ => anyhow::bail!("--tls-cert and --tls-key are required for TLS"),
+    };
+
+    // Listeners (passed by systemd or bound)
+    let listen_addrs = if args.listen.is_empty() {
+        vec!["127.0.0.1:3000".to_string()]
+    } else {
+        args.listen
+    };
+    let listen_addrs = listen_addrs
+        .iter()
+        .map(|spec| listener::parse_listen_addr(spec))
+        .collect::<Result<Vec<_>, _>>()?;
+    let unix_socket_mode = args
+        .unix_socket_mode
+        .as_deref()
+        .map(listener::parse_unix_socket_mode)
+        .transpose()?;
+    let mut listeners = Listener::from_systemd()?;
+    let bound_addrs = if listeners.is_empty() {
+        listeners = listen_addrs
+            .iter()
+            .map(|addr| Listener::bind(addr, unix_socket_mode))
+            .collect::<Result<Vec<_>, _>>()?;
+        listen_addrs
+    } else {
+        vec![]
+    };
+
+    // Shutdown, triggered by SIGTERM/SIGINT
+    let shutdown = Shutdown::new();
+    let signal = shutdown.signal();
+
+    // Background tasks
+    let mut background_tasks: Vec<(&str, JoinHandle<()>)> = vec![];
+    background_tasks.push((
+        "purge",
+        tokio::spawn(tasks::purge::run_purge_task(
+            queries.clone(),
+            Duration::from_secs(args.purge_retention_days * 24 * 3600),
+            Duration::from_secs(args.purge_interval_secs),
+            signal.clone(),
+        )),
+    ));
+    background_tasks.push((
+        "webhook delivery",
+        tokio::spawn(tasks::webhooks::run_webhook_delivery_task(
+            queries.clone(),
+            Duration::from_secs(args.webhook_interval_secs),
+            args.webhook_allow_private_hosts,
+            signal.clone(),
+        )),
+    ));
+    background_tasks.push((
+        "job",
+        tokio::spawn(tasks::jobs::run_job_task(
+            queries.clone(),
+            args.job_workers,
+            Duration::from_secs(args.job_interval_secs),
+            signal.clone(),
+        )),
+    ));
+    let outbox_sinks = if args.outbox_sink.is_empty() {
+        vec!["log".to_string()]
+    } else {
+        args.outbox_sink
+    };
+    let mut outbox_sinks = outbox_sinks
+        .iter()
+        .map(|spec| outbox::parse_sink(spec))
+        .collect::<Result<Vec<_>, _>>()?;
+    // The webhook deliveries are enqueued from the outbox
+    outbox_sinks.push(Box::new(webhooks::WebhookSink {
+        queries: queries.clone(),
+    }));
+    background_tasks.push((
+        "outbox relay",
+        tokio::spawn(tasks::outbox::run_outbox_relay_task(
+            queries.clone(),
+            outbox_sinks,
+            Duration::from_secs(args.outbox_interval_secs),
+            signal.clone(),
+        )),
+    ));
+    background_tasks.push((
+        "CDC",
+        tokio::spawn(tasks::cdc::run_cdc_task(
+            queries.create_cdc_reader().await?,
+            Duration::from_secs(args.cdc_interval_secs),
+            signal.clone(),
+        )),
+    ));
+
+    // App settings
+    let rate_limits = args
+        .rate_limit
+        .iter()
+        .map(|spec| rate_limit::parse_rule(spec))
+        .collect::<Result<Vec<_>, _>>()?;
+    let default_config = Config::default();
+    let config = Config {
+        default_consumption_in_kwh_per_100km: args.default_consumption,
+        battery_health_alert_threshold_in_percent: args.battery_health_alert_threshold,
+        max_batch_size: args.max_batch_size,
+        max_telemetry_batch_size: args.max_telemetry_batch_size,
+        rate_limits,
+        rate_limit_shared: args.rate_limit_shared,
+        rate_limit_client_ip_header: args.rate_limit_client_ip_header,
+        api_keys: args.api_key,
+        cors_allowed_origins: args.cors_allowed_origin,
+        cors_allowed_methods: if args.cors_allowed_method.is_empty() {
+            default_config.cors_allowed_methods
+        } else {
+            args.cors_allowed_method
+        },
+        cors_allowed_headers: if args.cors_allowed_header.is_empty() {
+            default_config.cors_allowed_headers
+        } else {
+            args.cors_allowed_header
+        },
+        compression: !args.no_compression,
+        max_body_size: args.max_body_size,
+        max_sync_import_body_size: args.max_sync_import_body_size,
+        max_import_body_size: args.max_import_body_size,
+        admin_api_key: args.admin_api_key,
+        webhook_allow_private_hosts: args.webhook_allow_private_hosts,
+    };
+
+    // gRPC server (separate port)
+    let grpc_addr = SocketAddr::from(([127, 0, 0, 1], args.grpc_port));
+    let grpc_service = VehicleServiceServer::new(VehicleGrpcService::new(
+        queries.clone(),
+        Arc::new(config.clone()),
+        signal.clone(),
+    ));
+    let mut grpc_signal = signal.clone();
+    background_tasks.push((
+        "gRPC server",
+        tokio::spawn(async move {
+            tracing::debug!("gRPC listening on {}", grpc_addr);
+            if let Err(e) = tonic::transport::Server::builder()
+                .add_service(grpc_service)
+                .serve_with_shutdown(grpc_addr, async move {
+                    grpc_signal.wait().await;
+                })
                 .await
-                .expect("failed to listen for signal");
-            tracing::error!("Ctrl-C received!");
-        })
-        .await?;
+            {
+                tracing::error!("gRPC server failed: {}", e);
+            }
+        }),
+    ));
+
+    // Create app
+    let app = App::new(queries.clone(), config, signal.clone());
+
+    // On SIGTERM/SIGINT: readiness probe failure, then shutdown (listeners stopped)
+    let shared_state = app.shared_state.clone();
+    let shutdown_delay = Duration::from_secs(args.shutdown_delay_secs);
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout_secs);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shared_state
+            .write()
+            .expect("state lock poisoned")
+            .shutting_down = true;
+        tokio::time::sleep(shutdown_delay).await;
+
+        tracing::info!("shutting down (timeout: {:?})", shutdown_timeout);
+        shutdown.trigger(Instant::now() + shutdown_timeout);
+    });
+
+    // Start server (until drained)
+    for listener in listeners.iter() {
+        tracing::debug!("listening on {:?}", listener);
+    }
+    let connections = listener::incoming(listeners);
+    let make_service = IntoMakeConnectionService::new(app.into_router());
+    let open_connections = make_service.open_connections();
+    let mut server_signal = signal.clone();
+    let graceful_shutdown = async move {
+        server_signal.wait().await;
+    };
+    match tls_config {
+        Some(tls_config) => {
+            let resolver = Arc::new(ReloadingCertResolver::try_new(
+                &tls_config.cert_path,
+                &tls_config.key_path,
+            )?);
+            background_tasks.push((
+                "TLS reload",
+                tokio::spawn(tasks::tls::run_tls_reload_task(
+                    resolver.clone(),
+                    Duration::from_secs(args.tls_reload_interval_secs),
+                    signal.clone(),
+                )),
+            ));
+            let acceptor = tls::create_server_config(&tls_config, resolver)?.into();
+
+            let server = axum::Server::builder(hyper::server::accept::from_stream(tls::incoming(
+                connections,
+                acceptor,
+            )))
+            .serve(make_service)
+            .with_graceful_shutdown(graceful_shutdown);
+            drain(server, signal.clone(), open_connections).await?;
+        }
+        None => {
+            let server = axum::Server::builder(hyper::server::accept::from_stream(
+                connections.map(Ok::<_, io::Error>),
+            ))
+            .serve(make_service)
+            .with_graceful_shutdown(graceful_shutdown);
+            drain(server, signal.clone(), open_connections).await?;
+        }
+    }
+
+    // Remove the socket files (not the ones of systemd)
+    for addr in bound_addrs.iter() {
+        if let ListenAddr::Unix(path) = addr {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!("failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    // Background tasks (their current work finished before the deadline)
+    let deadline = signal.clone().wait().await;
+    for (name, mut task) in background_tasks.into_iter() {
+        match tokio::time::timeout_at(deadline, &mut task).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => tracing::error!("{} task failed: {}", name, e),
+            Err(_) => {
+                tracing::warn!("{} task cut off by the shutdown", name);
+                task.abort();
+                let _ = task.await;
+            }
+        }
+    }
+
+    // Close the database session (once the last queries are dropped)
+    match Arc::try_unwrap(queries) {
+        Ok(queries) => {
+            drop(queries);
+            tracing::info!("closed the database session");
+        }
+        Err(queries) => tracing::warn!(
+            "database session still used by {} cut off task(s)",
+            Arc::strong_count(&queries) - 1
+        ),
+    }
+
+    Ok(())
+}
+
+/// Run the server until the shutdown, then until its connections are drained or the deadline
+async fn drain<F>(
+    server: F,
+    mut signal: ShutdownSignal,
+    open_connections: OpenConnections,
+) -> Result<()>
+where
+    F: Future<Output = hyper::Result<()>>,
+{
+    tokio::pin!(server);
+
+    let deadline = tokio::select! {
+        result = &mut server => return Ok(result?),
+        deadline = signal.wait() => deadline,
+    };
+    tracing::info!("draining {} connection(s)", open_connections.count());
+
+    match tokio::time::timeout_at(deadline, server).await {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            tracing::warn!(
+                "{} connection(s) cut off by the shutdown",
+                open_connections.count()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// SIGTERM (e.g. Docker, Kubernetes) or SIGINT (Ctrl-C)
+async fn shutdown_signal() {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to listen for signal");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => tracing::info!("SIGINT received"),
+        _ = sigterm.recv() => tracing::info!("SIGTERM received"),
+    }
+}
+
+async fn rebuild_rollups<Q: Queries>(queries: &Q, command: RebuildRollupsCommand) -> Result<()> {
+    let to = command.to.unwrap_or_else(|| Utc::now().naive_utc().date());
+    let vins = match command.vin {
+        Some(vin) => vec![vin],
+        None => {
+            queries
+                .telemetry_queries()
+                .find_vins_with_telemetry()
+                .await?
+        }
+    };
+
+    for vin in vins.iter() {
+        let days = rollups::rebuild_soc_rollups(queries, vin, command.from, to).await?;
+        tracing::info!("rebuilt {} day(s) of rollups for vehicle {}", days, vin);
+    }
+
+    Ok(())
+}
+
+async fn export_vehicles<Q: Queries>(queries: &Q, command: ExportCommand) -> Result<()> {
+    let vehicles = queries.vehicle_queries().find_all_vehicles().await?;
+    let mut chunks = export::export_vehicles(vehicles, command.format, command.engine_type).boxed();
+
+    let mut file = tokio::fs::File::create(&command.output).await?;
+    while let Some(chunk) = chunks.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+
+    tracing::info!("exported the vehicles to {}", command.output);
 
     Ok(())
 }
@@ -0,0 +1,85 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::model::vehicle::{Engine, Vehicle};
+
+/// Fire an alert when the SoC of the matching vehicles falls below the threshold
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AlertRule {
+    pub id: Uuid,
+    pub scope: AlertScope,
+    /// Condition: `soc_in_percent < soc_below_in_percent`
+    pub soc_below_in_percent: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Vehicles evaluated by a rule
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertScope {
+    Vin(String),
+    Owner(String),
+    EngineType(Engine),
+    Fleet,
+}
+
+impl AlertScope {
+    pub fn matches(&self, vehicle: &Vehicle) -> bool {
+        match self {
+            AlertScope::Vin(vin) => vehicle.vin == *vin,
+            AlertScope::Owner(owner) => vehicle.owner.as_ref() == Some(owner),
+            AlertScope::EngineType(engine) => vehicle.engine == *engine,
+            AlertScope::Fleet => true,
+        }
+    }
+}
+
+/// Body of "create alert rule" requests
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CreateAlertRule {
+    pub scope: AlertScope,
+    pub soc_below_in_percent: i32,
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Debug,
+    strum_macros::ToString,
+    strum_macros::EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum AlertStatus {
+    Active,
+    Acknowledged,
+    Resolved,
+}
+
+/// Alert fired by a rule for a vehicle
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Alert {
+    pub id: Uuid,
+    pub rule_id: Uuid,
+    pub vin: String,
+    pub status: AlertStatus,
+
+    /// SoC which fired the alert
+    pub soc_in_percent: i32,
+    pub fired_at: DateTime<Utc>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acknowledged_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+impl Alert {
+    pub fn is_resolved(&self) -> bool {
+        self.status == AlertStatus::Resolved
+    }
+}
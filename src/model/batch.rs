@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+
+use crate::model::vehicle::Vehicle;
+
+/// Body of the batch requests
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BatchVins {
+    pub vins: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default, Debug)]
+pub struct BatchGetResult {
+    pub vehicles: Vec<Vehicle>,
+    /// Unknown or deleted vehicles
+    pub missing: Vec<String>,
+}
+
+/// Vehicle which could not be deleted
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BatchFailure {
+    pub vin: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default, Debug)]
+pub struct BatchDeleteResult {
+    pub deleted: Vec<String>,
+    /// Unknown or already deleted vehicles
+    pub missing: Vec<String>,
+    pub failed: Vec<BatchFailure>,
+}
@@ -0,0 +1,81 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ChargingSession {
+    pub id: Uuid,
+    pub vin: String,
+
+    pub start_time: DateTime<Utc>,
+    /// None while charging
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<DateTime<Utc>>,
+
+    pub start_soc_in_percent: i32,
+    /// Latest known SoC while charging
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_soc_in_percent: Option<i32>,
+
+    pub energy_added_in_kwh: f64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_power_in_kw: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+
+    /// Detected from the SoC telemetry (instead of reported by the vehicle)
+    #[serde(default)]
+    pub detected: bool,
+}
+
+impl ChargingSession {
+    pub fn is_ended(&self) -> bool {
+        self.end_time.is_some()
+    }
+
+    pub fn overlaps(&self, other: &ChargingSession) -> bool {
+        let self_end = self.end_time.unwrap_or(self.start_time);
+        let other_end = other.end_time.unwrap_or(other.start_time);
+
+        self.start_time <= other_end && other.start_time <= self_end
+    }
+}
+
+/// Body of "start charging session" requests
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct StartChargingSession {
+    /// Default: now
+    pub start_time: Option<DateTime<Utc>>,
+    pub start_soc_in_percent: i32,
+    pub location: Option<String>,
+}
+
+/// Body of "update/end charging session" requests
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct UpdateChargingSession {
+    /// Only when ending the session (default: now)
+    pub end_time: Option<DateTime<Utc>>,
+    pub soc_in_percent: Option<i32>,
+    /// Total energy added since the start of the session
+    pub energy_added_in_kwh: Option<f64>,
+    /// Current charging power
+    pub power_in_kw: Option<f64>,
+    pub location: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct ChargingSummary {
+    pub session_count: usize,
+    pub total_energy_in_kwh: f64,
+    pub weeks: Vec<WeeklyChargingSummary>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct WeeklyChargingSummary {
+    /// Monday of the (ISO) week
+    pub week_start: NaiveDate,
+    pub session_count: usize,
+    pub energy_in_kwh: f64,
+}
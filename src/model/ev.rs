@@ -0,0 +1,138 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Minimum distance of the reported trips before the learned consumption is used
+pub const MIN_LEARNING_DISTANCE_IN_KM: f64 = 100.0;
+
+/// Energy consumption settings and statistics of a vehicle
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct EvConsumption {
+    /// Set by the user, takes precedence over the learned consumption
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub configured_in_kwh_per_100km: Option<f64>,
+
+    /// Sum of the reported trips
+    pub trip_distance_in_km: f64,
+    pub trip_energy_in_kwh: f64,
+}
+
+impl EvConsumption {
+    /// Average consumption of the reported trips
+    pub fn learned_in_kwh_per_100km(&self) -> Option<f64> {
+        if self.trip_distance_in_km < MIN_LEARNING_DISTANCE_IN_KM {
+            return None;
+        }
+
+        Some(self.trip_energy_in_kwh / self.trip_distance_in_km * 100.0)
+    }
+
+    /// Configured, learned or default consumption (in that order)
+    pub fn resolve(&self, default_in_kwh_per_100km: f64) -> (f64, ConsumptionSource) {
+        if let Some(configured) = self.configured_in_kwh_per_100km {
+            (configured, ConsumptionSource::Configured)
+        } else if let Some(learned) = self.learned_in_kwh_per_100km() {
+            (learned, ConsumptionSource::Learned)
+        } else {
+            (default_in_kwh_per_100km, ConsumptionSource::Default)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsumptionSource {
+    Configured,
+    Learned,
+    Default,
+}
+
+/// Body of "configure consumption" requests
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ConfigureConsumption {
+    /// None: use the learned (or default) consumption again
+    pub consumption_in_kwh_per_100km: Option<f64>,
+}
+
+/// Trip reported by a vehicle, used to learn its consumption
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Trip {
+    pub distance_in_km: f64,
+    pub energy_used_in_kwh: f64,
+}
+
+impl Trip {
+    pub fn is_valid(&self) -> bool {
+        self.distance_in_km > 0.0 && self.energy_used_in_kwh >= 0.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EvEstimate {
+    pub soc_in_percent: i32,
+    pub remaining_energy_in_kwh: f64,
+
+    pub consumption_in_kwh_per_100km: f64,
+    pub consumption_source: ConsumptionSource,
+    pub range_in_km: f64,
+
+    pub charger_in_kw: f64,
+    pub target_soc_in_percent: i32,
+    pub energy_to_target_in_kwh: f64,
+    pub charge_time_in_minutes: i64,
+}
+
+/// Usable battery capacity measured at a given time
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BatteryHealth {
+    pub vin: String,
+    pub measured_at: DateTime<Utc>,
+    pub usable_capacity_in_kwh: f64,
+    /// Nominal capacity of the vehicle at measurement time
+    pub nominal_capacity_in_kwh: i32,
+}
+
+impl BatteryHealth {
+    /// State of health: usable vs nominal capacity
+    pub fn soh_in_percent(&self) -> f64 {
+        self.usable_capacity_in_kwh / self.nominal_capacity_in_kwh as f64 * 100.0
+    }
+}
+
+/// Body of "report battery capacity" requests (e.g. measured by the BMS)
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ReportBatteryCapacity {
+    /// Default: now
+    pub measured_at: Option<DateTime<Utc>>,
+    pub usable_capacity_in_kwh: f64,
+}
+
+/// Entry of the SoH history of a vehicle
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BatteryHealthEntry {
+    pub measured_at: DateTime<Utc>,
+    pub usable_capacity_in_kwh: f64,
+    pub soh_in_percent: f64,
+}
+
+impl From<&BatteryHealth> for BatteryHealthEntry {
+    fn from(health: &BatteryHealth) -> Self {
+        BatteryHealthEntry {
+            measured_at: health.measured_at,
+            usable_capacity_in_kwh: health.usable_capacity_in_kwh,
+            soh_in_percent: health.soh_in_percent(),
+        }
+    }
+}
+
+/// Battery health of a vehicle in the fleet report
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct VehicleBatteryHealth {
+    pub vin: String,
+    pub soh_in_percent: f64,
+    /// SoH lost per year (None with less than 2 measurements)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub degradation_in_percent_per_year: Option<f64>,
+    pub measurement_count: usize,
+    /// SoH below the alert threshold
+    pub alert: bool,
+}
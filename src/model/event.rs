@@ -0,0 +1,46 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::model::{telemetry::SocReading, vehicle::Vehicle};
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Debug,
+    strum_macros::ToString,
+    strum_macros::EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum VehicleEventType {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// Change of a vehicle, published on the event bus
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct VehicleEvent {
+    /// Sequence number, increasing for the lifetime of the process
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub event_type: VehicleEventType,
+    pub vin: String,
+    pub occurred_at: DateTime<Utc>,
+
+    /// Vehicle after the change (not set for deleted vehicles)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vehicle: Option<Vehicle>,
+}
+
+/// SoC readings of a vehicle, published on the event bus when they are stored
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TelemetryEvent {
+    pub vin: String,
+    pub readings: Vec<SocReading>,
+}
@@ -0,0 +1,32 @@
+use chrono::{DateTime, Utc};
+
+/// Response of a request sent with an `Idempotency-Key` header, replayed on retries
+#[derive(Clone, PartialEq, Debug)]
+pub struct IdempotentResponse {
+    pub key: String,
+    /// Hash of the route and payload of the original request
+    pub fingerprint: String,
+    /// 0 while the original request is in flight
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl IdempotentResponse {
+    /// Claim of a key, stored while the original request is in flight
+    pub fn pending(key: impl Into<String>, fingerprint: impl Into<String>) -> Self {
+        IdempotentResponse {
+            key: key.into(),
+            fingerprint: fingerprint.into(),
+            status: 0,
+            content_type: None,
+            body: Vec::new(),
+            created_at: Utc::now(),
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == 0
+    }
+}
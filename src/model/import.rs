@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportLineStatus {
+    /// Created (or would be created, in dry run mode)
+    Created,
+    /// A vehicle with the same VIN exists (possibly deleted) or is imported by a previous line
+    AlreadyExists,
+    Invalid,
+    /// Valid vehicle which could not be stored
+    Failed,
+}
+
+/// Result of the import of one line
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ImportLine {
+    /// Line number in the body, starting at 1 (the CSV header is line 1)
+    pub line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vin: Option<String>,
+    pub status: ImportLineStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Report of a vehicle import (empty lines are ignored)
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default, Debug)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub created: usize,
+    pub already_exists: usize,
+    pub invalid: usize,
+    pub failed: usize,
+    pub lines: Vec<ImportLine>,
+}
+
+impl ImportReport {
+    pub fn add_line(&mut self, line: ImportLine) {
+        match line.status {
+            ImportLineStatus::Created => self.created += 1,
+            ImportLineStatus::AlreadyExists => self.already_exists += 1,
+            ImportLineStatus::Invalid => self.invalid += 1,
+            ImportLineStatus::Failed => self.failed += 1,
+        }
+        self.lines.push(line);
+    }
+}
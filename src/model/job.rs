@@ -0,0 +1,82 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Debug,
+    strum_macros::ToString,
+    strum_macros::EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum JobKind {
+    /// Vehicle import, see `POST /vehicle/import`
+    Import,
+    /// Vehicle export (NDJSON or CSV), see `GET /vehicle/export`
+    Export,
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Debug,
+    strum_macros::ToString,
+    strum_macros::EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+/// Long-running operation, processed in the background chunk by chunk
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Job {
+    pub id: Uuid,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    /// Parameters of the job, depending on its kind
+    pub params: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub total_chunks: i32,
+    /// Checkpoint: the processing restarts from this chunk (e.g. after a restart)
+    pub processed_chunks: i32,
+    pub cancel_requested: bool,
+    /// Result of the processed chunks, depending on the kind of job
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Part of the input of a job
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct JobChunk {
+    pub index: i32,
+    /// Line number of the first line of the chunk in the input, starting at 1
+    pub first_line: i64,
+    /// Lines of the chunk, separated by '\n'
+    pub data: String,
+}
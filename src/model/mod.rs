@@ -1 +1,14 @@
+pub mod alert;
+pub mod batch;
+pub mod charging;
+pub mod ev;
+pub mod event;
+pub mod idempotency;
+pub mod import;
+pub mod job;
+pub mod outbox;
+pub mod rate_limit;
+pub mod telemetry;
 pub mod vehicle;
+pub mod webhook;
+pub mod ws;
@@ -0,0 +1,65 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::model::{event::VehicleEventType, vehicle::Vehicle};
+
+/// Number of partitions of the outbox (the entries of a vehicle are always in the same one)
+pub const OUTBOX_BUCKETS: i32 = 16;
+
+/// Duration of the time windows of the outbox partitions: each bucket gets a new partition per
+/// window, so that the tombstones of the relayed entries are not read again once the window
+/// has been relayed
+pub const OUTBOX_WINDOW_SECS: i64 = 60;
+
+/// Vehicle change written to the outbox together with the change itself
+///
+/// The entry is delivered at least once to each sink: the sinks are expected to ignore the
+/// entries whose id has already been received.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct OutboxEntry {
+    /// Partition of the outbox, derived from the VIN
+    #[serde(skip)]
+    pub bucket: i32,
+    /// Time window of the partition, when the entry was created (not when the change occurred)
+    #[serde(skip)]
+    pub time_window: i64,
+    /// Event id, identical for all the sinks and redeliveries
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub event_type: VehicleEventType,
+    pub vin: String,
+    pub occurred_at: DateTime<Utc>,
+
+    /// Vehicle after the change (not set for deleted vehicles)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vehicle: Option<Vehicle>,
+}
+
+impl OutboxEntry {
+    pub fn new(event_type: VehicleEventType, vin: &str, vehicle: Option<Vehicle>) -> Self {
+        OutboxEntry {
+            bucket: outbox_bucket(vin),
+            time_window: outbox_window(Utc::now()),
+            id: Uuid::new_v4(),
+            event_type,
+            vin: vin.to_string(),
+            occurred_at: Utc::now(),
+            vehicle,
+        }
+    }
+}
+
+/// Time window of the outbox partitions at the given time
+pub fn outbox_window(time: DateTime<Utc>) -> i64 {
+    time.timestamp().div_euclid(OUTBOX_WINDOW_SECS)
+}
+
+/// Partition of the outbox entries of the vehicle (stable across restarts)
+pub fn outbox_bucket(vin: &str) -> i32 {
+    let hash = vin.bytes().fold(0u32, |hash, byte| {
+        hash.wrapping_mul(31).wrapping_add(byte as u32)
+    });
+
+    (hash % OUTBOX_BUCKETS as u32) as i32
+}
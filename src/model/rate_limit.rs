@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// Token bucket of a client for a route: `capacity` requests, refilled continuously over `period`
+#[derive(Clone, PartialEq, Debug)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl TokenBucket {
+    pub fn full(capacity: u32, now: DateTime<Utc>) -> Self {
+        TokenBucket {
+            tokens: capacity as f64,
+            updated_at: now,
+        }
+    }
+
+    /// Refill the bucket up to now, then take one token if any (false if the bucket is empty)
+    pub fn take(&mut self, capacity: u32, period: Duration, now: DateTime<Utc>) -> bool {
+        let elapsed_in_secs = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        self.tokens =
+            (self.tokens + elapsed_in_secs * refill_rate(capacity, period)).min(capacity as f64);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Number of whole tokens left
+    pub fn remaining(&self) -> u32 {
+        self.tokens.max(0.0).floor() as u32
+    }
+
+    /// Seconds until the next token is available (0 if there is one)
+    pub fn next_token_in_secs(&self, capacity: u32, period: Duration) -> u64 {
+        ((1.0 - self.tokens).max(0.0) / refill_rate(capacity, period)).ceil() as u64
+    }
+
+    /// Seconds until the bucket is full again
+    pub fn full_in_secs(&self, capacity: u32, period: Duration) -> u64 {
+        ((capacity as f64 - self.tokens).max(0.0) / refill_rate(capacity, period)).ceil() as u64
+    }
+}
+
+/// Tokens added per second
+fn refill_rate(capacity: u32, period: Duration) -> f64 {
+    capacity as f64 / period.as_secs_f64().max(0.001)
+}
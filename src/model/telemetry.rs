@@ -0,0 +1,63 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SocReading {
+    pub timestamp: DateTime<Utc>,
+    pub soc_in_percent: i32,
+}
+
+impl SocReading {
+    pub fn is_valid(&self) -> bool {
+        (0..=100).contains(&self.soc_in_percent)
+    }
+}
+
+/// Duration of the pre-computed aggregate buckets (aligned on UTC)
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Debug,
+    strum_macros::ToString,
+    strum_macros::EnumString,
+)]
+pub enum AggregateInterval {
+    #[serde(rename = "1h")]
+    #[strum(serialize = "1h")]
+    Hour,
+    #[serde(rename = "1d")]
+    #[strum(serialize = "1d")]
+    Day,
+}
+
+impl AggregateInterval {
+    pub fn duration(&self) -> chrono::Duration {
+        match self {
+            AggregateInterval::Hour => chrono::Duration::hours(1),
+            AggregateInterval::Day => chrono::Duration::days(1),
+        }
+    }
+
+    /// Start of the bucket containing the given time
+    pub fn bucket_start(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        let duration_ms = self.duration().num_milliseconds();
+        let offset_ms = timestamp.timestamp_millis().rem_euclid(duration_ms);
+
+        timestamp - chrono::Duration::milliseconds(offset_ms)
+    }
+}
+
+/// SoC statistics of the readings in [start, start + interval)
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SocAggregate {
+    pub start: DateTime<Utc>,
+    pub min: i32,
+    pub max: i32,
+    pub avg: f64,
+    pub first: i32,
+    pub last: i32,
+    pub count: i64,
+}
@@ -4,6 +4,9 @@ use serde::{Deserialize, Serialize};
 pub struct Vehicle {
     pub vin: String,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+
     #[serde(rename = "engine_type")]
     pub engine: Engine,
 
@@ -0,0 +1,180 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::model::{event::VehicleEventType, vehicle::Vehicle};
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Debug,
+    strum_macros::ToString,
+    strum_macros::EnumString,
+)]
+pub enum WebhookEvent {
+    #[serde(rename = "vehicle.created")]
+    #[strum(serialize = "vehicle.created")]
+    VehicleCreated,
+    #[serde(rename = "vehicle.updated")]
+    #[strum(serialize = "vehicle.updated")]
+    VehicleUpdated,
+    #[serde(rename = "vehicle.deleted")]
+    #[strum(serialize = "vehicle.deleted")]
+    VehicleDeleted,
+}
+
+// VehicleEventType -> WebhookEvent
+impl From<VehicleEventType> for WebhookEvent {
+    fn from(event_type: VehicleEventType) -> Self {
+        match event_type {
+            VehicleEventType::Created => WebhookEvent::VehicleCreated,
+            VehicleEventType::Updated => WebhookEvent::VehicleUpdated,
+            VehicleEventType::Deleted => WebhookEvent::VehicleDeleted,
+        }
+    }
+}
+
+/// Subscription of a downstream system to vehicle lifecycle events
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature of the deliveries (never returned)
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    pub fn is_subscribed(&self, event: WebhookEvent) -> bool {
+        self.events.contains(&event)
+    }
+}
+
+/// Body of "create/update webhook" requests
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ConfigureWebhook {
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+}
+
+impl ConfigureWebhook {
+    /// The URL must be HTTP(S), and its host neither loopback, private nor link-local (unless
+    /// allowed), the names are resolved separately
+    pub fn is_valid(&self, allow_private_hosts: bool) -> bool {
+        let url = match reqwest::Url::parse(&self.url) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        let host = match url.host_str() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return false,
+        };
+        let private_host = host.eq_ignore_ascii_case("localhost")
+            || host.parse::<IpAddr>().map_or(false, is_private_ip);
+
+        (url.scheme() == "http" || url.scheme() == "https")
+            && (allow_private_hosts || !private_host)
+            && !self.secret.is_empty()
+            && !self.events.is_empty()
+    }
+}
+
+/// Loopback, private, link-local, shared or unspecified address, i.e. not to be called by
+/// the webhooks
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // Shared address space (100.64.0.0/10)
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            match segments {
+                // IPv4-mapped address
+                [0, 0, 0, 0, 0, 0xffff, high, low] => is_private_ip(IpAddr::V4(Ipv4Addr::new(
+                    (high >> 8) as u8,
+                    high as u8,
+                    (low >> 8) as u8,
+                    low as u8,
+                ))),
+                _ => {
+                    ip.is_loopback()
+                        || ip.is_unspecified()
+                        // Unique local (fc00::/7) and link-local (fe80::/10) addresses
+                        || (segments[0] & 0xfe00) == 0xfc00
+                        || (segments[0] & 0xffc0) == 0xfe80
+                }
+            }
+        }
+    }
+}
+
+/// Body of the deliveries
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct WebhookPayload {
+    /// Event id, identical for all the subscriptions and redeliveries
+    pub id: Uuid,
+    pub event: WebhookEvent,
+    pub occurred_at: DateTime<Utc>,
+    pub vin: String,
+
+    /// Vehicle after the change (not set for deleted vehicles)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vehicle: Option<Vehicle>,
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Debug,
+    strum_macros::ToString,
+    strum_macros::EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Waiting for the first attempt or for a retry
+    Pending,
+    Delivered,
+    /// No more retries, can only be redelivered manually
+    DeadLetter,
+}
+
+/// Delivery of an event to a webhook, with the result of the last attempt
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct WebhookDelivery {
+    pub webhook_id: Uuid,
+    pub id: Uuid,
+    pub event: WebhookEvent,
+    /// Serialized `WebhookPayload`, i.e. the signed body
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub created_at: DateTime<Utc>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_status_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+use crate::model::{
+    event::{TelemetryEvent, VehicleEvent},
+    telemetry::SocReading,
+    vehicle::{Engine, Vehicle},
+};
+
+/// Message sent by a WebSocket client (JSON text frame, tagged by `type`)
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Add VINs and/or engine types to the subscription
+    Subscribe {
+        #[serde(default)]
+        vins: Vec<String>,
+        #[serde(default)]
+        engine_types: Vec<Engine>,
+    },
+    /// Remove VINs and/or engine types from the subscription
+    Unsubscribe {
+        #[serde(default)]
+        vins: Vec<String>,
+        #[serde(default)]
+        engine_types: Vec<Engine>,
+    },
+    /// Query a vehicle, answered by a `vehicle` message
+    GetVehicle {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        vin: String,
+    },
+    /// Query the newest SoC reading of a vehicle, answered by a `latest_telemetry` message
+    GetLatestTelemetry {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        vin: String,
+    },
+}
+
+/// Message sent by the server (JSON text frame, tagged by `type`)
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Current subscription, after each (un)subscribe message
+    Subscribed {
+        vins: Vec<String>,
+        engine_types: Vec<Engine>,
+    },
+    /// Change of a subscribed vehicle
+    VehicleEvent { event: VehicleEvent },
+    /// New SoC readings of a subscribed vehicle
+    Telemetry { telemetry: TelemetryEvent },
+    Vehicle {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        vehicle: Vehicle,
+    },
+    LatestTelemetry {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        vin: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reading: Option<SocReading>,
+    },
+    /// Events were dropped because the client did not read them fast enough
+    Lagged { missed: u64 },
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        message: String,
+    },
+}
@@ -0,0 +1,262 @@
+use async_trait::async_trait;
+use axum::{
+    body::Full,
+    extract::{FromRequest, RequestParts},
+    http::{self, header, HeaderMap, StatusCode},
+};
+use http_body::Body as HttpBody;
+use serde::{de::DeserializeOwned, Serialize};
+use tower::BoxError;
+
+use crate::{error::AppError, request_body, response::AppResponseResult, result::AppResult};
+
+/// Serialization format of the request and response bodies
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MediaType {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl MediaType {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            MediaType::Json => "application/json",
+            MediaType::MsgPack => "application/msgpack",
+            MediaType::Cbor => "application/cbor",
+        }
+    }
+
+    /// Media type of a `Content-Type` or `Accept` value (without parameters)
+    fn from_essence(essence: &str) -> Option<Self> {
+        match essence.to_ascii_lowercase().as_str() {
+            "application/json" => Some(MediaType::Json),
+            "application/msgpack" | "application/x-msgpack" => Some(MediaType::MsgPack),
+            "application/cbor" => Some(MediaType::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Format of the request body, from the `Content-Type` header
+    pub fn from_content_type(headers: &HeaderMap) -> AppResult<Self> {
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .and_then(|essence| MediaType::from_essence(essence.trim()))
+            .ok_or(AppError::UnsupportedMediaType("Content-Type"))
+    }
+
+    /// Preferred format of the response body, from the `Accept` header
+    ///
+    /// JSON is used without `Accept` header or for wildcards, the first one of the media types
+    /// with the highest quality wins.
+    pub fn from_accept(headers: &HeaderMap) -> AppResult<Self> {
+        let mut values = headers.get_all(header::ACCEPT).iter().peekable();
+        if values.peek().is_none() {
+            return Ok(MediaType::Json);
+        }
+
+        let mut best: Option<(MediaType, f32)> = None;
+        for range in values
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let mut parts = range.split(';').map(str::trim);
+            let essence = parts.next().unwrap_or_default();
+            let quality = parts
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let media_type = match essence {
+                "*/*" | "application/*" => Some(MediaType::Json),
+                _ => MediaType::from_essence(essence),
+            };
+            match (media_type, best) {
+                (Some(_), _) if quality <= 0.0 => (),
+                (Some(media_type), Some((_, best_quality))) if quality > best_quality => {
+                    best = Some((media_type, quality))
+                }
+                (Some(media_type), None) => best = Some((media_type, quality)),
+                _ => (),
+            }
+        }
+
+        best.map(|(media_type, _)| media_type)
+            .ok_or(AppError::NotAcceptable("Accept"))
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> AppResult<Vec<u8>> {
+        // Note: MessagePack structs are serialized as maps, for the optional fields to be skipped
+        match self {
+            MediaType::Json => serde_json::to_vec(value).map_err(anyhow::Error::from),
+            MediaType::MsgPack => rmp_serde::to_vec_named(value).map_err(anyhow::Error::from),
+            MediaType::Cbor => serde_cbor::to_vec(value).map_err(anyhow::Error::from),
+        }
+        .map_err(|_| AppError::ConversionError("Response body"))
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> AppResult<T> {
+        match self {
+            MediaType::Json => serde_json::from_slice(bytes).map_err(anyhow::Error::from),
+            MediaType::MsgPack => rmp_serde::from_read_ref(bytes).map_err(anyhow::Error::from),
+            MediaType::Cbor => serde_cbor::from_slice(bytes).map_err(anyhow::Error::from),
+        }
+        .map_err(|_| AppError::InvalidInput("Request body"))
+    }
+}
+
+/// Extractor of the preferred response format (`Accept` header)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Accept(pub MediaType);
+
+impl Accept {
+    /// e.g.: accept.to_response(StatusCode::OK, &vehicle)
+    pub fn to_response<T: Serialize>(&self, status: StatusCode, value: &T) -> AppResponseResult {
+        let body = self.0.serialize(value)?;
+
+        let response = http::Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, self.0.content_type())
+            .header(header::VARY, "Accept")
+            .body(Full::from(body))
+            .map_err(anyhow::Error::from)?;
+
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for Accept
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        match req.headers() {
+            Some(headers) => Ok(Accept(MediaType::from_accept(headers)?)),
+            None => Ok(Accept(MediaType::Json)),
+        }
+    }
+}
+
+/// Extractor of a request body in any supported format (`Content-Type` header)
+#[derive(Debug)]
+pub struct Negotiated<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for Negotiated<T>
+where
+    T: DeserializeOwned,
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let media_type = match req.headers() {
+            Some(headers) => MediaType::from_content_type(headers)?,
+            None => return Err(AppError::UnsupportedMediaType("Content-Type")),
+        };
+        let body = req
+            .take_body()
+            .ok_or(AppError::InvalidInput("Request body"))?;
+        let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+            if request_body::is_too_large(&e.into()) {
+                AppError::PayloadTooLarge("Request body")
+            } else {
+                AppError::InvalidInput("Request body")
+            }
+        })?;
+
+        Ok(Negotiated(media_type.deserialize(&bytes)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::model::vehicle::{Engine, EvData, Vehicle};
+
+    fn accept(value: &str) -> AppResult<MediaType> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+
+        MediaType::from_accept(&headers)
+    }
+
+    #[test]
+    fn test_from_accept() {
+        assert_eq!(
+            MediaType::from_accept(&HeaderMap::new()).unwrap(),
+            MediaType::Json
+        );
+        assert_eq!(accept("*/*").unwrap(), MediaType::Json);
+        assert_eq!(accept("application/cbor").unwrap(), MediaType::Cbor);
+        assert_eq!(
+            accept("text/html, application/msgpack;q=0.9, application/json;q=0.8").unwrap(),
+            MediaType::MsgPack
+        );
+        assert_eq!(
+            accept("application/cbor;q=0.5, application/msgpack").unwrap(),
+            MediaType::MsgPack
+        );
+
+        // TODO: user assert_matches! when stable
+        assert!(matches!(
+            accept("text/html"),
+            Err(AppError::NotAcceptable("Accept"))
+        ));
+        assert!(matches!(
+            accept("application/json;q=0"),
+            Err(AppError::NotAcceptable("Accept"))
+        ));
+    }
+
+    #[test]
+    fn test_from_content_type() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        assert_eq!(
+            MediaType::from_content_type(&headers).unwrap(),
+            MediaType::Json
+        );
+
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/xml"));
+        assert!(matches!(
+            MediaType::from_content_type(&headers),
+            Err(AppError::UnsupportedMediaType("Content-Type"))
+        ));
+    }
+
+    #[test]
+    fn test_serialize_deserialize() {
+        let vehicle = Vehicle {
+            vin: "vin1".to_string(),
+            owner: None,
+            engine: Engine::Ev,
+            ev_data: Some(EvData {
+                battery_capacity_in_kwh: 50,
+                soc_in_percent: 80,
+            }),
+        };
+
+        for media_type in [MediaType::Json, MediaType::MsgPack, MediaType::Cbor].iter() {
+            let bytes = media_type.serialize(&vehicle).unwrap();
+            assert_eq!(media_type.deserialize::<Vehicle>(&bytes).unwrap(), vehicle);
+        }
+
+        // Binary formats are smaller
+        let json = MediaType::Json.serialize(&vehicle).unwrap();
+        assert!(MediaType::MsgPack.serialize(&vehicle).unwrap().len() < json.len());
+        assert!(MediaType::Cbor.serialize(&vehicle).unwrap().len() < json.len());
+    }
+}
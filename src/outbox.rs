@@ -0,0 +1,328 @@
+use std::{path::PathBuf, sync::Mutex, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    db::queries::{OutboxQueries, Queries},
+    error::AppError,
+    model::outbox::{outbox_window, OutboxEntry, OUTBOX_BUCKETS},
+    result::AppResult,
+};
+
+/// Header with the event id of the entries posted by the HTTP sink
+pub const EVENT_ID_HEADER: &str = "X-Event-Id";
+
+/// Maximum number of entries of a bucket relayed at once
+pub const OUTBOX_BATCH_SIZE: usize = 100;
+
+/// Number of time windows read back when a bucket has no cursor yet (1 hour)
+pub const OUTBOX_INITIAL_LOOKBACK_WINDOWS: i64 = 60;
+
+/// Timeout of a delivery to the HTTP sink
+pub const OUTBOX_HTTP_TIMEOUT_SECS: u64 = 10;
+
+/// Destination of the outbox entries
+///
+/// An entry can be delivered more than once (e.g. if the process dies before it is removed
+/// from the outbox), the receivers are expected to deduplicate the entries by id.
+#[async_trait]
+pub trait OutboxSink: std::fmt::Debug + Send + Sync + 'static {
+    async fn deliver(&self, entry: &OutboxEntry) -> AppResult<()>;
+}
+
+/// Log the entries
+#[derive(Debug)]
+pub struct LogSink;
+
+#[async_trait]
+impl OutboxSink for LogSink {
+    async fn deliver(&self, entry: &OutboxEntry) -> AppResult<()> {
+        tracing::info!(
+            "vehicle {} {} (event {})",
+            entry.vin,
+            entry.event_type.to_string(),
+            entry.id
+        );
+
+        Ok(())
+    }
+}
+
+/// Append the entries to a file, one JSON object per line
+#[derive(Debug)]
+pub struct FileSink {
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl OutboxSink for FileSink {
+    async fn deliver(&self, entry: &OutboxEntry) -> AppResult<()> {
+        let mut line = serde_json::to_vec(entry).map_err(anyhow::Error::from)?;
+        line.push(b'\n');
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(anyhow::Error::from)?;
+        file.write_all(&line).await.map_err(anyhow::Error::from)?;
+
+        Ok(())
+    }
+}
+
+/// POST the entries (JSON) to a URL, any non-2xx status is a failure
+#[derive(Debug)]
+pub struct HttpSink {
+    pub client: reqwest::Client,
+    pub url: String,
+}
+
+#[async_trait]
+impl OutboxSink for HttpSink {
+    async fn deliver(&self, entry: &OutboxEntry) -> AppResult<()> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header(EVENT_ID_HEADER, entry.id.to_string())
+            .timeout(Duration::from_secs(OUTBOX_HTTP_TIMEOUT_SECS))
+            .json(entry)
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("HTTP sink answered {}", response.status()).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Keep the entries in memory (for tests)
+#[derive(Default, Debug)]
+pub struct MemorySink {
+    pub entries: Mutex<Vec<OutboxEntry>>,
+}
+
+#[async_trait]
+impl OutboxSink for MemorySink {
+    async fn deliver(&self, entry: &OutboxEntry) -> AppResult<()> {
+        self.entries
+            .lock()
+            .expect("memory sink lock poisoned")
+            .push(entry.clone());
+
+        Ok(())
+    }
+}
+
+/// Sink described on the command line: `log`, `file:<path>` or an http(s) URL
+pub fn parse_sink(spec: &str) -> AppResult<Box<dyn OutboxSink>> {
+    if spec == "log" {
+        Ok(Box::new(LogSink))
+    } else if let Some(path) = spec.strip_prefix("file:") {
+        Ok(Box::new(FileSink {
+            path: PathBuf::from(path),
+        }))
+    } else if spec.starts_with("http://") || spec.starts_with("https://") {
+        Ok(Box::new(HttpSink {
+            client: reqwest::Client::new(),
+            url: spec.to_string(),
+        }))
+    } else {
+        Err(AppError::InvalidInput("Outbox sink"))
+    }
+}
+
+/// Deliver the pending entries to all the sinks, returns the number of relayed entries
+///
+/// The entries of a bucket are relayed in order, time window after time window from the
+/// cursor of the bucket: the first failure stops the bucket until the next run, and the entry
+/// is then delivered again to all the sinks (at-least-once). The cursor moves past a window
+/// once it is relayed and closed, i.e. one window after its end, so that the entries created
+/// just before the end of the window are not skipped.
+pub async fn relay_outbox_entries<Q: Queries>(
+    queries: &Q,
+    sinks: &[Box<dyn OutboxSink>],
+    now: DateTime<Utc>,
+) -> AppResult<usize> {
+    let mut relayed = 0;
+    let current_window = outbox_window(now);
+
+    for bucket in 0..OUTBOX_BUCKETS {
+        let cursor = queries.outbox_queries().find_outbox_cursor(bucket).await?;
+        let first_window = cursor.unwrap_or(current_window - OUTBOX_INITIAL_LOOKBACK_WINDOWS);
+
+        let mut next_cursor = first_window;
+        'windows: for time_window in first_window..=current_window {
+            let entries = queries
+                .outbox_queries()
+                .find_outbox_entries(bucket, time_window, OUTBOX_BATCH_SIZE)
+                .await?;
+
+            for entry in entries.iter() {
+                for sink in sinks.iter() {
+                    if let Err(e) = sink.deliver(entry).await {
+                        tracing::warn!(
+                            "failed to relay outbox entry {} to {:?}: {}",
+                            entry.id,
+                            sink,
+                            e
+                        );
+                        break 'windows;
+                    }
+                }
+
+                queries.outbox_queries().delete_outbox_entry(entry).await?;
+                relayed += 1;
+            }
+
+            // The remaining entries of the window are relayed by the next run
+            if entries.len() == OUTBOX_BATCH_SIZE {
+                break;
+            }
+            if time_window < current_window - 1 {
+                next_cursor = time_window + 1;
+            }
+        }
+
+        if cursor != Some(next_cursor) {
+            queries
+                .outbox_queries()
+                .update_outbox_cursor(bucket, next_cursor)
+                .await?;
+        }
+    }
+
+    Ok(relayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use mockall::predicate::{always, eq, function};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{db::queries, model::event::VehicleEventType};
+
+    /// Fails all the deliveries of the given entry
+    #[derive(Debug)]
+    struct FailingSink(Uuid);
+
+    #[async_trait]
+    impl OutboxSink for FailingSink {
+        async fn deliver(&self, entry: &OutboxEntry) -> AppResult<()> {
+            if entry.id == self.0 {
+                return Err(AppError::InvalidInput("Entry"));
+            }
+
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct SharedSink(Arc<MemorySink>);
+
+    #[async_trait]
+    impl OutboxSink for SharedSink {
+        async fn deliver(&self, entry: &OutboxEntry) -> AppResult<()> {
+            self.0.deliver(entry).await
+        }
+    }
+
+    fn entry(vin: &str) -> OutboxEntry {
+        OutboxEntry {
+            bucket: 0,
+            ..OutboxEntry::new(VehicleEventType::Created, vin, None)
+        }
+    }
+
+    #[test]
+    fn test_parse_sink() {
+        assert!(parse_sink("log").is_ok());
+        assert!(parse_sink("file:/tmp/outbox.ndjson").is_ok());
+        assert!(parse_sink("http://localhost:8080/events").is_ok());
+        assert!(parse_sink("kafka").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay_outbox_entries() -> anyhow::Result<()> {
+        let now: DateTime<Utc> = "2021-09-01T10:00:30Z".parse()?;
+        let current_window = outbox_window(now);
+
+        let entries = vec![entry("vin1"), entry("vin2"), entry("vin3")];
+        let entries_clone = entries.clone();
+        let failing_id = entries[1].id;
+        let delivered_id = entries[0].id;
+
+        // Entries in the bucket 0 only, 2 windows ago
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .outbox_queries
+            .expect_find_outbox_cursor()
+            .with(eq(0))
+            .returning(move |_| Ok(Some(current_window - 3)));
+        mock_queries
+            .outbox_queries
+            .expect_find_outbox_cursor()
+            .with(function(|bucket: &i32| *bucket != 0))
+            .returning(|_| Ok(None));
+        mock_queries
+            .outbox_queries
+            .expect_find_outbox_entries()
+            .with(eq(0), eq(current_window - 2), always())
+            .returning(move |_, _, _| Ok(entries_clone.clone()));
+        mock_queries
+            .outbox_queries
+            .expect_find_outbox_entries()
+            .withf(move |bucket, time_window, _| *bucket != 0 || *time_window != current_window - 2)
+            .returning(|_, _, _| Ok(vec![]));
+        mock_queries
+            .outbox_queries
+            .expect_delete_outbox_entry()
+            .withf(move |entry| entry.id == delivered_id)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        // The bucket 0 stops at the window of the failure, the other buckets move to the last
+        // closed window
+        mock_queries
+            .outbox_queries
+            .expect_update_outbox_cursor()
+            .with(eq(0), eq(current_window - 2))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock_queries
+            .outbox_queries
+            .expect_update_outbox_cursor()
+            .with(
+                function(|bucket: &i32| *bucket != 0),
+                eq(current_window - 1),
+            )
+            .times(OUTBOX_BUCKETS as usize - 1)
+            .returning(|_, _| Ok(()));
+
+        // The failure of the second entry stops the bucket (the third one is not delivered)
+        let memory_sink = Arc::new(MemorySink::default());
+        let sinks: Vec<Box<dyn OutboxSink>> = vec![
+            Box::new(SharedSink(memory_sink.clone())),
+            Box::new(FailingSink(failing_id)),
+        ];
+        let relayed = relay_outbox_entries(&mock_queries, &sinks, now).await?;
+
+        assert_eq!(relayed, 1);
+        let delivered = memory_sink.entries.lock().unwrap();
+        assert_eq!(
+            delivered.iter().map(|entry| entry.id).collect::<Vec<_>>(),
+            vec![delivered_id, failing_id]
+        );
+
+        Ok(())
+    }
+}
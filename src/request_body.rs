@@ -0,0 +1,322 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder, ZstdDecoder};
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, Request},
+};
+use futures::{future::BoxFuture, StreamExt, TryStreamExt};
+use tokio::io::AsyncRead;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tower::{BoxError, Layer, Service};
+
+use crate::{error::AppError, result::AppResult};
+
+/// Maximum sizes of the request bodies, after decompression
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RequestBodyLimits {
+    pub max_size: usize,
+    /// Imports run within the request (`/vehicle/import`), small enough for the request timeout
+    pub max_sync_import_size: usize,
+    /// Imports run as background jobs (`/jobs/import`)
+    pub max_import_size: usize,
+}
+
+impl RequestBodyLimits {
+    pub fn max_size_of(&self, path: &str) -> usize {
+        if path.trim_end_matches('/') == "/vehicle/import" {
+            self.max_sync_import_size
+        } else if path.ends_with("/import") {
+            self.max_import_size
+        } else {
+            self.max_size
+        }
+    }
+
+    /// Error message of the too large bodies of the path
+    pub fn too_large_message_of(&self, path: &str) -> &'static str {
+        if path.trim_end_matches('/') == "/vehicle/import" {
+            "Import body, submit large imports to /jobs/import"
+        } else {
+            "Request body"
+        }
+    }
+}
+
+/// Supported `Content-Encoding` of the request bodies
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+    Br,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// None for `identity`
+    pub fn from_header(value: &HeaderValue) -> AppResult<Option<Self>> {
+        let value = value
+            .to_str()
+            .map_err(|_| AppError::UnsupportedMediaType("Content-Encoding"))?;
+
+        match value.trim().to_ascii_lowercase().as_str() {
+            "identity" => Ok(None),
+            "gzip" | "x-gzip" => Ok(Some(ContentEncoding::Gzip)),
+            "deflate" => Ok(Some(ContentEncoding::Deflate)),
+            "br" => Ok(Some(ContentEncoding::Br)),
+            "zstd" => Ok(Some(ContentEncoding::Zstd)),
+            _ => Err(AppError::UnsupportedMediaType("Content-Encoding")),
+        }
+    }
+}
+
+/// True if the body could not be read because it is larger than the limit
+pub fn is_too_large(e: &BoxError) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&**e);
+    while let Some(error) = source {
+        if let Some(AppError::PayloadTooLarge(_)) = error.downcast_ref::<AppError>() {
+            return true;
+        }
+        source = error.source();
+    }
+
+    false
+}
+
+/// Decompress the body, e.g. with gzip (HTTP `deflate` is zlib)
+fn decompress(body: Body, encoding: ContentEncoding) -> Body {
+    let reader = StreamReader::new(body.map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+    let decoder: Pin<Box<dyn AsyncRead + Send>> = match encoding {
+        ContentEncoding::Gzip => Box::pin(GzipDecoder::new(reader)),
+        ContentEncoding::Deflate => Box::pin(ZlibDecoder::new(reader)),
+        ContentEncoding::Br => Box::pin(BrotliDecoder::new(reader)),
+        ContentEncoding::Zstd => Box::pin(ZstdDecoder::new(reader)),
+    };
+
+    Body::wrap_stream(ReaderStream::new(decoder))
+}
+
+/// Fail the body (with the given message) as soon as more than `max_size` bytes are read
+fn limit(body: Body, max_size: usize, too_large_message: &'static str) -> Body {
+    let mut size = 0;
+
+    Body::wrap_stream(body.map(move |chunk| {
+        let chunk = chunk.map_err(BoxError::from)?;
+        size += chunk.len();
+        if size > max_size {
+            return Err(BoxError::from(AppError::PayloadTooLarge(too_large_message)));
+        }
+
+        Ok(chunk)
+    }))
+}
+
+/// Decompressed and size-limited request
+///
+/// The requests with a too large `Content-Length` are rejected upfront, the other ones once
+/// the limit is reached while reading the body.
+fn prepare_request(request: Request<Body>, limits: RequestBodyLimits) -> AppResult<Request<Body>> {
+    let (mut parts, body) = request.into_parts();
+    let max_size = limits.max_size_of(parts.uri.path());
+    let too_large_message = limits.too_large_message_of(parts.uri.path());
+
+    if content_length(&parts.headers).map_or(false, |length| length > max_size) {
+        return Err(AppError::PayloadTooLarge(too_large_message));
+    }
+
+    let encoding = match parts.headers.get(header::CONTENT_ENCODING) {
+        Some(value) => ContentEncoding::from_header(value)?,
+        None => None,
+    };
+    let body = match encoding {
+        Some(encoding) => {
+            parts.headers.remove(header::CONTENT_ENCODING);
+            parts.headers.remove(header::CONTENT_LENGTH);
+            decompress(body, encoding)
+        }
+        None => body,
+    };
+
+    Ok(Request::from_parts(
+        parts,
+        limit(body, max_size, too_large_message),
+    ))
+}
+
+fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Tower layer decompressing the request bodies (`Content-Encoding`) and limiting their size
+#[derive(Clone, Debug)]
+pub struct RequestBodyLayer {
+    limits: RequestBodyLimits,
+}
+
+impl RequestBodyLayer {
+    pub fn new(limits: RequestBodyLimits) -> Self {
+        RequestBodyLayer { limits }
+    }
+}
+
+impl<S> Layer<S> for RequestBodyLayer {
+    type Service = RequestBody<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestBody {
+            inner,
+            limits: self.limits,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestBody<S> {
+    inner: S,
+    limits: RequestBodyLimits,
+}
+
+impl<S> Service<Request<Body>> for RequestBody<S>
+where
+    S: Service<Request<Body>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        match prepare_request(request, self.limits) {
+            Ok(request) => {
+                let future = self.inner.call(request);
+                Box::pin(async move { future.await.map_err(Into::into) })
+            }
+            Err(e) => Box::pin(futures::future::ready(Err(BoxError::from(e)))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::bufread::GzipEncoder;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    const LIMITS: RequestBodyLimits = RequestBodyLimits {
+        max_size: 16,
+        max_sync_import_size: 128,
+        max_import_size: 1024,
+    };
+
+    async fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        GzipEncoder::new(data)
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+        compressed
+    }
+
+    async fn read_body(request: Request<Body>) -> Result<Vec<u8>, BoxError> {
+        Ok(hyper::body::to_bytes(request.into_body()).await?.to_vec())
+    }
+
+    #[test]
+    fn test_content_encoding() {
+        assert_eq!(
+            ContentEncoding::from_header(&HeaderValue::from_static("GZIP")).unwrap(),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(
+            ContentEncoding::from_header(&HeaderValue::from_static("identity")).unwrap(),
+            None
+        );
+
+        // TODO: user assert_matches! when stable
+        assert!(matches!(
+            ContentEncoding::from_header(&HeaderValue::from_static("compress")),
+            Err(AppError::UnsupportedMediaType("Content-Encoding"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_decompress() {
+        let request = Request::builder()
+            .uri("/vehicle")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(gzip(b"{\"vin\":\"vin1\"}").await))
+            .unwrap();
+
+        let request = prepare_request(request, LIMITS).unwrap();
+        assert!(request.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(read_body(request).await.unwrap(), b"{\"vin\":\"vin1\"}");
+    }
+
+    #[tokio::test]
+    async fn test_limit() {
+        // Too large Content-Length
+        let request = Request::builder()
+            .uri("/vehicle")
+            .header(header::CONTENT_LENGTH, "17")
+            .body(Body::from(vec![b' '; 17]))
+            .unwrap();
+        assert!(matches!(
+            prepare_request(request, LIMITS),
+            Err(AppError::PayloadTooLarge("Request body"))
+        ));
+
+        // Small compressed body, too large once decompressed
+        let request = Request::builder()
+            .uri("/vehicle")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(gzip(&[b' '; 100]).await))
+            .unwrap();
+        let e = read_body(prepare_request(request, LIMITS).unwrap())
+            .await
+            .unwrap_err();
+        assert!(is_too_large(&e));
+
+        // Larger limits of the imports (the largest one for the import jobs)
+        for (path, size) in vec![("/vehicle/import", 100), ("/jobs/import", 1000)] {
+            let request = Request::builder()
+                .uri(path)
+                .body(Body::from(vec![b' '; size]))
+                .unwrap();
+            assert_eq!(
+                read_body(prepare_request(request, LIMITS).unwrap())
+                    .await
+                    .unwrap()
+                    .len(),
+                size
+            );
+        }
+
+        // Too large synchronous import => import jobs suggested
+        let request = Request::builder()
+            .uri("/vehicle/import")
+            .header(header::CONTENT_LENGTH, "1000")
+            .body(Body::from(vec![b' '; 1000]))
+            .unwrap();
+        assert!(matches!(
+            prepare_request(request, LIMITS),
+            Err(AppError::PayloadTooLarge(
+                "Import body, submit large imports to /jobs/import"
+            ))
+        ));
+    }
+}
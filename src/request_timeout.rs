@@ -0,0 +1,121 @@
+use std::{
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::http::{Method, Request};
+use futures::future::BoxFuture;
+use tower::{timeout::error::Elapsed, BoxError, Layer, Service};
+
+/// Time to respond to a request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// True for the uploads stored as they are received (e.g. `POST /jobs/import`), which can take
+/// longer than `REQUEST_TIMEOUT`
+pub fn is_upload(method: &Method, path: &str) -> bool {
+    method == Method::POST && path.trim_end_matches('/') == "/jobs/import"
+}
+
+/// Tower layer failing the requests not responded to in time, except the uploads
+#[derive(Clone, Debug)]
+pub struct RequestTimeoutLayer {
+    timeout: Duration,
+}
+
+impl RequestTimeoutLayer {
+    pub fn new(timeout: Duration) -> Self {
+        RequestTimeoutLayer { timeout }
+    }
+}
+
+impl<S> Layer<S> for RequestTimeoutLayer {
+    type Service = RequestTimeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestTimeout {
+            inner,
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestTimeout<S> {
+    inner: S,
+    timeout: Duration,
+}
+
+impl<S, B> Service<Request<B>> for RequestTimeout<S>
+where
+    S: Service<Request<B>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let timeout = if is_upload(request.method(), request.uri().path()) {
+            None
+        } else {
+            Some(self.timeout)
+        };
+        let future = self.inner.call(request);
+
+        Box::pin(async move {
+            match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, future).await {
+                    Ok(result) => result.map_err(Into::into),
+                    Err(_) => Err(BoxError::from(Elapsed::new())),
+                },
+                None => future.await.map_err(Into::into),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    #[test]
+    fn test_is_upload() {
+        assert!(is_upload(&Method::POST, "/jobs/import"));
+        assert!(!is_upload(&Method::GET, "/jobs/import"));
+        assert!(!is_upload(&Method::POST, "/jobs/export"));
+        assert!(!is_upload(&Method::POST, "/vehicle"));
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let slow = service_fn(|_: Request<()>| async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok::<_, BoxError>(())
+        });
+        let service = RequestTimeoutLayer::new(Duration::from_millis(10)).layer(slow);
+
+        let request = |method: Method, uri: &str| {
+            Request::builder().method(method).uri(uri).body(()).unwrap()
+        };
+
+        let e = service
+            .clone()
+            .oneshot(request(Method::POST, "/vehicle"))
+            .await
+            .unwrap_err();
+        assert!(e.is::<Elapsed>());
+
+        // Uploads are not limited in time
+        service
+            .oneshot(request(Method::POST, "/jobs/import"))
+            .await
+            .unwrap();
+    }
+}
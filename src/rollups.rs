@@ -0,0 +1,235 @@
+use std::collections::BTreeSet;
+
+use chrono::{NaiveDate, TimeZone, Utc};
+
+use crate::{
+    db::queries::{Queries, TelemetryQueries},
+    error::AppError,
+    model::telemetry::{AggregateInterval, SocAggregate, SocReading},
+    result::AppResult,
+};
+
+/// Intervals of the pre-computed rollups
+pub const ROLLUP_INTERVALS: [AggregateInterval; 2] =
+    [AggregateInterval::Hour, AggregateInterval::Day];
+
+/// Aggregate the readings (ordered by timestamp) into buckets of the given interval
+pub fn aggregate_soc_readings(
+    interval: AggregateInterval,
+    readings: &[SocReading],
+) -> Vec<SocAggregate> {
+    let mut aggregates: Vec<SocAggregate> = Vec::new();
+    let mut sum = 0i64;
+
+    for reading in readings {
+        let start = interval.bucket_start(reading.timestamp);
+        let soc = reading.soc_in_percent;
+
+        match aggregates.last_mut() {
+            Some(aggregate) if aggregate.start == start => {
+                aggregate.min = aggregate.min.min(soc);
+                aggregate.max = aggregate.max.max(soc);
+                aggregate.last = soc;
+                aggregate.count += 1;
+                sum += soc as i64;
+                aggregate.avg = sum as f64 / aggregate.count as f64;
+            }
+            _ => {
+                sum = soc as i64;
+                aggregates.push(SocAggregate {
+                    start,
+                    min: soc,
+                    max: soc,
+                    avg: soc as f64,
+                    first: soc,
+                    last: soc,
+                    count: 1,
+                });
+            }
+        }
+    }
+
+    aggregates
+}
+
+/// Refresh the rollups of the days touched by the new readings
+pub async fn refresh_soc_rollups<Q: Queries>(
+    queries: &Q,
+    vin: &str,
+    readings: &[SocReading],
+) -> AppResult<()> {
+    let days: BTreeSet<NaiveDate> = readings
+        .iter()
+        .map(|reading| reading.timestamp.naive_utc().date())
+        .collect();
+
+    for day in days {
+        rebuild_soc_rollups_of_day(queries, vin, day).await?;
+    }
+
+    Ok(())
+}
+
+/// Rebuild the rollups of all the days in [from, to], returns the number of rebuilt days
+pub async fn rebuild_soc_rollups<Q: Queries>(
+    queries: &Q,
+    vin: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> AppResult<usize> {
+    let mut count = 0;
+
+    for day in from.iter_days().take_while(|day| *day <= to) {
+        rebuild_soc_rollups_of_day(queries, vin, day).await?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Recompute the rollups of one day from the raw readings
+///
+/// Buckets never span several days, so this is idempotent and safe with re-sent readings.
+async fn rebuild_soc_rollups_of_day<Q: Queries>(
+    queries: &Q,
+    vin: &str,
+    day: NaiveDate,
+) -> AppResult<()> {
+    let from = day
+        .and_hms_opt(0, 0, 0)
+        .map(|start| Utc.from_utc_datetime(&start))
+        .ok_or(AppError::ConversionError("Day start"))?;
+    let to = from + chrono::Duration::days(1);
+
+    let readings = queries
+        .telemetry_queries()
+        .find_soc_readings(vin, from, to)
+        .await?;
+    if readings.is_empty() {
+        return Ok(());
+    }
+
+    for interval in ROLLUP_INTERVALS.iter() {
+        let aggregates = aggregate_soc_readings(*interval, &readings);
+        queries
+            .telemetry_queries()
+            .upsert_soc_aggregates(vin, *interval, &aggregates)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use mockall::predicate::{always, eq};
+
+    use super::*;
+    use crate::db::queries::{self};
+
+    fn reading(timestamp: &str, soc_in_percent: i32) -> SocReading {
+        SocReading {
+            timestamp: datetime(timestamp),
+            soc_in_percent,
+        }
+    }
+
+    fn datetime(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_aggregate_soc_readings() {
+        let readings = vec![
+            reading("2021-09-01T10:00:00Z", 50),
+            reading("2021-09-01T10:20:00Z", 40),
+            reading("2021-09-01T10:40:00Z", 45),
+            reading("2021-09-01T12:00:00Z", 80),
+        ];
+
+        assert_eq!(
+            aggregate_soc_readings(AggregateInterval::Hour, &readings),
+            vec![
+                SocAggregate {
+                    start: datetime("2021-09-01T10:00:00Z"),
+                    min: 40,
+                    max: 50,
+                    avg: 45.0,
+                    first: 50,
+                    last: 45,
+                    count: 3,
+                },
+                SocAggregate {
+                    start: datetime("2021-09-01T12:00:00Z"),
+                    min: 80,
+                    max: 80,
+                    avg: 80.0,
+                    first: 80,
+                    last: 80,
+                    count: 1,
+                },
+            ]
+        );
+
+        assert_eq!(
+            aggregate_soc_readings(AggregateInterval::Day, &readings),
+            vec![SocAggregate {
+                start: datetime("2021-09-01T00:00:00Z"),
+                min: 40,
+                max: 80,
+                avg: 53.75,
+                first: 50,
+                last: 80,
+                count: 4,
+            }]
+        );
+
+        assert!(aggregate_soc_readings(AggregateInterval::Day, &[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_soc_rollups() -> anyhow::Result<()> {
+        let day_readings = vec![
+            reading("2021-09-01T10:00:00Z", 50),
+            reading("2021-09-01T11:00:00Z", 40),
+        ];
+        let day_readings_clone = day_readings.clone();
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .telemetry_queries
+            .expect_find_soc_readings()
+            .with(
+                eq("vin"),
+                eq(datetime("2021-09-01T00:00:00Z")),
+                eq(datetime("2021-09-02T00:00:00Z")),
+            )
+            .times(1)
+            .returning(move |_, _, _| Ok(day_readings_clone.clone()));
+        mock_queries
+            .telemetry_queries
+            .expect_upsert_soc_aggregates()
+            .with(eq("vin"), eq(AggregateInterval::Hour), always())
+            .times(1)
+            .returning(|_, _, aggregates| {
+                assert_eq!(aggregates.len(), 2);
+                Ok(())
+            });
+        mock_queries
+            .telemetry_queries
+            .expect_upsert_soc_aggregates()
+            .with(eq("vin"), eq(AggregateInterval::Day), always())
+            .times(1)
+            .returning(|_, _, aggregates| {
+                assert_eq!(aggregates.len(), 1);
+                Ok(())
+            });
+
+        // Both new readings are in the same day => the day is rebuilt only once
+        refresh_soc_rollups(&mock_queries, "vin", &day_readings).await?;
+        refresh_soc_rollups(&mock_queries, "vin", &[]).await?;
+
+        Ok(())
+    }
+}
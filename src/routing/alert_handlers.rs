@@ -0,0 +1,291 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{self, Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    db::queries::{AlertQueries, Queries},
+    error::AppError,
+    model::alert::{Alert, AlertRule, AlertStatus, CreateAlertRule},
+    response::AppResponseResult,
+};
+
+#[derive(Deserialize, Default, Debug)]
+pub struct AlertsParams {
+    /// Only the alerts with this status (default: all)
+    pub status: Option<AlertStatus>,
+    /// Only the alerts of this vehicle (default: all)
+    pub vin: Option<String>,
+}
+
+#[tracing::instrument(err)]
+pub async fn post_alert_rule<Q: Queries>(
+    Json(payload): Json<CreateAlertRule>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    if !(0..=100).contains(&payload.soc_below_in_percent) {
+        return Err(AppError::InvalidInput("Invalid SoC threshold"));
+    }
+
+    let rule = AlertRule {
+        id: Uuid::new_v4(),
+        scope: payload.scope,
+        soc_below_in_percent: payload.soc_below_in_percent,
+        created_at: Utc::now(),
+    };
+    queries.alert_queries().create_alert_rule(&rule).await?;
+
+    Ok((StatusCode::CREATED, Json(rule)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_alert_rules<Q: Queries>(queries: extract::Extension<Arc<Q>>) -> AppResponseResult {
+    let rules = queries.alert_queries().find_alert_rules().await?;
+
+    Ok((StatusCode::OK, Json(rules)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn delete_alert_rule<Q: Queries>(
+    Path(id): Path<Uuid>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    queries.alert_queries().delete_alert_rule(id).await?;
+
+    Ok((StatusCode::OK, Json(())).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_alerts<Q: Queries>(
+    Query(params): Query<AlertsParams>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let alerts = match &params.vin {
+        Some(vin) => queries.alert_queries().find_vehicle_alerts(vin).await?,
+        None => queries.alert_queries().find_all_alerts().await?,
+    };
+
+    let alerts: Vec<Alert> = alerts
+        .into_iter()
+        .filter(|alert| params.status.map_or(true, |status| alert.status == status))
+        .collect();
+
+    Ok((StatusCode::OK, Json(alerts)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn acknowledge_alert<Q: Queries>(
+    Path((vin, id)): Path<(String, Uuid)>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    // TODO: use LWT to avoid concurrent modifications of the alert
+    let alert = queries.alert_queries().find_one_alert(&vin, id).await?;
+    let alert = match alert.status {
+        AlertStatus::Active => Alert {
+            status: AlertStatus::Acknowledged,
+            acknowledged_at: Some(Utc::now()),
+            ..alert
+        },
+        AlertStatus::Acknowledged => alert,
+        AlertStatus::Resolved => return Err(AppError::InvalidInput("Alert already resolved")),
+    };
+    queries.alert_queries().upsert_alert(&alert).await?;
+
+    Ok((StatusCode::OK, Json(alert)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn resolve_alert<Q: Queries>(
+    Path((vin, id)): Path<(String, Uuid)>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    // TODO: use LWT to avoid concurrent modifications of the alert
+    let alert = queries.alert_queries().find_one_alert(&vin, id).await?;
+    if alert.is_resolved() {
+        return Ok((StatusCode::OK, Json(alert)).into_response());
+    }
+
+    let alert = Alert {
+        status: AlertStatus::Resolved,
+        resolved_at: Some(Utc::now()),
+        ..alert
+    };
+    queries.alert_queries().upsert_alert(&alert).await?;
+
+    Ok((StatusCode::OK, Json(alert)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::{eq, function};
+
+    use super::*;
+    use crate::{
+        db::queries::{self},
+        model::alert::AlertScope,
+        routing::test_utils::to_bytes,
+    };
+
+    fn alert(status: AlertStatus) -> Alert {
+        Alert {
+            id: Uuid::nil(),
+            rule_id: Uuid::nil(),
+            vin: "vin".to_string(),
+            status,
+            soc_in_percent: 10,
+            fired_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+            acknowledged_at: None,
+            resolved_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_alert_rule_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .alert_queries
+            .expect_create_alert_rule()
+            .with(function(|rule: &AlertRule| {
+                rule.scope == AlertScope::Fleet && rule.soc_below_in_percent == 15
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let response = post_alert_rule(
+            Json(CreateAlertRule {
+                scope: AlertScope::Fleet,
+                soc_below_in_percent: 15,
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_post_alert_rule_invalid() {
+        let mock_queries = queries::MockQueries::default();
+
+        let response = post_alert_rule(
+            Json(CreateAlertRule {
+                scope: AlertScope::Fleet,
+                soc_below_in_percent: 101,
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_alerts_by_status() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .alert_queries
+            .expect_find_vehicle_alerts()
+            .with(eq("vin"))
+            .times(1)
+            .returning(|_| {
+                Ok(vec![
+                    alert(AlertStatus::Resolved),
+                    alert(AlertStatus::Active),
+                ])
+            });
+
+        let response = get_alerts(
+            Query(AlertsParams {
+                status: Some(AlertStatus::Active),
+                vin: Some("vin".to_string()),
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(Json(vec![alert(AlertStatus::Active)])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_acknowledge_alert_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .alert_queries
+            .expect_find_one_alert()
+            .with(eq("vin"), eq(Uuid::nil()))
+            .returning(|_, _| Ok(alert(AlertStatus::Active)));
+        mock_queries
+            .alert_queries
+            .expect_upsert_alert()
+            .with(function(|alert: &Alert| {
+                alert.status == AlertStatus::Acknowledged && alert.acknowledged_at.is_some()
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let response = acknowledge_alert(
+            Path(("vin".to_string(), Uuid::nil())),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_acknowledge_alert_resolved() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .alert_queries
+            .expect_find_one_alert()
+            .returning(|_, _| Ok(alert(AlertStatus::Resolved)));
+
+        let response = acknowledge_alert(
+            Path(("vin".to_string(), Uuid::nil())),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::InvalidInput("Alert already resolved")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_alert_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .alert_queries
+            .expect_find_one_alert()
+            .returning(|_, _| Ok(alert(AlertStatus::Acknowledged)));
+        mock_queries
+            .alert_queries
+            .expect_upsert_alert()
+            .with(function(|alert: &Alert| {
+                alert.status == AlertStatus::Resolved && alert.resolved_at.is_some()
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let response = resolve_alert(
+            Path(("vin".to_string(), Uuid::nil())),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
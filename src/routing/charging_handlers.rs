@@ -0,0 +1,545 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{self, Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    charging,
+    db::queries::{ChargingQueries, Queries, VehicleQueries},
+    error::AppError,
+    ev,
+    model::{
+        charging::{ChargingSession, StartChargingSession, UpdateChargingSession},
+        vehicle::Engine,
+    },
+    response::AppResponseResult,
+    result::AppResult,
+};
+
+/// Maximum time range of charging session queries
+const MAX_RANGE_DAYS: i64 = 366;
+
+#[derive(Deserialize, Default, Debug)]
+pub struct ChargingRangeParams {
+    /// Default: 30 days before `to`
+    pub from: Option<DateTime<Utc>>,
+    /// Default: now
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl ChargingRangeParams {
+    fn time_range(&self) -> AppResult<(DateTime<Utc>, DateTime<Utc>)> {
+        let to = self.to.unwrap_or_else(Utc::now);
+        let from = self.from.unwrap_or(to - chrono::Duration::days(30));
+
+        if from >= to {
+            return Err(AppError::InvalidInput("Empty charging range"));
+        }
+        if to - from > chrono::Duration::days(MAX_RANGE_DAYS) {
+            return Err(AppError::InvalidInput("Charging range too large"));
+        }
+
+        Ok((from, to))
+    }
+}
+
+fn is_valid_soc(soc_in_percent: i32) -> bool {
+    (0..=100).contains(&soc_in_percent)
+}
+
+fn apply_update(session: &mut ChargingSession, update: UpdateChargingSession) -> AppResult<()> {
+    if session.is_ended() {
+        return Err(AppError::InvalidInput("Charging session already ended"));
+    }
+
+    if let Some(soc_in_percent) = update.soc_in_percent {
+        if !is_valid_soc(soc_in_percent) {
+            return Err(AppError::InvalidInput("SoC must be between 0 and 100"));
+        }
+        session.end_soc_in_percent = Some(soc_in_percent);
+    }
+    if let Some(energy_added_in_kwh) = update.energy_added_in_kwh {
+        if energy_added_in_kwh < 0.0 {
+            return Err(AppError::InvalidInput("Energy must be positive"));
+        }
+        session.energy_added_in_kwh = energy_added_in_kwh;
+    }
+    if let Some(power_in_kw) = update.power_in_kw {
+        session.max_power_in_kw = Some(
+            session
+                .max_power_in_kw
+                .map_or(power_in_kw, |max| max.max(power_in_kw)),
+        );
+    }
+    if update.location.is_some() {
+        session.location = update.location;
+    }
+
+    Ok(())
+}
+
+/// Ensure that the vehicle can be found, and can be charged
+async fn ensure_chargeable_vehicle<Q: Queries>(queries: &Q, vin: &str) -> AppResult<()> {
+    let vehicle = queries.vehicle_queries().find_one_vehicle(vin).await?;
+    if vehicle.engine == Engine::Combustion {
+        return Err(AppError::InvalidInput(
+            "Combustion vehicle cannot be charged",
+        ));
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(err)]
+pub async fn start_charging_session<Q: Queries>(
+    Path(vin): Path<String>,
+    Json(payload): Json<StartChargingSession>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    if !is_valid_soc(payload.start_soc_in_percent) {
+        return Err(AppError::InvalidInput("SoC must be between 0 and 100"));
+    }
+
+    ensure_chargeable_vehicle(queries.0.as_ref(), &vin).await?;
+
+    let session = ChargingSession {
+        id: Uuid::new_v4(),
+        vin,
+        start_time: payload.start_time.unwrap_or_else(Utc::now),
+        end_time: None,
+        start_soc_in_percent: payload.start_soc_in_percent,
+        end_soc_in_percent: None,
+        energy_added_in_kwh: 0.0,
+        max_power_in_kw: None,
+        location: payload.location,
+        detected: false,
+    };
+    queries
+        .charging_queries()
+        .upsert_charging_session(&session)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(session)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_charging_sessions<Q: Queries>(
+    Path(vin): Path<String>,
+    Query(params): Query<ChargingRangeParams>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let (from, to) = params.time_range()?;
+
+    // Ensure that the vehicle can be found
+    let _ = queries.vehicle_queries().find_one_vehicle(&vin).await?;
+
+    let sessions = queries
+        .charging_queries()
+        .find_charging_sessions(&vin, from, to)
+        .await?;
+
+    Ok((StatusCode::OK, Json(sessions)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_charging_session<Q: Queries>(
+    Path((vin, id)): Path<(String, Uuid)>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    ensure_chargeable_vehicle(queries.0.as_ref(), &vin).await?;
+
+    let session = queries
+        .charging_queries()
+        .find_one_charging_session(&vin, id)
+        .await?;
+
+    Ok((StatusCode::OK, Json(session)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn put_charging_session<Q: Queries>(
+    Path((vin, id)): Path<(String, Uuid)>,
+    Json(payload): Json<UpdateChargingSession>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    if payload.end_time.is_some() {
+        return Err(AppError::InvalidInput("End time only allowed when ending"));
+    }
+    ensure_chargeable_vehicle(queries.0.as_ref(), &vin).await?;
+
+    // TODO: use LWT to avoid concurrent updates
+    let mut session = queries
+        .charging_queries()
+        .find_one_charging_session(&vin, id)
+        .await?;
+    apply_update(&mut session, payload)?;
+    queries
+        .charging_queries()
+        .upsert_charging_session(&session)
+        .await?;
+
+    Ok((StatusCode::OK, Json(session)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn end_charging_session<Q: Queries>(
+    Path((vin, id)): Path<(String, Uuid)>,
+    Json(payload): Json<UpdateChargingSession>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    ensure_chargeable_vehicle(queries.0.as_ref(), &vin).await?;
+
+    // TODO: use LWT to avoid concurrent updates
+    let mut session = queries
+        .charging_queries()
+        .find_one_charging_session(&vin, id)
+        .await?;
+
+    let end_time = payload.end_time.unwrap_or_else(Utc::now);
+    if end_time < session.start_time {
+        return Err(AppError::InvalidInput("End time before start time"));
+    }
+    apply_update(&mut session, payload)?;
+    session.end_time = Some(end_time);
+
+    queries
+        .charging_queries()
+        .upsert_charging_session(&session)
+        .await?;
+    ev::record_battery_health_from_charging_session(queries.0.as_ref(), &session).await?;
+
+    Ok((StatusCode::OK, Json(session)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_vehicle_charging_summary<Q: Queries>(
+    Path(vin): Path<String>,
+    Query(params): Query<ChargingRangeParams>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let (from, to) = params.time_range()?;
+
+    // Ensure that the vehicle can be found
+    let _ = queries.vehicle_queries().find_one_vehicle(&vin).await?;
+
+    let sessions = queries
+        .charging_queries()
+        .find_charging_sessions(&vin, from, to)
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(charging::summarize_charging_sessions(&sessions)),
+    )
+        .into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_fleet_charging_summary<Q: Queries>(
+    Query(params): Query<ChargingRangeParams>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let (from, to) = params.time_range()?;
+
+    let sessions = queries
+        .charging_queries()
+        .find_all_charging_sessions(from, to)
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(charging::summarize_charging_sessions(&sessions)),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::{always, eq, function};
+
+    use super::*;
+    use crate::{
+        db::queries::{self},
+        model::{charging::ChargingSummary, vehicle::Vehicle},
+        routing::test_utils::to_bytes,
+    };
+
+    fn datetime(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn vehicle(engine: Engine) -> Vehicle {
+        Vehicle {
+            vin: "vin".to_string(),
+            owner: None,
+            engine,
+            ev_data: None,
+        }
+    }
+
+    fn session() -> ChargingSession {
+        ChargingSession {
+            id: Uuid::nil(),
+            vin: "vin".to_string(),
+            start_time: datetime("2021-09-01T10:00:00Z"),
+            end_time: None,
+            start_soc_in_percent: 20,
+            end_soc_in_percent: Some(50),
+            energy_added_in_kwh: 15.0,
+            max_power_in_kw: Some(11.0),
+            location: None,
+            detected: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_start_charging_session_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin"))
+            .returning(|_| Ok(vehicle(Engine::Phev)));
+        mock_queries
+            .charging_queries
+            .expect_upsert_charging_session()
+            .with(function(|session: &ChargingSession| {
+                session.vin == "vin"
+                    && session.start_soc_in_percent == 20
+                    && !session.is_ended()
+                    && !session.detected
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let response = start_charging_session(
+            Path("vin".to_string()),
+            Json(StartChargingSession {
+                start_time: Some(datetime("2021-09-01T10:00:00Z")),
+                start_soc_in_percent: 20,
+                location: Some("Home".to_string()),
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_start_charging_session_combustion_vehicle() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .returning(|_| Ok(vehicle(Engine::Combustion)));
+        mock_queries
+            .charging_queries
+            .expect_upsert_charging_session()
+            .times(0);
+
+        let response = start_charging_session(
+            Path("vin".to_string()),
+            Json(StartChargingSession {
+                start_time: None,
+                start_soc_in_percent: 20,
+                location: None,
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::InvalidInput(
+                "Combustion vehicle cannot be charged"
+            ))
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_charging_session_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin"))
+            .returning(|_| Ok(vehicle(Engine::Ev)));
+        mock_queries
+            .charging_queries
+            .expect_find_one_charging_session()
+            .with(eq("vin"), eq(Uuid::nil()))
+            .returning(|_, _| Ok(session()));
+        mock_queries
+            .charging_queries
+            .expect_upsert_charging_session()
+            .with(eq(ChargingSession {
+                end_soc_in_percent: Some(60),
+                energy_added_in_kwh: 20.0,
+                max_power_in_kw: Some(11.0),
+                ..session()
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let response = put_charging_session(
+            Path(("vin".to_string(), Uuid::nil())),
+            Json(UpdateChargingSession {
+                soc_in_percent: Some(60),
+                energy_added_in_kwh: Some(20.0),
+                power_in_kw: Some(7.4),
+                ..Default::default()
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_put_charging_session_vehicle_not_found() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .returning(|_| Err(AppError::NotFound("Vehicle")));
+        mock_queries
+            .charging_queries
+            .expect_upsert_charging_session()
+            .times(0);
+
+        let response = put_charging_session(
+            Path(("vin".to_string(), Uuid::nil())),
+            Json(UpdateChargingSession {
+                soc_in_percent: Some(60),
+                ..Default::default()
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_end_charging_session_ok() {
+        let ended_session = ChargingSession {
+            end_time: Some(datetime("2021-09-01T12:00:00Z")),
+            end_soc_in_percent: Some(80),
+            ..session()
+        };
+        let ended_session_clone = ended_session.clone();
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .charging_queries
+            .expect_find_one_charging_session()
+            .returning(|_, _| Ok(session()));
+        mock_queries
+            .charging_queries
+            .expect_upsert_charging_session()
+            .with(eq(ended_session_clone))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        // Unknown nominal capacity => no battery health measurement
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin"))
+            .returning(|_| Ok(vehicle(Engine::Ev)));
+        mock_queries
+            .ev_queries
+            .expect_insert_battery_health()
+            .times(0);
+
+        let response = end_charging_session(
+            Path(("vin".to_string(), Uuid::nil())),
+            Json(UpdateChargingSession {
+                end_time: Some(datetime("2021-09-01T12:00:00Z")),
+                soc_in_percent: Some(80),
+                ..Default::default()
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(Json(ended_session)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_end_charging_session_already_ended() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .returning(|_| Ok(vehicle(Engine::Ev)));
+        mock_queries
+            .charging_queries
+            .expect_find_one_charging_session()
+            .returning(|_, _| {
+                Ok(ChargingSession {
+                    end_time: Some(datetime("2021-09-01T12:00:00Z")),
+                    ..session()
+                })
+            });
+        mock_queries
+            .charging_queries
+            .expect_upsert_charging_session()
+            .times(0);
+
+        let response = end_charging_session(
+            Path(("vin".to_string(), Uuid::nil())),
+            Json(UpdateChargingSession::default()),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::InvalidInput("Charging session already ended")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_fleet_charging_summary_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .charging_queries
+            .expect_find_all_charging_sessions()
+            .with(always(), eq(datetime("2021-10-01T00:00:00Z")))
+            .times(1)
+            .returning(|_, _| Ok(vec![session(), session()]));
+
+        let response = get_fleet_charging_summary(
+            Query(ChargingRangeParams {
+                from: None,
+                to: Some(datetime("2021-10-01T00:00:00Z")),
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let summary: ChargingSummary = serde_json::from_slice(&to_bytes(response).await).unwrap();
+        assert_eq!(summary.session_count, 2);
+        assert_eq!(summary.total_energy_in_kwh, 30.0);
+        assert_eq!(summary.weeks.len(), 1);
+    }
+}
@@ -0,0 +1,446 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{self, Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    db::queries::{EvQueries, Queries, VehicleQueries},
+    error::AppError,
+    ev,
+    model::{
+        ev::{
+            BatteryHealth, BatteryHealthEntry, ConfigureConsumption, ReportBatteryCapacity, Trip,
+        },
+        vehicle::{Engine, EvData},
+    },
+    response::AppResponseResult,
+    result::AppResult,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct EvEstimateParams {
+    /// Charger power, between 0.1 and 1000 kW (default: 11 kW)
+    #[serde(default = "default_charger_kw")]
+    pub charger_kw: f64,
+    /// SoC to charge to (default: 80%)
+    #[serde(default = "default_target_soc")]
+    pub target_soc: i32,
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct FleetBatteryHealthParams {
+    /// SoH in percent below which vehicles are flagged (default: from the config)
+    pub alert_threshold: Option<f64>,
+}
+
+fn default_charger_kw() -> f64 {
+    11.0
+}
+
+fn default_target_soc() -> i32 {
+    ev::TAPER_START_SOC
+}
+
+/// Find the EV data of a vehicle with a battery
+async fn find_ev_data<Q: Queries>(queries: &Q, vin: &str) -> AppResult<EvData> {
+    let vehicle = queries.vehicle_queries().find_one_vehicle(vin).await?;
+    if vehicle.engine == Engine::Combustion {
+        return Err(AppError::InvalidInput("Combustion vehicle has no battery"));
+    }
+
+    vehicle.ev_data.ok_or(AppError::NotFound("EV data"))
+}
+
+#[tracing::instrument(err)]
+pub async fn get_ev_estimate<Q: Queries>(
+    Path(vin): Path<String>,
+    Query(params): Query<EvEstimateParams>,
+    queries: extract::Extension<Arc<Q>>,
+    config: extract::Extension<Arc<Config>>,
+) -> AppResponseResult {
+    if !(ev::MIN_CHARGER_IN_KW..=ev::MAX_CHARGER_IN_KW).contains(&params.charger_kw) {
+        return Err(AppError::InvalidInput(
+            "Charger power must be between 0.1 and 1000 kW",
+        ));
+    }
+
+    let ev_data = find_ev_data(queries.0.as_ref(), &vin).await?;
+    let consumption = queries.ev_queries().find_ev_consumption(&vin).await?;
+
+    let estimate = ev::estimate(
+        &ev_data,
+        &consumption,
+        config.default_consumption_in_kwh_per_100km,
+        params.charger_kw,
+        params.target_soc,
+    )?;
+
+    Ok((StatusCode::OK, Json(estimate)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_ev_consumption<Q: Queries>(
+    Path(vin): Path<String>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let _ = find_ev_data(queries.0.as_ref(), &vin).await?;
+    let consumption = queries.ev_queries().find_ev_consumption(&vin).await?;
+
+    Ok((StatusCode::OK, Json(consumption)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn put_ev_consumption<Q: Queries>(
+    Path(vin): Path<String>,
+    Json(payload): Json<ConfigureConsumption>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    if payload
+        .consumption_in_kwh_per_100km
+        .map_or(false, |consumption| consumption <= 0.0)
+    {
+        return Err(AppError::InvalidInput("Consumption must be positive"));
+    }
+
+    let _ = find_ev_data(queries.0.as_ref(), &vin).await?;
+    queries
+        .ev_queries()
+        .configure_ev_consumption(&vin, payload.consumption_in_kwh_per_100km)
+        .await?;
+
+    Ok((StatusCode::OK, Json(())).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn post_ev_trip<Q: Queries>(
+    Path(vin): Path<String>,
+    Json(trip): Json<Trip>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    if !trip.is_valid() {
+        return Err(AppError::InvalidInput("Invalid trip"));
+    }
+
+    let _ = find_ev_data(queries.0.as_ref(), &vin).await?;
+    queries.ev_queries().add_ev_trip(&vin, &trip).await?;
+
+    Ok((StatusCode::CREATED, Json(())).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn post_battery_health<Q: Queries>(
+    Path(vin): Path<String>,
+    Json(payload): Json<ReportBatteryCapacity>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    if payload.usable_capacity_in_kwh <= 0.0 {
+        return Err(AppError::InvalidInput("Capacity must be positive"));
+    }
+
+    let ev_data = find_ev_data(queries.0.as_ref(), &vin).await?;
+    if ev_data.battery_capacity_in_kwh <= 0 {
+        return Err(AppError::InvalidInput("Unknown battery capacity"));
+    }
+
+    let health = BatteryHealth {
+        vin,
+        measured_at: payload.measured_at.unwrap_or_else(Utc::now),
+        usable_capacity_in_kwh: payload.usable_capacity_in_kwh,
+        nominal_capacity_in_kwh: ev_data.battery_capacity_in_kwh,
+    };
+    queries.ev_queries().insert_battery_health(&health).await?;
+
+    Ok((StatusCode::CREATED, Json(BatteryHealthEntry::from(&health))).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_battery_health<Q: Queries>(
+    Path(vin): Path<String>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let _ = find_ev_data(queries.0.as_ref(), &vin).await?;
+
+    let history: Vec<BatteryHealthEntry> = queries
+        .ev_queries()
+        .find_battery_health(&vin)
+        .await?
+        .iter()
+        .map(BatteryHealthEntry::from)
+        .collect();
+
+    Ok((StatusCode::OK, Json(history)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_fleet_battery_health<Q: Queries>(
+    Query(params): Query<FleetBatteryHealthParams>,
+    queries: extract::Extension<Arc<Q>>,
+    config: extract::Extension<Arc<Config>>,
+) -> AppResponseResult {
+    let alert_threshold = params
+        .alert_threshold
+        .unwrap_or(config.battery_health_alert_threshold_in_percent);
+
+    let measurements = queries.ev_queries().find_all_battery_health().await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(ev::rank_battery_health(&measurements, alert_threshold)),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::{
+        db::queries::{self},
+        model::{
+            ev::{ConsumptionSource, EvConsumption, EvEstimate, VehicleBatteryHealth},
+            vehicle::Vehicle,
+        },
+        routing::test_utils::to_bytes,
+    };
+
+    fn vehicle(engine: Engine) -> Vehicle {
+        Vehicle {
+            vin: "vin".to_string(),
+            owner: None,
+            engine,
+            ev_data: Some(EvData {
+                battery_capacity_in_kwh: 50,
+                soc_in_percent: 20,
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_ev_estimate_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin"))
+            .returning(|_| Ok(vehicle(Engine::Ev)));
+        mock_queries
+            .ev_queries
+            .expect_find_ev_consumption()
+            .with(eq("vin"))
+            .returning(|_| {
+                Ok(EvConsumption {
+                    configured_in_kwh_per_100km: None,
+                    trip_distance_in_km: 400.0,
+                    trip_energy_in_kwh: 60.0,
+                })
+            });
+
+        let response = get_ev_estimate(
+            Path("vin".to_string()),
+            Query(EvEstimateParams {
+                charger_kw: 10.0,
+                target_soc: 80,
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(Config::default())),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(Json(EvEstimate {
+                soc_in_percent: 20,
+                remaining_energy_in_kwh: 10.0,
+                consumption_in_kwh_per_100km: 15.0,
+                consumption_source: ConsumptionSource::Learned,
+                range_in_km: 10.0 / 15.0 * 100.0,
+                charger_in_kw: 10.0,
+                target_soc_in_percent: 80,
+                energy_to_target_in_kwh: 30.0,
+                charge_time_in_minutes: 180,
+            }))
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_ev_estimate_charger_out_of_range() {
+        for charger_kw in vec![1e-12, 0.0, 1001.0, f64::NAN] {
+            let mock_queries = queries::MockQueries::default();
+
+            let response = get_ev_estimate(
+                Path("vin".to_string()),
+                Query(EvEstimateParams {
+                    charger_kw,
+                    target_soc: 80,
+                }),
+                extract::Extension(Arc::new(mock_queries)),
+                extract::Extension(Arc::new(Config::default())),
+            )
+            .await
+            .into_response();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(
+                to_bytes(response).await,
+                to_bytes(AppError::InvalidInput(
+                    "Charger power must be between 0.1 and 1000 kW"
+                ))
+                .await
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_ev_estimate_combustion_vehicle() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .returning(|_| {
+                Ok(Vehicle {
+                    ev_data: None,
+                    ..vehicle(Engine::Combustion)
+                })
+            });
+
+        let response = get_ev_estimate(
+            Path("vin".to_string()),
+            Query(EvEstimateParams {
+                charger_kw: 11.0,
+                target_soc: 80,
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(Config::default())),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::InvalidInput("Combustion vehicle has no battery")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_ev_trip_ok() {
+        let trip = Trip {
+            distance_in_km: 42.0,
+            energy_used_in_kwh: 7.5,
+        };
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .returning(|_| Ok(vehicle(Engine::Phev)));
+        mock_queries
+            .ev_queries
+            .expect_add_ev_trip()
+            .with(eq("vin"), eq(trip.clone()))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let response = post_ev_trip(
+            Path("vin".to_string()),
+            Json(trip),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_put_ev_consumption_invalid() {
+        let mock_queries = queries::MockQueries::default();
+
+        let response = put_ev_consumption(
+            Path("vin".to_string()),
+            Json(ConfigureConsumption {
+                consumption_in_kwh_per_100km: Some(-1.0),
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_post_battery_health_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .returning(|_| Ok(vehicle(Engine::Ev)));
+        mock_queries
+            .ev_queries
+            .expect_insert_battery_health()
+            .with(eq(BatteryHealth {
+                vin: "vin".to_string(),
+                measured_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+                usable_capacity_in_kwh: 45.0,
+                nominal_capacity_in_kwh: 50,
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let response = post_battery_health(
+            Path("vin".to_string()),
+            Json(ReportBatteryCapacity {
+                measured_at: Some("2021-09-01T10:00:00Z".parse().unwrap()),
+                usable_capacity_in_kwh: 45.0,
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_get_fleet_battery_health_default_threshold() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .ev_queries
+            .expect_find_all_battery_health()
+            .times(1)
+            .returning(|| {
+                Ok(vec![BatteryHealth {
+                    vin: "vin".to_string(),
+                    measured_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+                    usable_capacity_in_kwh: 39.0,
+                    nominal_capacity_in_kwh: 50,
+                }])
+            });
+
+        let response = get_fleet_battery_health(
+            Query(FleetBatteryHealthParams::default()),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(Config::default())),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(Json(vec![VehicleBatteryHealth {
+                vin: "vin".to_string(),
+                soh_in_percent: 78.0,
+                degradation_in_percent_per_year: None,
+                measurement_count: 1,
+                alert: true,
+            }]))
+            .await
+        );
+    }
+}
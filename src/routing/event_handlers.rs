@@ -0,0 +1,142 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{self, Query},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    db::queries::Queries, events::EventBus, model::event::VehicleEvent, shutdown::ShutdownSignal,
+};
+
+/// Interval of the comments keeping idle connections open
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 15;
+
+#[derive(Deserialize, Default, Debug)]
+pub struct VehicleEventsParams {
+    /// Only the events of this vehicle (default: all)
+    pub vin: Option<String>,
+}
+
+/// Events of the bus following `last_event_id` (buffered ones first), then the live ones
+///
+/// The stream ends if the subscriber lags behind the bus or on shutdown (so that the connection
+/// can be drained), the client is then expected to reconnect with the id of the last event it
+/// has received.
+pub fn vehicle_event_stream(
+    event_bus: &EventBus,
+    vin: Option<String>,
+    last_event_id: Option<u64>,
+    mut shutdown: ShutdownSignal,
+) -> impl Stream<Item = VehicleEvent> {
+    let (missed, receiver) = event_bus.subscribe(last_event_id);
+
+    let live = futures::stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(event) => Some((event, receiver)),
+            Err(RecvError::Lagged(count)) => {
+                tracing::warn!("vehicle event subscriber lagged by {} event(s)", count);
+                None
+            }
+            Err(RecvError::Closed) => None,
+        }
+    });
+
+    futures::stream::iter(missed)
+        .chain(live)
+        .filter(move |event| {
+            futures::future::ready(vin.as_ref().map_or(true, |vin| event.vin == *vin))
+        })
+        .take_until(async move {
+            shutdown.wait().await;
+        })
+}
+
+#[tracing::instrument(skip(queries, shutdown))]
+pub async fn get_vehicle_events<Q: Queries>(
+    Query(params): Query<VehicleEventsParams>,
+    headers: HeaderMap,
+    queries: extract::Extension<Arc<Q>>,
+    shutdown: extract::Extension<ShutdownSignal>,
+) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
+    // Resume after the last event received before a disconnection
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    let stream = vehicle_event_stream(queries.event_bus(), params.vin, last_event_id, shutdown.0)
+        .map(|event| {
+            Event::default()
+                .id(event.id.to_string())
+                .event(event.event_type.to_string())
+                .json_data(&event)
+        });
+
+    Sse::new(stream).keep_alive(
+        KeepAlive::new()
+            .interval(Duration::from_secs(HEARTBEAT_INTERVAL_SECS))
+            .text("heartbeat"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::event::VehicleEventType, shutdown::Shutdown};
+
+    #[tokio::test]
+    async fn test_vehicle_event_stream() {
+        let event_bus = EventBus::new(8);
+        let shutdown = Shutdown::new();
+        let _ = event_bus.publish(VehicleEventType::Created, "vin1", None);
+        let _ = event_bus.publish(VehicleEventType::Created, "vin2", None);
+
+        // Replay after the first event, then live events, filtered by VIN
+        let stream = vehicle_event_stream(
+            &event_bus,
+            Some("vin2".to_string()),
+            Some(1),
+            shutdown.signal(),
+        );
+        let _ = event_bus.publish(VehicleEventType::Deleted, "vin1", None);
+        let _ = event_bus.publish(VehicleEventType::Deleted, "vin2", None);
+
+        let events: Vec<(u64, VehicleEventType)> = stream
+            .take(2)
+            .map(|event| (event.id, event.event_type))
+            .collect()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                (2, VehicleEventType::Created),
+                (4, VehicleEventType::Deleted)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vehicle_event_stream_shutdown() {
+        let event_bus = EventBus::new(8);
+        let shutdown = Shutdown::new();
+        let _ = event_bus.publish(VehicleEventType::Created, "vin1", None);
+
+        let mut stream = Box::pin(vehicle_event_stream(
+            &event_bus,
+            None,
+            Some(0),
+            shutdown.signal(),
+        ));
+        assert_eq!(stream.next().await.map(|event| event.id), Some(1));
+
+        // Ends on shutdown, even with pending events
+        let _ = event_bus.publish(VehicleEventType::Deleted, "vin1", None);
+        shutdown.trigger(tokio::time::Instant::now());
+        assert_eq!(stream.next().await, None);
+    }
+}
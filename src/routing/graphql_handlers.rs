@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use async_graphql::http::graphiql_source;
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::{
+    extract,
+    response::{Html, IntoResponse},
+};
+
+use crate::{
+    auth::RequestApiKey,
+    config::Config,
+    db::queries::Queries,
+    graphql::{self, GraphQLSchema},
+};
+
+pub async fn post_graphql<Q: Queries>(
+    api_key: RequestApiKey,
+    request: GraphQLRequest,
+    schema: extract::Extension<GraphQLSchema<Q>>,
+    config: extract::Extension<Arc<Config>>,
+    queries: extract::Extension<Arc<Q>>,
+) -> GraphQLResponse {
+    let (vehicle_loader, latest_soc_loader) = graphql::create_loaders(queries.0.clone());
+
+    schema
+        .execute(
+            request
+                .into_inner()
+                .data(vehicle_loader)
+                .data(latest_soc_loader)
+                .data(api_key)
+                .data(config.0),
+        )
+        .await
+        .into()
+}
+
+/// GraphiQL playground
+pub async fn get_graphiql() -> impl IntoResponse {
+    Html(graphiql_source("/graphql", None))
+}
@@ -0,0 +1,44 @@
+use std::sync::{Arc, RwLock};
+
+use axum::{extract, http::StatusCode, response::IntoResponse};
+
+use crate::{error::AppError, response::AppResponseResult, state::State};
+
+/// Liveness probe
+pub async fn get_live() -> AppResponseResult {
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Readiness probe, failing as soon as the shutdown starts (before the connections are drained)
+pub async fn get_ready(shared_state: extract::Extension<Arc<RwLock<State>>>) -> AppResponseResult {
+    if shared_state
+        .read()
+        .expect("state lock poisoned")
+        .shutting_down
+    {
+        return Err(AppError::ServiceUnavailable("Shutting down"));
+    }
+
+    Ok(StatusCode::OK.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get_ready() {
+        let shared_state = Arc::new(RwLock::new(State::default()));
+
+        let response = get_ready(extract::Extension(shared_state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        shared_state.write().unwrap().shutting_down = true;
+        let response = get_ready(extract::Extension(shared_state))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
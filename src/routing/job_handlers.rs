@@ -0,0 +1,178 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{self, Path, Query},
+    http::{self, header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use futures::TryStreamExt;
+use uuid::Uuid;
+
+use crate::{
+    db::queries::{JobQueries, Queries},
+    error::AppError,
+    import::{self, ImportFormat},
+    jobs,
+    response::AppResponseResult,
+    routing::vehicle_handlers::{ExportVehiclesParams, ImportVehiclesParams},
+};
+
+/// Submit a vehicle import (same body as `POST /vehicle/import`), processed in the background
+#[tracing::instrument(skip(body), err)]
+pub async fn post_import_job<Q: Queries>(
+    Query(params): Query<ImportVehiclesParams>,
+    headers: HeaderMap,
+    body: extract::BodyStream,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let format = ImportFormat::from_headers(&headers)?;
+
+    let job = jobs::create_import_job(
+        queries.0.clone(),
+        format,
+        import::split_lines(body),
+        params.dry_run,
+    )
+    .await?;
+
+    Ok((StatusCode::ACCEPTED, Json(job)).into_response())
+}
+
+/// Submit a vehicle export (NDJSON or CSV), its output is then read with `GET /jobs/:id/output`
+#[tracing::instrument(err)]
+pub async fn post_export_job<Q: Queries>(
+    Query(params): Query<ExportVehiclesParams>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let job =
+        jobs::create_export_job(queries.0.as_ref(), params.format, params.engine_type).await?;
+
+    Ok((StatusCode::ACCEPTED, Json(job)).into_response())
+}
+
+/// Output of a succeeded export job (streamed)
+#[tracing::instrument(err)]
+pub async fn get_job_output<Q: Queries>(
+    Path(id): Path<Uuid>,
+    queries: extract::Extension<Arc<Q>>,
+) -> Result<http::Response<Body>, AppError> {
+    let job = queries.job_queries().find_one_job(id).await?;
+    let (format, output) = jobs::job_output(queries.0.clone(), &job)?;
+
+    let response = http::Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"vehicles.{}\"", format.to_string()),
+        )
+        .body(Body::wrap_stream(output.map_ok(Bytes::from)))
+        .map_err(anyhow::Error::from)?;
+
+    Ok(response)
+}
+
+#[tracing::instrument(err)]
+pub async fn get_job<Q: Queries>(
+    Path(id): Path<Uuid>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let job = queries.job_queries().find_one_job(id).await?;
+
+    Ok((StatusCode::OK, Json(job)).into_response())
+}
+
+/// Request the cancellation of an unfinished job (applied before its next chunk)
+#[tracing::instrument(err)]
+pub async fn cancel_job<Q: Queries>(
+    Path(id): Path<Uuid>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let job = queries.job_queries().find_one_job(id).await?;
+    if job.status.is_finished() {
+        return Err(AppError::InvalidInput("Finished job"));
+    }
+
+    queries.job_queries().cancel_job(id).await?;
+    let job = queries.job_queries().find_one_job(id).await?;
+
+    Ok((StatusCode::ACCEPTED, Json(job)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::{
+        db::queries,
+        model::job::{Job, JobKind, JobStatus},
+        routing::test_utils::to_bytes,
+    };
+
+    fn job(status: JobStatus) -> Job {
+        Job {
+            id: Uuid::nil(),
+            kind: JobKind::Import,
+            status,
+            params: serde_json::json!({}),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            total_chunks: 1,
+            processed_chunks: 0,
+            cancel_requested: false,
+            result: None,
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_job_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .job_queries
+            .expect_find_one_job()
+            .with(eq(Uuid::nil()))
+            .times(2)
+            .returning(|_| Ok(job(JobStatus::Running)));
+        mock_queries
+            .job_queries
+            .expect_cancel_job()
+            .with(eq(Uuid::nil()))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let response = cancel_job(
+            Path(Uuid::nil()),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_job_finished() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .job_queries
+            .expect_find_one_job()
+            .returning(|_| Ok(job(JobStatus::Succeeded)));
+        mock_queries.job_queries.expect_cancel_job().never();
+
+        let response = cancel_job(
+            Path(Uuid::nil()),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::InvalidInput("Finished job")).await
+        );
+    }
+}
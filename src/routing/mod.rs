@@ -1,3 +1,4 @@
+use axum::http::{header, HeaderName};
 use axum::response::IntoResponse;
 use axum::routing::BoxRoute;
 use axum::{AddExtensionLayer, Router};
@@ -7,42 +8,267 @@ use std::{
     time::Duration,
 };
 use tower::ServiceBuilder;
-use tower_http::trace::TraceLayer;
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    trace::TraceLayer,
+};
 
+use crate::config::Config;
 use crate::db::queries::Queries;
 use crate::error::AppError;
+use crate::graphql;
+use crate::idempotency::IDEMPOTENT_REPLAYED_HEADER;
+use crate::rate_limit::{RateLimitExceeded, RateLimitLayer, RateLimiter};
+use crate::request_body::{RequestBodyLayer, RequestBodyLimits};
+use crate::request_timeout::{RequestTimeoutLayer, REQUEST_TIMEOUT};
 use crate::response::AppResponse;
+use crate::shutdown::ShutdownSignal;
 use crate::state::State;
 
+pub mod alert_handlers;
+pub mod charging_handlers;
+pub mod ev_handlers;
+pub mod event_handlers;
+pub mod graphql_handlers;
+pub mod health_handlers;
+pub mod job_handlers;
+pub mod telemetry_handlers;
 pub mod vehicle_handlers;
+pub mod webhook_handlers;
+pub mod ws_handlers;
+
+#[cfg(test)]
+mod test_utils;
 
 #[tracing::instrument]
 pub fn create_router<Q: Queries>(
     shared_state: Arc<RwLock<State>>,
     queries: Arc<Q>,
+    config: Arc<Config>,
+    shutdown: ShutdownSignal,
 ) -> Router<BoxRoute> {
+    // Rate limits, with local or shared (database) buckets
+    let api_keys = config
+        .api_keys
+        .iter()
+        .chain(config.admin_api_key.iter())
+        .cloned()
+        .collect();
+    let rate_limiter = RateLimiter::new(
+        config.rate_limits.clone(),
+        api_keys,
+        config.rate_limit_client_ip_header.clone(),
+        config.rate_limit_shared.then(|| queries.clone()),
+    );
+
     // Middlewares: Tower layer stack
     let middleware_stack = ServiceBuilder::new()
-        .timeout(Duration::from_secs(5))
+        .layer(RequestTimeoutLayer::new(REQUEST_TIMEOUT))
         .layer(TraceLayer::new_for_http())
+        .layer(RateLimitLayer::new(rate_limiter))
+        .layer(RequestBodyLayer::new(RequestBodyLimits {
+            max_size: config.max_body_size,
+            max_sync_import_size: config.max_sync_import_body_size,
+            max_import_size: config.max_import_body_size,
+        }))
         .into_inner();
 
+    // Response compression and CORS (outside of the error handling, to apply to all responses)
+    let compression_layer = if config.compression {
+        CompressionLayer::new()
+    } else {
+        CompressionLayer::new()
+            .no_br()
+            .no_deflate()
+            .no_gzip()
+            .no_zstd()
+    };
+    let cors_layer = create_cors_layer(&config);
+
     // Route
-    use axum::handler::{get, post};
+    use axum::handler::{delete, get, post, put};
     Router::new()
         .route("/vehicle", post(vehicle_handlers::post_vehicle::<Q>))
         .route(
             "/vehicle/:vin",
             get(vehicle_handlers::get_vehicle::<Q>).delete(vehicle_handlers::delete_vehicle::<Q>),
         )
+        .route(
+            "/vehicle/import",
+            post(vehicle_handlers::post_vehicle_import::<Q>),
+        )
+        .route(
+            "/vehicle/export",
+            get(vehicle_handlers::get_vehicle_export::<Q>),
+        )
+        .route(
+            "/vehicle/batch-get",
+            post(vehicle_handlers::post_vehicle_batch_get::<Q>),
+        )
+        .route(
+            "/vehicle/batch-delete",
+            post(vehicle_handlers::post_vehicle_batch_delete::<Q>),
+        )
+        .route(
+            "/vehicle/events",
+            get(event_handlers::get_vehicle_events::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/restore",
+            post(vehicle_handlers::restore_vehicle::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/telemetry",
+            get(telemetry_handlers::get_telemetry::<Q>)
+                .post(telemetry_handlers::post_telemetry::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/telemetry/aggregate",
+            get(telemetry_handlers::get_telemetry_aggregate::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/charging-sessions",
+            get(charging_handlers::get_charging_sessions::<Q>)
+                .post(charging_handlers::start_charging_session::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/charging-sessions/:id",
+            get(charging_handlers::get_charging_session::<Q>)
+                .put(charging_handlers::put_charging_session::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/charging-sessions/:id/end",
+            post(charging_handlers::end_charging_session::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/charging-summary",
+            get(charging_handlers::get_vehicle_charging_summary::<Q>),
+        )
+        .route(
+            "/fleet/charging-summary",
+            get(charging_handlers::get_fleet_charging_summary::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/ev/estimate",
+            get(ev_handlers::get_ev_estimate::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/ev/consumption",
+            get(ev_handlers::get_ev_consumption::<Q>).put(ev_handlers::put_ev_consumption::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/ev/trips",
+            post(ev_handlers::post_ev_trip::<Q>),
+        )
+        .route(
+            "/vehicle/:vin/battery-health",
+            get(ev_handlers::get_battery_health::<Q>).post(ev_handlers::post_battery_health::<Q>),
+        )
+        .route(
+            "/fleet/battery-health",
+            get(ev_handlers::get_fleet_battery_health::<Q>),
+        )
+        .route(
+            "/alert-rules",
+            get(alert_handlers::get_alert_rules::<Q>).post(alert_handlers::post_alert_rule::<Q>),
+        )
+        .route(
+            "/alert-rules/:id",
+            delete(alert_handlers::delete_alert_rule::<Q>),
+        )
+        .route("/alerts", get(alert_handlers::get_alerts::<Q>))
+        .route(
+            "/alerts/:vin/:id/acknowledge",
+            post(alert_handlers::acknowledge_alert::<Q>),
+        )
+        .route(
+            "/alerts/:vin/:id/resolve",
+            post(alert_handlers::resolve_alert::<Q>),
+        )
+        .route(
+            "/webhooks",
+            get(webhook_handlers::get_webhooks::<Q>).post(webhook_handlers::post_webhook::<Q>),
+        )
+        .route(
+            "/webhooks/:id",
+            get(webhook_handlers::get_webhook::<Q>)
+                .put(webhook_handlers::put_webhook::<Q>)
+                .delete(webhook_handlers::delete_webhook::<Q>),
+        )
+        .route(
+            "/webhooks/:id/deliveries",
+            get(webhook_handlers::get_webhook_deliveries::<Q>),
+        )
+        .route(
+            "/webhooks/:id/deliveries/:delivery_id/redeliver",
+            post(webhook_handlers::redeliver_webhook_delivery::<Q>),
+        )
+        .route("/jobs/:id", get(job_handlers::get_job::<Q>))
+        .route("/jobs/import", post(job_handlers::post_import_job::<Q>))
+        .route("/jobs/export", post(job_handlers::post_export_job::<Q>))
+        .route("/jobs/:id/output", get(job_handlers::get_job_output::<Q>))
+        .route("/jobs/:id/cancel", post(job_handlers::cancel_job::<Q>))
+        .route("/ws", get(ws_handlers::get_ws::<Q>))
+        .route(
+            "/graphql",
+            get(graphql_handlers::get_graphiql).post(graphql_handlers::post_graphql::<Q>),
+        )
+        .route("/health/live", get(health_handlers::get_live))
+        .route("/health/ready", get(health_handlers::get_ready))
         .layer(middleware_stack)
+        .layer(AddExtensionLayer::new(graphql::create_schema(
+            queries.clone(),
+        )))
         .layer(AddExtensionLayer::new(queries))
+        .layer(AddExtensionLayer::new(config))
         .layer(AddExtensionLayer::new(shared_state))
+        .layer(AddExtensionLayer::new(shutdown))
         .handle_error(|e| Ok::<_, Infallible>(convert_tower_error_into_response(e)))
+        .layer(compression_layer)
+        .layer(cors_layer)
         .boxed()
 }
 
+fn create_cors_layer(config: &Config) -> CorsLayer {
+    let allow_origin = if config
+        .cors_allowed_origins
+        .iter()
+        .any(|origin| origin == "*")
+    {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(config.cors_allowed_origins.clone())
+    };
+
+    // Note: the browsers only expose the CORS-safelisted response headers by default
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(config.cors_allowed_methods.clone())
+        .allow_headers(config.cors_allowed_headers.clone())
+        .expose_headers(vec![
+            HeaderName::from_static("ratelimit-limit"),
+            HeaderName::from_static("ratelimit-remaining"),
+            HeaderName::from_static("ratelimit-reset"),
+            header::RETRY_AFTER,
+            HeaderName::from_static(IDEMPOTENT_REPLAYED_HEADER),
+        ])
+        .max_age(Duration::from_secs(3600))
+}
+
 fn convert_tower_error_into_response(e: tower::BoxError) -> AppResponse {
+    // Rate limit (with the RateLimit-* headers)
+    let e = match e.downcast::<RateLimitExceeded>() {
+        Ok(exceeded) => return exceeded.to_response(),
+        Err(e) => e,
+    };
+
+    // App errors of the layers (e.g. request body too large)
+    let e = match e.downcast::<AppError>() {
+        Ok(e) => return e.into_response(),
+        Err(e) => e,
+    };
+
     let response_error = if e.is::<tower::timeout::error::Elapsed>() {
         // Timeout
         match e.downcast() {
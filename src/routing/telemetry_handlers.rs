@@ -0,0 +1,530 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{self, Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{
+    alerts, charging,
+    config::Config,
+    db::queries::{Queries, TelemetryQueries, VehicleQueries},
+    error::AppError,
+    model::{
+        telemetry::{AggregateInterval, SocReading},
+        vehicle::{Engine, EvData, Vehicle},
+    },
+    response::AppResponseResult,
+    result::AppResult,
+    rollups,
+};
+
+/// Maximum time range of raw telemetry queries
+const MAX_RANGE_DAYS: i64 = 31;
+
+/// Maximum number of buckets returned by aggregate queries
+const MAX_AGGREGATE_BUCKETS: i32 = 10_000;
+
+/// Tolerated clock skew of the vehicles: newer readings are rejected, as the latest reading
+/// of a vehicle is the one with the greatest timestamp
+const MAX_READING_CLOCK_SKEW_SECS: i64 = 60;
+
+#[derive(Deserialize, Default, Debug)]
+pub struct TelemetryRangeParams {
+    /// Default: 1 day before `to`
+    pub from: Option<DateTime<Utc>>,
+    /// Default: now
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TelemetryAggregateParams {
+    pub interval: AggregateInterval,
+    /// Default: 30 days before `to`
+    pub from: Option<DateTime<Utc>>,
+    /// Default: now
+    pub to: Option<DateTime<Utc>>,
+}
+
+fn time_range(
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    default_range: chrono::Duration,
+    max_range: chrono::Duration,
+) -> AppResult<(DateTime<Utc>, DateTime<Utc>)> {
+    let to = to.unwrap_or_else(Utc::now);
+    let from = from.unwrap_or(to - default_range);
+
+    if from >= to {
+        return Err(AppError::InvalidInput("Empty telemetry range"));
+    }
+    if to - from > max_range {
+        return Err(AppError::InvalidInput("Telemetry range too large"));
+    }
+
+    Ok((from, to))
+}
+
+#[tracing::instrument(err)]
+pub async fn post_telemetry<Q: Queries>(
+    Path(vin): Path<String>,
+    Json(readings): Json<Vec<SocReading>>,
+    queries: extract::Extension<Arc<Q>>,
+    config: extract::Extension<Arc<Config>>,
+) -> AppResponseResult {
+    if readings.len() > config.max_telemetry_batch_size {
+        return Err(AppError::InvalidInput("Telemetry batch size"));
+    }
+    let newest_reading = readings
+        .iter()
+        .max_by_key(|reading| reading.timestamp)
+        .ok_or(AppError::InvalidInput("Empty telemetry batch"))?;
+    if !readings.iter().all(SocReading::is_valid) {
+        return Err(AppError::InvalidInput("SoC must be between 0 and 100"));
+    }
+    if newest_reading.timestamp
+        > Utc::now() + chrono::Duration::seconds(MAX_READING_CLOCK_SKEW_SECS)
+    {
+        return Err(AppError::InvalidInput("SoC reading in the future"));
+    }
+
+    let vehicle = queries.vehicle_queries().find_one_vehicle(&vin).await?;
+    if vehicle.engine == Engine::Combustion {
+        return Err(AppError::InvalidInput("Combustion vehicle has no SoC"));
+    }
+
+    let latest_reading = queries
+        .telemetry_queries()
+        .find_latest_soc_reading(&vin)
+        .await?;
+
+    queries
+        .telemetry_queries()
+        .insert_soc_readings(&vin, &readings)
+        .await?;
+    rollups::refresh_soc_rollups(queries.0.as_ref(), &vin, &readings).await?;
+    charging::detect_charging_sessions_from_telemetry(queries.0.as_ref(), &vehicle, &readings)
+        .await?;
+
+    // Update the current EV data only if the batch contains the newest reading
+    if latest_reading.map_or(true, |latest| newest_reading.timestamp > latest.timestamp) {
+        let ev_data = EvData {
+            soc_in_percent: newest_reading.soc_in_percent,
+            ..vehicle.ev_data.clone().unwrap_or_default()
+        };
+        queries
+            .vehicle_queries()
+            .update_vehicle_ev_data(&vin, &ev_data)
+            .await?;
+
+        let vehicle = Vehicle {
+            ev_data: Some(ev_data),
+            ..vehicle
+        };
+        alerts::evaluate_alert_rules(queries.0.as_ref(), &vehicle).await?;
+    }
+
+    Ok((StatusCode::CREATED, Json(())).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_telemetry<Q: Queries>(
+    Path(vin): Path<String>,
+    Query(params): Query<TelemetryRangeParams>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let (from, to) = time_range(
+        params.from,
+        params.to,
+        chrono::Duration::days(1),
+        chrono::Duration::days(MAX_RANGE_DAYS),
+    )?;
+
+    // Ensure that the vehicle can be found
+    let _ = queries.vehicle_queries().find_one_vehicle(&vin).await?;
+
+    let readings = queries
+        .telemetry_queries()
+        .find_soc_readings(&vin, from, to)
+        .await?;
+
+    Ok((StatusCode::OK, Json(readings)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_telemetry_aggregate<Q: Queries>(
+    Path(vin): Path<String>,
+    Query(params): Query<TelemetryAggregateParams>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let (from, to) = time_range(
+        params.from,
+        params.to,
+        chrono::Duration::days(30),
+        params.interval.duration() * MAX_AGGREGATE_BUCKETS,
+    )?;
+
+    // Ensure that the vehicle can be found
+    let _ = queries.vehicle_queries().find_one_vehicle(&vin).await?;
+
+    // Include the bucket containing `from`
+    let aggregates = queries
+        .telemetry_queries()
+        .find_soc_aggregates(
+            &vin,
+            params.interval,
+            params.interval.bucket_start(from),
+            to,
+        )
+        .await?;
+
+    Ok((StatusCode::OK, Json(aggregates)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::{
+        db::queries::{self},
+        model::telemetry::SocAggregate,
+        routing::test_utils::to_bytes,
+    };
+
+    fn ev_vehicle() -> Vehicle {
+        Vehicle {
+            vin: "vin".to_string(),
+            owner: None,
+            engine: Engine::Ev,
+            ev_data: Some(EvData {
+                battery_capacity_in_kwh: 62,
+                soc_in_percent: 50,
+            }),
+        }
+    }
+
+    fn datetime(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn reading(hour: u32, soc_in_percent: i32) -> SocReading {
+        SocReading {
+            timestamp: datetime(&format!("2021-09-01T{:02}:00:00Z", hour)),
+            soc_in_percent,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_telemetry_newest_reading() {
+        let readings = vec![reading(10, 55), reading(12, 60), reading(11, 58)];
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin"))
+            .times(1)
+            .returning(|_| Ok(ev_vehicle()));
+        mock_queries
+            .telemetry_queries
+            .expect_find_latest_soc_reading()
+            .with(eq("vin"))
+            .times(1)
+            .returning(|_| Ok(Some(reading(9, 50))));
+        mock_queries
+            .telemetry_queries
+            .expect_insert_soc_readings()
+            .with(eq("vin"), eq(readings.clone()))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock_queries
+            .telemetry_queries
+            .expect_find_soc_readings()
+            .returning(|_, _, _| Ok(vec![]));
+        mock_queries
+            .vehicle_queries
+            .expect_update_vehicle_ev_data()
+            .with(
+                eq("vin"),
+                eq(EvData {
+                    battery_capacity_in_kwh: 62,
+                    soc_in_percent: 60,
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock_queries
+            .alert_queries
+            .expect_find_alert_rules()
+            .times(1)
+            .returning(|| Ok(vec![]));
+
+        let response = post_telemetry(
+            Path("vin".to_string()),
+            Json(readings),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(Config::default())),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_post_telemetry_older_readings() {
+        let readings = vec![reading(10, 55)];
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .returning(|_| Ok(ev_vehicle()));
+        mock_queries
+            .telemetry_queries
+            .expect_find_latest_soc_reading()
+            .returning(|_| Ok(Some(reading(11, 50))));
+        mock_queries
+            .telemetry_queries
+            .expect_insert_soc_readings()
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock_queries
+            .telemetry_queries
+            .expect_find_soc_readings()
+            .returning(|_, _, _| Ok(vec![]));
+        mock_queries
+            .vehicle_queries
+            .expect_update_vehicle_ev_data()
+            .times(0);
+
+        let response = post_telemetry(
+            Path("vin".to_string()),
+            Json(readings),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(Config::default())),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_post_telemetry_combustion_vehicle() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .returning(|_| {
+                Ok(Vehicle {
+                    engine: Engine::Combustion,
+                    ev_data: None,
+                    ..ev_vehicle()
+                })
+            });
+        mock_queries
+            .telemetry_queries
+            .expect_insert_soc_readings()
+            .times(0);
+
+        let response = post_telemetry(
+            Path("vin".to_string()),
+            Json(vec![reading(10, 55)]),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(Config::default())),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::InvalidInput("Combustion vehicle has no SoC")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_telemetry_invalid_soc() {
+        let mock_queries = queries::MockQueries::default();
+
+        let response = post_telemetry(
+            Path("vin".to_string()),
+            Json(vec![reading(10, 101)]),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(Config::default())),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_post_telemetry_future_reading() {
+        let mock_queries = queries::MockQueries::default();
+        let future_reading = SocReading {
+            timestamp: Utc::now() + chrono::Duration::hours(1),
+            soc_in_percent: 55,
+        };
+
+        let response = post_telemetry(
+            Path("vin".to_string()),
+            Json(vec![reading(10, 50), future_reading]),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(Config::default())),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::InvalidInput("SoC reading in the future")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_telemetry_too_large() {
+        let mock_queries = queries::MockQueries::default();
+        let config = Config {
+            max_telemetry_batch_size: 1,
+            ..Default::default()
+        };
+
+        let response = post_telemetry(
+            Path("vin".to_string()),
+            Json(vec![reading(10, 50), reading(11, 55)]),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(config)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::InvalidInput("Telemetry batch size")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_telemetry_ok() {
+        let readings = vec![reading(10, 55), reading(11, 58)];
+        let readings_clone = readings.clone();
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin"))
+            .returning(|_| Ok(ev_vehicle()));
+        mock_queries
+            .telemetry_queries
+            .expect_find_soc_readings()
+            .with(
+                eq("vin"),
+                eq(datetime("2021-09-01T00:00:00Z")),
+                eq(datetime("2021-09-02T00:00:00Z")),
+            )
+            .times(1)
+            .returning(move |_, _, _| Ok(readings_clone.clone()));
+
+        let response = get_telemetry(
+            Path("vin".to_string()),
+            Query(TelemetryRangeParams {
+                from: Some(datetime("2021-09-01T00:00:00Z")),
+                to: Some(datetime("2021-09-02T00:00:00Z")),
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(to_bytes(response).await, to_bytes(Json(readings)).await);
+    }
+
+    #[tokio::test]
+    async fn test_get_telemetry_invalid_range() {
+        let mock_queries = queries::MockQueries::default();
+
+        let response = get_telemetry(
+            Path("vin".to_string()),
+            Query(TelemetryRangeParams {
+                from: Some(datetime("2021-01-01T00:00:00Z")),
+                to: Some(datetime("2021-09-01T00:00:00Z")),
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::InvalidInput("Telemetry range too large")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_telemetry_aggregate_ok() {
+        let aggregates = vec![SocAggregate {
+            start: datetime("2021-09-01T10:00:00Z"),
+            min: 40,
+            max: 50,
+            avg: 45.0,
+            first: 50,
+            last: 45,
+            count: 3,
+        }];
+        let aggregates_clone = aggregates.clone();
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin"))
+            .returning(|_| Ok(ev_vehicle()));
+        mock_queries
+            .telemetry_queries
+            .expect_find_soc_aggregates()
+            .with(
+                eq("vin"),
+                eq(AggregateInterval::Hour),
+                eq(datetime("2021-09-01T10:00:00Z")),
+                eq(datetime("2021-09-02T00:00:00Z")),
+            )
+            .times(1)
+            .returning(move |_, _, _, _| Ok(aggregates_clone.clone()));
+
+        let response = get_telemetry_aggregate(
+            Path("vin".to_string()),
+            Query(TelemetryAggregateParams {
+                interval: AggregateInterval::Hour,
+                from: Some(datetime("2021-09-01T10:30:00Z")),
+                to: Some(datetime("2021-09-02T00:00:00Z")),
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(to_bytes(response).await, to_bytes(Json(aggregates)).await);
+    }
+
+    #[tokio::test]
+    async fn test_get_telemetry_aggregate_too_many_buckets() {
+        let mock_queries = queries::MockQueries::default();
+
+        let response = get_telemetry_aggregate(
+            Path("vin".to_string()),
+            Query(TelemetryAggregateParams {
+                interval: AggregateInterval::Hour,
+                from: Some(datetime("2019-01-01T00:00:00Z")),
+                to: Some(datetime("2021-09-01T00:00:00Z")),
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,11 @@
+use axum::response::IntoResponse;
+
+pub async fn to_bytes<R>(response: R) -> axum::body::Bytes
+where
+    R: IntoResponse,
+{
+    hyper::body::to_bytes(response.into_response().into_body())
+        .await
+        .map_err(Into::into)
+        .unwrap()
+}
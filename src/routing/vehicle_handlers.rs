@@ -1,63 +1,284 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use axum::{
-    extract::{self, Path},
-    http::StatusCode,
-    response::IntoResponse,
-    Json,
+    body::Body,
+    extract::{self, Path, Query},
+    http::{self, header, HeaderMap, StatusCode},
 };
+use serde::Deserialize;
 
 use crate::{
+    alerts,
+    auth::{self, RequestApiKey},
+    config::Config,
     db::queries::{Queries, VehicleQueries},
-    model::vehicle::Vehicle,
+    error::AppError,
+    export::{self, ExportFormat},
+    idempotency::{idempotent, IdempotencyKey},
+    import::{self, ImportFormat},
+    model::{
+        batch::{BatchDeleteResult, BatchFailure, BatchGetResult, BatchVins},
+        vehicle::{Engine, Vehicle},
+    },
+    negotiation::{Accept, Negotiated},
     response::AppResponseResult,
+    result::AppResult,
 };
 
 #[tracing::instrument(err)]
 pub async fn post_vehicle<Q: Queries>(
-    Json(payload): Json<Vehicle>,
+    accept: Accept,
+    idempotency_key: IdempotencyKey,
+    Negotiated(payload): Negotiated<Vehicle>,
     queries: extract::Extension<Arc<Q>>,
 ) -> AppResponseResult {
-    queries.vehicle_queries().create_vehicle(&payload).await?;
-
-    Ok((StatusCode::CREATED, Json(payload)).into_response())
+    let queries = queries.0.as_ref();
+    idempotent(queries, idempotency_key, "POST /vehicle", &payload, async {
+        queries.vehicle_queries().create_vehicle(&payload).await?;
+        alerts::evaluate_alert_rules(queries, &payload).await?;
+
+        accept.to_response(StatusCode::CREATED, &payload)
+    })
+    .await
 }
 
 #[tracing::instrument(err)]
 pub async fn get_vehicle<Q: Queries>(
+    accept: Accept,
     Path(vin): Path<String>,
     queries: extract::Extension<Arc<Q>>,
 ) -> AppResponseResult {
     let vehicle = queries.vehicle_queries().find_one_vehicle(&vin).await?;
 
-    Ok((StatusCode::OK, Json(vehicle)).into_response())
+    accept.to_response(StatusCode::OK, &vehicle)
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct DeleteVehicleParams {
+    /// Remove the vehicle from the database instead of marking it as deleted
+    #[serde(default)]
+    pub purge: bool,
 }
 
 #[tracing::instrument(err)]
 pub async fn delete_vehicle<Q: Queries>(
+    accept: Accept,
     Path(vin): Path<String>,
+    Query(params): Query<DeleteVehicleParams>,
+    api_key: RequestApiKey,
+    config: extract::Extension<Arc<Config>>,
     queries: extract::Extension<Arc<Q>>,
 ) -> AppResponseResult {
-    queries.vehicle_queries().delete_one_vehicle(&vin).await?;
+    if params.purge {
+        auth::ensure_admin(&api_key, &config)?;
+        queries.vehicle_queries().purge_one_vehicle(&vin).await?;
+    } else {
+        queries.vehicle_queries().delete_one_vehicle(&vin).await?;
+    }
+
+    accept.to_response(StatusCode::OK, &())
+}
+
+#[tracing::instrument(err)]
+pub async fn restore_vehicle<Q: Queries>(
+    accept: Accept,
+    idempotency_key: IdempotencyKey,
+    Path(vin): Path<String>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let queries = queries.0.as_ref();
+    idempotent(
+        queries,
+        idempotency_key,
+        "POST /vehicle/:vin/restore",
+        &vin,
+        async {
+            let vehicle = queries.vehicle_queries().restore_one_vehicle(&vin).await?;
+
+            accept.to_response(StatusCode::OK, &vehicle)
+        },
+    )
+    .await
+}
+
+/// VINs of a batch request without duplicates (in order), within the max batch size
+fn batch_vins(vins: Vec<String>, max_batch_size: usize) -> AppResult<Vec<String>> {
+    let mut seen = HashSet::new();
+    let vins: Vec<String> = vins
+        .into_iter()
+        .filter(|vin| seen.insert(vin.clone()))
+        .collect();
+
+    if vins.is_empty() {
+        return Err(AppError::InvalidInput("Empty batch"));
+    }
+    if vins.len() > max_batch_size {
+        return Err(AppError::InvalidInput("Batch size"));
+    }
 
-    Ok((StatusCode::OK, Json(())).into_response())
+    Ok(vins)
+}
+
+/// Find the vehicles in one query, the missing ones are listed
+#[tracing::instrument(err)]
+pub async fn post_vehicle_batch_get<Q: Queries>(
+    accept: Accept,
+    Negotiated(payload): Negotiated<BatchVins>,
+    queries: extract::Extension<Arc<Q>>,
+    config: extract::Extension<Arc<Config>>,
+) -> AppResponseResult {
+    let vins = batch_vins(payload.vins, config.max_batch_size)?;
+
+    let mut found: HashMap<String, Vehicle> = queries
+        .vehicle_queries()
+        .find_vehicles(&vins)
+        .await?
+        .into_iter()
+        .map(|vehicle| (vehicle.vin.clone(), vehicle))
+        .collect();
+
+    // Same order as the request
+    let mut result = BatchGetResult::default();
+    for vin in vins.into_iter() {
+        match found.remove(&vin) {
+            Some(vehicle) => result.vehicles.push(vehicle),
+            None => result.missing.push(vin),
+        }
+    }
+
+    accept.to_response(StatusCode::OK, &result)
+}
+
+/// Delete the vehicles concurrently, each vehicle is reported as deleted, missing or failed
+#[tracing::instrument(err)]
+pub async fn post_vehicle_batch_delete<Q: Queries>(
+    accept: Accept,
+    idempotency_key: IdempotencyKey,
+    Negotiated(payload): Negotiated<BatchVins>,
+    queries: extract::Extension<Arc<Q>>,
+    config: extract::Extension<Arc<Config>>,
+) -> AppResponseResult {
+    let vins = batch_vins(payload.vins, config.max_batch_size)?;
+
+    let queries = queries.0.as_ref();
+    idempotent(
+        queries,
+        idempotency_key,
+        "POST /vehicle/batch-delete",
+        &vins,
+        async {
+            let results = queries.vehicle_queries().delete_vehicles(&vins).await;
+
+            let mut result = BatchDeleteResult::default();
+            for (vin, deletion) in vins.iter().zip(results.into_iter()) {
+                match deletion {
+                    Ok(()) => result.deleted.push(vin.clone()),
+                    Err(AppError::NotFound(_)) => result.missing.push(vin.clone()),
+                    Err(e) => result.failed.push(BatchFailure {
+                        vin: vin.clone(),
+                        error: e.to_string(),
+                    }),
+                }
+            }
+
+            accept.to_response(StatusCode::OK, &result)
+        },
+    )
+    .await
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct ImportVehiclesParams {
+    /// Validate the lines and look for existing vehicles, without creating anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Import vehicles from a CSV (`text/csv`) or NDJSON (`application/x-ndjson`) body
+#[tracing::instrument(skip(body), err)]
+pub async fn post_vehicle_import<Q: Queries>(
+    accept: Accept,
+    Query(params): Query<ImportVehiclesParams>,
+    headers: HeaderMap,
+    body: extract::BodyStream,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let format = ImportFormat::from_headers(&headers)?;
+
+    let report = import::import_vehicles(
+        queries.0.as_ref(),
+        format,
+        import::split_lines(body),
+        1,
+        params.dry_run,
+    )
+    .await?;
+
+    accept.to_response(StatusCode::OK, &report)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportVehiclesParams {
+    #[serde(default = "default_export_format")]
+    pub format: ExportFormat,
+
+    /// Only export the vehicles with this engine type
+    pub engine_type: Option<Engine>,
+}
+
+fn default_export_format() -> ExportFormat {
+    ExportFormat::Ndjson
+}
+
+/// Note: the response is streamed (not an AppResponse), errors after the first page truncate it
+#[tracing::instrument(err)]
+pub async fn get_vehicle_export<Q: Queries>(
+    Query(params): Query<ExportVehiclesParams>,
+    queries: extract::Extension<Arc<Q>>,
+) -> Result<http::Response<Body>, AppError> {
+    let vehicles = queries.vehicle_queries().find_all_vehicles().await?;
+    let stream = export::export_vehicles(vehicles, params.format, params.engine_type);
+
+    let response = http::Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, params.format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"vehicles.{}\"",
+                params.format.to_string()
+            ),
+        )
+        .body(Body::wrap_stream(stream))
+        .map_err(anyhow::Error::from)?;
+
+    Ok(response)
 }
 
 #[cfg(test)]
 mod tests {
+    use axum::{response::IntoResponse, Json};
+    use futures::StreamExt;
     use mockall::predicate::eq;
 
     use super::*;
     use crate::{
+        auth::ApiKey,
         db::queries::{self},
-        error::AppError,
-        model::vehicle,
+        idempotency,
+        model::{idempotency::IdempotentResponse, vehicle},
+        negotiation::MediaType,
+        routing::test_utils::to_bytes,
     };
 
     #[tokio::test]
     async fn test_post_vehicle_ok() {
         let vehicle = Vehicle {
             vin: "vin".to_string(),
+            owner: None,
             engine: vehicle::Engine::Combustion,
             ev_data: None,
         };
@@ -71,7 +292,9 @@ mod tests {
         let mock_queries = create_queries(mock_vehicle_queries);
 
         let response = post_vehicle(
-            Json(vehicle.clone()),
+            Accept(MediaType::Json),
+            IdempotencyKey(None),
+            Negotiated(vehicle.clone()),
             extract::Extension(Arc::new(mock_queries)),
         )
         .await
@@ -87,6 +310,7 @@ mod tests {
     async fn test_post_vehicle_already_exists() {
         let vehicle = Vehicle {
             vin: "vin".to_string(),
+            owner: None,
             engine: vehicle::Engine::Combustion,
             ev_data: None,
         };
@@ -100,7 +324,9 @@ mod tests {
         let mock_queries = create_queries(mock_vehicle_queries);
 
         let response = post_vehicle(
-            Json(vehicle.clone()),
+            Accept(MediaType::Json),
+            IdempotencyKey(None),
+            Negotiated(vehicle.clone()),
             extract::Extension(Arc::new(mock_queries)),
         )
         .await
@@ -116,6 +342,7 @@ mod tests {
     async fn test_post_vehicle_error() {
         let vehicle = Vehicle {
             vin: "vin".to_string(),
+            owner: None,
             engine: vehicle::Engine::Combustion,
             ev_data: None,
         };
@@ -128,9 +355,14 @@ mod tests {
             .returning(|_| Err("Test error".into()));
         let mock_queries = create_queries(mock_vehicle_queries);
 
-        let response = post_vehicle(Json(vehicle), extract::Extension(Arc::new(mock_queries)))
-            .await
-            .into_response();
+        let response = post_vehicle(
+            Accept(MediaType::Json),
+            IdempotencyKey(None),
+            Negotiated(vehicle),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
         assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
         assert_eq!(
             to_bytes(response).await,
@@ -138,10 +370,54 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_post_vehicle_idempotency_key_reused() {
+        let vehicle = Vehicle {
+            vin: "vin".to_string(),
+            owner: None,
+            engine: vehicle::Engine::Combustion,
+            ev_data: None,
+        };
+        let stored = IdempotentResponse {
+            key: "key".to_string(),
+            fingerprint: idempotency::fingerprint("POST /vehicle", &"other payload").unwrap(),
+            status: 201,
+            content_type: Some("application/json".to_string()),
+            body: vec![],
+            created_at: chrono::Utc::now(),
+        };
+
+        // The vehicle is not created
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries.expect_create_vehicle().never();
+        let mut mock_queries = create_queries(mock_vehicle_queries);
+        mock_queries
+            .idempotency_queries
+            .expect_claim_idempotency_key()
+            .withf(|pending| pending.key == "key")
+            .times(1)
+            .returning(move |_| Ok(Some(stored.clone())));
+
+        let response = post_vehicle(
+            Accept(MediaType::Json),
+            IdempotencyKey(Some("key".to_string())),
+            Negotiated(vehicle),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::UnprocessableEntity("Idempotency-Key")).await
+        );
+    }
+
     #[tokio::test]
     async fn test_get_vehicle_ok() {
         let vehicle = Vehicle {
             vin: "vin".to_string(),
+            owner: None,
             engine: vehicle::Engine::Combustion,
             ev_data: None,
         };
@@ -156,6 +432,7 @@ mod tests {
         let mock_queries = create_queries(mock_vehicle_queries);
 
         let response = get_vehicle(
+            Accept(MediaType::Json),
             Path("vin".to_string()),
             extract::Extension(Arc::new(mock_queries)),
         )
@@ -165,6 +442,44 @@ mod tests {
         assert_eq!(to_bytes(response).await, to_bytes(Json(vehicle)).await);
     }
 
+    #[tokio::test]
+    async fn test_get_vehicle_msgpack() {
+        let vehicle = Vehicle {
+            vin: "vin".to_string(),
+            owner: None,
+            engine: vehicle::Engine::Combustion,
+            ev_data: None,
+        };
+        let vehicle_clone = vehicle.clone();
+
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin"))
+            .times(1)
+            .returning(move |_| Ok(vehicle_clone.clone()));
+        let mock_queries = create_queries(mock_vehicle_queries);
+
+        let response = get_vehicle(
+            Accept(MediaType::MsgPack),
+            Path("vin".to_string()),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/msgpack"
+        );
+        assert_eq!(
+            MediaType::MsgPack
+                .deserialize::<Vehicle>(&to_bytes(response).await)
+                .unwrap(),
+            vehicle
+        );
+    }
+
     #[tokio::test]
     async fn test_get_vehicle_not_found() {
         let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
@@ -176,6 +491,7 @@ mod tests {
         let mock_queries = create_queries(mock_vehicle_queries);
 
         let response = get_vehicle(
+            Accept(MediaType::Json),
             Path("vin".to_string()),
             extract::Extension(Arc::new(mock_queries)),
         )
@@ -199,6 +515,7 @@ mod tests {
         let mock_queries = create_queries(mock_vehicle_queries);
 
         let response = get_vehicle(
+            Accept(MediaType::Json),
             Path("vin".to_string()),
             extract::Extension(Arc::new(mock_queries)),
         )
@@ -222,7 +539,11 @@ mod tests {
         let mock_queries = create_queries(mock_vehicle_queries);
 
         let response = delete_vehicle(
+            Accept(MediaType::Json),
             Path("vin".to_string()),
+            Query(DeleteVehicleParams::default()),
+            RequestApiKey(None),
+            extract::Extension(Arc::new(Config::default())),
             extract::Extension(Arc::new(mock_queries)),
         )
         .await
@@ -242,7 +563,11 @@ mod tests {
         let mock_queries = create_queries(mock_vehicle_queries);
 
         let response = delete_vehicle(
+            Accept(MediaType::Json),
             Path("vin".to_string()),
+            Query(DeleteVehicleParams::default()),
+            RequestApiKey(None),
+            extract::Extension(Arc::new(Config::default())),
             extract::Extension(Arc::new(mock_queries)),
         )
         .await
@@ -265,7 +590,11 @@ mod tests {
         let mock_queries = create_queries(mock_vehicle_queries);
 
         let response = delete_vehicle(
+            Accept(MediaType::Json),
             Path("vin".to_string()),
+            Query(DeleteVehicleParams::default()),
+            RequestApiKey(None),
+            extract::Extension(Arc::new(Config::default())),
             extract::Extension(Arc::new(mock_queries)),
         )
         .await
@@ -277,30 +606,295 @@ mod tests {
         );
     }
 
-    async fn to_bytes<R>(response: R) -> axum::body::Bytes
-    where
-        R: IntoResponse,
-    {
-        hyper::body::to_bytes(response.into_response().into_body())
+    #[tokio::test]
+    async fn test_delete_vehicle_purge_ok() {
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_purge_one_vehicle()
+            .with(eq("vin"))
+            .times(1)
+            .returning(move |_| Ok(()));
+        let mock_queries = create_queries(mock_vehicle_queries);
+
+        let response = delete_vehicle(
+            Accept(MediaType::Json),
+            Path("vin".to_string()),
+            Query(DeleteVehicleParams { purge: true }),
+            RequestApiKey(Some(ApiKey::new("admin"))),
+            extract::Extension(Arc::new(admin_config())),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(to_bytes(response).await, to_bytes(Json(())).await);
+    }
+
+    #[tokio::test]
+    async fn test_delete_vehicle_purge_forbidden() {
+        // Not purged
+        let mock_queries = create_queries(queries::MockVehicleQueries::default());
+
+        let response = delete_vehicle(
+            Accept(MediaType::Json),
+            Path("vin".to_string()),
+            Query(DeleteVehicleParams { purge: true }),
+            RequestApiKey(Some(ApiKey::new("other"))),
+            extract::Extension(Arc::new(admin_config())),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::Forbidden("Admin API key")).await
+        );
+    }
+
+    fn admin_config() -> Config {
+        Config {
+            admin_api_key: Some(ApiKey::new("admin")),
+            ..Config::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_vehicle_ok() {
+        let vehicle = Vehicle {
+            vin: "vin".to_string(),
+            owner: None,
+            engine: vehicle::Engine::Combustion,
+            ev_data: None,
+        };
+        let vehicle_clone = vehicle.clone();
+
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_restore_one_vehicle()
+            .with(eq("vin"))
+            .times(1)
+            .returning(move |_| Ok(vehicle_clone.clone()));
+        let mock_queries = create_queries(mock_vehicle_queries);
+
+        let response = restore_vehicle(
+            Accept(MediaType::Json),
+            IdempotencyKey(None),
+            Path("vin".to_string()),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(to_bytes(response).await, to_bytes(Json(vehicle)).await);
+    }
+
+    #[tokio::test]
+    async fn test_restore_vehicle_not_found() {
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_restore_one_vehicle()
+            .with(eq("vin"))
+            .times(1)
+            .returning(|_| Err(AppError::NotFound("Deleted vehicle")));
+        let mock_queries = create_queries(mock_vehicle_queries);
+
+        let response = restore_vehicle(
+            Accept(MediaType::Json),
+            IdempotencyKey(None),
+            Path("vin".to_string()),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::NotFound("Deleted vehicle")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_vehicle_export_ok() {
+        let vehicles = vec![
+            Vehicle {
+                vin: "vin1".to_string(),
+                owner: None,
+                engine: vehicle::Engine::Combustion,
+                ev_data: None,
+            },
+            Vehicle {
+                vin: "vin2".to_string(),
+                owner: None,
+                engine: vehicle::Engine::Ev,
+                ev_data: Some(vehicle::EvData {
+                    battery_capacity_in_kwh: 50,
+                    soc_in_percent: 80,
+                }),
+            },
+        ];
+
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_find_all_vehicles()
+            .times(1)
+            .returning(move || {
+                Ok(futures::stream::iter(vehicles.clone().into_iter().map(Ok)).boxed())
+            });
+        let mock_queries = create_queries(mock_vehicle_queries);
+
+        let response = get_vehicle_export(
+            Query(ExportVehiclesParams {
+                format: ExportFormat::Csv,
+                engine_type: Some(Engine::Ev),
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv");
+        assert_eq!(
+            to_bytes(response).await,
+            "vin,owner,engine_type,battery_capacity_in_kwh,soc_in_percent\nvin2,,Ev,50,80\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_vehicle_export_error() {
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_find_all_vehicles()
+            .times(1)
+            .returning(|| Err("Test error".into()));
+        let mock_queries = create_queries(mock_vehicle_queries);
+
+        let response = get_vehicle_export(
+            Query(ExportVehiclesParams {
+                format: ExportFormat::Ndjson,
+                engine_type: None,
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::from("Test error")).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_vehicle_batch_get_ok() {
+        let vehicle = Vehicle {
+            vin: "vin2".to_string(),
+            owner: None,
+            engine: vehicle::Engine::Combustion,
+            ev_data: None,
+        };
+        let vehicle_clone = vehicle.clone();
+
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_find_vehicles()
+            .withf(|vins| vins == ["vin1".to_string(), "vin2".to_string()])
+            .times(1)
+            .returning(move |_| Ok(vec![vehicle_clone.clone()]));
+        let mock_queries = create_queries(mock_vehicle_queries);
+
+        // Duplicated VIN => only read once
+        let response = post_vehicle_batch_get(
+            Accept(MediaType::Json),
+            Negotiated(BatchVins {
+                vins: vec!["vin1".to_string(), "vin2".to_string(), "vin1".to_string()],
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(Config::default())),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(Json(BatchGetResult {
+                vehicles: vec![vehicle],
+                missing: vec!["vin1".to_string()],
+            }))
             .await
-            .map_err(Into::into)
-            .unwrap()
+        );
     }
 
-    #[derive(Debug)]
-    struct TestQueries {
-        vehicle_queries: queries::MockVehicleQueries,
+    #[tokio::test]
+    async fn test_post_vehicle_batch_get_too_large() {
+        let mock_queries = create_queries(queries::MockVehicleQueries::default());
+        let config = Config {
+            max_batch_size: 1,
+            ..Default::default()
+        };
+
+        let response = post_vehicle_batch_get(
+            Accept(MediaType::Json),
+            Negotiated(BatchVins {
+                vins: vec!["vin1".to_string(), "vin2".to_string()],
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(config)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(AppError::InvalidInput("Batch size")).await
+        );
     }
 
-    impl Queries for TestQueries {
-        type VQ = queries::MockVehicleQueries;
+    #[tokio::test]
+    async fn test_post_vehicle_batch_delete_partial_failure() {
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_delete_vehicles()
+            .times(1)
+            .returning(|_| {
+                vec![
+                    Ok(()),
+                    Err(AppError::NotFound("Vehicle")),
+                    Err("Test error".into()),
+                ]
+            });
+        let mock_queries = create_queries(mock_vehicle_queries);
 
-        fn vehicle_queries(&self) -> &Self::VQ {
-            &self.vehicle_queries
-        }
+        let response = post_vehicle_batch_delete(
+            Accept(MediaType::Json),
+            IdempotencyKey(None),
+            Negotiated(BatchVins {
+                vins: vec!["vin1".to_string(), "vin2".to_string(), "vin3".to_string()],
+            }),
+            extract::Extension(Arc::new(mock_queries)),
+            extract::Extension(Arc::new(Config::default())),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            to_bytes(response).await,
+            to_bytes(Json(BatchDeleteResult {
+                deleted: vec!["vin1".to_string()],
+                missing: vec!["vin2".to_string()],
+                failed: vec![BatchFailure {
+                    vin: "vin3".to_string(),
+                    error: "Test error".to_string(),
+                }],
+            }))
+            .await
+        );
     }
 
-    fn create_queries(vehicle_queries: queries::MockVehicleQueries) -> TestQueries {
-        TestQueries { vehicle_queries }
+    fn create_queries(vehicle_queries: queries::MockVehicleQueries) -> queries::MockQueries {
+        queries::MockQueries {
+            vehicle_queries,
+            ..Default::default()
+        }
     }
 }
@@ -0,0 +1,319 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{self, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::{
+    auth::{self, RequestApiKey},
+    config::Config,
+    db::queries::{Queries, WebhookQueries},
+    error::AppError,
+    model::webhook::{ConfigureWebhook, Webhook},
+    response::AppResponseResult,
+    webhooks,
+};
+
+/// Ensure that the webhook is valid, and that its host resolves to public addresses (unless
+/// private hosts are allowed)
+async fn validate_webhook(payload: &ConfigureWebhook, config: &Config) -> AppResult<()> {
+    if !payload.is_valid(config.webhook_allow_private_hosts) {
+        return Err(AppError::InvalidInput("Invalid webhook"));
+    }
+    if !config.webhook_allow_private_hosts && !webhooks::resolves_to_public_host(&payload.url).await
+    {
+        return Err(AppError::InvalidInput(
+            "Webhook host does not resolve to public addresses",
+        ));
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(err, skip(payload))]
+pub async fn post_webhook<Q: Queries>(
+    Json(payload): Json<ConfigureWebhook>,
+    api_key: RequestApiKey,
+    config: extract::Extension<Arc<Config>>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    auth::ensure_admin(&api_key, &config)?;
+    validate_webhook(&payload, &config).await?;
+
+    let webhook = Webhook {
+        id: Uuid::new_v4(),
+        url: payload.url,
+        secret: payload.secret,
+        events: payload.events,
+        created_at: Utc::now(),
+    };
+    queries.webhook_queries().create_webhook(&webhook).await?;
+
+    Ok((StatusCode::CREATED, Json(webhook)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_webhooks<Q: Queries>(queries: extract::Extension<Arc<Q>>) -> AppResponseResult {
+    let webhooks = queries.webhook_queries().find_webhooks().await?;
+
+    Ok((StatusCode::OK, Json(webhooks)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_webhook<Q: Queries>(
+    Path(id): Path<Uuid>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let webhook = queries.webhook_queries().find_one_webhook(id).await?;
+
+    Ok((StatusCode::OK, Json(webhook)).into_response())
+}
+
+#[tracing::instrument(err, skip(payload))]
+pub async fn put_webhook<Q: Queries>(
+    Path(id): Path<Uuid>,
+    Json(payload): Json<ConfigureWebhook>,
+    api_key: RequestApiKey,
+    config: extract::Extension<Arc<Config>>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    auth::ensure_admin(&api_key, &config)?;
+    validate_webhook(&payload, &config).await?;
+
+    let webhook = queries.webhook_queries().find_one_webhook(id).await?;
+    let webhook = Webhook {
+        url: payload.url,
+        secret: payload.secret,
+        events: payload.events,
+        ..webhook
+    };
+    queries.webhook_queries().update_webhook(&webhook).await?;
+
+    Ok((StatusCode::OK, Json(webhook)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn delete_webhook<Q: Queries>(
+    Path(id): Path<Uuid>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    queries.webhook_queries().delete_webhook(id).await?;
+
+    Ok((StatusCode::OK, Json(())).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn get_webhook_deliveries<Q: Queries>(
+    Path(id): Path<Uuid>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let _ = queries.webhook_queries().find_one_webhook(id).await?;
+    let deliveries = queries.webhook_queries().find_deliveries(id).await?;
+
+    Ok((StatusCode::OK, Json(deliveries)).into_response())
+}
+
+#[tracing::instrument(err)]
+pub async fn redeliver_webhook_delivery<Q: Queries>(
+    Path((id, delivery_id)): Path<(Uuid, Uuid)>,
+    queries: extract::Extension<Arc<Q>>,
+) -> AppResponseResult {
+    let _ = queries.webhook_queries().find_one_webhook(id).await?;
+    let delivery = queries
+        .webhook_queries()
+        .find_one_delivery(id, delivery_id)
+        .await?;
+
+    // Attempted by the delivery task
+    let delivery = webhooks::reset_delivery(&delivery, Utc::now());
+    queries.webhook_queries().upsert_delivery(&delivery).await?;
+
+    Ok((StatusCode::ACCEPTED, Json(delivery)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::{eq, function};
+
+    use super::*;
+    use crate::{
+        auth::ApiKey,
+        db::queries::{self},
+        model::webhook::{DeliveryStatus, WebhookDelivery, WebhookEvent},
+    };
+
+    fn configure_webhook() -> ConfigureWebhook {
+        ConfigureWebhook {
+            url: "https://93.184.216.34/hook".to_string(),
+            secret: "secret".to_string(),
+            events: vec![WebhookEvent::VehicleCreated],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_webhook_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .webhook_queries
+            .expect_create_webhook()
+            .with(function(|webhook: &Webhook| {
+                webhook.url == "https://93.184.216.34/hook" && webhook.secret == "secret"
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let response = post_webhook(
+            Json(configure_webhook()),
+            RequestApiKey(Some(ApiKey::new("admin"))),
+            extract::Extension(Arc::new(admin_config())),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_post_webhook_invalid() {
+        for url in vec![
+            "ftp://93.184.216.34/hook",
+            "http://localhost:8080/hook",
+            "http://127.0.0.1:8080/hook",
+            "http://10.0.0.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://[fe80::1]/hook",
+        ] {
+            let mock_queries = queries::MockQueries::default();
+
+            let response = post_webhook(
+                Json(ConfigureWebhook {
+                    url: url.to_string(),
+                    ..configure_webhook()
+                }),
+                RequestApiKey(Some(ApiKey::new("admin"))),
+                extract::Extension(Arc::new(admin_config())),
+                extract::Extension(Arc::new(mock_queries)),
+            )
+            .await
+            .into_response();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_webhook_forbidden() {
+        for api_key in vec![None, Some(ApiKey::new("other"))] {
+            let response = post_webhook(
+                Json(configure_webhook()),
+                RequestApiKey(api_key),
+                extract::Extension(Arc::new(admin_config())),
+                extract::Extension(Arc::new(queries::MockQueries::default())),
+            )
+            .await
+            .into_response();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_webhook_forbidden() {
+        let mock_queries = queries::MockQueries::default();
+
+        let response = put_webhook(
+            Path(Uuid::nil()),
+            Json(configure_webhook()),
+            RequestApiKey(None),
+            extract::Extension(Arc::new(admin_config())),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    fn admin_config() -> Config {
+        Config {
+            admin_api_key: Some(ApiKey::new("admin")),
+            ..Config::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redeliver_webhook_delivery_ok() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .webhook_queries
+            .expect_find_one_webhook()
+            .with(eq(Uuid::nil()))
+            .returning(|_| {
+                Ok(Webhook {
+                    id: Uuid::nil(),
+                    url: "http://localhost:8080/hook".to_string(),
+                    secret: "secret".to_string(),
+                    events: vec![WebhookEvent::VehicleCreated],
+                    created_at: "2021-09-01T00:00:00Z".parse().unwrap(),
+                })
+            });
+        mock_queries
+            .webhook_queries
+            .expect_find_one_delivery()
+            .with(eq(Uuid::nil()), eq(Uuid::from_u128(1)))
+            .returning(|_, _| {
+                Ok(WebhookDelivery {
+                    webhook_id: Uuid::nil(),
+                    id: Uuid::from_u128(1),
+                    event: WebhookEvent::VehicleCreated,
+                    payload: "{}".to_string(),
+                    status: DeliveryStatus::DeadLetter,
+                    attempts: 8,
+                    created_at: "2021-09-01T10:00:00Z".parse().unwrap(),
+                    next_attempt_at: None,
+                    last_attempt_at: Some("2021-09-01T11:00:00Z".parse().unwrap()),
+                    last_status_code: Some(500),
+                    last_error: None,
+                })
+            });
+        mock_queries
+            .webhook_queries
+            .expect_upsert_delivery()
+            .with(function(|delivery: &WebhookDelivery| {
+                delivery.status == DeliveryStatus::Pending
+                    && delivery.attempts == 0
+                    && delivery.next_attempt_at.is_some()
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let response = redeliver_webhook_delivery(
+            Path((Uuid::nil(), Uuid::from_u128(1))),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    async fn test_delete_webhook_not_found() {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .webhook_queries
+            .expect_delete_webhook()
+            .times(1)
+            .returning(|_| Err(AppError::NotFound("Webhook")));
+
+        let response = delete_webhook(
+            Path(Uuid::nil()),
+            extract::Extension(Arc::new(mock_queries)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
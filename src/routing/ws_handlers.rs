@@ -0,0 +1,363 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{
+        self,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::IntoResponse,
+};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    db::queries::{Queries, TelemetryQueries, VehicleQueries},
+    error::AppError,
+    model::{
+        vehicle::Engine,
+        ws::{ClientMessage, ServerMessage},
+    },
+    shutdown::ShutdownSignal,
+};
+
+/// Time after which a client which does not read its messages is disconnected
+pub const WS_SEND_TIMEOUT_SECS: u64 = 10;
+
+/// VINs and engine types subscribed by a WebSocket client
+#[derive(Default, PartialEq, Debug)]
+pub struct Subscription {
+    pub vins: BTreeSet<String>,
+    pub engine_types: Vec<Engine>,
+}
+
+impl Subscription {
+    pub fn matches(&self, vin: &str, engine: Option<&Engine>) -> bool {
+        self.vins.contains(vin) || engine.map_or(false, |engine| self.engine_types.contains(engine))
+    }
+
+    fn to_message(&self) -> ServerMessage {
+        ServerMessage::Subscribed {
+            vins: self.vins.iter().cloned().collect(),
+            engine_types: self.engine_types.clone(),
+        }
+    }
+}
+
+/// Update the subscription or run the query of the message, returns the answer
+pub async fn handle_client_message<Q: Queries>(
+    queries: &Q,
+    subscription: &mut Subscription,
+    message: ClientMessage,
+) -> ServerMessage {
+    let error_message = |request_id: Option<String>, e: AppError| ServerMessage::Error {
+        request_id,
+        message: e.to_string(),
+    };
+
+    match message {
+        ClientMessage::Subscribe { vins, engine_types } => {
+            subscription.vins.extend(vins);
+            for engine in engine_types.into_iter() {
+                if !subscription.engine_types.contains(&engine) {
+                    subscription.engine_types.push(engine);
+                }
+            }
+
+            subscription.to_message()
+        }
+        ClientMessage::Unsubscribe { vins, engine_types } => {
+            for vin in vins.iter() {
+                subscription.vins.remove(vin);
+            }
+            subscription
+                .engine_types
+                .retain(|engine| !engine_types.contains(engine));
+
+            subscription.to_message()
+        }
+        ClientMessage::GetVehicle { request_id, vin } => {
+            match queries.vehicle_queries().find_one_vehicle(&vin).await {
+                Ok(vehicle) => ServerMessage::Vehicle {
+                    request_id,
+                    vehicle,
+                },
+                Err(e) => error_message(request_id, e),
+            }
+        }
+        ClientMessage::GetLatestTelemetry { request_id, vin } => {
+            match queries
+                .telemetry_queries()
+                .find_latest_soc_reading(&vin)
+                .await
+            {
+                Ok(reading) => ServerMessage::LatestTelemetry {
+                    request_id,
+                    vin,
+                    reading,
+                },
+                Err(e) => error_message(request_id, e),
+            }
+        }
+    }
+}
+
+/// Engine type of the vehicle, cached for the lifetime of the connection
+async fn find_engine<Q: Queries>(
+    queries: &Q,
+    engines: &mut HashMap<String, Engine>,
+    vin: &str,
+) -> Option<Engine> {
+    if let Some(engine) = engines.get(vin) {
+        return Some(engine.clone());
+    }
+
+    let engine = queries
+        .vehicle_queries()
+        .find_one_vehicle(vin)
+        .await
+        .ok()?
+        .engine;
+    engines.insert(vin.to_string(), engine.clone());
+
+    Some(engine)
+}
+
+/// Send the message, returns false if the connection must be closed
+async fn send_message(socket: &mut WebSocket, message: &ServerMessage) -> bool {
+    let text = match serde_json::to_string(message) {
+        Ok(text) => text,
+        Err(e) => {
+            tracing::error!("failed to serialize WebSocket message: {}", e);
+            return true;
+        }
+    };
+
+    match tokio::time::timeout(
+        Duration::from_secs(WS_SEND_TIMEOUT_SECS),
+        socket.send(Message::Text(text)),
+    )
+    .await
+    {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            tracing::debug!("WebSocket closed: {}", e);
+            false
+        }
+        Err(_) => {
+            tracing::warn!("WebSocket client too slow, disconnecting");
+            false
+        }
+    }
+}
+
+/// Serve a WebSocket client until it disconnects, or until the shutdown (the connection is then
+/// closed, so that it can be drained)
+///
+/// Back-pressure: the bus is not read while a message is being sent, so the events of a slow
+/// client are buffered by the bus, then dropped (the client gets a `lagged` message). A client
+/// which does not read at all is disconnected after `WS_SEND_TIMEOUT_SECS`.
+async fn run_connection<Q: Queries>(
+    mut socket: WebSocket,
+    queries: Arc<Q>,
+    mut shutdown: ShutdownSignal,
+) {
+    let (_, mut vehicle_events) = queries.event_bus().subscribe(None);
+    let mut telemetry_events = queries.event_bus().subscribe_telemetry();
+    let mut subscription = Subscription::default();
+    let mut engines: HashMap<String, Engine> = HashMap::new();
+
+    loop {
+        let message = tokio::select! {
+            _ = shutdown.wait() => {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(message) => Some(
+                        handle_client_message(queries.as_ref(), &mut subscription, message).await,
+                    ),
+                    Err(e) => Some(ServerMessage::Error {
+                        request_id: None,
+                        message: format!("Invalid message ({})", e),
+                    }),
+                },
+                Some(Ok(Message::Binary(_))) => Some(ServerMessage::Error {
+                    request_id: None,
+                    message: "Binary messages are not supported".to_string(),
+                }),
+                // Pings are answered by the WebSocket implementation
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => None,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            },
+            event = vehicle_events.recv() => match event {
+                Ok(event) => {
+                    let engine = match &event.vehicle {
+                        Some(vehicle) => {
+                            engines.insert(event.vin.clone(), vehicle.engine.clone());
+                            Some(vehicle.engine.clone())
+                        }
+                        None => engines.remove(&event.vin),
+                    };
+
+                    if subscription.matches(&event.vin, engine.as_ref()) {
+                        Some(ServerMessage::VehicleEvent { event })
+                    } else {
+                        None
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => Some(ServerMessage::Lagged { missed }),
+                Err(RecvError::Closed) => break,
+            },
+            event = telemetry_events.recv() => match event {
+                Ok(event) => {
+                    let engine = if subscription.vins.contains(&event.vin)
+                        || subscription.engine_types.is_empty()
+                    {
+                        None
+                    } else {
+                        find_engine(queries.as_ref(), &mut engines, &event.vin).await
+                    };
+
+                    if subscription.matches(&event.vin, engine.as_ref()) {
+                        Some(ServerMessage::Telemetry { telemetry: event })
+                    } else {
+                        None
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => Some(ServerMessage::Lagged { missed }),
+                Err(RecvError::Closed) => break,
+            },
+        };
+
+        if let Some(message) = message {
+            if !send_message(&mut socket, &message).await {
+                break;
+            }
+        }
+    }
+}
+
+#[tracing::instrument(skip(ws, queries, shutdown))]
+pub async fn get_ws<Q: Queries>(
+    ws: WebSocketUpgrade,
+    queries: extract::Extension<Arc<Q>>,
+    shutdown: extract::Extension<ShutdownSignal>,
+) -> impl IntoResponse {
+    let queries = queries.0;
+    let shutdown = shutdown.0;
+
+    ws.on_upgrade(move |socket| run_connection(socket, queries, shutdown))
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::{db::queries, model::vehicle::Vehicle};
+
+    #[tokio::test]
+    async fn test_subscribe_and_unsubscribe() {
+        let mock_queries = queries::MockQueries::default();
+        let mut subscription = Subscription::default();
+
+        let message = handle_client_message(
+            &mock_queries,
+            &mut subscription,
+            ClientMessage::Subscribe {
+                vins: vec!["vin2".to_string(), "vin1".to_string()],
+                engine_types: vec![Engine::Ev],
+            },
+        )
+        .await;
+        assert_eq!(
+            message,
+            ServerMessage::Subscribed {
+                vins: vec!["vin1".to_string(), "vin2".to_string()],
+                engine_types: vec![Engine::Ev],
+            }
+        );
+        assert!(subscription.matches("vin1", None));
+        assert!(subscription.matches("vin3", Some(&Engine::Ev)));
+        assert!(!subscription.matches("vin3", Some(&Engine::Phev)));
+
+        let message = handle_client_message(
+            &mock_queries,
+            &mut subscription,
+            ClientMessage::Unsubscribe {
+                vins: vec!["vin1".to_string()],
+                engine_types: vec![Engine::Ev],
+            },
+        )
+        .await;
+        assert_eq!(
+            message,
+            ServerMessage::Subscribed {
+                vins: vec!["vin2".to_string()],
+                engine_types: vec![],
+            }
+        );
+        assert!(!subscription.matches("vin1", Some(&Engine::Ev)));
+    }
+
+    #[tokio::test]
+    async fn test_get_vehicle() {
+        let vehicle = Vehicle {
+            vin: "vin1".to_string(),
+            owner: None,
+            engine: Engine::Combustion,
+            ev_data: None,
+        };
+        let vehicle_clone = vehicle.clone();
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin1"))
+            .returning(move |_| Ok(vehicle_clone.clone()));
+        mock_queries
+            .vehicle_queries
+            .expect_find_one_vehicle()
+            .with(eq("vin2"))
+            .returning(|_| Err(AppError::NotFound("Vehicle")));
+
+        let message = handle_client_message(
+            &mock_queries,
+            &mut Subscription::default(),
+            ClientMessage::GetVehicle {
+                request_id: Some("1".to_string()),
+                vin: "vin1".to_string(),
+            },
+        )
+        .await;
+        assert_eq!(
+            message,
+            ServerMessage::Vehicle {
+                request_id: Some("1".to_string()),
+                vehicle,
+            }
+        );
+
+        let message = handle_client_message(
+            &mock_queries,
+            &mut Subscription::default(),
+            ClientMessage::GetVehicle {
+                request_id: Some("2".to_string()),
+                vin: "vin2".to_string(),
+            },
+        )
+        .await;
+        assert_eq!(
+            message,
+            ServerMessage::Error {
+                request_id: Some("2".to_string()),
+                message: AppError::NotFound("Vehicle").to_string(),
+            }
+        );
+    }
+}
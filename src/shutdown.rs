@@ -0,0 +1,95 @@
+use tokio::{sync::watch, time::Instant};
+
+/// Shutdown of the app, signaled once to all the background tasks and servers
+///
+/// The signal carries the deadline of the in-flight work (requests, jobs, ...).
+#[derive(Debug)]
+pub struct Shutdown {
+    sender: watch::Sender<Option<Instant>>,
+    receiver: watch::Receiver<Option<Instant>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(None);
+        Shutdown { sender, receiver }
+    }
+
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.receiver.clone())
+    }
+
+    /// Request the shutdown, the in-flight work is cut off after the deadline
+    pub fn trigger(&self, deadline: Instant) {
+        // Cannot fail: a receiver is kept
+        let _ = self.sender.send(Some(deadline));
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ShutdownSignal(watch::Receiver<Option<Instant>>);
+
+impl ShutdownSignal {
+    pub fn is_triggered(&self) -> bool {
+        self.0.borrow().is_some()
+    }
+
+    /// Wait for the shutdown (immediately ready once triggered), returns its deadline
+    ///
+    /// A dropped `Shutdown` is a shutdown without delay.
+    pub async fn wait(&mut self) -> Instant {
+        loop {
+            let deadline = *self.0.borrow();
+            if let Some(deadline) = deadline {
+                return deadline;
+            }
+            if self.0.changed().await.is_err() {
+                return Instant::now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let shutdown = Shutdown::new();
+        let mut signal = shutdown.signal();
+        let mut other_signal = signal.clone();
+        assert!(!signal.is_triggered());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), signal.wait())
+                .await
+                .is_err()
+        );
+
+        let waiting = tokio::spawn(async move { other_signal.wait().await });
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        shutdown.trigger(deadline);
+        assert_eq!(waiting.await.unwrap(), deadline);
+
+        // Still signaled for the later waits
+        assert!(signal.is_triggered());
+        assert_eq!(signal.wait().await, deadline);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_dropped() {
+        let mut signal = Shutdown::new().signal();
+
+        let before = Instant::now();
+        assert!(signal.wait().await >= before);
+    }
+}
@@ -1,2 +1,5 @@
-#[derive(Debug)]
-pub struct State;
+#[derive(Default, Debug)]
+pub struct State {
+    /// Set on SIGTERM/SIGINT, the readiness probe then fails
+    pub shutting_down: bool,
+}
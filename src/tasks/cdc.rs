@@ -0,0 +1,21 @@
+use std::time::Duration;
+
+use crate::{db::scylla::cdc_reader::ScyllaCdcReader, shutdown::ShutdownSignal};
+
+/// Periodically publish the new changes of the CDC log
+pub async fn run_cdc_task(reader: ScyllaCdcReader, period: Duration, mut shutdown: ShutdownSignal) {
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shutdown.wait() => break,
+        }
+
+        match reader.read_changes().await {
+            Ok(0) => (),
+            Ok(count) => tracing::debug!("published {} change(s) of the CDC log", count),
+            Err(e) => tracing::error!("failed to read the CDC log: {}", e),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::{
+    db::queries::{JobQueries, Queries},
+    jobs,
+    shutdown::ShutdownSignal,
+};
+
+/// Jobs are leased for 60 seconds, renewed every 20 seconds while they run
+const JOB_LEASE_SECS: i64 = 60;
+const JOB_LEASE_RENEWAL_SECS: u64 = 20;
+
+/// Periodically start the pending jobs (and resume the interrupted ones) on a pool of workers
+///
+/// Each job is claimed with a lease before being run, so that a job is run by a single
+/// instance; the jobs of a stopped instance are resumed by another one once their lease expired.
+///
+/// On shutdown, no job is started anymore and the running ones are awaited until the deadline
+/// (the ones cut off are resumed on the next start).
+pub async fn run_job_task<Q: Queries>(
+    queries: Arc<Q>,
+    workers: usize,
+    period: Duration,
+    mut shutdown: ShutdownSignal,
+) {
+    let owner = Uuid::new_v4();
+    let lease_until = || chrono::Utc::now() + chrono::Duration::seconds(JOB_LEASE_SECS);
+    let semaphore = Arc::new(Semaphore::new(workers));
+    let running: Arc<Mutex<HashSet<Uuid>>> = Arc::new(Mutex::new(HashSet::new()));
+    let renewal_period = Duration::from_secs(JOB_LEASE_RENEWAL_SECS);
+    let mut interval = tokio::time::interval(period);
+
+    let deadline = loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            deadline = shutdown.wait() => break deadline,
+        }
+
+        let unfinished_jobs = match queries.job_queries().find_unfinished_jobs().await {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                tracing::error!("failed to find the unfinished jobs: {}", e);
+                continue;
+            }
+        };
+
+        for job in unfinished_jobs.into_iter() {
+            if running.lock().expect("job lock poisoned").contains(&job.id) {
+                continue;
+            }
+
+            // All the workers are busy
+            let permit = match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+
+            // Run by another instance
+            match queries
+                .job_queries()
+                .claim_job(job.id, owner, lease_until())
+                .await
+            {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(e) => {
+                    tracing::error!("failed to claim job {}: {}", job.id, e);
+                    continue;
+                }
+            }
+            running.lock().expect("job lock poisoned").insert(job.id);
+
+            let queries = queries.clone();
+            let running = running.clone();
+            tokio::spawn(async move {
+                let id = job.id;
+                let run = jobs::run_job(queries.as_ref(), job);
+                tokio::pin!(run);
+
+                // The lease is renewed until the job is finished, the job is stopped (and resumed
+                // from its last processed chunk by the new owner) if the lease is lost
+                let mut renewal = tokio::time::interval_at(
+                    tokio::time::Instant::now() + renewal_period,
+                    renewal_period,
+                );
+                loop {
+                    tokio::select! {
+                        result = &mut run => {
+                            match result {
+                                Ok(job) => tracing::info!("job {} {}", id, job.status.to_string()),
+                                Err(e) => tracing::error!("failed to run job {}: {}", id, e),
+                            }
+                            break;
+                        }
+                        _ = renewal.tick() => {
+                            match queries.job_queries().renew_job_lease(id, owner, lease_until()).await {
+                                Ok(true) => (),
+                                Ok(false) => {
+                                    tracing::warn!("job {} stopped, its lease has been lost", id);
+                                    break;
+                                }
+                                Err(e) => tracing::error!("failed to renew the lease of job {}: {}", id, e),
+                            }
+                        }
+                    }
+                }
+
+                running.lock().expect("job lock poisoned").remove(&id);
+                drop(permit);
+            });
+        }
+    };
+
+    // All the permits available once the running jobs are finished
+    let all_finished = semaphore.acquire_many(workers as u32);
+    if tokio::time::timeout_at(deadline, all_finished)
+        .await
+        .is_err()
+    {
+        for id in running.lock().expect("job lock poisoned").iter() {
+            tracing::warn!(
+                "job {} cut off by the shutdown, resumed on the next start",
+                id
+            );
+        }
+    }
+}
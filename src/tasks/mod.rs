@@ -0,0 +1,6 @@
+pub mod cdc;
+pub mod jobs;
+pub mod outbox;
+pub mod purge;
+pub mod tls;
+pub mod webhooks;
@@ -0,0 +1,30 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    db::queries::Queries,
+    outbox::{self, OutboxSink},
+    shutdown::ShutdownSignal,
+};
+
+/// Periodically relay the pending outbox entries to the sinks
+pub async fn run_outbox_relay_task<Q: Queries>(
+    queries: Arc<Q>,
+    sinks: Vec<Box<dyn OutboxSink>>,
+    period: Duration,
+    mut shutdown: ShutdownSignal,
+) {
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shutdown.wait() => break,
+        }
+
+        match outbox::relay_outbox_entries(queries.as_ref(), &sinks, chrono::Utc::now()).await {
+            Ok(0) => (),
+            Ok(count) => tracing::debug!("relayed {} outbox entries", count),
+            Err(e) => tracing::error!("failed to relay outbox entries: {}", e),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+
+use crate::{
+    db::queries::{Queries, VehicleQueries},
+    result::AppResult,
+    shutdown::ShutdownSignal,
+};
+
+/// Periodically purge the vehicles which have been (soft) deleted for longer than the retention window
+pub async fn run_purge_task<Q: Queries>(
+    queries: Arc<Q>,
+    retention: Duration,
+    period: Duration,
+    mut shutdown: ShutdownSignal,
+) {
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shutdown.wait() => break,
+        }
+
+        match purge_deleted_vehicles(queries.as_ref(), retention).await {
+            Ok(0) => (),
+            Ok(count) => tracing::info!("purged {} deleted vehicle(s)", count),
+            Err(e) => tracing::error!("failed to purge deleted vehicles: {}", e),
+        }
+    }
+}
+
+/// Purge the vehicles which have been (soft) deleted for longer than the retention window
+pub async fn purge_deleted_vehicles<Q: Queries>(
+    queries: &Q,
+    retention: Duration,
+) -> AppResult<usize> {
+    let deleted_before = Utc::now() - chrono::Duration::milliseconds(retention.as_millis() as i64);
+
+    queries
+        .vehicle_queries()
+        .purge_deleted_vehicles(deleted_before)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use mockall::predicate::function;
+
+    use super::*;
+    use crate::db::queries::{self};
+
+    #[tokio::test]
+    async fn test_purge_deleted_vehicles() -> anyhow::Result<()> {
+        let retention = Duration::from_secs(3600);
+        let latest_deleted_before = Utc::now() - chrono::Duration::hours(1);
+
+        let mut mock_vehicle_queries = queries::MockVehicleQueries::default();
+        mock_vehicle_queries
+            .expect_purge_deleted_vehicles()
+            .with(function(move |deleted_before: &DateTime<Utc>| {
+                *deleted_before >= latest_deleted_before
+                    && *deleted_before <= Utc::now() - chrono::Duration::hours(1)
+            }))
+            .times(1)
+            .returning(|_| Ok(2));
+
+        let mock_queries = queries::MockQueries {
+            vehicle_queries: mock_vehicle_queries,
+            ..Default::default()
+        };
+
+        let count = purge_deleted_vehicles(&mock_queries, retention).await?;
+        assert_eq!(count, 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,25 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{shutdown::ShutdownSignal, tls::ReloadingCertResolver};
+
+/// Periodically reload the TLS certificate when its files change
+pub async fn run_tls_reload_task(
+    resolver: Arc<ReloadingCertResolver>,
+    period: Duration,
+    mut shutdown: ShutdownSignal,
+) {
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shutdown.wait() => break,
+        }
+
+        match resolver.reload_if_changed() {
+            Ok(false) => (),
+            Ok(true) => tracing::info!("reloaded the TLS certificate"),
+            Err(e) => tracing::error!("failed to reload the TLS certificate: {}", e),
+        }
+    }
+}
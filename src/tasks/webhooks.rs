@@ -0,0 +1,32 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{db::queries::Queries, shutdown::ShutdownSignal, webhooks};
+
+/// Periodically attempt the due webhook deliveries (first attempts and retries)
+///
+/// The redirects are not followed: they could lead to private hosts.
+pub async fn run_webhook_delivery_task<Q: Queries>(
+    queries: Arc<Q>,
+    period: Duration,
+    allow_private_hosts: bool,
+    mut shutdown: ShutdownSignal,
+) {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap_or_else(|_| reqwest::Client::new());
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shutdown.wait() => break,
+        }
+
+        match webhooks::deliver_due_webhooks(queries.as_ref(), &client, allow_private_hosts).await {
+            Ok(0) => (),
+            Ok(count) => tracing::debug!("delivered {} webhook event(s)", count),
+            Err(e) => tracing::error!("failed to deliver webhook events: {}", e),
+        }
+    }
+}
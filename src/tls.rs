@@ -0,0 +1,296 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context as _;
+use futures::{Stream, StreamExt};
+use rustls::{
+    server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+use crate::{error::AppError, listener::ConnectionInfo, result::AppResult};
+
+/// Slow clients cannot hold a handshake slot for longer
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of TLS handshakes run concurrently by the listener
+pub const MAX_CONCURRENT_TLS_HANDSHAKES: usize = 128;
+
+/// TLS settings of the server (from the command line)
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// PEM file of the certificate chain
+    pub cert_path: PathBuf,
+    /// PEM file of the private key (PKCS#8, RSA or EC)
+    pub key_path: PathBuf,
+    /// PEM file of the CA of the client certificates, which are then required (mTLS)
+    pub client_ca_path: Option<PathBuf>,
+}
+
+/// Identity of a client authenticated with a certificate (mTLS)
+///
+/// Added to the requests of the connection, e.g. in a handler:
+/// `client_identity: Option<extract::Extension<ClientIdentity>>`
+#[derive(Clone, PartialEq, Debug)]
+pub struct ClientIdentity {
+    /// Subject of the certificate, e.g. `CN=client1, O=Example`
+    pub subject: String,
+    pub common_name: Option<String>,
+    /// Hex-encoded SHA-256 of the certificate (DER)
+    pub fingerprint: String,
+}
+
+impl ClientIdentity {
+    pub fn from_certificate(der: &[u8]) -> AppResult<Self> {
+        let (_, certificate) = x509_parser::parse_x509_certificate(der)
+            .map_err(|_| AppError::ConversionError("Client certificate"))?;
+        let subject = certificate.subject();
+
+        Ok(ClientIdentity {
+            subject: subject.to_string(),
+            common_name: subject
+                .iter_common_name()
+                .next()
+                .and_then(|common_name| common_name.as_str().ok())
+                .map(str::to_string),
+            fingerprint: hex::encode(Sha256::digest(der)),
+        })
+    }
+}
+
+fn load_certs(path: &Path) -> AppResult<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    if certs.is_empty() {
+        return Err(AppError::InvalidInput("TLS certificate"));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &Path) -> AppResult<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(AppError::InvalidInput("TLS private key"))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> AppResult<CertifiedKey> {
+    let certs = load_certs(cert_path)?;
+    let key = sign::any_supported_type(&load_private_key(key_path)?)
+        .map_err(|_| AppError::InvalidInput("TLS private key"))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Modification times of the files (None if not readable)
+fn modified(paths: &[&Path]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+/// Server certificate, reloaded when its files change (new connections only)
+pub struct ReloadingCertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    loaded: RwLock<LoadedCert>,
+}
+
+impl ReloadingCertResolver {
+    pub fn try_new(cert_path: &Path, key_path: &Path) -> AppResult<Self> {
+        let modified = modified(&[cert_path, key_path]);
+        let key = Arc::new(load_certified_key(cert_path, key_path)?);
+
+        Ok(ReloadingCertResolver {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            loaded: RwLock::new(LoadedCert { key, modified }),
+        })
+    }
+
+    /// Reload the certificate if one of its files changed, returns true if reloaded
+    ///
+    /// The current certificate is kept if the files cannot be loaded (e.g. partially written),
+    /// the reload is then attempted again on the next call.
+    pub fn reload_if_changed(&self) -> AppResult<bool> {
+        let modified = modified(&[&self.cert_path, &self.key_path]);
+        if self.loaded.read().expect("TLS lock poisoned").modified == modified {
+            return Ok(false);
+        }
+
+        let key = Arc::new(load_certified_key(&self.cert_path, &self.key_path)?);
+        *self.loaded.write().expect("TLS lock poisoned") = LoadedCert { key, modified };
+
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.loaded.read().expect("TLS lock poisoned").key.clone())
+    }
+}
+
+/// Server config negotiating HTTP/2 or HTTP/1.1 (ALPN)
+pub fn create_server_config(
+    config: &TlsConfig,
+    resolver: Arc<ReloadingCertResolver>,
+) -> AppResult<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+
+    let mut server_config = match &config.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_path)?.iter() {
+                roots
+                    .add(cert)
+                    .map_err(|_| AppError::InvalidInput("TLS client CA"))?;
+            }
+            builder
+                .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+                .with_cert_resolver(resolver)
+        }
+        None => builder.with_no_client_auth().with_cert_resolver(resolver),
+    };
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(Arc::new(server_config))
+}
+
+/// TLS connections, for `hyper::server::accept::from_stream`
+///
+/// The handshakes are run concurrently, the failed ones are logged and skipped (instead of
+/// stopping the server).
+pub fn incoming<IO>(
+    connections: impl Stream<Item = IO>,
+    acceptor: TlsAcceptor,
+) -> impl Stream<Item = io::Result<TlsStream<IO>>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    connections
+        .map(move |stream| {
+            let acceptor = acceptor.clone();
+            async move { tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await }
+        })
+        .buffer_unordered(MAX_CONCURRENT_TLS_HANDSHAKES)
+        .filter_map(|handshake| async move {
+            match handshake {
+                Ok(Ok(stream)) => Some(Ok(stream)),
+                Ok(Err(e)) => {
+                    tracing::debug!("TLS handshake failed: {}", e);
+                    None
+                }
+                Err(_) => {
+                    tracing::debug!("TLS handshake timed out");
+                    None
+                }
+            }
+        })
+}
+
+impl<IO: ConnectionInfo> ConnectionInfo for TlsStream<IO> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.remote_addr()
+    }
+
+    fn client_identity(&self) -> Option<ClientIdentity> {
+        let cert = self.get_ref().1.peer_certificates()?.first()?;
+
+        ClientIdentity::from_certificate(&cert.0)
+            .map_err(|e| tracing::warn!("invalid client certificate: {}", e))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed certificate and key files in a new temporary directory
+    fn write_cert_files(name: &str) -> (PathBuf, PathBuf, rcgen::Certificate) {
+        let dir = std::env::temp_dir().join(format!("hello-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        (cert_path, key_path, cert)
+    }
+
+    fn served_cert(resolver: &ReloadingCertResolver) -> Vec<u8> {
+        resolver.loaded.read().unwrap().key.cert[0].0.clone()
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let (cert_path, key_path, cert) = write_cert_files("localhost");
+        let resolver = ReloadingCertResolver::try_new(&cert_path, &key_path).unwrap();
+        assert_eq!(served_cert(&resolver), cert.serialize_der().unwrap());
+        assert!(!resolver.reload_if_changed().unwrap());
+
+        // Partially written files => current certificate kept
+        std::fs::write(&cert_path, "").unwrap();
+        assert!(resolver.reload_if_changed().is_err());
+        assert_eq!(served_cert(&resolver), cert.serialize_der().unwrap());
+
+        // New certificate
+        let new_cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&cert_path, new_cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, new_cert.serialize_private_key_pem()).unwrap();
+        assert!(resolver.reload_if_changed().unwrap());
+        assert_eq!(served_cert(&resolver), new_cert.serialize_der().unwrap());
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "client1");
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let der = cert.serialize_der().unwrap();
+
+        let identity = ClientIdentity::from_certificate(&der).unwrap();
+        assert_eq!(identity.common_name.as_deref(), Some("client1"));
+        assert_eq!(identity.subject, "CN=client1");
+        assert_eq!(identity.fingerprint, hex::encode(Sha256::digest(&der)));
+
+        // TODO: user assert_matches! when stable
+        assert!(matches!(
+            ClientIdentity::from_certificate(b"invalid"),
+            Err(AppError::ConversionError("Client certificate"))
+        ));
+    }
+}
@@ -0,0 +1,484 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    db::queries::{Queries, WebhookQueries},
+    error::AppError,
+    model::{
+        outbox::OutboxEntry,
+        webhook::{
+            is_private_ip, DeliveryStatus, Webhook, WebhookDelivery, WebhookEvent, WebhookPayload,
+        },
+    },
+    outbox::OutboxSink,
+    result::AppResult,
+};
+
+/// Header with the hex-encoded HMAC-SHA256 of the body, e.g. `sha256=5bdc...`
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const EVENT_HEADER: &str = "X-Webhook-Event";
+pub const DELIVERY_HEADER: &str = "X-Webhook-Delivery";
+
+/// Number of attempts before a delivery is dead-lettered
+pub const WEBHOOK_MAX_ATTEMPTS: i32 = 8;
+
+/// Delay before the first retry, doubled after each failed attempt
+pub const WEBHOOK_RETRY_BASE_DELAY_SECS: i64 = 10;
+
+pub const WEBHOOK_MAX_RETRY_DELAY_SECS: i64 = 3600;
+
+/// Timeout of a delivery attempt
+pub const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+/// Maximum number of concurrent delivery attempts
+const WEBHOOK_CONCURRENCY: usize = 8;
+
+/// Signature of the body, as sent in the signature header
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Delay before the next attempt after the given number of failed attempts
+pub fn retry_delay(attempts: i32) -> chrono::Duration {
+    let exponent = (attempts - 1).clamp(0, 16) as u32;
+    let delay = WEBHOOK_RETRY_BASE_DELAY_SECS.saturating_mul(2_i64.pow(exponent));
+
+    chrono::Duration::seconds(delay.min(WEBHOOK_MAX_RETRY_DELAY_SECS))
+}
+
+/// Whether the host of the URL resolves to public addresses only (no loopback, private or
+/// link-local address), checked when the webhook is configured and before each attempt
+pub async fn resolves_to_public_host(url: &str) -> bool {
+    let url = match reqwest::Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    let (host, port) = match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => (host.trim_start_matches('[').trim_end_matches(']'), port),
+        _ => return false,
+    };
+
+    match tokio::net::lookup_host((host, port)).await {
+        Ok(addrs) => {
+            let addrs: Vec<_> = addrs.collect();
+            !addrs.is_empty() && addrs.iter().all(|addr| !is_private_ip(addr.ip()))
+        }
+        Err(_) => false,
+    }
+}
+
+/// Persist a pending delivery of the vehicle change for each subscribed webhook
+///
+/// The webhooks created after the change are skipped. The ids of the deliveries are derived
+/// from the id of the outbox entry, and the existing deliveries are kept as is, so that the
+/// deliveries are neither duplicated nor attempted again if the entry is relayed again.
+pub async fn enqueue_webhook_deliveries<Q: Queries>(
+    queries: &Q,
+    entry: &OutboxEntry,
+) -> AppResult<()> {
+    let event = WebhookEvent::from(entry.event_type);
+    let webhooks: Vec<Webhook> = queries
+        .webhook_queries()
+        .find_webhooks()
+        .await?
+        .into_iter()
+        .filter(|webhook| webhook.is_subscribed(event) && webhook.created_at <= entry.occurred_at)
+        .collect();
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    let payload = serde_json::to_string(&WebhookPayload {
+        id: entry.id,
+        event,
+        occurred_at: entry.occurred_at,
+        vin: entry.vin.clone(),
+        vehicle: entry.vehicle.clone(),
+    })
+    .map_err(|_| AppError::ConversionError("WebhookPayload to JSON"))?;
+
+    for webhook in webhooks.iter() {
+        let delivery = WebhookDelivery {
+            webhook_id: webhook.id,
+            id: Uuid::new_v5(&entry.id, webhook.id.as_bytes()),
+            event,
+            payload: payload.clone(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            created_at: now,
+            next_attempt_at: Some(now),
+            last_attempt_at: None,
+            last_status_code: None,
+            last_error: None,
+        };
+        match queries.webhook_queries().insert_delivery(&delivery).await {
+            Ok(()) | Err(AppError::AlreadyExists(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Outbox sink enqueuing the deliveries of the vehicle changes to the subscribed webhooks
+///
+/// The changes made through the API and the ones read from the CDC log are both written to
+/// the outbox, so that no change is missed if the process stops right after it.
+pub struct WebhookSink<Q: Queries> {
+    pub queries: Arc<Q>,
+}
+
+impl<Q: Queries> std::fmt::Debug for WebhookSink<Q> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebhookSink").finish()
+    }
+}
+
+#[async_trait]
+impl<Q: Queries> OutboxSink for WebhookSink<Q> {
+    async fn deliver(&self, entry: &OutboxEntry) -> AppResult<()> {
+        enqueue_webhook_deliveries(self.queries.as_ref(), entry).await
+    }
+}
+
+/// Outcome of a delivery attempt: HTTP status code, or error if no response was received
+pub type AttemptResult = Result<u16, String>;
+
+/// New state of the delivery after an attempt
+pub fn apply_attempt_result(
+    delivery: &WebhookDelivery,
+    result: AttemptResult,
+    now: DateTime<Utc>,
+) -> WebhookDelivery {
+    let attempts = delivery.attempts + 1;
+    let (last_status_code, last_error) = match &result {
+        Ok(status_code) => (Some(*status_code as i32), None),
+        Err(e) => (None, Some(e.clone())),
+    };
+
+    let (status, next_attempt_at) = match result {
+        Ok(status_code) if (200..300).contains(&status_code) => (DeliveryStatus::Delivered, None),
+        _ if attempts >= WEBHOOK_MAX_ATTEMPTS => (DeliveryStatus::DeadLetter, None),
+        _ => (DeliveryStatus::Pending, Some(now + retry_delay(attempts))),
+    };
+
+    WebhookDelivery {
+        status,
+        attempts,
+        next_attempt_at,
+        last_attempt_at: Some(now),
+        last_status_code,
+        last_error,
+        ..delivery.clone()
+    }
+}
+
+/// POST the signed payload to the webhook URL
+///
+/// The client must not follow redirects, the host of the URL being checked here only.
+pub async fn send_delivery(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+    allow_private_hosts: bool,
+) -> AttemptResult {
+    if !allow_private_hosts && !resolves_to_public_host(&webhook.url).await {
+        return Err("Webhook host does not resolve to public addresses".to_string());
+    }
+
+    let response = client
+        .post(&webhook.url)
+        .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, sign(&webhook.secret, &delivery.payload))
+        .header(EVENT_HEADER, delivery.event.to_string())
+        .header(DELIVERY_HEADER, delivery.id.to_string())
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(response.status().as_u16())
+}
+
+/// Attempt the due deliveries, returns the number of successful ones
+pub async fn deliver_due_webhooks<Q: Queries>(
+    queries: &Q,
+    client: &reqwest::Client,
+    allow_private_hosts: bool,
+) -> AppResult<usize> {
+    let deliveries = queries
+        .webhook_queries()
+        .find_due_deliveries(Utc::now())
+        .await?;
+
+    let results: Vec<AppResult<WebhookDelivery>> = futures::stream::iter(deliveries)
+        .map(|delivery| async move {
+            let result = match queries
+                .webhook_queries()
+                .find_one_webhook(delivery.webhook_id)
+                .await
+            {
+                Ok(webhook) => {
+                    send_delivery(client, &webhook, &delivery, allow_private_hosts).await
+                }
+                Err(AppError::NotFound(_)) => {
+                    // No more retries for deleted webhooks
+                    let delivery = WebhookDelivery {
+                        status: DeliveryStatus::DeadLetter,
+                        next_attempt_at: None,
+                        last_error: Some("Webhook deleted".to_string()),
+                        ..delivery
+                    };
+                    queries.webhook_queries().upsert_delivery(&delivery).await?;
+                    return Ok(delivery);
+                }
+                Err(e) => return Err(e),
+            };
+
+            let delivery = apply_attempt_result(&delivery, result, Utc::now());
+            if delivery.status == DeliveryStatus::DeadLetter {
+                tracing::warn!(
+                    "webhook delivery {} of webhook {} dead-lettered after {} attempt(s)",
+                    delivery.id,
+                    delivery.webhook_id,
+                    delivery.attempts
+                );
+            }
+            queries.webhook_queries().upsert_delivery(&delivery).await?;
+
+            Ok(delivery)
+        })
+        .buffer_unordered(WEBHOOK_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut delivered = 0;
+    for result in results.into_iter() {
+        if result?.status == DeliveryStatus::Delivered {
+            delivered += 1;
+        }
+    }
+
+    Ok(delivered)
+}
+
+/// Schedule a new series of attempts for the delivery (e.g. after it was dead-lettered)
+pub fn reset_delivery(delivery: &WebhookDelivery, now: DateTime<Utc>) -> WebhookDelivery {
+    WebhookDelivery {
+        status: DeliveryStatus::Pending,
+        attempts: 0,
+        next_attempt_at: Some(now),
+        ..delivery.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::function;
+
+    use super::*;
+    use crate::{
+        db::queries,
+        model::{
+            event::VehicleEventType,
+            vehicle::{Engine, Vehicle},
+        },
+    };
+
+    fn datetime(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn webhook(events: Vec<WebhookEvent>) -> Webhook {
+        Webhook {
+            id: Uuid::nil(),
+            url: "http://localhost:8080/hook".to_string(),
+            secret: "secret".to_string(),
+            events,
+            created_at: datetime("2021-09-01T00:00:00Z"),
+        }
+    }
+
+    fn pending_delivery(attempts: i32) -> WebhookDelivery {
+        WebhookDelivery {
+            webhook_id: Uuid::nil(),
+            id: Uuid::nil(),
+            event: WebhookEvent::VehicleCreated,
+            payload: "{}".to_string(),
+            status: DeliveryStatus::Pending,
+            attempts,
+            created_at: datetime("2021-09-01T10:00:00Z"),
+            next_attempt_at: Some(datetime("2021-09-01T10:00:00Z")),
+            last_attempt_at: None,
+            last_status_code: None,
+            last_error: None,
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), chrono::Duration::seconds(10));
+        assert_eq!(retry_delay(2), chrono::Duration::seconds(20));
+        assert_eq!(retry_delay(4), chrono::Duration::seconds(80));
+        assert_eq!(retry_delay(20), chrono::Duration::seconds(3600));
+    }
+
+    #[test]
+    fn test_apply_attempt_result() {
+        let now = datetime("2021-09-01T12:00:00Z");
+
+        // Success
+        let delivery = apply_attempt_result(&pending_delivery(0), Ok(204), now);
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.next_attempt_at, None);
+        assert_eq!(delivery.last_status_code, Some(204));
+
+        // Failure => retry with backoff
+        let delivery = apply_attempt_result(&pending_delivery(2), Ok(500), now);
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert_eq!(delivery.attempts, 3);
+        assert_eq!(
+            delivery.next_attempt_at,
+            Some(datetime("2021-09-01T12:00:40Z"))
+        );
+
+        // Last failure => dead letter
+        let delivery = apply_attempt_result(
+            &pending_delivery(WEBHOOK_MAX_ATTEMPTS - 1),
+            Err("connection refused".to_string()),
+            now,
+        );
+        assert_eq!(delivery.status, DeliveryStatus::DeadLetter);
+        assert_eq!(delivery.next_attempt_at, None);
+        assert_eq!(delivery.last_error, Some("connection refused".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_webhook_deliveries() -> anyhow::Result<()> {
+        let vehicle = Vehicle {
+            vin: "vin".to_string(),
+            owner: None,
+            engine: Engine::Combustion,
+            ev_data: None,
+        };
+
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .webhook_queries
+            .expect_find_webhooks()
+            .returning(|| {
+                Ok(vec![
+                    webhook(vec![WebhookEvent::VehicleCreated]),
+                    Webhook {
+                        id: Uuid::from_u128(1),
+                        ..webhook(vec![WebhookEvent::VehicleDeleted])
+                    },
+                ])
+            });
+
+        let entry = OutboxEntry::new(VehicleEventType::Created, "vin", Some(vehicle));
+        let entry_id = entry.id;
+
+        // Only the subscribed webhook gets a delivery, identified by the outbox entry
+        mock_queries
+            .webhook_queries
+            .expect_insert_delivery()
+            .with(function(|delivery: &WebhookDelivery| {
+                let payload: WebhookPayload = serde_json::from_str(&delivery.payload).unwrap();
+
+                delivery.webhook_id == Uuid::nil()
+                    && delivery.id == Uuid::new_v5(&entry_id, Uuid::nil().as_bytes())
+                    && payload.id == entry_id
+                    && delivery.status == DeliveryStatus::Pending
+                    && payload.event == WebhookEvent::VehicleCreated
+                    && payload.vin == "vin"
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        enqueue_webhook_deliveries(&mock_queries, &entry).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_webhook_deliveries_relayed_again() -> anyhow::Result<()> {
+        let mut mock_queries = queries::MockQueries::default();
+        mock_queries
+            .webhook_queries
+            .expect_find_webhooks()
+            .returning(|| Ok(vec![webhook(vec![WebhookEvent::VehicleDeleted])]));
+
+        // Existing delivery (e.g. already delivered) => kept as is
+        mock_queries
+            .webhook_queries
+            .expect_insert_delivery()
+            .times(1)
+            .returning(|_| Err(AppError::AlreadyExists("Webhook delivery")));
+        mock_queries
+            .webhook_queries
+            .expect_upsert_delivery()
+            .times(0);
+
+        let entry = OutboxEntry::new(VehicleEventType::Deleted, "vin", None);
+        enqueue_webhook_deliveries(&mock_queries, &entry).await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_private_ip() {
+        for ip in vec![
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_private_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in vec![
+            "93.184.216.34",
+            "2606:2800:220:1::1",
+            "::ffff:93.184.216.34",
+        ] {
+            assert!(!is_private_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolves_to_public_host() {
+        assert!(resolves_to_public_host("https://93.184.216.34/hook").await);
+        assert!(!resolves_to_public_host("http://127.0.0.1:8080/hook").await);
+        assert!(!resolves_to_public_host("http://[::1]/hook").await);
+        assert!(!resolves_to_public_host("not a url").await);
+    }
+}
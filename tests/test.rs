@@ -4,15 +4,21 @@ use serde_json::json;
 use std::{
     net::{SocketAddr, TcpListener},
     sync::Arc,
+    time::Duration,
 };
 
 use hello::{
     self,
+    auth::ApiKey,
     db::{
-        queries::{Queries, VehicleQueries},
+        queries::{Queries, TelemetryQueries, VehicleQueries},
         scylla::queries::ScyllaQueries,
     },
-    model::vehicle::{Engine, Vehicle},
+    model::{
+        telemetry::SocReading,
+        vehicle::{Engine, EvData, Vehicle},
+    },
+    shutdown::Shutdown,
 };
 
 #[tokio::test]
@@ -60,6 +66,32 @@ async fn test_post_vehicle() -> Result<()> {
         .await?;
     assert_eq!(res.status(), StatusCode::CONFLICT);
 
+    // Concurrent insertions of the same vehicle => a single one is CREATED (and stored)
+    let responses = futures::future::join_all((0..4).map(|i| {
+        client
+            .post(format!("http://{}/vehicle", ctx.addr))
+            .json(&json!({ "vin": "vin2", "owner": format!("owner{}", i), "engine_type": "Combustion" }))
+            .send()
+    }))
+    .await;
+
+    let mut created = vec![];
+    for (i, res) in responses.into_iter().enumerate() {
+        match res?.status() {
+            StatusCode::CREATED => created.push(format!("owner{}", i)),
+            status => assert_eq!(status, StatusCode::CONFLICT),
+        }
+    }
+    assert_eq!(created.len(), 1);
+    assert_eq!(
+        ctx.queries
+            .vehicle_queries()
+            .find_one_vehicle("vin2")
+            .await?
+            .owner,
+        created.pop()
+    );
+
     Ok(())
 }
 
@@ -79,6 +111,7 @@ async fn test_get_vehicle() -> Result<()> {
     // Add vehicle to database
     let vehicle = Vehicle {
         vin: "vin1".to_string(),
+        owner: None,
         engine: Engine::Combustion,
         ev_data: None,
     };
@@ -117,6 +150,7 @@ async fn test_delete_vehicle() -> Result<()> {
     // Add vehicle to database
     let vehicle = Vehicle {
         vin: "vin1".to_string(),
+        owner: None,
         engine: Engine::Combustion,
         ev_data: None,
     };
@@ -143,57 +177,2169 @@ async fn test_delete_vehicle() -> Result<()> {
     Ok(())
 }
 
-fn json_value(s: &str) -> Result<serde_json::Value> {
-    Ok(serde_json::from_str::<serde_json::Value>(s)?)
-}
+#[tokio::test]
+async fn test_restore_vehicle() -> Result<()> {
+    let ctx = Context::try_new().await?;
 
-const TEST_KEYSPACE: &'static str = "hello_test";
+    let client = reqwest::Client::new();
 
-struct Context {
-    queries: Arc<ScyllaQueries>,
-    addr: SocketAddr,
-}
+    // Restore non-existing vehicle => NOT_FOUND
+    let res = client
+        .post(format!("http://{}/vehicle/vin1/restore", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
 
-impl Context {
-    async fn try_new() -> Result<Self> {
-        let queries = Arc::new(create_test_queries().await?);
-        let addr = serve(queries.clone()).await?;
+    // Add vehicle to database
+    let vehicle = Vehicle {
+        vin: "vin1".to_string(),
+        owner: None,
+        engine: Engine::Combustion,
+        ev_data: None,
+    };
+    ctx.queries
+        .vehicle_queries()
+        .create_vehicle(&vehicle)
+        .await?;
 
-        Ok(Self { queries, addr })
-    }
+    // Restore non-deleted vehicle => NOT_FOUND
+    let res = client
+        .post(format!("http://{}/vehicle/vin1/restore", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // Delete vehicle, then insert it again => CONFLICT
+    ctx.queries
+        .vehicle_queries()
+        .delete_one_vehicle("vin1")
+        .await?;
+    let res = client
+        .post(format!("http://{}/vehicle", ctx.addr))
+        .json(&vehicle)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    // Restore deleted vehicle => OK
+    let res = client
+        .post(format!("http://{}/vehicle/vin1/restore", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // Check returned vehicle
+    let body = res.text().await.unwrap();
+    assert_eq!(json_value(&body)?, serde_json::to_value(&vehicle)?);
+
+    // Ensure that it can be found again
+    assert_eq!(
+        ctx.queries
+            .vehicle_queries()
+            .find_one_vehicle("vin1")
+            .await
+            .ok(),
+        Some(vehicle),
+    );
+
+    Ok(())
 }
 
-impl Drop for Context {
-    fn drop(&mut self) {}
+#[tokio::test]
+async fn test_purge_vehicle() -> Result<()> {
+    let ctx = Context::try_new_with_config(hello::config::Config {
+        admin_api_key: Some(ApiKey::new("admin")),
+        ..Default::default()
+    })
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    // Purge non-existing vehicle => NOT_FOUND
+    let res = client
+        .delete(format!("http://{}/vehicle/vin1?purge=true", ctx.addr))
+        .header("X-Api-Key", "admin")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // Add vehicle and its telemetry to database, then delete it
+    let vehicle = Vehicle {
+        vin: "vin1".to_string(),
+        owner: None,
+        engine: Engine::Ev,
+        ev_data: Some(EvData {
+            battery_capacity_in_kwh: 62,
+            soc_in_percent: 50,
+        }),
+    };
+    ctx.queries
+        .vehicle_queries()
+        .create_vehicle(&vehicle)
+        .await?;
+    let now = chrono::Utc::now();
+    ctx.queries
+        .telemetry_queries()
+        .insert_soc_readings(
+            "vin1",
+            &[SocReading {
+                timestamp: now,
+                soc_in_percent: 50,
+            }],
+        )
+        .await?;
+    ctx.queries
+        .vehicle_queries()
+        .delete_one_vehicle("vin1")
+        .await?;
+
+    // Purge without the admin API key => FORBIDDEN
+    let res = client
+        .delete(format!("http://{}/vehicle/vin1?purge=true", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = client
+        .delete(format!("http://{}/vehicle/vin1?purge=true", ctx.addr))
+        .header("X-Api-Key", "other")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // Purge deleted vehicle => OK
+    let res = client
+        .delete(format!("http://{}/vehicle/vin1?purge=true", ctx.addr))
+        .header("X-Api-Key", "admin")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // Ensure that its telemetry has been removed too
+    let readings = ctx
+        .queries
+        .telemetry_queries()
+        .find_soc_readings(
+            "vin1",
+            now - chrono::Duration::days(1),
+            now + chrono::Duration::days(1),
+        )
+        .await?;
+    assert!(readings.is_empty());
+    assert!(ctx
+        .queries
+        .telemetry_queries()
+        .find_latest_soc_reading("vin1")
+        .await?
+        .is_none());
+
+    // Ensure that it cannot be restored anymore
+    let res = client
+        .post(format!("http://{}/vehicle/vin1/restore", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // Vehicles deleted before the retention window are purged by the background task
+    ctx.queries
+        .vehicle_queries()
+        .create_vehicle(&vehicle)
+        .await?;
+    ctx.queries
+        .vehicle_queries()
+        .delete_one_vehicle("vin1")
+        .await?;
+    let count = ctx
+        .queries
+        .vehicle_queries()
+        .purge_deleted_vehicles(chrono::Utc::now() + chrono::Duration::seconds(1))
+        .await?;
+    assert_eq!(count, 1);
+
+    Ok(())
 }
-async fn create_test_queries() -> Result<ScyllaQueries> {
+
+#[tokio::test]
+async fn test_migrate_vehicles_table() -> Result<()> {
     use scylla::SessionBuilder;
 
     let uri = std::env::var("SCYLLA_URI").unwrap_or_else(|_| "127.0.0.1:9042".to_string());
-    let session = SessionBuilder::new().known_node(uri).build().await.unwrap();
-
-    // First, delete test keyspace to have a fresh test data
+    let session = SessionBuilder::new().known_node(uri).build().await?;
     session
         .query(format!("DROP KEYSPACE IF EXISTS {}", TEST_KEYSPACE), &[])
-        .await
-        .unwrap_or_default();
+        .await?;
 
-    Ok(ScyllaQueries::new(session, TEST_KEYSPACE).await?)
+    // Vehicles table created by a previous version (without the owner and deleted_at columns)
+    let cql_array = [
+        format!("CREATE KEYSPACE {} WITH REPLICATION = {{'class' : 'SimpleStrategy', 'replication_factor' : 1}}", TEST_KEYSPACE),
+        format!("CREATE TYPE {}.ev_data (battery_capacity_in_kwh int, soc_in_percent int)", TEST_KEYSPACE),
+        format!("CREATE TABLE {}.vehicles (vin text primary key, engine_type text, ev_data ev_data)", TEST_KEYSPACE),
+    ];
+    for cql in cql_array.iter() {
+        session.query(cql.as_ref(), &[]).await?;
+    }
+
+    // Missing columns added
+    let queries = ScyllaQueries::new(session, TEST_KEYSPACE).await?;
+    let vehicle = Vehicle {
+        vin: "vin1".to_string(),
+        owner: Some("Alice".to_string()),
+        engine: Engine::Combustion,
+        ev_data: None,
+    };
+    queries.vehicle_queries().create_vehicle(&vehicle).await?;
+    queries.vehicle_queries().delete_one_vehicle("vin1").await?;
+    assert_eq!(
+        queries
+            .vehicle_queries()
+            .restore_one_vehicle("vin1")
+            .await?,
+        vehicle
+    );
+
+    Ok(())
 }
 
-async fn serve<Q: Queries>(queries: Arc<Q>) -> Result<SocketAddr> {
-    // TCP listener
-    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
-    let listener = TcpListener::bind(&addr)?;
-    let addr = listener.local_addr()?;
+#[tokio::test]
+async fn test_telemetry() -> Result<()> {
+    let ctx = Context::try_new().await?;
 
-    // App
-    let app = hello::app::App::new(queries);
+    let client = reqwest::Client::new();
 
-    // Run our app
-    tracing::debug!("listening on {:?}", listener);
-    let server = axum::Server::from_tcp(listener)?.serve(app.router.into_make_service());
-    tokio::spawn(async move { server.await });
+    // Add vehicle to database
+    let vehicle = Vehicle {
+        vin: "vin1".to_string(),
+        owner: None,
+        engine: Engine::Ev,
+        ev_data: Some(EvData {
+            battery_capacity_in_kwh: 62,
+            soc_in_percent: 50,
+        }),
+    };
+    ctx.queries
+        .vehicle_queries()
+        .create_vehicle(&vehicle)
+        .await?;
 
-    Ok(addr)
+    // Insert readings spanning 2 days => CREATED
+    let readings_json = json!([
+        { "timestamp": "2021-09-01T23:00:00Z", "soc_in_percent": 55 },
+        { "timestamp": "2021-09-02T01:00:00Z", "soc_in_percent": 61 },
+        { "timestamp": "2021-09-02T00:00:00Z", "soc_in_percent": 58 },
+    ]);
+    let res = client
+        .post(format!("http://{}/vehicle/vin1/telemetry", ctx.addr))
+        .json(&readings_json)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    // Ensure that the current SoC is the newest one
+    assert_eq!(
+        ctx.queries
+            .vehicle_queries()
+            .find_one_vehicle("vin1")
+            .await?
+            .ev_data,
+        Some(EvData {
+            battery_capacity_in_kwh: 62,
+            soc_in_percent: 61,
+        }),
+    );
+
+    // Get readings in range => OK, ordered by timestamp
+    let res = client
+        .get(format!("http://{}/vehicle/vin1/telemetry", ctx.addr))
+        .query(&[
+            ("from", "2021-09-01T00:00:00Z"),
+            ("to", "2021-09-02T01:00:00Z"),
+        ])
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = res.text().await.unwrap();
+    assert_eq!(
+        json_value(&body)?,
+        json!([
+            { "timestamp": "2021-09-01T23:00:00Z", "soc_in_percent": 55 },
+            { "timestamp": "2021-09-02T00:00:00Z", "soc_in_percent": 58 },
+        ])
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_telemetry_aggregate() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Add vehicle to database
+    let vehicle = Vehicle {
+        vin: "vin1".to_string(),
+        owner: None,
+        engine: Engine::Phev,
+        ev_data: None,
+    };
+    ctx.queries
+        .vehicle_queries()
+        .create_vehicle(&vehicle)
+        .await?;
+
+    // Insert readings in 2 batches (rollups are refreshed after each one)
+    for readings_json in [
+        json!([
+            { "timestamp": "2021-09-01T10:00:00Z", "soc_in_percent": 50 },
+            { "timestamp": "2021-09-01T10:20:00Z", "soc_in_percent": 40 },
+        ]),
+        json!([
+            { "timestamp": "2021-09-01T10:40:00Z", "soc_in_percent": 45 },
+            { "timestamp": "2021-09-01T12:00:00Z", "soc_in_percent": 80 },
+        ]),
+    ]
+    .iter()
+    {
+        let res = client
+            .post(format!("http://{}/vehicle/vin1/telemetry", ctx.addr))
+            .json(readings_json)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    // Get hourly aggregates => OK
+    let res = client
+        .get(format!(
+            "http://{}/vehicle/vin1/telemetry/aggregate",
+            ctx.addr
+        ))
+        .query(&[
+            ("interval", "1h"),
+            ("from", "2021-09-01T00:00:00Z"),
+            ("to", "2021-09-02T00:00:00Z"),
+        ])
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = res.text().await.unwrap();
+    assert_eq!(
+        json_value(&body)?,
+        json!([
+            { "start": "2021-09-01T10:00:00Z", "min": 40, "max": 50, "avg": 45.0, "first": 50, "last": 45, "count": 3 },
+            { "start": "2021-09-01T12:00:00Z", "min": 80, "max": 80, "avg": 80.0, "first": 80, "last": 80, "count": 1 },
+        ])
+    );
+
+    // Get aggregates with unknown interval => BAD_REQUEST
+    let res = client
+        .get(format!(
+            "http://{}/vehicle/vin1/telemetry/aggregate?interval=1y",
+            ctx.addr
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_charging_sessions() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Add vehicles to database
+    for vehicle in [
+        Vehicle {
+            vin: "vin1".to_string(),
+            owner: None,
+            engine: Engine::Ev,
+            ev_data: Some(EvData {
+                battery_capacity_in_kwh: 50,
+                soc_in_percent: 20,
+            }),
+        },
+        Vehicle {
+            vin: "vin2".to_string(),
+            owner: None,
+            engine: Engine::Combustion,
+            ev_data: None,
+        },
+    ]
+    .iter()
+    {
+        ctx.queries
+            .vehicle_queries()
+            .create_vehicle(vehicle)
+            .await?;
+    }
+
+    // Start session of combustion vehicle => BAD_REQUEST
+    let res = client
+        .post(format!(
+            "http://{}/vehicle/vin2/charging-sessions",
+            ctx.addr
+        ))
+        .json(&json!({ "start_soc_in_percent": 20 }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // Start session => CREATED
+    let res = client
+        .post(format!(
+            "http://{}/vehicle/vin1/charging-sessions",
+            ctx.addr
+        ))
+        .json(&json!({
+            "start_time": "2021-09-01T10:00:00Z",
+            "start_soc_in_percent": 20,
+            "location": "Home",
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let session = json_value(&res.text().await?)?;
+    let id = session["id"].as_str().unwrap().to_string();
+
+    // Update session => OK
+    let res = client
+        .put(format!(
+            "http://{}/vehicle/vin1/charging-sessions/{}",
+            ctx.addr, id
+        ))
+        .json(&json!({ "soc_in_percent": 50, "energy_added_in_kwh": 15.0, "power_in_kw": 11.0 }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // End session => OK
+    let res = client
+        .post(format!(
+            "http://{}/vehicle/vin1/charging-sessions/{}/end",
+            ctx.addr, id
+        ))
+        .json(&json!({ "end_time": "2021-09-01T13:00:00Z", "soc_in_percent": 80, "energy_added_in_kwh": 30.0 }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // End session again => BAD_REQUEST
+    let res = client
+        .post(format!(
+            "http://{}/vehicle/vin1/charging-sessions/{}/end",
+            ctx.addr, id
+        ))
+        .json(&json!({}))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // Get session => OK
+    let res = client
+        .get(format!(
+            "http://{}/vehicle/vin1/charging-sessions/{}",
+            ctx.addr, id
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        json_value(&res.text().await?)?,
+        json!({
+            "id": id,
+            "vin": "vin1",
+            "start_time": "2021-09-01T10:00:00Z",
+            "end_time": "2021-09-01T13:00:00Z",
+            "start_soc_in_percent": 20,
+            "end_soc_in_percent": 80,
+            "energy_added_in_kwh": 30.0,
+            "max_power_in_kw": 11.0,
+            "location": "Home",
+            "detected": false,
+        })
+    );
+
+    // Insert telemetry with a SoC rise => charging session detected
+    let res = client
+        .post(format!("http://{}/vehicle/vin1/telemetry", ctx.addr))
+        .json(&json!([
+            { "timestamp": "2021-09-02T10:00:00Z", "soc_in_percent": 40 },
+            { "timestamp": "2021-09-02T10:30:00Z", "soc_in_percent": 60 },
+            { "timestamp": "2021-09-02T11:00:00Z", "soc_in_percent": 70 },
+        ]))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    // Get fleet summary => OK, reported and detected sessions in the same week
+    let res = client
+        .get(format!("http://{}/fleet/charging-summary", ctx.addr))
+        .query(&[
+            ("from", "2021-09-01T00:00:00Z"),
+            ("to", "2021-10-01T00:00:00Z"),
+        ])
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        json_value(&res.text().await?)?,
+        json!({
+            "session_count": 2,
+            "total_energy_in_kwh": 45.0,
+            "weeks": [
+                { "week_start": "2021-08-30", "session_count": 2, "energy_in_kwh": 45.0 },
+            ],
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ev_estimate() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Add vehicle to database
+    let vehicle = Vehicle {
+        vin: "vin1".to_string(),
+        owner: None,
+        engine: Engine::Ev,
+        ev_data: Some(EvData {
+            battery_capacity_in_kwh: 50,
+            soc_in_percent: 20,
+        }),
+    };
+    ctx.queries
+        .vehicle_queries()
+        .create_vehicle(&vehicle)
+        .await?;
+
+    // Report trips => consumption learned (15 kWh/100km)
+    for trip_json in [
+        json!({ "distance_in_km": 150.0, "energy_used_in_kwh": 20.0 }),
+        json!({ "distance_in_km": 50.0, "energy_used_in_kwh": 10.0 }),
+    ]
+    .iter()
+    {
+        let res = client
+            .post(format!("http://{}/vehicle/vin1/ev/trips", ctx.addr))
+            .json(trip_json)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    // Get estimate => OK
+    let res = client
+        .get(format!(
+            "http://{}/vehicle/vin1/ev/estimate?charger_kw=10&target_soc=80",
+            ctx.addr
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = json_value(&res.text().await?)?;
+    assert_eq!(body["consumption_source"], json!("learned"));
+    assert_eq!(body["consumption_in_kwh_per_100km"], json!(15.0));
+    assert_eq!(body["charge_time_in_minutes"], json!(180));
+
+    // Configure consumption => used instead of the learned one
+    let res = client
+        .put(format!("http://{}/vehicle/vin1/ev/consumption", ctx.addr))
+        .json(&json!({ "consumption_in_kwh_per_100km": 20.0 }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client
+        .get(format!("http://{}/vehicle/vin1/ev/estimate", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = json_value(&res.text().await?)?;
+    assert_eq!(body["consumption_source"], json!("configured"));
+    assert_eq!(body["range_in_km"], json!(50.0));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_battery_health() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Add vehicle to database
+    let vehicle = Vehicle {
+        vin: "vin1".to_string(),
+        owner: None,
+        engine: Engine::Ev,
+        ev_data: Some(EvData {
+            battery_capacity_in_kwh: 50,
+            soc_in_percent: 20,
+        }),
+    };
+    ctx.queries
+        .vehicle_queries()
+        .create_vehicle(&vehicle)
+        .await?;
+
+    // Report measured capacities => CREATED
+    for report_json in [
+        json!({ "measured_at": "2020-01-01T00:00:00Z", "usable_capacity_in_kwh": 48.0 }),
+        json!({ "measured_at": "2021-01-01T00:00:00Z", "usable_capacity_in_kwh": 42.0 }),
+    ]
+    .iter()
+    {
+        let res = client
+            .post(format!("http://{}/vehicle/vin1/battery-health", ctx.addr))
+            .json(report_json)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    // Get SoH history => OK, ordered by measurement time
+    let res = client
+        .get(format!("http://{}/vehicle/vin1/battery-health", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        json_value(&res.text().await?)?,
+        json!([
+            { "measured_at": "2020-01-01T00:00:00Z", "usable_capacity_in_kwh": 48.0, "soh_in_percent": 96.0 },
+            { "measured_at": "2021-01-01T00:00:00Z", "usable_capacity_in_kwh": 42.0, "soh_in_percent": 84.0 },
+        ])
+    );
+
+    // Get fleet report => OK, flagged below the threshold
+    let res = client
+        .get(format!(
+            "http://{}/fleet/battery-health?alert_threshold=85",
+            ctx.addr
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = json_value(&res.text().await?)?;
+    assert_eq!(body[0]["vin"], json!("vin1"));
+    assert_eq!(body[0]["soh_in_percent"], json!(84.0));
+    assert_eq!(body[0]["alert"], json!(true));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_alerts() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Add vehicle to database
+    let vehicle = Vehicle {
+        vin: "vin1".to_string(),
+        owner: Some("owner1".to_string()),
+        engine: Engine::Ev,
+        ev_data: Some(EvData {
+            battery_capacity_in_kwh: 50,
+            soc_in_percent: 50,
+        }),
+    };
+    ctx.queries
+        .vehicle_queries()
+        .create_vehicle(&vehicle)
+        .await?;
+
+    // Create rule => CREATED
+    let res = client
+        .post(format!("http://{}/alert-rules", ctx.addr))
+        .json(&json!({ "scope": { "engine_type": "Ev" }, "soc_below_in_percent": 15 }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    // Low SoC, then flapping around the threshold => only one alert
+    for (timestamp, soc) in [
+        ("2021-09-01T10:00:00Z", 10),
+        ("2021-09-01T10:05:00Z", 16),
+        ("2021-09-01T10:10:00Z", 14),
+    ]
+    .iter()
+    {
+        let res = client
+            .post(format!("http://{}/vehicle/vin1/telemetry", ctx.addr))
+            .json(&json!([{ "timestamp": timestamp, "soc_in_percent": soc }]))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    let res = client
+        .get(format!("http://{}/alerts?status=active", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = json_value(&res.text().await?)?;
+    assert_eq!(body.as_array().map(|alerts| alerts.len()), Some(1));
+    assert_eq!(body[0]["vin"], json!("vin1"));
+    assert_eq!(body[0]["soc_in_percent"], json!(10));
+    let id = body[0]["id"].as_str().unwrap_or_default().to_string();
+
+    // Acknowledge => OK
+    let res = client
+        .post(format!(
+            "http://{}/alerts/vin1/{}/acknowledge",
+            ctx.addr, id
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        json_value(&res.text().await?)?["status"],
+        json!("acknowledged")
+    );
+
+    // Resolve => OK
+    let res = client
+        .post(format!("http://{}/alerts/vin1/{}/resolve", ctx.addr, id))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // Acknowledge resolved alert => BAD REQUEST
+    let res = client
+        .post(format!(
+            "http://{}/alerts/vin1/{}/acknowledge",
+            ctx.addr, id
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // No more active alert
+    let res = client
+        .get(format!("http://{}/alerts?status=active&vin=vin1", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(json_value(&res.text().await?)?, json!([]));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_webhooks() -> Result<()> {
+    let ctx = Context::try_new_with_config(hello::config::Config {
+        admin_api_key: Some(ApiKey::new("admin")),
+        webhook_allow_private_hosts: true,
+        ..Default::default()
+    })
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    // Local receiver, outbox relay (enqueuing the deliveries) and delivery task
+    let (receiver_addr, mut received) = serve_webhook_receiver().await?;
+    let shutdown = hello::shutdown::Shutdown::new();
+    let sinks: Vec<Box<dyn hello::outbox::OutboxSink>> =
+        vec![Box::new(hello::webhooks::WebhookSink {
+            queries: ctx.queries.clone(),
+        })];
+    tokio::spawn(hello::tasks::outbox::run_outbox_relay_task(
+        ctx.queries.clone(),
+        sinks,
+        Duration::from_millis(100),
+        shutdown.signal(),
+    ));
+    tokio::spawn(hello::tasks::webhooks::run_webhook_delivery_task(
+        ctx.queries.clone(),
+        Duration::from_millis(100),
+        true,
+        shutdown.signal(),
+    ));
+
+    // Subscribe without the admin API key => FORBIDDEN
+    let res = client
+        .post(format!("http://{}/webhooks", ctx.addr))
+        .json(&json!({
+            "url": format!("http://{}/hook", receiver_addr),
+            "secret": "secret",
+            "events": ["vehicle.created"],
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // Subscribe to created vehicles => CREATED, secret not returned
+    let res = client
+        .post(format!("http://{}/webhooks", ctx.addr))
+        .header("X-Api-Key", "admin")
+        .json(&json!({
+            "url": format!("http://{}/hook", receiver_addr),
+            "secret": "secret",
+            "events": ["vehicle.created"],
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let webhook = json_value(&res.text().await?)?;
+    assert_eq!(webhook["events"], json!(["vehicle.created"]));
+    assert_eq!(webhook.get("secret"), None);
+    let webhook_id = webhook["id"].as_str().unwrap_or_default().to_string();
+
+    // Create then delete vehicle => only the creation is delivered, with a valid signature
+    let res = client
+        .post(format!("http://{}/vehicle", ctx.addr))
+        .json(&json!({ "vin": "vin1", "engine_type": "Combustion" }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = client
+        .delete(format!("http://{}/vehicle/vin1", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let (signature, body) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+        .await?
+        .unwrap_or_default();
+    assert_eq!(signature, hello::webhooks::sign("secret", &body));
+
+    let payload = json_value(&body)?;
+    assert_eq!(payload["event"], json!("vehicle.created"));
+    assert_eq!(payload["vin"], json!("vin1"));
+    assert_eq!(payload["vehicle"]["engine_type"], json!("Combustion"));
+
+    // Delivery log => delivered after one attempt
+    let res = client
+        .get(format!(
+            "http://{}/webhooks/{}/deliveries",
+            ctx.addr, webhook_id
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let deliveries = json_value(&res.text().await?)?;
+    assert_eq!(
+        deliveries.as_array().map(|deliveries| deliveries.len()),
+        Some(1)
+    );
+    assert_eq!(deliveries[0]["status"], json!("delivered"));
+    assert_eq!(deliveries[0]["attempts"], json!(1));
+    assert_eq!(deliveries[0]["last_status_code"], json!(200));
+    let delivery_id = deliveries[0]["id"].as_str().unwrap_or_default().to_string();
+
+    // Redeliver => ACCEPTED, same event delivered again
+    let res = client
+        .post(format!(
+            "http://{}/webhooks/{}/deliveries/{}/redeliver",
+            ctx.addr, webhook_id, delivery_id
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let (_, redelivered_body) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+        .await?
+        .unwrap_or_default();
+    assert_eq!(redelivered_body, body);
+
+    // Unsubscribe => OK, then NOT FOUND
+    let res = client
+        .delete(format!("http://{}/webhooks/{}", ctx.addr, webhook_id))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client
+        .get(format!("http://{}/webhooks/{}", ctx.addr, webhook_id))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vehicle_events() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Subscribe to the events of vin1 => OK
+    let res = client
+        .get(format!("http://{}/vehicle/events?vin=vin1", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok()),
+        Some("text/event-stream")
+    );
+    let events = res.bytes_stream();
+
+    // Create vin2, then create and delete vin1
+    for vin in ["vin2", "vin1"].iter() {
+        let res = client
+            .post(format!("http://{}/vehicle", ctx.addr))
+            .json(&json!({ "vin": vin, "engine_type": "Combustion" }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+    let res = client
+        .delete(format!("http://{}/vehicle/vin1", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // Only the events of vin1 are streamed
+    let text = read_events_until(events, "event: deleted").await?;
+    assert!(text.contains("event: created"));
+    assert!(text.contains("id: 2\n"));
+    assert!(text.contains("id: 3\n"));
+    assert!(!text.contains("vin2"));
+
+    // Resume after the creation of vin1 => the deletion is replayed
+    let res = client
+        .get(format!("http://{}/vehicle/events", ctx.addr))
+        .header("Last-Event-ID", "2")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let text = read_events_until(res.bytes_stream(), "event: deleted").await?;
+    assert!(text.contains("id: 3\n"));
+    assert!(!text.contains("event: created"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_websocket() -> Result<()> {
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Connect and subscribe to EVs
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", ctx.addr)).await?;
+    socket
+        .send(Message::Text(
+            json!({ "type": "subscribe", "engine_types": ["Ev"] }).to_string(),
+        ))
+        .await?;
+    assert_eq!(
+        next_ws_message(&mut socket).await?,
+        json!({ "type": "subscribed", "vins": [], "engine_types": ["Ev"] })
+    );
+
+    // Create a combustion vehicle and an EV => only the EV is notified
+    for vehicle_json in [
+        json!({ "vin": "vin1", "engine_type": "Combustion" }),
+        json!({ "vin": "vin2", "engine_type": "Ev", "ev_data": { "battery_capacity_in_kwh": 50, "soc_in_percent": 20 } }),
+    ]
+    .iter()
+    {
+        let res = client
+            .post(format!("http://{}/vehicle", ctx.addr))
+            .json(vehicle_json)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    let message = next_ws_message(&mut socket).await?;
+    assert_eq!(message["type"], json!("vehicle_event"));
+    assert_eq!(message["event"]["type"], json!("created"));
+    assert_eq!(message["event"]["vin"], json!("vin2"));
+
+    // Post telemetry => telemetry and vehicle update
+    let res = client
+        .post(format!("http://{}/vehicle/vin2/telemetry", ctx.addr))
+        .json(&json!([{ "timestamp": "2021-09-01T10:00:00Z", "soc_in_percent": 30 }]))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let mut types = vec![];
+    for _ in 0..2 {
+        let message = next_ws_message(&mut socket).await?;
+        types.push(message["type"].as_str().unwrap_or_default().to_string());
+    }
+    types.sort();
+    assert_eq!(types, vec!["telemetry", "vehicle_event"]);
+
+    // Query => answer with the request id
+    socket
+        .send(Message::Text(
+            json!({ "type": "get_vehicle", "request_id": "42", "vin": "vin1" }).to_string(),
+        ))
+        .await?;
+    let message = next_ws_message(&mut socket).await?;
+    assert_eq!(message["type"], json!("vehicle"));
+    assert_eq!(message["request_id"], json!("42"));
+    assert_eq!(message["vehicle"]["engine_type"], json!("Combustion"));
+
+    // Invalid message => error
+    socket.send(Message::Text("{}".to_string())).await?;
+    assert_eq!(next_ws_message(&mut socket).await?["type"], json!("error"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vehicle_import() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Existing vehicle
+    let vehicle = Vehicle {
+        vin: "vin2".to_string(),
+        owner: None,
+        engine: Engine::Combustion,
+        ev_data: None,
+    };
+    ctx.queries
+        .vehicle_queries()
+        .create_vehicle(&vehicle)
+        .await?;
+
+    let csv = "vin,owner,engine_type,battery_capacity_in_kwh,soc_in_percent\n\
+               vin1,Alice,Ev,50,80\n\
+               vin2,,Combustion,,\n\
+               vin3,,Diesel,,\n";
+
+    // Dry run => nothing is created
+    let res = client
+        .post(format!("http://{}/vehicle/import?dry_run=true", ctx.addr))
+        .header("Content-Type", "text/csv")
+        .body(csv)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let report = json_value(&res.text().await?)?;
+    assert_eq!(report["dry_run"], json!(true));
+    assert_eq!(report["created"], json!(1));
+    assert!(ctx
+        .queries
+        .vehicle_queries()
+        .find_one_vehicle("vin1")
+        .await
+        .is_err());
+
+    // Import => per-line report
+    let res = client
+        .post(format!("http://{}/vehicle/import", ctx.addr))
+        .header("Content-Type", "text/csv")
+        .body(csv)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let report = json_value(&res.text().await?)?;
+    assert_eq!(
+        report,
+        json!({
+            "dry_run": false,
+            "created": 1,
+            "already_exists": 1,
+            "invalid": 1,
+            "failed": 0,
+            "lines": [
+                {"line": 2, "vin": "vin1", "status": "created"},
+                {"line": 3, "vin": "vin2", "status": "already_exists"},
+                {"line": 4, "status": "invalid", "reason": "Invalid engine type (Diesel)"},
+            ]
+        })
+    );
+    assert!(ctx
+        .queries
+        .vehicle_queries()
+        .find_one_vehicle("vin1")
+        .await
+        .is_ok());
+
+    // NDJSON
+    let res = client
+        .post(format!("http://{}/vehicle/import", ctx.addr))
+        .header("Content-Type", "application/x-ndjson")
+        .body("{\"vin\":\"vin4\",\"engine_type\":\"Phev\"}\n")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(json_value(&res.text().await?)?["created"], json!(1));
+
+    // Unsupported content type => BAD_REQUEST
+    let res = client
+        .post(format!("http://{}/vehicle/import", ctx.addr))
+        .header("Content-Type", "text/plain")
+        .body("vin1")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_import_job() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Job workers
+    let shutdown = hello::shutdown::Shutdown::new();
+    tokio::spawn(hello::tasks::jobs::run_job_task(
+        ctx.queries.clone(),
+        2,
+        Duration::from_millis(100),
+        shutdown.signal(),
+    ));
+
+    // Submit import => ACCEPTED, pending job
+    let ndjson = "{\"vin\":\"vin1\",\"engine_type\":\"Combustion\"}\n{\"vin\":\"vin2\"}\n";
+    let res = client
+        .post(format!("http://{}/jobs/import", ctx.addr))
+        .header("Content-Type", "application/x-ndjson")
+        .body(ndjson)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let job = json_value(&res.text().await?)?;
+    assert_eq!(job["kind"], json!("import"));
+    assert_eq!(job["status"], json!("pending"));
+    assert_eq!(job["total_chunks"], json!(1));
+    let job_id = job["id"].as_str().unwrap_or_default().to_string();
+
+    // Wait for the end of the job
+    let mut job = json!({});
+    for _ in 0..50 {
+        let res = client
+            .get(format!("http://{}/jobs/{}", ctx.addr, job_id))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        job = json_value(&res.text().await?)?;
+        if job["status"] == json!("succeeded") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(job["status"], json!("succeeded"));
+    assert_eq!(job["processed_chunks"], json!(1));
+    assert_eq!(job["result"]["created"], json!(1));
+    assert_eq!(job["result"]["invalid"], json!(1));
+    assert!(ctx
+        .queries
+        .vehicle_queries()
+        .find_one_vehicle("vin1")
+        .await
+        .is_ok());
+
+    // Cancel finished job => BAD_REQUEST
+    let res = client
+        .post(format!("http://{}/jobs/{}/cancel", ctx.addr, job_id))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // Unknown job => NOT_FOUND
+    let res = client
+        .get(format!("http://{}/jobs/{}", ctx.addr, uuid::Uuid::nil()))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_export_job() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Job workers
+    let shutdown = hello::shutdown::Shutdown::new();
+    tokio::spawn(hello::tasks::jobs::run_job_task(
+        ctx.queries.clone(),
+        2,
+        Duration::from_millis(100),
+        shutdown.signal(),
+    ));
+
+    // Vehicles
+    for (vin, engine) in vec![("vin1", Engine::Ev), ("vin2", Engine::Combustion)] {
+        let vehicle = Vehicle {
+            vin: vin.to_string(),
+            owner: None,
+            engine,
+            ev_data: None,
+        };
+        ctx.queries
+            .vehicle_queries()
+            .create_vehicle(&vehicle)
+            .await?;
+    }
+
+    // Parquet export job => BAD_REQUEST
+    let res = client
+        .post(format!("http://{}/jobs/export?format=parquet", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // Submit export => ACCEPTED, pending job
+    let res = client
+        .post(format!(
+            "http://{}/jobs/export?format=csv&engine_type=Combustion",
+            ctx.addr
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let job = json_value(&res.text().await?)?;
+    assert_eq!(job["kind"], json!("export"));
+    assert_eq!(job["status"], json!("pending"));
+    let job_id = job["id"].as_str().unwrap_or_default().to_string();
+
+    // Wait for the end of the job
+    let mut job = json!({});
+    for _ in 0..50 {
+        let res = client
+            .get(format!("http://{}/jobs/{}", ctx.addr, job_id))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        job = json_value(&res.text().await?)?;
+        if job["status"] == json!("succeeded") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(job["status"], json!("succeeded"));
+    assert_eq!(job["result"]["exported"], json!(1));
+
+    // Get output => OK
+    let res = client
+        .get(format!("http://{}/jobs/{}/output", ctx.addr, job_id))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get("content-type").unwrap().to_str()?,
+        "text/csv"
+    );
+    assert_eq!(
+        res.text().await?,
+        "vin,owner,engine_type,battery_capacity_in_kwh,soc_in_percent\nvin2,,Combustion,,\n"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vehicle_export() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    // Vehicles (the deleted one is not exported)
+    for (vin, engine, ev_data) in vec![
+        (
+            "vin1",
+            Engine::Ev,
+            Some(EvData {
+                battery_capacity_in_kwh: 50,
+                soc_in_percent: 80,
+            }),
+        ),
+        ("vin2", Engine::Combustion, None),
+        ("vin3", Engine::Combustion, None),
+    ] {
+        let vehicle = Vehicle {
+            vin: vin.to_string(),
+            owner: None,
+            engine,
+            ev_data,
+        };
+        ctx.queries
+            .vehicle_queries()
+            .create_vehicle(&vehicle)
+            .await?;
+    }
+    ctx.queries
+        .vehicle_queries()
+        .delete_one_vehicle("vin3")
+        .await?;
+
+    // NDJSON (default)
+    let res = client
+        .get(format!("http://{}/vehicle/export", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["Content-Type"], "application/x-ndjson");
+    let mut vins = res
+        .text()
+        .await?
+        .lines()
+        .map(|line| Ok(json_value(line)?["vin"].clone()))
+        .collect::<Result<Vec<_>>>()?;
+    vins.sort_by_key(|vin| vin.to_string());
+    assert_eq!(vins, vec![json!("vin1"), json!("vin2")]);
+
+    // CSV, EVs only
+    let res = client
+        .get(format!(
+            "http://{}/vehicle/export?format=csv&engine_type=Ev",
+            ctx.addr
+        ))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["Content-Type"], "text/csv");
+    assert_eq!(
+        res.text().await?,
+        "vin,owner,engine_type,battery_capacity_in_kwh,soc_in_percent\nvin1,,Ev,50,80\n"
+    );
+
+    // Parquet
+    let res = client
+        .get(format!("http://{}/vehicle/export?format=parquet", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = res.bytes().await?;
+    assert!(bytes.starts_with(b"PAR1"));
+    assert!(bytes.ends_with(b"PAR1"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vehicle_content_negotiation() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    let vehicle = Vehicle {
+        vin: "vin1".to_string(),
+        owner: None,
+        engine: Engine::Ev,
+        ev_data: Some(EvData {
+            battery_capacity_in_kwh: 50,
+            soc_in_percent: 80,
+        }),
+    };
+
+    // MessagePack request, CBOR response => CREATED
+    let res = client
+        .post(format!("http://{}/vehicle", ctx.addr))
+        .header("Content-Type", "application/msgpack")
+        .header("Accept", "application/cbor")
+        .body(rmp_serde::to_vec_named(&vehicle)?)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(res.headers()["Content-Type"], "application/cbor");
+    assert_eq!(
+        serde_cbor::from_slice::<Vehicle>(&res.bytes().await?)?,
+        vehicle
+    );
+
+    // MessagePack response
+    let res = client
+        .get(format!("http://{}/vehicle/vin1", ctx.addr))
+        .header("Accept", "application/msgpack")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        rmp_serde::from_read_ref::<_, Vehicle>(&res.bytes().await?)?,
+        vehicle
+    );
+
+    // Unsupported response format => NOT_ACCEPTABLE
+    let res = client
+        .get(format!("http://{}/vehicle/vin1", ctx.addr))
+        .header("Accept", "text/html")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+
+    // Unsupported request format => UNSUPPORTED_MEDIA_TYPE
+    let res = client
+        .post(format!("http://{}/vehicle", ctx.addr))
+        .header("Content-Type", "text/xml")
+        .body("<vehicle/>")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_grpc_vehicle_service() -> Result<()> {
+    use futures::StreamExt;
+    use hello::grpc::{
+        proto::{self, vehicle_service_client::VehicleServiceClient},
+        vehicle_service::VehicleGrpcService,
+    };
+
+    let ctx = Context::try_new().await?;
+
+    // gRPC server
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let grpc_addr = listener.local_addr()?;
+    let shutdown = Shutdown::new();
+    let service =
+        proto::vehicle_service_server::VehicleServiceServer::new(VehicleGrpcService::new(
+            ctx.queries.clone(),
+            Arc::new(Default::default()),
+            shutdown.signal(),
+        ));
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+    );
+
+    let mut client = VehicleServiceClient::connect(format!("http://{}", grpc_addr)).await?;
+
+    let vehicle = proto::Vehicle {
+        vin: "vin1".to_string(),
+        owner: "Alice".to_string(),
+        engine_type: proto::EngineType::Ev as i32,
+        ev_data: Some(proto::EvData {
+            battery_capacity_in_kwh: 50,
+            soc_in_percent: 80,
+        }),
+    };
+
+    // Create => also visible from the REST API
+    let created = client.create(vehicle.clone()).await?.into_inner();
+    assert_eq!(created, vehicle);
+    let res = reqwest::get(format!("http://{}/vehicle/vin1", ctx.addr)).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // Create again => ALREADY_EXISTS
+    let status = client.create(vehicle.clone()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::AlreadyExists);
+
+    // Missing engine type => INVALID_ARGUMENT
+    let status = client
+        .create(proto::Vehicle {
+            vin: "vin2".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    // Get, list
+    let found = client
+        .get(proto::GetVehicleRequest {
+            vin: "vin1".to_string(),
+        })
+        .await?
+        .into_inner();
+    assert_eq!(found, vehicle);
+    let listed: Vec<_> = client
+        .list(proto::ListVehiclesRequest::default())
+        .await?
+        .into_inner()
+        .collect()
+        .await;
+    assert_eq!(listed.len(), 1);
+
+    // Watch from the beginning => creation event
+    let mut events = client
+        .watch(proto::WatchVehiclesRequest {
+            vin: "vin1".to_string(),
+            last_event_id: u64::MAX,
+        })
+        .await?
+        .into_inner();
+    let event = events.next().await.expect("no event")?;
+    assert_eq!(event.event_type, proto::VehicleEventType::Created as i32);
+    assert_eq!(event.vehicle, Some(vehicle));
+
+    // Shutdown => the watch stream ends
+    shutdown.trigger(tokio::time::Instant::now() + Duration::from_secs(30));
+    assert!(events.next().await.is_none());
+
+    // Delete => NOT_FOUND
+    client
+        .delete(proto::DeleteVehicleRequest {
+            vin: "vin1".to_string(),
+            purge: false,
+        })
+        .await?;
+    let status = client
+        .get(proto::GetVehicleRequest {
+            vin: "vin1".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_graphql() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+    let graphql = |query: &str| {
+        client
+            .post(format!("http://{}/graphql", ctx.addr))
+            .json(&json!({ "query": query }))
+            .send()
+    };
+
+    // Create vehicles
+    for vin in ["vin1", "vin2", "vin3"].iter() {
+        let res = graphql(&format!(
+            r#"mutation {{ createVehicle(vehicle: {{vin: "{}", engineType: EV, evData: {{batteryCapacityInKwh: 50, socInPercent: 80}}}}) {{ vin }} }}"#,
+            vin
+        ))
+        .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            json_value(&res.text().await?)?,
+            json!({"data": {"createVehicle": {"vin": vin}}})
+        );
+    }
+    let res = client
+        .post(format!("http://{}/vehicle/vin1/telemetry", ctx.addr))
+        .json(&json!([{"timestamp": "2021-09-01T10:00:00Z", "soc_in_percent": 75}]))
+        .send()
+        .await?;
+    assert!(res.status().is_success());
+
+    // Vehicle with its latest SoC
+    let res = graphql(r#"{ vehicle(vin: "vin1") { vin engineType latestSoc { socInPercent } } }"#)
+        .await?;
+    assert_eq!(
+        json_value(&res.text().await?)?,
+        json!({"data": {"vehicle": {"vin": "vin1", "engineType": "EV", "latestSoc": {"socInPercent": 75}}}})
+    );
+
+    // Pages of 2 vehicles
+    let query = |after: &str| {
+        format!(
+            r#"{{ vehicles(first: 2{}) {{ edges {{ node {{ vin }} }} pageInfo {{ hasNextPage endCursor }} }} }}"#,
+            after
+        )
+    };
+    let page = json_value(&graphql(&query("")).await?.text().await?)?;
+    assert_eq!(
+        page["data"]["vehicles"]["edges"].as_array().map(Vec::len),
+        Some(2)
+    );
+    assert_eq!(
+        page["data"]["vehicles"]["pageInfo"]["hasNextPage"],
+        json!(true)
+    );
+    let after = format!(
+        r#", after: {}"#,
+        page["data"]["vehicles"]["pageInfo"]["endCursor"]
+    );
+    let page = json_value(&graphql(&query(&after)).await?.text().await?)?;
+    assert_eq!(
+        page["data"]["vehicles"]["edges"].as_array().map(Vec::len),
+        Some(1)
+    );
+    assert_eq!(
+        page["data"]["vehicles"]["pageInfo"]["hasNextPage"],
+        json!(false)
+    );
+
+    // Delete => not found anymore
+    let res = graphql(r#"mutation { deleteVehicle(vin: "vin1") }"#).await?;
+    assert_eq!(
+        json_value(&res.text().await?)?,
+        json!({"data": {"deleteVehicle": true}})
+    );
+    let res = graphql(r#"{ vehicle(vin: "vin1") { vin } }"#).await?;
+    assert_eq!(
+        json_value(&res.text().await?)?,
+        json!({"data": {"vehicle": null}})
+    );
+
+    // GraphiQL playground
+    let res = reqwest::get(format!("http://{}/graphql", ctx.addr)).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vehicle_batch() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    for vin in ["vin1", "vin2"].iter() {
+        let vehicle = Vehicle {
+            vin: vin.to_string(),
+            owner: None,
+            engine: Engine::Combustion,
+            ev_data: None,
+        };
+        ctx.queries
+            .vehicle_queries()
+            .create_vehicle(&vehicle)
+            .await?;
+    }
+
+    // Batch get => found and missing vehicles
+    let res = client
+        .post(format!("http://{}/vehicle/batch-get", ctx.addr))
+        .json(&json!({"vins": ["vin1", "vin3", "vin2"]}))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        json_value(&res.text().await?)?,
+        json!({
+            "vehicles": [
+                {"vin": "vin1", "engine_type": "Combustion"},
+                {"vin": "vin2", "engine_type": "Combustion"},
+            ],
+            "missing": ["vin3"],
+        })
+    );
+
+    // Batch delete => deleted and missing vehicles
+    let res = client
+        .post(format!("http://{}/vehicle/batch-delete", ctx.addr))
+        .json(&json!({"vins": ["vin1", "vin3"]}))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        json_value(&res.text().await?)?,
+        json!({"deleted": ["vin1"], "missing": ["vin3"], "failed": []})
+    );
+    assert!(ctx
+        .queries
+        .vehicle_queries()
+        .find_one_vehicle("vin1")
+        .await
+        .is_err());
+
+    // Too many VINs => BAD_REQUEST
+    let vins: Vec<String> = (0..101).map(|i| format!("vin{}", i)).collect();
+    let res = client
+        .post(format!("http://{}/vehicle/batch-get", ctx.addr))
+        .json(&json!({ "vins": vins }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vehicle_idempotency_key() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+    let vehicle = json!({"vin": "vin1", "engine_type": "Combustion"});
+
+    // First request => CREATED
+    let res = client
+        .post(format!("http://{}/vehicle", ctx.addr))
+        .header("Idempotency-Key", "key1")
+        .json(&vehicle)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert!(res.headers().get("Idempotent-Replayed").is_none());
+    let body = res.text().await?;
+
+    // Retry with the same body => original response replayed (instead of CONFLICT)
+    let res = client
+        .post(format!("http://{}/vehicle", ctx.addr))
+        .header("Idempotency-Key", "key1")
+        .json(&vehicle)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(res.headers()["Idempotent-Replayed"], "true");
+    assert_eq!(res.text().await?, body);
+
+    // Same key with another body => UNPROCESSABLE_ENTITY
+    let res = client
+        .post(format!("http://{}/vehicle", ctx.addr))
+        .header("Idempotency-Key", "key1")
+        .json(&json!({"vin": "vin2", "engine_type": "Combustion"}))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(ctx
+        .queries
+        .vehicle_queries()
+        .find_one_vehicle("vin2")
+        .await
+        .is_err());
+
+    // Without key => CONFLICT
+    let res = client
+        .post(format!("http://{}/vehicle", ctx.addr))
+        .json(&vehicle)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rate_limit() -> Result<()> {
+    let config = hello::config::Config {
+        rate_limits: vec![hello::rate_limit::parse_rule("GET /vehicle/:vin=2/60")?],
+        api_keys: vec![ApiKey::new("key1")],
+        ..Default::default()
+    };
+    let ctx = Context::try_new_with_config(config).await?;
+
+    let client = reqwest::Client::new();
+
+    // Within the limit => NOT_FOUND, with the remaining requests
+    for remaining in vec!["1", "0"] {
+        let res = client
+            .get(format!("http://{}/vehicle/vin1", ctx.addr))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()["ratelimit-limit"], "2");
+        assert_eq!(res.headers()["ratelimit-remaining"], remaining);
+    }
+
+    // Above the limit => TOO_MANY_REQUESTS
+    let res = client
+        .get(format!("http://{}/vehicle/vin1", ctx.addr))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers()["retry-after"], "30");
+
+    // Unknown API key => still limited by IP address
+    let res = client
+        .get(format!("http://{}/vehicle/vin1", ctx.addr))
+        .header("X-Api-Key", "key2")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Other client (known API key) or route => not limited
+    let res = client
+        .get(format!("http://{}/vehicle/vin1", ctx.addr))
+        .header("X-Api-Key", "key1")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = client
+        .delete(format!("http://{}/vehicle/vin1", ctx.addr))
+        .send()
+        .await?;
+    assert!(res.headers().get("ratelimit-limit").is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cors_compression_and_body_limit() -> Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let config = hello::config::Config {
+        cors_allowed_origins: vec!["https://app.example.com".parse()?],
+        max_body_size: 128,
+        ..Default::default()
+    };
+    let ctx = Context::try_new_with_config(config).await?;
+
+    let client = reqwest::Client::new();
+
+    // CORS preflight => allowed origin
+    let res = client
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("http://{}/vehicle", ctx.addr),
+        )
+        .header("Origin", "https://app.example.com")
+        .header("Access-Control-Request-Method", "POST")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()["access-control-allow-origin"],
+        "https://app.example.com"
+    );
+
+    // Gzip-compressed request body => CREATED
+    let vehicle_json = json!({"vin": "vin1", "engine_type": "Combustion"});
+    let mut body = Vec::new();
+    async_compression::tokio::bufread::GzipEncoder::new(&serde_json::to_vec(&vehicle_json)?[..])
+        .read_to_end(&mut body)
+        .await?;
+    let res = client
+        .post(format!("http://{}/vehicle", ctx.addr))
+        .header("Content-Type", "application/json")
+        .header("Content-Encoding", "gzip")
+        .body(body)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    // Too large request body => PAYLOAD_TOO_LARGE
+    let res = client
+        .post(format!("http://{}/vehicle", ctx.addr))
+        .json(&json!({"vin": "vin2".repeat(50), "engine_type": "Combustion"}))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Compressed response
+    let res = client
+        .get(format!("http://{}/vehicle/export", ctx.addr))
+        .header("Accept-Encoding", "br, gzip;q=0.5")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-encoding"], "br");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_http2_and_client_certificate() -> Result<()> {
+    // CA, server certificate and client certificate
+    let mut ca_params = rcgen::CertificateParams::new(vec![]);
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = rcgen::Certificate::from_params(ca_params)?;
+    let server_cert = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+        "localhost".to_string(),
+    ]))?;
+    let mut client_params = rcgen::CertificateParams::new(vec![]);
+    client_params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "client1");
+    let client_cert = rcgen::Certificate::from_params(client_params)?;
+
+    let dir = std::env::temp_dir().join(format!("hello-test-tls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let tls_config = hello::tls::TlsConfig {
+        cert_path: dir.join("cert.pem"),
+        key_path: dir.join("key.pem"),
+        client_ca_path: Some(dir.join("ca.pem")),
+    };
+    std::fs::write(
+        &tls_config.cert_path,
+        server_cert.serialize_pem_with_signer(&ca)?,
+    )?;
+    std::fs::write(
+        &tls_config.key_path,
+        server_cert.serialize_private_key_pem(),
+    )?;
+    std::fs::write(dir.join("ca.pem"), ca.serialize_pem()?)?;
+
+    let queries = Arc::new(create_test_queries().await?);
+    let addr = serve_tls(queries, hello::config::Config::default(), &tls_config).await?;
+    let url = format!("https://localhost:{}/vehicle/vin1", addr.port());
+
+    let ca_cert = reqwest::Certificate::from_pem(ca.serialize_pem()?.as_bytes())?;
+
+    // No client certificate => handshake failure
+    let client = reqwest::Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(ca_cert.clone())
+        .build()?;
+    assert!(client.get(&url).send().await.is_err());
+
+    // Client certificate => HTTP/2
+    let identity = reqwest::Identity::from_pem(
+        format!(
+            "{}{}",
+            client_cert.serialize_private_key_pem(),
+            client_cert.serialize_pem_with_signer(&ca)?
+        )
+        .as_bytes(),
+    )?;
+    let client = reqwest::Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(ca_cert)
+        .identity(identity)
+        .build()?;
+    let res = client.get(&url).send().await?;
+    assert_eq!(res.version(), reqwest::Version::HTTP_2);
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unix_socket() -> Result<()> {
+    use futures::StreamExt;
+    use hello::listener::{self, IntoMakeConnectionService, ListenAddr, Listener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = std::env::temp_dir().join(format!("hello-test-unix-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("hello.sock");
+
+    // App on a Unix socket
+    let listener = Listener::bind(&ListenAddr::Unix(path.clone()), Some(0o600))?;
+    let shutdown = Shutdown::new();
+    let app = hello::app::App::new(
+        Arc::new(create_test_queries().await?),
+        hello::config::Config::default(),
+        shutdown.signal(),
+    );
+    let server = axum::Server::builder(hyper::server::accept::from_stream(
+        listener::incoming(vec![listener]).map(Ok::<_, std::io::Error>),
+    ))
+    .serve(IntoMakeConnectionService::new(app.router));
+    tokio::spawn(async move {
+        let _shutdown = shutdown;
+        server.await
+    });
+
+    // Unknown vehicle => NOT_FOUND
+    let mut stream = tokio::net::UnixStream::connect(&path).await?;
+    stream
+        .write_all(b"GET /vehicle/vin1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 404"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health() -> Result<()> {
+    let ctx = Context::try_new().await?;
+
+    let client = reqwest::Client::new();
+
+    for path in vec!["live", "ready"] {
+        let res = client
+            .get(format!("http://{}/health/{}", ctx.addr, path))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    Ok(())
+}
+
+fn json_value(s: &str) -> Result<serde_json::Value> {
+    Ok(serde_json::from_str::<serde_json::Value>(s)?)
+}
+
+const TEST_KEYSPACE: &'static str = "hello_test";
+
+struct Context {
+    queries: Arc<ScyllaQueries>,
+    addr: SocketAddr,
+}
+
+impl Context {
+    async fn try_new() -> Result<Self> {
+        Self::try_new_with_config(hello::config::Config::default()).await
+    }
+
+    async fn try_new_with_config(config: hello::config::Config) -> Result<Self> {
+        let queries = Arc::new(create_test_queries().await?);
+        let addr = serve(queries.clone(), config).await?;
+
+        Ok(Self { queries, addr })
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {}
+}
+async fn create_test_queries() -> Result<ScyllaQueries> {
+    use scylla::SessionBuilder;
+
+    let uri = std::env::var("SCYLLA_URI").unwrap_or_else(|_| "127.0.0.1:9042".to_string());
+    let session = SessionBuilder::new().known_node(uri).build().await.unwrap();
+
+    // First, delete test keyspace to have a fresh test data
+    session
+        .query(format!("DROP KEYSPACE IF EXISTS {}", TEST_KEYSPACE), &[])
+        .await
+        .unwrap_or_default();
+
+    Ok(ScyllaQueries::new(session, TEST_KEYSPACE).await?)
+}
+
+async fn serve<Q: Queries>(queries: Arc<Q>, config: hello::config::Config) -> Result<SocketAddr> {
+    // TCP listener
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let listener = TcpListener::bind(&addr)?;
+    let addr = listener.local_addr()?;
+
+    // App (never shut down, the shutdown is kept by the server task)
+    let shutdown = Shutdown::new();
+    let app = hello::app::App::new(queries, config, shutdown.signal());
+
+    // Run our app
+    tracing::debug!("listening on {:?}", listener);
+    let server = axum::Server::from_tcp(listener)?.serve(
+        app.router
+            .into_make_service_with_connect_info::<SocketAddr, _>(),
+    );
+    tokio::spawn(async move {
+        let _shutdown = shutdown;
+        server.await
+    });
+
+    Ok(addr)
+}
+
+/// Serve the app over TLS (certificate files of the config)
+async fn serve_tls<Q: Queries>(
+    queries: Arc<Q>,
+    config: hello::config::Config,
+    tls_config: &hello::tls::TlsConfig,
+) -> Result<SocketAddr> {
+    use hello::{
+        listener::{self, IntoMakeConnectionService, Listener},
+        tls::{self, ReloadingCertResolver},
+    };
+
+    // TCP listener
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    let addr = listener.local_addr()?;
+
+    // TLS acceptor
+    let resolver = Arc::new(ReloadingCertResolver::try_new(
+        &tls_config.cert_path,
+        &tls_config.key_path,
+    )?);
+    let acceptor = tls::create_server_config(tls_config, resolver)?.into();
+
+    // App (never shut down, the shutdown is kept by the server task)
+    let shutdown = Shutdown::new();
+    let app = hello::app::App::new(queries, config, shutdown.signal());
+
+    // Run our app
+    let connections = listener::incoming(vec![Listener::Tcp(listener)]);
+    let server = axum::Server::builder(hyper::server::accept::from_stream(tls::incoming(
+        connections,
+        acceptor,
+    )))
+    .serve(IntoMakeConnectionService::new(app.router));
+    tokio::spawn(async move {
+        let _shutdown = shutdown;
+        server.await
+    });
+
+    Ok(addr)
+}
+
+/// Receive the webhook deliveries: (signature header, body)
+async fn serve_webhook_receiver() -> Result<(
+    SocketAddr,
+    tokio::sync::mpsc::UnboundedReceiver<(String, String)>,
+)> {
+    use axum::{handler::post, http::HeaderMap, Router};
+
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    // TCP listener
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let listener = TcpListener::bind(&addr)?;
+    let addr = listener.local_addr()?;
+
+    // Receiver app
+    let router = Router::new().route(
+        "/hook",
+        post(move |headers: HeaderMap, body: String| {
+            let sender = sender.clone();
+            async move {
+                let signature = headers
+                    .get(hello::webhooks::SIGNATURE_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                let _ = sender.send((signature, body));
+
+                StatusCode::OK
+            }
+        }),
+    );
+
+    let server = axum::Server::from_tcp(listener)?.serve(router.into_make_service());
+    tokio::spawn(async move { server.await });
+
+    Ok((addr, receiver))
+}
+
+/// Read the server-sent events until the marker is received
+async fn read_events_until<S>(events: S, marker: &str) -> Result<String>
+where
+    S: futures::Stream<Item = reqwest::Result<axum::body::Bytes>>,
+{
+    use futures::StreamExt;
+
+    let mut events = Box::pin(events);
+    let mut text = String::new();
+    while !text.contains(marker) {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("event stream closed"))??;
+        text.push_str(&String::from_utf8_lossy(&chunk));
+    }
+
+    Ok(text)
+}
+
+/// Read the next JSON message of the WebSocket
+async fn next_ws_message<S>(socket: &mut S) -> Result<serde_json::Value>
+where
+    S: futures::Stream<
+            Item = Result<
+                tokio_tungstenite::tungstenite::Message,
+                tokio_tungstenite::tungstenite::Error,
+            >,
+        > + Unpin,
+{
+    use futures::StreamExt;
+
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("WebSocket closed"))??;
+
+        if let tokio_tungstenite::tungstenite::Message::Text(text) = message {
+            return json_value(&text);
+        }
+    }
 }